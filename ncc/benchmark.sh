@@ -0,0 +1,19 @@
+#!/usr/bin/env bash
+# Compile programs with -DBENCHMARK and time them with each UVM execution engine
+# Usage: ./benchmark.sh [programs...], defaults to fib.c and raycaster.c
+set -e
+
+PROGRAMS=${*:-examples/fib.c examples/raycaster.c}
+TIMEFORMAT="%Rs"
+
+cargo build --release
+(cd ../vm && cargo build --release)
+
+for PROGRAM in $PROGRAMS; do
+    ./target/release/ncc -DBENCHMARK $PROGRAM
+
+    for ENGINE in "" "--predecode" "--jit"; do
+        echo -n "$PROGRAM ${ENGINE:-(interpreter)}: "
+        time ../vm/target/release/uvm $ENGINE out.asm > /dev/null
+    done
+done
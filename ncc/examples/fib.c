@@ -4,6 +4,14 @@
 
 #include <assert.h>
 
+#ifdef BENCHMARK
+#define FIB_N 32
+#define FIB_RESULT 2178309
+#else
+#define FIB_N 27
+#define FIB_RESULT 196418
+#endif
+
 unsigned long fib(unsigned long n)
 {
     if (n < 2)
@@ -14,6 +22,6 @@ unsigned long fib(unsigned long n)
 
 void main()
 {
-    unsigned long r = fib(27);
-    assert(r == 196418);
+    unsigned long r = fib(FIB_N);
+    assert(r == FIB_RESULT);
 }
@@ -5,12 +5,16 @@
 #include <stdlib.h>
 #include <uvm/syscalls.h>
 #include <uvm/utils.h>
+#include <uvm/net.h>
 
 #define MAX_NAME_LEN 32
 #define MAX_MSG_LEN 2048
 #define MAX_USERS 512
 #define MAX_MESSAGES 4096
 
+// One poll entry for the listening socket plus one per user
+#define MAX_POLL_ENTRIES 513
+
 // User states
 #define STATE_DEFAULT 0
 #define STATE_PICK_NAME 1
@@ -58,6 +62,10 @@ size_t num_messages = 0;
 // TCP socket to listen for new connections
 u64 listen_sock;
 
+// Sockets to poll, entry 0 is the listening socket
+NetPollEntry poll_entries[MAX_POLL_ENTRIES];
+u64 num_poll_entries = 0;
+
 // Process start time
 u64 start_time;
 
@@ -104,6 +112,26 @@ void free_user(user_t* p_user)
     --num_users;
 }
 
+void add_poll_entry(u64 socket_id)
+{
+    assert(num_poll_entries < MAX_POLL_ENTRIES);
+    NetPollEntry* p_entry = &poll_entries[num_poll_entries];
+    p_entry->socket_id = socket_id;
+    p_entry->events = NET_POLL_READ;
+    p_entry->revents = 0;
+    ++num_poll_entries;
+}
+
+void remove_poll_entry(u64 idx)
+{
+    // Move the last entry into the freed slot
+    --num_poll_entries;
+    if (idx != num_poll_entries)
+    {
+        memcpy(&poll_entries[idx], &poll_entries[num_poll_entries], sizeof(NetPollEntry));
+    }
+}
+
 // Strip trailing whitespace from a string
 void rstrip_ws(char* buf)
 {
@@ -217,11 +245,15 @@ void write_post_help(u64 socket_id)
 
 void on_new_conn(u64 listen_sock)
 {
-    puts("got new connection");
-
     char client_addr[128];
-    u64 client_sock = net_accept(listen_sock, client_addr, sizeof(client_addr), on_incoming_data);
+    i64 client_sock = net_accept(listen_sock, client_addr, sizeof(client_addr));
+
+    if (client_sock < 0)
+    {
+        return;
+    }
 
+    puts("got new connection");
     printf("client address: %s\n", client_addr);
 
     user_t* p_user = alloc_user(client_sock);
@@ -234,6 +266,8 @@ void on_new_conn(u64 listen_sock)
         return;
     }
 
+    add_poll_entry(client_sock);
+
     char* welcome_msg = (
         "\n"
         "##########################################################\n"
@@ -271,22 +305,34 @@ void on_new_conn(u64 listen_sock)
     write_help(client_sock);
 }
 
-void on_incoming_data(u64 socket_id, u64 num_bytes)
+// Returns zero if the connection was closed
+int on_incoming_data(u64 socket_id)
 {
-    printf("received %d bytes of incoming data\n", num_bytes);
-
     char read_buf[2048];
     memset(read_buf, 0, sizeof(read_buf));
 
-    // If we receive a ton of bytes at once, this is likely
-    // malicious, close the socket and ignore it.
-    if (num_bytes > sizeof(read_buf) - 1)
+    i64 num_bytes = net_read(socket_id, read_buf, sizeof(read_buf) - 1);
+
+    if (num_bytes == NET_ERR_WOULD_BLOCK)
     {
+        return 1;
+    }
+
+    // Find the user associated with this socket
+    user_t* p_user = find_user(socket_id);
+
+    // If the connection was closed or we receive a ton of bytes
+    // at once, which is likely malicious, close the socket
+    if (num_bytes <= 0 || num_bytes == sizeof(read_buf) - 1)
+    {
+        puts("closing connection");
         net_close(socket_id);
-        return;
+        if (p_user)
+            free_user(p_user);
+        return 0;
     }
 
-    net_read(socket_id, read_buf, sizeof(read_buf) - 1);
+    printf("received %d bytes of incoming data\n", num_bytes);
 
     // If the buffer contains control characters, ignore it
     for (int i = 0; i < sizeof(read_buf); ++i)
@@ -295,16 +341,13 @@ void on_incoming_data(u64 socket_id, u64 num_bytes)
         if (ch == 0)
             break;
         if (!isprint(ch) && ch != '\r' && ch != '\n')
-            return;
+            return 1;
     }
 
-    // Find the user associated with this socket
-    user_t* p_user = find_user(socket_id);
-
     if (!p_user)
     {
         puts("could not find user for socket id");
-        return;
+        return 1;
     }
 
     puts(read_buf);
@@ -319,13 +362,13 @@ void on_incoming_data(u64 socket_id, u64 num_bytes)
         if (len == 0)
         {
             write_str(socket_id, "Username too short. Try again:\n");
-            return;
+            return 1;
         }
 
         if (len > MAX_NAME_LEN - 1)
         {
             write_str(socket_id, "Username too long. Try again:\n");
-            return;
+            return 1;
         }
 
         for (int i = 0; i < len; ++i)
@@ -333,7 +376,7 @@ void on_incoming_data(u64 socket_id, u64 num_bytes)
             if (!isalnum(read_buf[i]) && read_buf[i] != '_')
             {
                 write_str(socket_id, "Username should only contain alphanumeric characters and underscores. Try again:\n");
-                return;
+                return 1;
             }
         }
 
@@ -345,7 +388,7 @@ void on_incoming_data(u64 socket_id, u64 num_bytes)
         p_user->state = STATE_WRITE_MSG;
         write_post_help(socket_id);
 
-        return;
+        return 1;
     }
 
     if (p_user->state == STATE_WRITE_MSG)
@@ -377,7 +420,7 @@ void on_incoming_data(u64 socket_id, u64 num_bytes)
             p_user->state = STATE_DEFAULT;
             memset(p_user->msg_buf, 0, sizeof(p_user->msg_buf));
 
-            return;
+            return 1;
         }
 
         // Add back a newline on the message buffer because
@@ -392,7 +435,7 @@ void on_incoming_data(u64 socket_id, u64 num_bytes)
 
         printf("strlen(p_user->msg_buf)=%d\n", strlen(p_user->msg_buf));
 
-        return;
+        return 1;
     }
 
     char ch = toupper(read_buf[0]);
@@ -412,7 +455,7 @@ void on_incoming_data(u64 socket_id, u64 num_bytes)
             "Visit https://github.com/maximecb/uvm for more! :)\n"
             "\n"
         );
-        return;
+        return 1;
     }
 
     // Exit/Quit
@@ -422,8 +465,8 @@ void on_incoming_data(u64 socket_id, u64 num_bytes)
         char* response = "Goodbye!\n";
         net_write(socket_id, response, strlen(response));
         net_close(socket_id);
-        free_user(socket_id);
-        return;
+        free_user(p_user);
+        return 0;
     }
 
     // Post message
@@ -441,7 +484,7 @@ void on_incoming_data(u64 socket_id, u64 num_bytes)
             write_post_help(socket_id);
         }
 
-        return;
+        return 1;
     }
 
     // Read message
@@ -452,13 +495,13 @@ void on_incoming_data(u64 socket_id, u64 num_bytes)
             write_str(socket_id, "\n");
             write_str(socket_id, "There are no messages to read. Enter (P) to write one!\n");
             write_str(socket_id, "\n");
-            return;
+            return 1;
         }
 
         p_user->state = STATE_READ_MSGS;
         p_user->cur_index = (int)num_messages - 1;
         view_cur_message(p_user);
-        return;
+        return 1;
     }
 
     // Next message
@@ -470,11 +513,12 @@ void on_incoming_data(u64 socket_id, u64 num_bytes)
             p_user->cur_index = (int)num_messages - 1;
 
         view_cur_message(p_user);
-        return;
+        return 1;
     }
 
     //write_str(socket_id, "\nUnknown command\n\n");
     write_help(socket_id);
+    return 1;
 }
 
 void main()
@@ -482,7 +526,36 @@ void main()
     start_time = time_current_ms();
 
     puts("Starting TCP server");
-    listen_sock = net_listen("0.0.0.0:9001", on_new_conn);
+    i64 sock = net_listen("0.0.0.0:9001");
 
-    enable_event_loop();
+    if (sock < 0)
+    {
+        puts("Could not listen on port 9001");
+        return;
+    }
+
+    listen_sock = sock;
+    add_poll_entry(listen_sock);
+
+    for (;;)
+    {
+        net_poll(poll_entries, num_poll_entries, -1);
+
+        // Iterate backwards so that closed connections can be removed
+        for (int i = (int)num_poll_entries - 1; i > 0; --i)
+        {
+            if (poll_entries[i].revents != 0)
+            {
+                if (!on_incoming_data(poll_entries[i].socket_id))
+                {
+                    remove_poll_entry(i);
+                }
+            }
+        }
+
+        if (poll_entries[0].revents & NET_POLL_READ)
+        {
+            on_new_conn(listen_sock);
+        }
+    }
 }
@@ -177,6 +177,29 @@ void mouseup(u16 btn_id)
 
 Event event;
 
+void on_event(Event* p_event)
+{
+    if (p_event->kind == EVENT_QUIT)
+    {
+        exit(0);
+    }
+
+    if (p_event->kind == EVENT_MOUSEDOWN)
+    {
+        mousedown(p_event->button);
+    }
+
+    if (p_event->kind == EVENT_MOUSEUP)
+    {
+        mouseup(p_event->button);
+    }
+
+    if (p_event->kind == EVENT_MOUSEMOVE)
+    {
+        mousemove(p_event->x, p_event->y);
+    }
+}
+
 void main()
 {
     window_create(FRAME_WIDTH, FRAME_HEIGHT, "UVM Paint Program Example", 0);
@@ -197,28 +220,6 @@ void main()
 
     window_draw_frame(0, frame_buffer);
 
-    for (;;)
-    {
-        window_wait_event(&event);
-
-        if (event.kind == EVENT_QUIT)
-        {
-            exit(0);
-        }
-
-        if (event.kind == EVENT_MOUSEDOWN)
-        {
-            mousedown(event.button);
-        }
-
-        if (event.kind == EVENT_MOUSEUP)
-        {
-            mouseup(event.button);
-        }
-
-        if (event.kind == EVENT_MOUSEMOVE)
-        {
-            mousemove(event.x, event.y);
-        }
-    }
+    // Return to the VM, which calls on_event for each window event
+    window_on_event(0, &event, on_event);
 }
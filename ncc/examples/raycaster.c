@@ -339,7 +339,9 @@ void draw_frame()
     u64 frame_end_time = time_current_ms();
     printf("render time %d ms\n", frame_end_time - frame_start_time);
 
+#ifndef BENCHMARK
     window_draw_frame(0, frame_buffer);
+#endif
 }
 
 void keydown(u16 keycode)
@@ -386,6 +388,17 @@ Event event;
 
 void main()
 {
+#ifdef BENCHMARK
+    // Render a fixed number of frames without a window,
+    // turning the camera between frames
+    for (int i = 0; i < 100; ++i)
+    {
+        draw_frame();
+        keydown(KEY_LEFT);
+    }
+    return;
+#endif
+
     window_create(FRAME_WIDTH, FRAME_HEIGHT, "Ray-Casting Example", 0);
 
     for (;;)
@@ -0,0 +1,132 @@
+#include <stdio.h>
+#include <string.h>
+#include <uvm/syscalls.h>
+#include <uvm/net.h>
+
+// One entry for the listening socket plus up to 64 connections
+#define MAX_ENTRIES 65
+
+// Entry 0 is the listening socket, the others are client connections
+NetPollEntry poll_entries[MAX_ENTRIES];
+u64 num_entries = 0;
+
+void add_poll_entry(u64 socket_id)
+{
+    NetPollEntry* p_entry = &poll_entries[num_entries];
+    p_entry->socket_id = socket_id;
+    p_entry->events = NET_POLL_READ;
+    p_entry->revents = 0;
+    ++num_entries;
+}
+
+void remove_poll_entry(u64 idx)
+{
+    // Move the last entry into the freed slot
+    --num_entries;
+    if (idx != num_entries)
+    {
+        memcpy(&poll_entries[idx], &poll_entries[num_entries], sizeof(NetPollEntry));
+    }
+}
+
+void on_new_conn(u64 listen_sock)
+{
+    char client_addr[128];
+    i64 conn_sock = net_accept(listen_sock, client_addr, sizeof(client_addr));
+
+    if (conn_sock < 0)
+    {
+        return;
+    }
+
+    puts("got new connection");
+    printf("client address: %s\n", client_addr);
+
+    if (num_entries == MAX_ENTRIES)
+    {
+        puts("too many connections");
+        net_close(conn_sock);
+        return;
+    }
+
+    add_poll_entry(conn_sock);
+}
+
+// Returns zero if the connection was closed
+int on_incoming_data(u64 socket_id)
+{
+    char read_buf[1024];
+    memset(read_buf, 0, 1024);
+    i64 num_bytes = net_read(socket_id, read_buf, 1024 - 1);
+
+    if (num_bytes == NET_ERR_WOULD_BLOCK)
+    {
+        return 1;
+    }
+
+    if (num_bytes <= 0)
+    {
+        puts("connection closed");
+        net_close(socket_id);
+        return 0;
+    }
+
+    printf("received %d bytes of incoming data\n", num_bytes);
+
+    // If this is a telnet command (non-printable), ignore it
+    if (read_buf[0] & 0x80)
+    {
+        return 1;
+    }
+
+    puts(read_buf);
+
+    if (strncmp(read_buf, "exit", 4) == 0)
+    {
+        puts("got exit command");
+        char* response = "Goodbye!\n";
+        net_write(socket_id, response, strlen(response));
+        net_close(socket_id);
+        return 0;
+    }
+
+    char* response = "Hello!\n";
+    net_write(socket_id, response, strlen(response));
+    return 1;
+}
+
+void main()
+{
+    puts("starting TCP server");
+    i64 listen_sock = net_listen("127.0.0.1:9000");
+
+    if (listen_sock < 0)
+    {
+        puts("could not listen on port 9000");
+        return;
+    }
+
+    add_poll_entry(listen_sock);
+
+    for (;;)
+    {
+        net_poll(poll_entries, num_entries, -1);
+
+        // Iterate backwards so that closed connections can be removed
+        for (int i = (int)num_entries - 1; i > 0; --i)
+        {
+            if (poll_entries[i].revents != 0)
+            {
+                if (!on_incoming_data(poll_entries[i].socket_id))
+                {
+                    remove_poll_entry(i);
+                }
+            }
+        }
+
+        if (poll_entries[0].revents & NET_POLL_READ)
+        {
+            on_new_conn(listen_sock);
+        }
+    }
+}
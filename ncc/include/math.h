@@ -13,30 +13,19 @@
 #define sinf(f) (asm (f) -> float { sin_f32; })
 #define cosf(f) (asm (f) -> float { cos_f32; })
 #define tanf(f) (asm (f) -> float { tan_f32; })
-#define atanf(f) (asm (f) -> float { atans_f32; })
+#define atanf(f) (asm (f) -> float { atan_f32; })
+#define atan2f(y, x) (asm (y, x) -> float { atan2_f32; })
 #define powf(x, y) (asm (x, y) -> float { pow_f32; })
 #define sqrtf(f) (asm (f) -> float { sqrt_f32; })
-
-float fabsf(float x)
-{
-    if (x < 0)
-        return -x;
-    return x;
-}
-
-float floorf(float x)
-{
-    float xi = (float)(int)x;
-
-    if (x < xi)
-        return xi - 1.0f;
-
-    return xi;
-}
-
-float truncf(float x)
-{
-    return (float)(int)x;
-}
+#define expf(f) (asm (f) -> float { exp_f32; })
+#define logf(f) (asm (f) -> float { log_f32; })
+#define fabsf(f) (asm (f) -> float { abs_f32; })
+#define floorf(f) (asm (f) -> float { floor_f32; })
+#define ceilf(f) (asm (f) -> float { ceil_f32; })
+#define roundf(f) (asm (f) -> float { round_f32; })
+#define truncf(f) (asm (f) -> float { trunc_f32; })
+#define fminf(x, y) (asm (x, y) -> float { min_f32; })
+#define fmaxf(x, y) (asm (x, y) -> float { max_f32; })
+#define fmodf(x, y) (asm (x, y) -> float { mod_f32; })
 
 #endif
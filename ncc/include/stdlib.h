@@ -98,7 +98,7 @@ void srand(unsigned int seed)
     __cur_rand__ = (seed << 1) + 1;
 }
 
-#define align_ptr(ptr, n_bytes) (((u64)(ptr) + ((n_bytes) - 1)) & ~((n_bytes) - 1))
+#define align_ptr(ptr, n_bytes) (((u64)(ptr) + ((n_bytes) - 1)) & ~((u64)(n_bytes) - 1))
 
 u64 __heap_size__ = 0;
 u8* __next_alloc__ = 0;
@@ -115,14 +115,24 @@ void* malloc(size_t size)
     // Bump the allocation pointer
     u8* header_ptr = __next_alloc__;
     u8* block_ptr = header_ptr + 8;
-    __next_alloc__ = align_ptr(block_ptr + size, 8);
+    u8* next_alloc = align_ptr(block_ptr + size, 8);
 
     // Resize the heap if needed
-    if (__next_alloc__ > __heap_size__)
+    if (next_alloc > __heap_size__)
     {
-        __heap_size__ = asm (__next_alloc__) -> u64 { syscall vm_grow_heap; };
+        u64 new_size = asm (next_alloc) -> u64 { syscall vm_grow_heap; };
+
+        // The heap couldn't be grown
+        if (new_size == 0)
+        {
+            return NULL;
+        }
+
+        __heap_size__ = new_size;
     }
 
+    __next_alloc__ = next_alloc;
+
     // Write a magic word at the beginning of the block for safety checks
     u32* magic_ptr = (u32*)header_ptr;
     *magic_ptr = 0x1337BAB3;
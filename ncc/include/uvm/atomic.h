@@ -0,0 +1,36 @@
+#ifndef __UVM_ATOMIC_H__
+#define __UVM_ATOMIC_H__
+
+// Atomic loads have acquire semantics and atomic stores have
+// release semantics. Read-modify-write operations have
+// acquire-release semantics and evaluate to the previous value.
+// Addresses must be aligned to the size of the operands.
+
+#define atomic_load_u32(p) (asm (p) -> u32 { atomic_load_u32; })
+#define atomic_load_u64(p) (asm (p) -> u64 { atomic_load_u64; })
+#define atomic_store_u32(p, v) (asm (p, v) -> void { atomic_store_u32; })
+#define atomic_store_u64(p, v) (asm (p, v) -> void { atomic_store_u64; })
+
+// Compare-and-swap, evaluates to the value found in memory,
+// which is equal to cmp if the swap succeeded
+#define atomic_cas_u32(p, cmp, v) (asm (p, cmp, v) -> u32 { atomic_cas_u32; })
+#define atomic_cas_u64(p, cmp, v) (asm (p, cmp, v) -> u64 { atomic_cas_u64; })
+
+#define atomic_fetch_add_u32(p, v) (asm (p, v) -> u32 { atomic_fetch_add_u32; })
+#define atomic_fetch_sub_u32(p, v) (asm (p, v) -> u32 { atomic_fetch_sub_u32; })
+#define atomic_fetch_and_u32(p, v) (asm (p, v) -> u32 { atomic_fetch_and_u32; })
+#define atomic_fetch_or_u32(p, v) (asm (p, v) -> u32 { atomic_fetch_or_u32; })
+#define atomic_fetch_xor_u32(p, v) (asm (p, v) -> u32 { atomic_fetch_xor_u32; })
+#define atomic_xchg_u32(p, v) (asm (p, v) -> u32 { atomic_xchg_u32; })
+
+#define atomic_fetch_add_u64(p, v) (asm (p, v) -> u64 { atomic_fetch_add_u64; })
+#define atomic_fetch_sub_u64(p, v) (asm (p, v) -> u64 { atomic_fetch_sub_u64; })
+#define atomic_fetch_and_u64(p, v) (asm (p, v) -> u64 { atomic_fetch_and_u64; })
+#define atomic_fetch_or_u64(p, v) (asm (p, v) -> u64 { atomic_fetch_or_u64; })
+#define atomic_fetch_xor_u64(p, v) (asm (p, v) -> u64 { atomic_fetch_xor_u64; })
+#define atomic_xchg_u64(p, v) (asm (p, v) -> u64 { atomic_xchg_u64; })
+
+// Sequentially consistent memory fence
+#define atomic_fence() (asm () -> void { fence; })
+
+#endif
@@ -0,0 +1,20 @@
+#ifndef __FS_H__
+#define __FS_H__
+
+#include <uvm/syscalls.h>
+
+// Information filled in by the fs_stat syscall
+typedef struct
+{
+    u64 size;
+
+    // Last modification time in milliseconds since the Unix epoch
+    u64 mtime_ms;
+
+    // One of the FS_KIND_* constants
+    u32 kind;
+
+    u32 pad;
+} FsStat;
+
+#endif
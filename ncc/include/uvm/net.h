@@ -0,0 +1,18 @@
+#ifndef __NET_H__
+#define __NET_H__
+
+#include <uvm/syscalls.h>
+
+// Entry passed to the net_poll syscall
+typedef struct
+{
+    u64 socket_id;
+
+    // NET_POLL_* events to wait for
+    u32 events;
+
+    // NET_POLL_* events that occurred
+    u32 revents;
+} NetPollEntry;
+
+#endif
@@ -26,9 +26,13 @@
 #define vm_heap_size() asm () -> u64 { syscall vm_heap_size; }
 
 // u64 vm_grow_heap(u64 num_bytes)
-// Grow the heap to a new size given in bytes. This is similar to the `brk()` system call on POSIX systems. Note that the heap may be resized to a size larger than requested. The heap size is guaranteed to be a multiple of 8 bytes. If the requested size is smaller than the current heap size, this is a no-op. Returns the new heap size in bytes.
+// Grow the heap to a new size given in bytes. This is similar to the `brk()` system call on POSIX systems. Note that the heap may be resized to a size larger than requested. The heap size is guaranteed to be a multiple of 8 bytes. If the requested size is smaller than the current heap size, this is a no-op. Returns the new heap size in bytes, or zero if the heap can't be grown, for example because this would exceed the maximum heap size set with the `--max-heap` command-line option. The heap size is unchanged in that case.
 #define vm_grow_heap(__num_bytes) asm (__num_bytes) -> u64 { syscall vm_grow_heap; }
 
+// i64 vm_snapshot(const char* path)
+// Save the state of the program to a snapshot file, which can be resumed with `uvm --resume <path>`. Only single-threaded programs run by the interpreters can be saved: this returns `SNAPSHOT_ERR_UNSUPPORTED` when other threads haven't been joined, or when the JIT compiler is enabled with `--jit` or `--jit-threshold`. The snapshot holds the code, the heap, and the call stack and thread-local variables of the calling thread. Host resources such as windows, files, sockets, timers and callbacks aren't saved. Returns 0 once the snapshot is saved, and 1 when execution continues from this call in a resumed program. The file is written like with `fs_open`, so it must be inside a directory granted with `--fs-root`, and file errors are returned as `FS_ERR_*` codes.
+#define vm_snapshot(__path) asm (__path) -> i64 { syscall vm_snapshot; }
+
 // void exit(i8 status)
 // End program execution with the specified exit status.
 #define exit(__status) asm (__status) -> void { syscall exit; }
@@ -45,10 +49,38 @@
 // Make the current thread sleep for at least the given time in milliseconds.
 #define thread_sleep(__time_ms) asm (__time_ms) -> void { syscall thread_sleep; }
 
+// void thread_sleep_us(u64 time_us)
+// Make the current thread sleep for at least the given time in microseconds. This is finer-grained than `thread_sleep`, for uses such as frame pacing.
+#define thread_sleep_us(__time_us) asm (__time_us) -> void { syscall thread_sleep_us; }
+
 // u64 thread_join(u64 tid)
 // Join on the thread with the given id. Produces the return value for the thread.
 #define thread_join(__tid) asm (__tid) -> u64 { syscall thread_join; }
 
+// i64 futex_wait(u32* addr, u32 expected, i64 timeout_ms)
+// Block the current thread until woken by `futex_wake` on the same address, as long as the 32-bit word at `addr` holds the `expected` value. The check and the start of the wait happen atomically with respect to `futex_wake`. A negative timeout waits indefinitely. Returns 0 when woken, `FUTEX_ERR_VALUE_CHANGED` if the word didn't hold the expected value, or `FUTEX_ERR_TIMED_OUT` if the timeout expired.
+#define futex_wait(__addr, __expected, __timeout_ms) asm (__addr, __expected, __timeout_ms) -> i64 { syscall futex_wait; }
+
+// u64 futex_wake(u32* addr, u64 count)
+// Wake up to `count` threads blocked in `futex_wait` on the given address, in the order they started waiting. Returns the number of threads woken.
+#define futex_wake(__addr, __count) asm (__addr, __count) -> u64 { syscall futex_wake; }
+
+// u64 chan_create(u64 capacity)
+// Create a channel to pass messages between threads. The capacity is the maximum number of queued messages, zero meaning unbounded. Returns a channel id.
+#define chan_create(__capacity) asm (__capacity) -> u64 { syscall chan_create; }
+
+// i64 chan_send(u64 chan_id, const void* buf, u64 len)
+// Send a message on a channel. The message bytes are copied, so the buffer can be reused immediately. Blocks while the channel is full. Returns 0 on success or `CHAN_ERR_CLOSED` if the channel is closed.
+#define chan_send(__chan_id, __buf, __len) asm (__chan_id, __buf, __len) -> i64 { syscall chan_send; }
+
+// i64 chan_recv(u64 chan_id, void* buf, u64 buf_len, i64 timeout_ms)
+// Receive the next message from a channel into a buffer, blocking until one is available or the timeout expires. A timeout of zero returns immediately and a negative timeout waits indefinitely. Returns the message length, `CHAN_ERR_TIMED_OUT` if no message arrived in time, `CHAN_ERR_CLOSED` if the channel is closed and has no queued messages left, or `CHAN_ERR_TOO_LONG` if the next message doesn't fit in the buffer, in which case it stays queued.
+#define chan_recv(__chan_id, __buf, __buf_len, __timeout_ms) asm (__chan_id, __buf, __buf_len, __timeout_ms) -> i64 { syscall chan_recv; }
+
+// void chan_close(u64 chan_id)
+// Close a channel. Threads blocked sending or receiving on the channel are woken up. Messages already queued can still be received. The channel is freed once it is closed and drained. Sending or receiving on it afterwards returns `CHAN_ERR_CLOSED`, and closing it again has no effect.
+#define chan_close(__chan_id) asm (__chan_id) -> void { syscall chan_close; }
+
 // void print_i64(i64 val)
 // Print an i64 value to standard output.
 #define print_i64(__val) asm (__val) -> void { syscall print_i64; }
@@ -77,6 +109,22 @@
 // Get the UNIX time stamp in milliseconds.
 #define time_current_ms() asm () -> u64 { syscall time_current_ms; }
 
+// u64 time_monotonic_ns()
+// Get the time in nanoseconds from a monotonic clock, which doesn't jump when the system time is changed. Its starting point is unspecified, so it is only meaningful for measuring elapsed time.
+#define time_monotonic_ns() asm () -> u64 { syscall time_monotonic_ns; }
+
+// bool time_local(u64 timestamp_ms, void* p_time)
+// Break down a UNIX time stamp in milliseconds into the local date and time. The result is written into a `{ i32 year; u32 month; u32 day; u32 hour; u32 minute; u32 second; i32 utc_offset; }` struct where `month` and `day` start at 1 and `utc_offset` is the offset of the local time zone from UTC in seconds. Returns false if the time stamp can't be represented.
+#define time_local(__timestamp_ms, __p_time) asm (__timestamp_ms, __p_time) -> bool { syscall time_local; }
+
+// u64 timer_set(u64 delay_ms, void* callback)
+// Call a function once after a delay in milliseconds. The callback is called by the event loop, which runs on the main thread after `main` returns, and receives the timer id as argument. Returns a timer id.
+#define timer_set(__delay_ms, __callback) asm (__delay_ms, __callback) -> u64 { syscall timer_set; }
+
+// void timer_cancel(u64 timer_id)
+// Cancel a pending timer. Cancelling a timer that has already fired does nothing.
+#define timer_cancel(__timer_id) asm (__timer_id) -> void { syscall timer_cancel; }
+
 // u32 window_create(u32 width, u32 height, const char* title, u64 flags)
 // Create a new window with a frame buffer to draw into. The window is initially hidden when created, and will appear as soon as the first frame of image data is drawn.
 #define window_create(__width, __height, __title, __flags) asm (__width, __height, __title, __flags) -> u32 { syscall window_create; }
@@ -93,8 +141,12 @@
 // Block until an window event is available.
 #define window_wait_event(__p_event) asm (__p_event) -> void { syscall window_wait_event; }
 
+// void window_on_event(u32 window_id, void* p_event, void* callback)
+// Register a callback called by the event loop for each window event. Each event is written into the event struct at `p_event`, which is then passed to the callback. Passing a null callback unregisters the callback. Must be called from the main thread.
+#define window_on_event(__window_id, __p_event, __callback) asm (__window_id, __p_event, __callback) -> void { syscall window_on_event; }
+
 // u32 audio_open_output(u32 sample_rate, u16 num_channels, u16 format, void* callback)
-// Open an audio output device, then spawn a new thread which will regularly call the specified callback function to generate audio samples.
+// Open an audio output device, then spawn a new thread which will regularly call the specified callback function to generate audio samples. Samples played on voices created with `audio_voice_create` are mixed on top of the samples produced by the callback.
 #define audio_open_output(__sample_rate, __num_channels, __format, __callback) asm (__sample_rate, __num_channels, __format, __callback) -> u32 { syscall audio_open_output; }
 
 // u32 audio_open_input(u32 sample_rate, u16 num_channels, u16 format, void* callback)
@@ -105,26 +157,108 @@
 // Read available input samples. Must be called from the audio input thread.
 #define audio_read_samples(__dst_buf, __num_samples) asm (__dst_buf, __num_samples) -> void { syscall audio_read_samples; }
 
-// u64 net_listen(const char* listen_addr, void* on_new_conn)
-// Open a listening TCP socket to accept incoming connections. A callback function is called when a new connection request is received.
-#define net_listen(__listen_addr, __on_new_conn) asm (__listen_addr, __on_new_conn) -> u64 { syscall net_listen; }
+// u32 audio_voice_create()
+// Create a voice to be mixed into the audio output by the host. The audio output device is opened in stereo at 44100Hz if it isn't open yet. A later call to `audio_open_output` reopens it with the requested number of channels, and voices are mixed in mono if the device is mono. Sound effects and music can each be played on their own voice without the program needing to implement its own mixer. At most 64 voices can exist at once. Must be called from the main thread.
+#define audio_voice_create() asm () -> u32 { syscall audio_voice_create; }
+
+// void audio_voice_submit(u32 voice_id, const void* samples, u32 num_samples, u16 format)
+// Queue a buffer of mono samples to be played on a voice after any samples already queued. The samples are copied, so the buffer can be reused immediately. The format is either `AUDIO_FORMAT_I16` or `AUDIO_FORMAT_F32`, with f32 samples in the [-1, 1] range. The sample rate is 44100Hz.
+#define audio_voice_submit(__voice_id, __samples, __num_samples, __format) asm (__voice_id, __samples, __num_samples, __format) -> void { syscall audio_voice_submit; }
+
+// void audio_voice_set_params(u32 voice_id, f32 volume, f32 pan)
+// Set the volume and stereo panning of a voice. A volume of 1 plays samples unchanged. The pan ranges from -1 (left only) to 1 (right only), with 0 being centered. Panning has no effect on a mono output device.
+#define audio_voice_set_params(__voice_id, __volume, __pan) asm (__voice_id, __volume, __pan) -> void { syscall audio_voice_set_params; }
+
+// u64 audio_voice_queued(u32 voice_id)
+// Get the number of samples queued on a voice that haven't been played yet. This can be used to stream audio by submitting more samples before the queue runs dry.
+#define audio_voice_queued(__voice_id) asm (__voice_id) -> u64 { syscall audio_voice_queued; }
 
-// u64 net_accept(u64 socket_id, char* client_addr_buf, u64 addr_buf_len, void* on_incoming_data)
-// Accept an incoming connection and creates a new socket. A callback function is called when incoming data is received on the new socket.
-#define net_accept(__socket_id, __client_addr_buf, __addr_buf_len, __on_incoming_data) asm (__socket_id, __client_addr_buf, __addr_buf_len, __on_incoming_data) -> u64 { syscall net_accept; }
+// void audio_voice_stop(u32 voice_id)
+// Stop playback on a voice by discarding all of its queued samples. The voice can be reused afterwards.
+#define audio_voice_stop(__voice_id) asm (__voice_id) -> void { syscall audio_voice_stop; }
 
-// u64 net_read(u64 socket_id, u8* buf_ptr, u64 buf_len)
-// Read data from a socket into a buffer with specified capacity. Data can only be read if available.
-#define net_read(__socket_id, __buf_ptr, __buf_len) asm (__socket_id, __buf_ptr, __buf_len) -> u64 { syscall net_read; }
+// void audio_voice_destroy(u32 voice_id)
+// Destroy a voice, discarding its queued samples. The voice id may be returned again by a later call to `audio_voice_create`.
+#define audio_voice_destroy(__voice_id) asm (__voice_id) -> void { syscall audio_voice_destroy; }
 
-// u64 net_write(u64 socket_id, const u8* buf_ptr, u64 buf_len)
-// Write data to an open socket. This function will attempt to write the entire buffer and may block if the output buffer is full.
-#define net_write(__socket_id, __buf_ptr, __buf_len) asm (__socket_id, __buf_ptr, __buf_len) -> u64 { syscall net_write; }
+// i64 net_listen(const char* listen_addr)
+// Open a non-blocking listening TCP socket to accept incoming connections. The address string includes a port number, for example `127.0.0.1:9000`. Returns a socket id, or a negative error code on failure.
+#define net_listen(__listen_addr) asm (__listen_addr) -> i64 { syscall net_listen; }
+
+// i64 net_accept(u64 socket_id, char* client_addr_buf, u64 addr_buf_len)
+// Accept an incoming connection on a listening socket and create a new non-blocking socket for it. The client address is written as a null-terminated string into the buffer, which can be null. Returns `NET_ERR_WOULD_BLOCK` if no connection is pending.
+#define net_accept(__socket_id, __client_addr_buf, __addr_buf_len) asm (__socket_id, __client_addr_buf, __addr_buf_len) -> i64 { syscall net_accept; }
+
+// i64 net_connect(const char* addr, i64 timeout_ms)
+// Open a TCP connection to a remote address and create a new non-blocking socket for it. The address must be a numeric IP address with a port number, for example `127.0.0.1:9000` or `[::1]:9000`, as host names are not resolved. Waits at most `timeout_ms` milliseconds for the connection to be established, and a timeout of zero or less waits as long as the operating system allows. Returns a socket id, or a negative error code such as `NET_ERR_REFUSED` or `NET_ERR_TIMED_OUT` on failure.
+#define net_connect(__addr, __timeout_ms) asm (__addr, __timeout_ms) -> i64 { syscall net_connect; }
+
+// i64 net_read(u64 socket_id, u8* buf_ptr, u64 buf_len)
+// Read data from a socket into a buffer with specified capacity, without blocking. Returns the number of bytes read, zero if the connection was closed by the peer, or `NET_ERR_WOULD_BLOCK` if no data is available yet.
+#define net_read(__socket_id, __buf_ptr, __buf_len) asm (__socket_id, __buf_ptr, __buf_len) -> i64 { syscall net_read; }
+
+// i64 net_write(u64 socket_id, const u8* buf_ptr, u64 buf_len)
+// Write data to an open socket. This function will attempt to write the entire buffer and may block if the output buffer is full. Returns the number of bytes written or a negative error code.
+#define net_write(__socket_id, __buf_ptr, __buf_len) asm (__socket_id, __buf_ptr, __buf_len) -> i64 { syscall net_write; }
 
 // void net_close(u64 socket_id)
-// Close an open socket.
+// Close an open socket. The socket id can't be used afterwards.
 #define net_close(__socket_id) asm (__socket_id) -> void { syscall net_close; }
 
+// i64 net_udp_bind(const char* bind_addr)
+// Open a non-blocking UDP socket bound to a local address. The address string includes a port number, for example `0.0.0.0:9000`, and port 0 picks any free port. Returns a socket id, or a negative error code on failure.
+#define net_udp_bind(__bind_addr) asm (__bind_addr) -> i64 { syscall net_udp_bind; }
+
+// i64 net_udp_send_to(u64 socket_id, const char* dst_addr, const u8* buf_ptr, u64 buf_len)
+// Send a datagram to a numeric destination address such as `127.0.0.1:9000`. This does not block. Returns the number of bytes sent, or a negative error code such as `NET_ERR_WOULD_BLOCK` if the datagram could not be queued.
+#define net_udp_send_to(__socket_id, __dst_addr, __buf_ptr, __buf_len) asm (__socket_id, __dst_addr, __buf_ptr, __buf_len) -> i64 { syscall net_udp_send_to; }
+
+// i64 net_udp_recv_from(u64 socket_id, u8* buf_ptr, u64 buf_len, char* src_addr_buf, u64 addr_buf_len)
+// Receive one datagram into a buffer with specified capacity, without blocking. Bytes that do not fit in the buffer are discarded. The sender address is written as a null-terminated string into the buffer, which can be null, and truncated to fit `addr_buf_len` bytes. Returns the number of bytes received, or `NET_ERR_WOULD_BLOCK` if no datagram is available yet.
+#define net_udp_recv_from(__socket_id, __buf_ptr, __buf_len, __src_addr_buf, __addr_buf_len) asm (__socket_id, __buf_ptr, __buf_len, __src_addr_buf, __addr_buf_len) -> i64 { syscall net_udp_recv_from; }
+
+// u64 net_poll(void* entries, u64 num_entries, i64 timeout_ms)
+// Wait until at least one socket in an array of poll entries is ready, or until the timeout expires. Each entry is a `{ u64 socket_id; u32 events; u32 revents; }` struct where `events` holds the `NET_POLL_*` flags to wait for and `revents` receives the flags for the events that occurred. A listening socket is readable when a connection is pending. A timeout of zero returns immediately and a negative timeout waits indefinitely. Returns the number of entries with a non-zero `revents`.
+#define net_poll(__entries, __num_entries, __timeout_ms) asm (__entries, __num_entries, __timeout_ms) -> u64 { syscall net_poll; }
+
+// void net_on_ready(u64 socket_id, u32 events, void* callback)
+// Register a callback called by the event loop when a socket is ready for the given `NET_POLL_*` events. The callback receives the socket id and the flags for the events that occurred. Passing zero events or a null callback unregisters the callback, and closing the socket unregisters it too.
+#define net_on_ready(__socket_id, __events, __callback) asm (__socket_id, __events, __callback) -> void { syscall net_on_ready; }
+
+// i64 fs_open(const char* path, u32 flags)
+// Open a file and return a file id, or a negative error code on failure. The flags are a combination of the `FS_OPEN_*` constants, and at least one of `FS_OPEN_READ` or `FS_OPEN_WRITE` must be specified. Only files inside the directories granted with the `--fs-root` command-line option can be accessed, otherwise `FS_ERR_ACCESS_DENIED` is returned. Relative paths are relative to the first granted directory. Symlinks that point outside of the granted directories, including dangling ones, can't be opened.
+#define fs_open(__path, __flags) asm (__path, __flags) -> i64 { syscall fs_open; }
+
+// i64 fs_read(u64 file_id, u8* buf_ptr, u64 buf_len)
+// Read data from a file into a buffer with specified capacity. Returns the number of bytes read, zero at the end of the file, or a negative error code on failure.
+#define fs_read(__file_id, __buf_ptr, __buf_len) asm (__file_id, __buf_ptr, __buf_len) -> i64 { syscall fs_read; }
+
+// i64 fs_write(u64 file_id, const u8* buf_ptr, u64 buf_len)
+// Write the entire contents of a buffer to a file. Returns the number of bytes written, or a negative error code on failure.
+#define fs_write(__file_id, __buf_ptr, __buf_len) asm (__file_id, __buf_ptr, __buf_len) -> i64 { syscall fs_write; }
+
+// i64 fs_seek(u64 file_id, i64 offset, u32 whence)
+// Move the read/write position of a file. The offset is relative to the start of the file, the current position or the end of the file depending on `whence`, which is one of the `FS_SEEK_*` constants. Returns the new position from the start of the file, or a negative error code on failure.
+#define fs_seek(__file_id, __offset, __whence) asm (__file_id, __offset, __whence) -> i64 { syscall fs_seek; }
+
+// void fs_close(u64 file_id)
+// Close a file, flushing any pending writes.
+#define fs_close(__file_id) asm (__file_id) -> void { syscall fs_close; }
+
+// i64 fs_stat(const char* path, void* stat_buf)
+// Get information about a file or directory. The information is written into a `{ u64 size; u64 mtime_ms; u32 kind; u32 pad; }` struct where `mtime_ms` is the last modification time in milliseconds since the Unix epoch and `kind` is one of the `FS_KIND_*` constants. Returns zero on success, or a negative error code on failure.
+#define fs_stat(__path, __stat_buf) asm (__path, __stat_buf) -> i64 { syscall fs_stat; }
+
+// i64 fs_list_dir(const char* path, char* buf_ptr, u64 buf_len)
+// List the entries of a directory, sorted by name. The entry names are written into the buffer one after the other, each followed by a null terminator. Returns the number of bytes needed to hold the full list, which can be larger than `buf_len`, in which case only the entries that fit are written. Returns a negative error code on failure.
+#define fs_list_dir(__path, __buf_ptr, __buf_len) asm (__path, __buf_ptr, __buf_len) -> i64 { syscall fs_list_dir; }
+
+#define FUTEX_ERR_VALUE_CHANGED -1
+#define FUTEX_ERR_TIMED_OUT -2
+#define CHAN_ERR_CLOSED -1
+#define CHAN_ERR_TIMED_OUT -2
+#define CHAN_ERR_TOO_LONG -3
+#define SNAPSHOT_ERR_UNSUPPORTED -5
 #define EVENT_QUIT 0
 #define EVENT_KEYDOWN 1
 #define EVENT_KEYUP 2
@@ -186,5 +320,31 @@
 #define KEY_DOWN 16004
 #define KEY_SHIFT 16005
 #define AUDIO_FORMAT_I16 0
+#define AUDIO_FORMAT_F32 1
+#define NET_POLL_READ 1
+#define NET_POLL_WRITE 2
+#define NET_POLL_HUP 4
+#define NET_ERR_WOULD_BLOCK -1
+#define NET_ERR_DISCONNECTED -2
+#define NET_ERR_INVALID_ADDR -3
+#define NET_ERR_ADDR_IN_USE -4
+#define NET_ERR_FAILED -5
+#define NET_ERR_REFUSED -6
+#define NET_ERR_TIMED_OUT -7
+#define FS_OPEN_READ 1
+#define FS_OPEN_WRITE 2
+#define FS_OPEN_CREATE 4
+#define FS_OPEN_TRUNCATE 8
+#define FS_OPEN_APPEND 16
+#define FS_SEEK_SET 0
+#define FS_SEEK_CUR 1
+#define FS_SEEK_END 2
+#define FS_KIND_FILE 1
+#define FS_KIND_DIR 2
+#define FS_KIND_OTHER 3
+#define FS_ERR_NOT_FOUND -1
+#define FS_ERR_ACCESS_DENIED -2
+#define FS_ERR_INVALID -3
+#define FS_ERR_FAILED -4
 
 #endif
@@ -0,0 +1,23 @@
+#ifndef __UVM_TIME_H__
+#define __UVM_TIME_H__
+
+#include <uvm/syscalls.h>
+
+// Local date and time filled in by the time_local syscall
+typedef struct
+{
+    i32 year;
+
+    // Month from 1 to 12 and day of the month from 1 to 31
+    u32 month;
+    u32 day;
+
+    u32 hour;
+    u32 minute;
+    u32 second;
+
+    // Offset of the local time zone from UTC in seconds
+    i32 utc_offset;
+} LocalTime;
+
+#endif
@@ -520,6 +520,9 @@ pub struct Global
 
     // Initialization expression
     pub init_expr: Option<Expr>,
+
+    // Placed in read-only memory, so that writes to it trap
+    pub read_only: bool,
 }
 
 pub type TypeDef = Rc<Box<RefCell<Type>>>;
@@ -539,4 +542,7 @@ pub struct Unit
 
     // Some functions in this unit use stack allocation
     pub stack_alloc: bool,
+
+    // Make the first heap page inaccessible so that null pointer dereferences trap
+    pub null_guard: bool,
 }
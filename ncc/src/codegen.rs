@@ -104,6 +104,24 @@ fn gen_array_init(array_type: &Type, init_expr: &Expr, out: &mut String) -> Resu
     Ok(())
 }
 
+/// Emit a labelled, aligned global variable in the current section
+fn gen_global(global: &Global, out: &mut String) -> Result<(), ParseError>
+{
+    // Align the data
+    let align_bytes = global.var_type.align_bytes();
+    out.push_str(&format!(".align {};\n", align_bytes));
+
+    // Write a label
+    out.push_str(&format!("{}:\n", global.name));
+
+    // Generate initialization data for the global
+    gen_global_init(&global.var_type, &global.init_expr, out)?;
+
+    out.push('\n');
+
+    Ok(())
+}
+
 impl Unit
 {
     pub fn gen_code(&self) -> Result<String, ParseError>
@@ -116,6 +134,10 @@ impl Unit
         out.push_str("#\n");
         out.push_str("\n");
 
+        if self.null_guard {
+            out.push_str(".null_guard;\n");
+        }
+
         out.push_str(".data;\n");
         out.push_str("\n");
 
@@ -124,18 +146,8 @@ impl Unit
         out.push_str("\n");
 
         // Global variable initialization
-        for global in &self.global_vars {
-            // Align the data
-            let align_bytes = global.var_type.align_bytes();
-            out.push_str(&format!(".align {};\n", align_bytes));
-
-            // Write a label
-            out.push_str(&format!("{}:\n", global.name));
-
-            // Generate initialization data for the global
-            gen_global_init(&global.var_type, &global.init_expr, &mut out)?;
-
-            out.push_str("\n");
+        for global in self.global_vars.iter().filter(|g| !g.read_only) {
+            gen_global(global, &mut out)?;
         }
 
         // If any function in this unit uses stack allocation,
@@ -151,6 +163,16 @@ impl Unit
             out.push_str("\n");
         }
 
+        // Constants such as string literals go in read-only memory
+        if self.global_vars.iter().any(|g| g.read_only) {
+            out.push_str(".rodata;\n");
+            out.push_str("\n");
+
+            for global in self.global_vars.iter().filter(|g| g.read_only) {
+                gen_global(global, &mut out)?;
+            }
+        }
+
         out.push_str(&("#".repeat(78) + "\n"));
         out.push_str("\n");
         out.push_str(".code;\n");
@@ -159,12 +181,19 @@ impl Unit
         // If there is a main function
         let main_fn: Vec<&Function> = self.fun_decls.iter().filter(|f| f.name == "main").collect();
         if let [main_fn] = main_fn[..] {
-            //
-            // TODO: support calling main with argc, argv as well
-            //
-
             out.push_str("# call the main function and then exit\n");
-            out.push_str("call main, 0;\n");
+
+            // The VM passes argc, argv and envp to the entry point
+            let num_params = main_fn.params.len();
+            if num_params > 3 {
+                return ParseError::msg_only("main can only take argc, argv and envp as parameters");
+            }
+
+            for idx in 0..num_params {
+                out.push_str(&format!("get_arg {};\n", idx));
+            }
+            out.push_str(&format!("call main, {};\n", num_params));
+
             out.push_str("ret;\n");
             out.push_str("\n");
         }
@@ -494,23 +523,24 @@ impl Expr
                         out.push_str(&format!("get_local {};\n", idx));
                     }
                     Decl::Global { name, t } => {
-                        out.push_str(&format!("push {};\n", name));
                         match t {
-                            Type::UInt(n) => out.push_str(&format!("load_u{};\n", n)),
-                            Type::Int(64) => out.push_str("load_u64;\n"),
+                            Type::UInt(n) => out.push_str(&format!("load_global_u{} {};\n", n, name)),
+                            Type::Int(64) => out.push_str(&format!("load_global_u64 {};\n", name)),
                             Type::Int(32) => {
-                                out.push_str("load_u32;\n");
+                                out.push_str(&format!("load_global_u32 {};\n", name));
                                 out.push_str("sx_i32_i64;\n");
                             }
                             Type::Float(32) => {
-                                out.push_str("load_u32;\n");
+                                out.push_str(&format!("load_global_u32 {};\n", name));
                             }
                             Type::Pointer(t) => {
-                                out.push_str("load_u64;\n");
+                                out.push_str(&format!("load_global_u64 {};\n", name));
                             }
-                            Type::Fun { .. } => {}
-                            Type::Array { .. } => {}
-                            Type::Struct { .. } => {}
+
+                            // For these, we push the address of the global
+                            Type::Fun { .. } => out.push_str(&format!("push {};\n", name)),
+                            Type::Array { .. } => out.push_str(&format!("push {};\n", name)),
+                            Type::Struct { .. } => out.push_str(&format!("push {};\n", name)),
                             _ => todo!()
                         }
                     }
@@ -607,9 +637,7 @@ impl Expr
                     let num_bits = size_bytes * 8;
 
                     if num_bits <= 64 {
-                        out.push_str(&format!("push {};\n", offset));
-                        out.push_str("add_u64;");
-                        out.push_str(&format!("load_u{};\n", num_bits));
+                        out.push_str(&format!("load_u{}_off {};\n", num_bits, offset));
                     }
                     else
                     {
@@ -1071,23 +1099,19 @@ fn gen_assign(
 
                         // Evaluate the base address
                         base.gen_code(sym, out)?;
-                        out.push_str(&format!("push {};\n", offset));
-                        out.push_str("add_u64;\n");
 
                         out.push_str("getn 1;\n");
-                        out.push_str(&format!("store_u{};\n", num_bits));
+                        out.push_str(&format!("store_u{}_off {};\n", num_bits, offset));
                     }
                     else
                     {
                         // Evaluate the base address
                         base.gen_code(sym, out)?;
-                        out.push_str(&format!("push {};\n", offset));
-                        out.push_str("add_u64;\n");
 
                         // Evaluate the value expression
                         rhs.gen_code(sym, out)?;
 
-                        out.push_str(&format!("store_u{};\n", num_bits));
+                        out.push_str(&format!("store_u{}_off {};\n", num_bits, offset));
                     }
                 }
                 else
@@ -1147,29 +1171,18 @@ fn gen_assign(
                 }
 
                 Decl::Global { name, t } => {
+                    // Evaluate the value expression
+                    rhs.gen_code(sym, out)?;
+
                     // If the output value is needed
                     if need_value {
-                        // Evaluate the value expression
-                        rhs.gen_code(sym, out)?;
-
-                        // Push the address
-                        out.push_str(&format!("push {};\n", name));
-
-                        out.push_str("getn 1;\n");
-                    }
-                    else
-                    {
-                        // Push the address
-                        out.push_str(&format!("push {};\n", name));
-
-                        // Evaluate the value expression
-                        rhs.gen_code(sym, out)?;
+                        out.push_str("dup;\n");
                     }
 
                     match t {
-                        Type::UInt(n) | Type::Int(n) => out.push_str(&format!("store_u{};\n", n)),
-                        Type::Pointer(_) => out.push_str(&format!("store_u64;\n")),
-                        Type::Float(32) => out.push_str("store_u32;\n"),
+                        Type::UInt(n) | Type::Int(n) => out.push_str(&format!("store_global_u{} {};\n", n, name)),
+                        Type::Pointer(_) => out.push_str(&format!("store_global_u64 {};\n", name)),
+                        Type::Float(32) => out.push_str(&format!("store_global_u32 {};\n", name)),
 
                         _ => todo!()
                     }
@@ -5,6 +5,24 @@ use std::io::{self, Write};
 use std::process::Command;
 use std::collections::HashSet;
 
+/// Directory granted to the file system tests
+fn fs_test_dir() -> String
+{
+    let dir = std::env::temp_dir().join(format!("ncc_fs_test_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir.display().to_string()
+}
+
+/// Extra uvm options needed to run some of the tests
+fn uvm_args(file_path: &str) -> Vec<String>
+{
+    match file_path.rsplit('/').next().unwrap() {
+        "main_args.c" => vec!["--env".to_string(), "UVM_TEST_VAR=foo".to_string()],
+        "fs_root.c" => vec!["--fs-root".to_string(), fs_test_dir()],
+        _ => vec![],
+    }
+}
+
 fn compile_and_run(file_path: &str, run_example: bool)
 {
     if run_example {
@@ -27,10 +45,32 @@ fn compile_and_run(file_path: &str, run_example: bool)
     let mut command = Command::new("target/debug/uvm");
     command.current_dir("../vm");
     if !run_example { command.arg("--parse-only"); }
+    command.args(uvm_args(file_path));
     command.arg("../ncc/out.asm");
     println!("{:?}", command);
     let output = command.output().unwrap();
     assert!(output.status.success(), "execution failed");
+
+    // Run it again with the pre-decoded interpreter, with the JIT compiler,
+    // and with the first heap page inaccessible
+    if run_example {
+        for flag in ["--predecode", "--jit", "--null-guard"] {
+            // The JIT compiler only supports x86-64 Linux
+            if flag == "--jit" && !cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+                continue;
+            }
+
+            let mut command = Command::new("target/debug/uvm");
+            command.current_dir("../vm");
+            command.arg(flag);
+            command.args(uvm_args(file_path));
+            command.arg("../ncc/out.asm");
+            println!("{:?}", command);
+            let flag_output = command.output().unwrap();
+            assert!(flag_output.status.success(), "execution with {} failed", flag);
+            assert!(flag_output.stdout == output.stdout, "output differs with {}", flag);
+        }
+    }
 }
 
 #[test]
@@ -63,6 +103,7 @@ fn exec_tests()
             compile_and_run(&file_path, true);
         }
     }
+    fs::remove_dir_all(fs_test_dir()).unwrap();
 
     // We only run a subset of examples
     // Some examples involve creating a UI window
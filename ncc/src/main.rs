@@ -37,6 +37,9 @@ struct Options
     // Output file
     out_file: String,
 
+    // Make the first heap page inaccessible so that null pointer dereferences trap
+    null_guard: bool,
+
     // Preprocessor definitions
     // -D<macroname>=<value>
     defs: HashMap<String, String>,
@@ -49,6 +52,7 @@ fn parse_args(args: Vec<String>) -> Options
     let mut opts = Options {
         print_cpp_out: false,
         out_file: "out.asm".to_string(),
+        null_guard: false,
         defs: HashMap::new(),
         rest: Vec::default(),
     };
@@ -74,6 +78,11 @@ fn parse_args(args: Vec<String>) -> Options
             continue;
         }
 
+        if arg == "-fnull-guard" {
+            opts.null_guard = true;
+            continue;
+        }
+
         // Output file name
         if arg == "-o" {
             opts.out_file = args[idx].clone();
@@ -124,6 +133,7 @@ fn compile_file(file_name: &str, opts: &Options) -> Result<(), ParseError>
     unit.resolve_syms()?;
     unit.check_types()?;
     unit.insert_casts()?;
+    unit.null_guard = opts.null_guard;
     let out = unit.gen_code()?;
 
     std::fs::write(&opts.out_file, out).unwrap();
@@ -1122,7 +1122,8 @@ pub fn parse_unit(input: &mut Input) -> Result<Unit, ParseError>
         unit.global_vars.push(Global {
             name,
             var_type: decl_type,
-            init_expr
+            init_expr,
+            read_only: false,
         });
     }
 
@@ -1196,6 +1197,7 @@ mod tests
         parse_ok("u64 main() { return 0; }");
         parse_ok("u64 main(u64 argc, char** argv) { return 0; }");
         parse_ok("void main(u64 argc, char** argv) {}");
+        parse_ok("int main(int argc, char** argv, char** envp) { return 0; }");
 
         parse_ok("void foo() {}");
         parse_ok("void foo() { /* hello! */}");
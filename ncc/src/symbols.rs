@@ -230,7 +230,8 @@ impl Unit
                 self.global_vars.push(Global {
                     name: name.clone(),
                     var_type: t.clone(),
-                    init_expr: Some(Expr::String(str_const.clone()))
+                    init_expr: Some(Expr::String(str_const.clone())),
+                    read_only: true,
                 });
             }
         }
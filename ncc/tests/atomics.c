@@ -0,0 +1,72 @@
+#include <assert.h>
+#include <stdio.h>
+#include <uvm/syscalls.h>
+#include <uvm/atomic.h>
+
+#define NUM_THREADS 50
+#define NUM_INCRS 200
+
+u64 thread_ids[NUM_THREADS];
+
+u64 counter64 = 0;
+u32 counter32 = 0;
+
+// Spin lock built on atomic exchange
+u32 locked = 0;
+u64 protected_count = 0;
+
+u64 flags = 0b1100;
+u32 word = 0xFF;
+
+void thread_fn()
+{
+    for (int i = 0; i < NUM_INCRS; ++i)
+    {
+        atomic_fetch_add_u64(&counter64, 2);
+        atomic_fetch_sub_u64(&counter64, 1);
+        atomic_fetch_add_u32(&counter32, 1);
+
+        while (atomic_xchg_u32(&locked, 1) != 0) {}
+        ++protected_count;
+        atomic_store_u32(&locked, 0);
+    }
+}
+
+int main()
+{
+    for (int i = 0; i < NUM_THREADS; ++i)
+    {
+        thread_ids[i] = thread_spawn(thread_fn, NULL);
+    }
+
+    for (int i = 0; i < NUM_THREADS; ++i)
+    {
+        thread_join(thread_ids[i]);
+    }
+
+    atomic_fence();
+    assert(atomic_load_u64(&counter64) == NUM_THREADS * NUM_INCRS);
+    assert(atomic_load_u32(&counter32) == NUM_THREADS * NUM_INCRS);
+    assert(protected_count == NUM_THREADS * NUM_INCRS);
+
+    // Bitwise operations evaluate to the previous value
+    assert(atomic_fetch_or_u64(&flags, 0b0011) == 0b1100);
+    assert(atomic_fetch_and_u64(&flags, 0b0110) == 0b1111);
+    assert(atomic_fetch_xor_u64(&flags, 0b0110) == 0b0110);
+    assert(flags == 0);
+
+    assert(atomic_fetch_or_u32(&word, 0xF00) == 0xFF);
+    assert(atomic_fetch_and_u32(&word, 0xF0F) == 0xFFF);
+    assert(atomic_fetch_xor_u32(&word, 0x00F) == 0xF0F);
+    assert(atomic_xchg_u64(&flags, 5) == 0);
+
+    // Compare-and-swap
+    assert(atomic_cas_u32(&word, 0xF00, 7) == 0xF00);
+    assert(atomic_cas_u32(&word, 0xF00, 9) == 7);
+    assert(atomic_cas_u64(&flags, 5, 6) == 5);
+    assert(flags == 6);
+
+    printf("counter = %d\n", counter64);
+
+    return 0;
+}
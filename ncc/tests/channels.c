@@ -0,0 +1,71 @@
+#include <assert.h>
+#include <stdio.h>
+#include <uvm/syscalls.h>
+
+#define NUM_WORKERS 4
+#define NUM_JOBS 100
+
+u64 worker_ids[NUM_WORKERS];
+
+u64 jobs;
+u64 results;
+
+// Square the numbers received until the job channel is closed
+void worker()
+{
+    u64 job[1];
+    u64 result[1];
+
+    for (;;)
+    {
+        i64 len = chan_recv(jobs, job, sizeof(job), -1);
+
+        if (len == CHAN_ERR_CLOSED)
+            break;
+
+        assert(len == sizeof(job));
+        result[0] = job[0] * job[0];
+        assert(chan_send(results, result, sizeof(result)) == 0);
+    }
+}
+
+int main()
+{
+    jobs = chan_create(8);
+    results = chan_create(0);
+
+    // Nothing to receive yet
+    u64 buf[1];
+    assert(chan_recv(results, buf, sizeof(buf), 0) == CHAN_ERR_TIMED_OUT);
+
+    for (int i = 0; i < NUM_WORKERS; ++i)
+    {
+        worker_ids[i] = thread_spawn(worker, NULL);
+    }
+
+    for (u64 i = 1; i <= NUM_JOBS; ++i)
+    {
+        buf[0] = i;
+        assert(chan_send(jobs, buf, sizeof(buf)) == 0);
+    }
+
+    chan_close(jobs);
+    assert(chan_send(jobs, buf, sizeof(buf)) == CHAN_ERR_CLOSED);
+
+    u64 sum = 0;
+    for (int i = 0; i < NUM_JOBS; ++i)
+    {
+        assert(chan_recv(results, buf, sizeof(buf), -1) == sizeof(buf));
+        sum = sum + buf[0];
+    }
+
+    for (int i = 0; i < NUM_WORKERS; ++i)
+    {
+        thread_join(worker_ids[i]);
+    }
+
+    assert(sum == 338350);
+    printf("sum of squares = %d\n", sum);
+
+    return 0;
+}
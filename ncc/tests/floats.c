@@ -70,5 +70,16 @@ int main()
     assert(truncf(4.5f) == 4.0f);
     assert(truncf(-4.5f) == -4.0f);
 
+    assert(ceilf(4.5f) == 5.0f);
+    assert(roundf(-4.5f) == -5.0f);
+    assert(fabsf(-4.5f) == 4.5f);
+    assert(fminf(1.0f, 2.0f) == 1.0f);
+    assert(fmaxf(1.0f, 2.0f) == 2.0f);
+    assert(fmodf(7.5f, 2.0f) == 1.5f);
+    assert(expf(0.0f) == 1.0f);
+    assert(logf(1.0f) == 0.0f);
+    assert(atanf(0.0f) == 0.0f);
+    assert(atan2f(0.0f, 1.0f) == 0.0f);
+
     return 0;
 }
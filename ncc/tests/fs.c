@@ -0,0 +1,15 @@
+#include <assert.h>
+#include <uvm/syscalls.h>
+#include <uvm/fs.h>
+
+FsStat stat;
+
+int main()
+{
+    // No directories are accessible unless granted with --fs-root
+    assert(fs_open("Cargo.toml", FS_OPEN_READ) == FS_ERR_ACCESS_DENIED);
+    assert(fs_open("new_file.txt", FS_OPEN_WRITE | FS_OPEN_CREATE) == FS_ERR_ACCESS_DENIED);
+    assert(fs_stat(".", &stat) == FS_ERR_ACCESS_DENIED);
+
+    return 0;
+}
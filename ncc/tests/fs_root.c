@@ -0,0 +1,50 @@
+#include <assert.h>
+#include <string.h>
+#include <uvm/syscalls.h>
+#include <uvm/fs.h>
+
+// The test runner grants access to an empty temporary directory with --fs-root,
+// and runs this test several times, so the file is truncated when opened
+
+FsStat stat;
+
+char buf[64];
+
+int main()
+{
+    char* text = "hello file";
+    size_t len = strlen(text);
+
+    // Relative paths are relative to the granted directory
+    i64 file = fs_open("hello.txt", FS_OPEN_WRITE | FS_OPEN_CREATE | FS_OPEN_TRUNCATE);
+    assert(file >= 0);
+    assert(fs_write(file, text, len) == len);
+    fs_close(file);
+
+    assert(fs_stat("hello.txt", &stat) == 0);
+    assert(stat.kind == FS_KIND_FILE);
+    assert(stat.size == len);
+
+    file = fs_open("hello.txt", FS_OPEN_READ);
+    assert(file >= 0);
+    assert(fs_read(file, buf, sizeof(buf)) == len);
+    assert(memcmp(buf, text, len) == 0);
+
+    // Read the end of the file again after seeking
+    assert(fs_seek(file, 6, FS_SEEK_SET) == 6);
+    assert(fs_read(file, buf, sizeof(buf)) == 4);
+    assert(memcmp(buf, "file", 4) == 0);
+    fs_close(file);
+
+    assert(fs_stat(".", &stat) == 0);
+    assert(stat.kind == FS_KIND_DIR);
+
+    // The directory only holds the file we wrote
+    assert(fs_list_dir(".", buf, sizeof(buf)) == 10);
+    assert(strcmp(buf, "hello.txt") == 0);
+
+    // Paths outside of the granted directory are still denied
+    assert(fs_open("../hello.txt", FS_OPEN_READ) == FS_ERR_ACCESS_DENIED);
+
+    return 0;
+}
@@ -0,0 +1,74 @@
+#include <assert.h>
+#include <stdio.h>
+#include <uvm/syscalls.h>
+#include <uvm/atomic.h>
+
+#define NUM_THREADS 20
+#define NUM_INCRS 100
+
+u64 thread_ids[NUM_THREADS];
+
+// Mutex state: 0 = unlocked, 1 = locked, 2 = locked with waiters
+u32 mutex = 0;
+
+u64 counter = 0;
+
+void mutex_lock()
+{
+    u32 state = atomic_cas_u32(&mutex, 0, 1);
+
+    if (state == 0)
+        return;
+
+    // Mark the mutex as contended, then sleep until it gets released
+    if (state != 2)
+        state = atomic_xchg_u32(&mutex, 2);
+
+    while (state != 0)
+    {
+        futex_wait(&mutex, 2, -1);
+        state = atomic_xchg_u32(&mutex, 2);
+    }
+}
+
+void mutex_unlock()
+{
+    if (atomic_fetch_sub_u32(&mutex, 1) != 1)
+    {
+        atomic_store_u32(&mutex, 0);
+        futex_wake(&mutex, 1);
+    }
+}
+
+void thread_fn()
+{
+    for (int i = 0; i < NUM_INCRS; ++i)
+    {
+        mutex_lock();
+        ++counter;
+        mutex_unlock();
+    }
+}
+
+int main()
+{
+    // The value doesn't match, so this returns immediately
+    assert(futex_wait(&mutex, 1, -1) == FUTEX_ERR_VALUE_CHANGED);
+    assert(futex_wait(&mutex, 0, 1) == FUTEX_ERR_TIMED_OUT);
+    assert(futex_wake(&mutex, 1) == 0);
+
+    for (int i = 0; i < NUM_THREADS; ++i)
+    {
+        thread_ids[i] = thread_spawn(thread_fn, NULL);
+    }
+
+    for (int i = 0; i < NUM_THREADS; ++i)
+    {
+        thread_join(thread_ids[i]);
+    }
+
+    assert(counter == NUM_THREADS * NUM_INCRS);
+    printf("counter = %d\n", counter);
+
+    return 0;
+}
@@ -0,0 +1,20 @@
+#include <string.h>
+#include <assert.h>
+
+// The test runner passes no arguments, so
+// argv only contains the program file name
+int main(int argc, char** argv, char** envp)
+{
+    assert(argc == 1);
+    assert(argv[1] == NULL);
+
+    size_t len = strlen(argv[0]);
+    assert(len >= 7);
+    assert(strcmp(argv[0] + len - 7, "out.asm") == 0);
+
+    // Only the variable passed with --env is in the environment
+    assert(strcmp(envp[0], "UVM_TEST_VAR=foo") == 0);
+    assert(envp[1] == NULL);
+
+    return 0;
+}
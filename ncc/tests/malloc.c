@@ -1,4 +1,5 @@
 #include <stdlib.h>
+#include <uvm/syscalls.h>
 #include <stdint.h>
 #include <assert.h>
 
@@ -26,4 +27,10 @@ void main()
     uint8_t* ptr0 = (uint8_t*)malloc(3);
     uint32_t* ptr1 = (uint32_t*)malloc(4);
     *ptr1 = 333;
+
+    // Allocations the heap can't grow to fit fail without changing the heap size
+    u64 heap_size = vm_heap_size();
+    assert(malloc(4398046511104) == NULL);
+    assert(vm_heap_size() == heap_size);
+    assert(malloc(8) != NULL);
 }
@@ -0,0 +1,27 @@
+#include <assert.h>
+#include <uvm/syscalls.h>
+#include <uvm/time.h>
+
+LocalTime local_time;
+
+int main()
+{
+    // Sleep for 2ms, which the monotonic clock must measure
+    u64 start_ns = time_monotonic_ns();
+    thread_sleep_us(2000);
+    u64 elapsed_ns = time_monotonic_ns() - start_ns;
+    assert(elapsed_ns >= 2000000);
+
+    assert(time_local(time_current_ms(), &local_time));
+    assert(local_time.year >= 2024);
+    assert(local_time.month >= 1 && local_time.month <= 12);
+    assert(local_time.day >= 1 && local_time.day <= 31);
+    assert(local_time.hour < 24 && local_time.minute < 60 && local_time.second <= 60);
+
+    // The Unix epoch, in whichever time zone this runs in
+    assert(time_local(0, &local_time));
+    assert(local_time.year == 1970 || local_time.year == 1969);
+    assert(local_time.utc_offset > -86400 && local_time.utc_offset < 86400);
+
+    return 0;
+}
@@ -0,0 +1,33 @@
+#include <assert.h>
+#include <stdio.h>
+#include <uvm/syscalls.h>
+
+u64 num_ticks = 0;
+u64 cancelled_id = 0;
+
+void never_called(u64 timer_id)
+{
+    assert(0);
+}
+
+void tick(u64 timer_id)
+{
+    ++num_ticks;
+    printf("tick %d\n", num_ticks);
+
+    // Keep the event loop running until the third tick
+    if (num_ticks < 3)
+    {
+        timer_set(1, tick);
+    }
+}
+
+int main()
+{
+    timer_set(5, tick);
+    cancelled_id = timer_set(1, never_called);
+    timer_cancel(cancelled_id);
+
+    // Returning from main runs the event loop until no timers are left
+    return 0;
+}
@@ -3,7 +3,7 @@ use std::convert::{TryFrom};
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::mem::transmute;
-use crate::vm::{Op};
+use crate::vm::{Op, ExtOp};
 use crate::program::*;
 
 #[derive(Debug)]
@@ -445,6 +445,7 @@ enum Section
 {
     Code,
     Data,
+    Rodata,
 }
 
 #[derive(Copy, Clone)]
@@ -461,7 +462,11 @@ enum LabelRefKind
 {
     Address32,
     Address64,
+    Offset8,
     Offset32(usize),
+
+    // 32-bit jump offset which may be relaxed to an 8-bit offset
+    Jump,
 }
 
 struct LabelRef
@@ -491,6 +496,9 @@ pub struct Assembler
     // Data section
     data: ByteArray,
 
+    // Read-only data section, placed after the data section
+    rodata: ByteArray,
+
     /// Label definitions (name, position)
     label_defs: HashMap<String, LabelDef>,
 
@@ -499,6 +507,12 @@ pub struct Assembler
 
     /// Current section
     section: Section,
+
+    /// Leave the first heap page unused so it can be made inaccessible
+    null_guard: bool,
+
+    /// End of the last alignment padding or raw data in the code section
+    code_barrier: usize,
 }
 
 impl Assembler
@@ -523,12 +537,22 @@ impl Assembler
             syscall_set: HashSet::new(),
             code: ByteArray::new(),
             data: ByteArray::new(),
+            rodata: ByteArray::new(),
             label_defs: HashMap::default(),
             label_refs: Vec::default(),
             section: Section::Code,
+            null_guard: false,
+            code_barrier: 0,
         }
     }
 
+    /// Lay out the data after a null guard page, as with the .null_guard directive
+    pub fn null_guard(mut self, null_guard: bool) -> Self
+    {
+        self.null_guard = null_guard;
+        self
+    }
+
     fn parse_input(mut self, input: &mut Input) -> Result<Program, ParseError>
     {
         // Until we've reached the end of the input
@@ -543,6 +567,19 @@ impl Assembler
             self.parse_line(input)?;
         }
 
+        // Use short jump encodings where the targets are in range
+        self.relax_jumps();
+
+        // The data starts after the null guard page if there is one,
+        // and the read-only data goes on its own pages after the data
+        let data_start = if self.null_guard { PAGE_SIZE } else { 0 };
+        let data_end = data_start + self.data.len();
+        let rodata_start = if self.rodata.len() > 0 {
+            data_end.next_multiple_of(PAGE_SIZE)
+        } else {
+            data_end
+        };
+
         // Link the labels
         for label_ref in self.label_refs {
             let def = self.label_defs.get(&label_ref.name);
@@ -557,9 +594,16 @@ impl Assembler
 
             let def = *def.unwrap();
 
+            // Address of the label in the code or heap space
+            let def_addr = match def.section {
+                Section::Code => def.pos,
+                Section::Data => data_start + def.pos,
+                Section::Rodata => rodata_start + def.pos,
+            };
+
             match label_ref.kind {
                 LabelRefKind::Address32 => {
-                    let ptr32 = u32::try_from(def.pos);
+                    let ptr32 = u32::try_from(def_addr);
 
                     if ptr32.is_err() {
                         return Err(ParseError {
@@ -572,16 +616,34 @@ impl Assembler
                     match label_ref.section {
                         Section::Code => self.code.write(label_ref.pos, ptr32.unwrap()),
                         Section::Data => self.data.write(label_ref.pos, ptr32.unwrap()),
+                        Section::Rodata => self.rodata.write(label_ref.pos, ptr32.unwrap()),
                     }
                 }
 
                 LabelRefKind::Address64 => {
-                    let ptr64 = def.pos as u64;
+                    let ptr64 = def_addr as u64;
 
                     match label_ref.section {
                         Section::Code => self.code.write(label_ref.pos, ptr64),
                         Section::Data => self.data.write(label_ref.pos, ptr64),
+                        Section::Rodata => self.rodata.write(label_ref.pos, ptr64),
+                    }
+                }
+
+                LabelRefKind::Offset8 => {
+                    assert!(def.section == Section::Code);
+                    assert!(def.section == label_ref.section);
+                    let offs8 = i8::try_from(def.pos as i64 - (label_ref.pos as i64 + 1));
+
+                    if offs8.is_err() {
+                        return Err(ParseError {
+                            msg: format!("jump offset doesn't fit in i8 {}", label_ref.name),
+                            line_no: label_ref.line_no,
+                            col_no: label_ref.col_no,
+                        });
                     }
+
+                    self.code.write(label_ref.pos, offs8.unwrap());
                 }
 
                 LabelRefKind::Offset32(end_offset) => {
@@ -592,18 +654,146 @@ impl Assembler
                     match label_ref.section {
                         Section::Code => self.code.write(label_ref.pos, offs32),
                         Section::Data => self.data.write(label_ref.pos, offs32),
+                        Section::Rodata => self.rodata.write(label_ref.pos, offs32),
                     }
                 }
+
+                LabelRefKind::Jump => unreachable!(),
             }
         }
 
+        // Lay out the heap image, with the read-only data padded to whole pages
+        let mut heap = ByteArray::from_vec(vec![0; data_start]);
+        heap.push_bytes(self.data.as_slice());
+        let mut rodata_end = rodata_start;
+        if self.rodata.len() > 0 {
+            rodata_end = (rodata_start + self.rodata.len()).next_multiple_of(PAGE_SIZE);
+            heap.push_bytes(&vec![0; rodata_start - heap.len()]);
+            heap.push_bytes(self.rodata.as_slice());
+            heap.push_bytes(&vec![0; rodata_end - heap.len()]);
+        }
+
         Ok(Program {
             code: self.code,
-            data: self.data,
+            data: heap,
+            rodata: rodata_start..rodata_end,
+            null_guard: self.null_guard,
             syscalls: self.syscall_set,
         })
     }
 
+    /// Branch relaxation. All relaxable jumps are initially emitted with
+    /// a 32-bit offset. We repeatedly shrink the jumps whose target is
+    /// within range of an 8-bit offset. Shrinking a jump never increases
+    /// the distance between other jumps and their targets, so this
+    /// converges, and jumps that were shrunk stay in range. Jumps placed
+    /// before alignment padding or raw data in the code section are left
+    /// as they are, since shrinking them would move the padding or data.
+    fn relax_jumps(&mut self)
+    {
+        // Size reduction when going from a 32-bit to an 8-bit offset
+        const DELTA: usize = 3;
+
+        // Relaxable jumps with a known target in the code section
+        let jump_idxs: Vec<usize> = self.label_refs.iter().enumerate().filter(|(_, r)| {
+            matches!(r.kind, LabelRefKind::Jump) &&
+            r.section == Section::Code &&
+            r.pos > self.code_barrier &&
+            self.label_defs.get(&r.name).is_some_and(|def| def.section == Section::Code)
+        }).map(|(idx, _)| idx).collect();
+
+        // Opcode positions of the jumps that have been shrunk, sorted
+        let mut short_pos: Vec<usize> = Vec::new();
+
+        // Map a position in the original code to a position after shrinking
+        fn map_pos(short_pos: &[usize], pos: usize) -> usize {
+            pos - DELTA * short_pos.partition_point(|&p| p < pos)
+        }
+
+        loop {
+            let mut new_short = Vec::new();
+
+            for &idx in &jump_idxs {
+                let label_ref = &self.label_refs[idx];
+                let op_pos = label_ref.pos - 1;
+
+                if short_pos.binary_search(&op_pos).is_ok() {
+                    continue;
+                }
+
+                let def_pos = self.label_defs[&label_ref.name].pos;
+                let end_pos = map_pos(&short_pos, op_pos) + 2;
+                let offset = map_pos(&short_pos, def_pos) as i64 - end_pos as i64;
+
+                if i8::try_from(offset).is_ok() {
+                    new_short.push(op_pos);
+                }
+            }
+
+            if new_short.is_empty() {
+                break;
+            }
+
+            short_pos.extend(new_short);
+            short_pos.sort();
+        }
+
+        // Rewrite the code with the short jump encodings
+        if !short_pos.is_empty() {
+            let src = self.code.as_slice();
+            let mut code = ByteArray::new();
+            let mut prev = 0;
+
+            for &op_pos in &short_pos {
+                for &byte in &src[prev..op_pos] {
+                    code.push_u8(byte);
+                }
+
+                let op = match src[op_pos] {
+                    op if op == Op::jmp as u8 => Op::jmp_8,
+                    op if op == Op::jz as u8 => Op::jz_8,
+                    op if op == Op::jnz as u8 => Op::jnz_8,
+                    _ => panic!("not a relaxable jump")
+                };
+                code.push_op(op);
+                code.push_i8(0);
+                prev = op_pos + 1 + 4;
+            }
+
+            for &byte in &src[prev..] {
+                code.push_u8(byte);
+            }
+
+            self.code = code;
+        }
+
+        for def in self.label_defs.values_mut() {
+            if def.section == Section::Code {
+                def.pos = map_pos(&short_pos, def.pos);
+            }
+        }
+
+        for label_ref in &mut self.label_refs {
+            if label_ref.section != Section::Code {
+                continue;
+            }
+
+            if matches!(label_ref.kind, LabelRefKind::Jump) {
+                let op_pos = label_ref.pos - 1;
+
+                if short_pos.binary_search(&op_pos).is_ok() {
+                    label_ref.pos = map_pos(&short_pos, op_pos) + 1;
+                    label_ref.kind = LabelRefKind::Offset8;
+                    continue;
+                }
+
+                label_ref.kind = LabelRefKind::Offset32(0);
+            }
+
+            label_ref.pos = map_pos(&short_pos, label_ref.pos);
+        }
+    }
+
     pub fn parse_file(mut self, file_name: &str) -> Result<Program, ParseError>
     {
         match std::fs::read_to_string(file_name) {
@@ -667,6 +857,7 @@ impl Assembler
         match self.section {
             Section::Code => &mut self.code,
             Section::Data => &mut self.data,
+            Section::Rodata => &mut self.rodata,
         }
     }
 
@@ -689,10 +880,32 @@ impl Assembler
         match kind {
             LabelRefKind::Address32 => self.mem().push_u32(0),
             LabelRefKind::Address64 => self.mem().push_u64(0),
+            LabelRefKind::Offset8 => self.mem().push_i8(0),
             LabelRefKind::Offset32(_) => self.mem().push_u32(0),
+            LabelRefKind::Jump => self.mem().push_u32(0),
         }
     }
 
+    /// Parse a 32-bit address argument, which is either
+    /// an integer constant or a label name
+    fn parse_addr32_arg(&mut self, input: &mut Input) -> Result<(), ParseError>
+    {
+        input.eat_ws()?;
+        let ch = input.peek_ch();
+
+        if ch.is_ascii_digit() || ch == '$' {
+            let addr: u32 = self.parse_int_arg(input)?;
+            self.code.push_u32(addr);
+        }
+        else
+        {
+            let label_name = input.parse_ident()?;
+            self.add_label_ref(input, label_name, LabelRefKind::Address32);
+        }
+
+        Ok(())
+    }
+
     /// Parse the current line of the input
     fn parse_line(&mut self, input: &mut Input) -> Result<(), ParseError>
     {
@@ -757,6 +970,8 @@ impl Assembler
         match cmd.as_str() {
             "code" => self.section = Section::Code,
             "data" => self.section = Section::Data,
+            "rodata" => self.section = Section::Rodata,
+            "null_guard" => self.null_guard = true,
 
             "align" => {
                 let align_bytes = self.parse_int_arg::<u32>(input)? as usize;
@@ -847,6 +1062,12 @@ impl Assembler
                 self.mem().push_u32(val_u32);
             }
 
+            // 64-bit floating-point value
+            "f64" => {
+                let val: f64 = input.parse_float()?;
+                self.mem().push_u64(val.to_bits());
+            }
+
             // Command to read an arbitrary number of bytes
             // with optional whitespace between bytes
             "hex" => {
@@ -903,6 +1124,12 @@ impl Assembler
             }
         }
 
+        // Code placed before padding or data can't be shrunk
+        let is_section_cmd = matches!(cmd.as_str(), "code" | "data" | "rodata" | "null_guard");
+        if self.section == Section::Code && !is_section_cmd {
+            self.code_barrier = self.code.len();
+        }
+
         input.expect_token(";")?;
 
         Ok(())
@@ -914,6 +1141,8 @@ impl Assembler
         match op_name.as_str() {
             "panic" => self.code.push_op(Op::panic),
             "nop" => self.code.push_op(Op::nop),
+            "panic_ext" => self.code.push_ext_op(ExtOp::panic),
+            "nop_ext" => self.code.push_ext_op(ExtOp::nop),
 
             "pop" => self.code.push_op(Op::pop),
             "dup" => self.code.push_op(Op::dup),
@@ -1008,6 +1237,13 @@ impl Assembler
                 self.code.push_u32(val_u32);
             }
 
+            // Push a 64-bit floating-point value
+            "push_f64" => {
+                let val: f64 = input.parse_float()?;
+                self.code.push_op(Op::push_u64);
+                self.code.push_u64(val.to_bits());
+            }
+
             // Variable-size push
             "push" => {
                 self.gen_push(input)?;
@@ -1056,6 +1292,21 @@ impl Assembler
             "div_i64" => self.code.push_op(Op::div_i64),
             "mod_i64" => self.code.push_op(Op::mod_i64),
 
+            "add_u32_ovf" => self.code.push_op(Op::add_u32_ovf),
+            "sub_u32_ovf" => self.code.push_op(Op::sub_u32_ovf),
+            "mul_u32_ovf" => self.code.push_op(Op::mul_u32_ovf),
+            "add_i32_ovf" => self.code.push_op(Op::add_i32_ovf),
+            "sub_i32_ovf" => self.code.push_op(Op::sub_i32_ovf),
+            "mul_i32_ovf" => self.code.push_op(Op::mul_i32_ovf),
+            "add_u64_ovf" => self.code.push_op(Op::add_u64_ovf),
+            "sub_u64_ovf" => self.code.push_op(Op::sub_u64_ovf),
+            "mul_u64_ovf" => self.code.push_op(Op::mul_u64_ovf),
+            "add_i64_ovf" => self.code.push_op(Op::add_i64_ovf),
+            "sub_i64_ovf" => self.code.push_op(Op::sub_i64_ovf),
+            "mul_i64_ovf" => self.code.push_op(Op::mul_i64_ovf),
+            "mul_u64_wide" => self.code.push_op(Op::mul_u64_wide),
+            "mul_i64_wide" => self.code.push_op(Op::mul_i64_wide),
+
             "eq_u64" => self.code.push_op(Op::eq_u64),
             "ne_u64" => self.code.push_op(Op::ne_u64),
             "lt_u64" => self.code.push_op(Op::lt_u64),
@@ -1102,6 +1353,52 @@ impl Assembler
             "i64_to_f32" => self.code.push_op(Op::i64_to_f32),
             "f32_to_i32" => self.code.push_op(Op::f32_to_i32),
 
+            "floor_f32" => self.code.push_op(Op::floor_f32),
+            "ceil_f32" => self.code.push_op(Op::ceil_f32),
+            "round_f32" => self.code.push_op(Op::round_f32),
+            "trunc_f32" => self.code.push_op(Op::trunc_f32),
+            "abs_f32" => self.code.push_op(Op::abs_f32),
+            "min_f32" => self.code.push_op(Op::min_f32),
+            "max_f32" => self.code.push_op(Op::max_f32),
+            "exp_f32" => self.code.push_op(Op::exp_f32),
+            "log_f32" => self.code.push_op(Op::log_f32),
+            "atan2_f32" => self.code.push_op(Op::atan2_f32),
+            "mod_f32" => self.code.push_op(Op::mod_f32),
+
+            "f32_to_i64" => self.code.push_op(Op::f32_to_i64),
+            "u32_to_f32" => self.code.push_op(Op::u32_to_f32),
+            "u64_to_f32" => self.code.push_op(Op::u64_to_f32),
+            "f32_to_u32" => self.code.push_op(Op::f32_to_u32),
+            "f32_to_u64" => self.code.push_op(Op::f32_to_u64),
+
+            "add_f64" => self.code.push_op(Op::add_f64),
+            "sub_f64" => self.code.push_op(Op::sub_f64),
+            "mul_f64" => self.code.push_op(Op::mul_f64),
+            "div_f64" => self.code.push_op(Op::div_f64),
+
+            "sin_f64" => self.code.push_ext_op(ExtOp::sin_f64),
+            "cos_f64" => self.code.push_ext_op(ExtOp::cos_f64),
+            "tan_f64" => self.code.push_ext_op(ExtOp::tan_f64),
+            "asin_f64" => self.code.push_ext_op(ExtOp::asin_f64),
+            "acos_f64" => self.code.push_ext_op(ExtOp::acos_f64),
+            "atan_f64" => self.code.push_ext_op(ExtOp::atan_f64),
+            "pow_f64" => self.code.push_ext_op(ExtOp::pow_f64),
+            "sqrt_f64" => self.code.push_ext_op(ExtOp::sqrt_f64),
+
+            "eq_f64" => self.code.push_op(Op::eq_f64),
+            "ne_f64" => self.code.push_op(Op::ne_f64),
+            "lt_f64" => self.code.push_op(Op::lt_f64),
+            "le_f64" => self.code.push_op(Op::le_f64),
+            "gt_f64" => self.code.push_op(Op::gt_f64),
+            "ge_f64" => self.code.push_op(Op::ge_f64),
+
+            "i32_to_f64" => self.code.push_op(Op::i32_to_f64),
+            "i64_to_f64" => self.code.push_op(Op::i64_to_f64),
+            "f64_to_i32" => self.code.push_op(Op::f64_to_i32),
+            "f64_to_i64" => self.code.push_op(Op::f64_to_i64),
+            "f32_to_f64" => self.code.push_op(Op::f32_to_f64),
+            "f64_to_f32" => self.code.push_op(Op::f64_to_f32),
+
             "load_u8" => self.code.push_op(Op::load_u8),
             "load_u16" => self.code.push_op(Op::load_u16),
             "load_u32" => self.code.push_op(Op::load_u32),
@@ -1111,26 +1408,148 @@ impl Assembler
             "store_u32" => self.code.push_op(Op::store_u32),
             "store_u64" => self.code.push_op(Op::store_u64),
 
+            "load_u8_off" => {
+                let offset: u32 = self.parse_int_arg(input)?;
+                self.code.push_op(Op::load_u8_off);
+                self.code.push_u32(offset);
+            }
+
+            "load_u16_off" => {
+                let offset: u32 = self.parse_int_arg(input)?;
+                self.code.push_op(Op::load_u16_off);
+                self.code.push_u32(offset);
+            }
+
+            "load_u32_off" => {
+                let offset: u32 = self.parse_int_arg(input)?;
+                self.code.push_op(Op::load_u32_off);
+                self.code.push_u32(offset);
+            }
+
+            "load_u64_off" => {
+                let offset: u32 = self.parse_int_arg(input)?;
+                self.code.push_op(Op::load_u64_off);
+                self.code.push_u32(offset);
+            }
+
+            "store_u8_off" => {
+                let offset: u32 = self.parse_int_arg(input)?;
+                self.code.push_op(Op::store_u8_off);
+                self.code.push_u32(offset);
+            }
+
+            "store_u16_off" => {
+                let offset: u32 = self.parse_int_arg(input)?;
+                self.code.push_op(Op::store_u16_off);
+                self.code.push_u32(offset);
+            }
+
+            "store_u32_off" => {
+                let offset: u32 = self.parse_int_arg(input)?;
+                self.code.push_op(Op::store_u32_off);
+                self.code.push_u32(offset);
+            }
+
+            "store_u64_off" => {
+                let offset: u32 = self.parse_int_arg(input)?;
+                self.code.push_op(Op::store_u64_off);
+                self.code.push_u32(offset);
+            }
+
+            "load_global_u8" => {
+                self.code.push_op(Op::load_global_u8);
+                self.parse_addr32_arg(input)?;
+            }
+
+            "load_global_u16" => {
+                self.code.push_op(Op::load_global_u16);
+                self.parse_addr32_arg(input)?;
+            }
+
+            "load_global_u32" => {
+                self.code.push_op(Op::load_global_u32);
+                self.parse_addr32_arg(input)?;
+            }
+
+            "load_global_u64" => {
+                self.code.push_op(Op::load_global_u64);
+                self.parse_addr32_arg(input)?;
+            }
+
+            "store_global_u8" => {
+                self.code.push_op(Op::store_global_u8);
+                self.parse_addr32_arg(input)?;
+            }
+
+            "store_global_u16" => {
+                self.code.push_op(Op::store_global_u16);
+                self.parse_addr32_arg(input)?;
+            }
+
+            "store_global_u32" => {
+                self.code.push_op(Op::store_global_u32);
+                self.parse_addr32_arg(input)?;
+            }
+
+            "store_global_u64" => {
+                self.code.push_op(Op::store_global_u64);
+                self.parse_addr32_arg(input)?;
+            }
+
+            "atomic_load_u32" => self.code.push_op(Op::atomic_load_u32),
             "atomic_load_u64" => self.code.push_op(Op::atomic_load_u64),
+            "atomic_store_u32" => self.code.push_op(Op::atomic_store_u32),
             "atomic_store_u64" => self.code.push_op(Op::atomic_store_u64),
+            "atomic_cas_u32" => self.code.push_op(Op::atomic_cas_u32),
             "atomic_cas_u64" => self.code.push_op(Op::atomic_cas_u64),
+            "atomic_fetch_add_u32" => self.code.push_op(Op::atomic_fetch_add_u32),
+            "atomic_fetch_sub_u32" => self.code.push_op(Op::atomic_fetch_sub_u32),
+            "atomic_fetch_and_u32" => self.code.push_op(Op::atomic_fetch_and_u32),
+            "atomic_fetch_or_u32" => self.code.push_op(Op::atomic_fetch_or_u32),
+            "atomic_fetch_xor_u32" => self.code.push_op(Op::atomic_fetch_xor_u32),
+            "atomic_xchg_u32" => self.code.push_op(Op::atomic_xchg_u32),
+            "atomic_fetch_add_u64" => self.code.push_op(Op::atomic_fetch_add_u64),
+            "atomic_fetch_sub_u64" => self.code.push_op(Op::atomic_fetch_sub_u64),
+            "atomic_fetch_and_u64" => self.code.push_op(Op::atomic_fetch_and_u64),
+            "atomic_fetch_or_u64" => self.code.push_op(Op::atomic_fetch_or_u64),
+            "atomic_fetch_xor_u64" => self.code.push_op(Op::atomic_fetch_xor_u64),
+            "atomic_xchg_u64" => self.code.push_op(Op::atomic_xchg_u64),
+            "fence" => self.code.push_op(Op::fence),
 
             "jmp" => {
                 self.code.push_op(Op::jmp);
                 let label_name = input.parse_ident()?;
-                self.add_label_ref(input, label_name, LabelRefKind::Offset32(0));
+                self.add_label_ref(input, label_name, LabelRefKind::Jump);
+            }
+
+            "jmp_8" => {
+                self.code.push_op(Op::jmp_8);
+                let label_name = input.parse_ident()?;
+                self.add_label_ref(input, label_name, LabelRefKind::Offset8);
             }
 
             "jz" => {
                 self.code.push_op(Op::jz);
                 let label_name = input.parse_ident()?;
-                self.add_label_ref(input, label_name, LabelRefKind::Offset32(0));
+                self.add_label_ref(input, label_name, LabelRefKind::Jump);
+            }
+
+            "jz_8" => {
+                self.code.push_op(Op::jz_8);
+                let label_name = input.parse_ident()?;
+                self.add_label_ref(input, label_name, LabelRefKind::Offset8);
             }
 
             "jnz" => {
                 self.code.push_op(Op::jnz);
                 let label_name = input.parse_ident()?;
-                self.add_label_ref(input, label_name, LabelRefKind::Offset32(0));
+                self.add_label_ref(input, label_name, LabelRefKind::Jump);
+            }
+
+            "jnz_8" => {
+                self.code.push_op(Op::jnz_8);
+                let label_name = input.parse_ident()?;
+                self.add_label_ref(input, label_name, LabelRefKind::Offset8);
             }
 
             "syscall" => {
@@ -1292,6 +1711,8 @@ mod tests
         parse_ok(".f32 123.456e+10;");
         parse_ok(".f32 123.456e-10;");
         parse_ok(".code; push_f32 3.5;");
+        parse_ok(".f64 -123.456e-100;");
+        parse_ok(".code; push_f64 3.5;");
 
         parse_fails(".f32 123e10.5;");
         parse_fails(".f32 123 e10.5;");
@@ -1329,6 +1750,47 @@ mod tests
         parse_ok("CB: ret; push_p32 CB; ret;");
     }
 
+    #[test]
+    fn test_jump_relaxation()
+    {
+        fn code_bytes(src: &str) -> Vec<u8> {
+            let prog = Assembler::new().parse_str(src).unwrap();
+            prog.code.as_slice().to_vec()
+        }
+
+        // Short backward and forward jumps
+        assert_eq!(code_bytes("L: jmp L;"), vec![Op::jmp_8 as u8, (-2i8) as u8]);
+        assert_eq!(code_bytes("jz L; L: ret;"), vec![Op::jz_8 as u8, 0, Op::ret as u8]);
+
+        // Jump target out of range for an 8-bit offset
+        let code = code_bytes(&format!("jnz L; {} L: ret;", "nop;".repeat(200)));
+        assert_eq!(code.len(), 5 + 200 + 1);
+        assert_eq!(code[0], Op::jnz as u8);
+        assert_eq!(i32::from_le_bytes(code[1..5].try_into().unwrap()), 200);
+
+        // The outer jump only fits once the inner jumps are shrunk
+        let code = code_bytes(&format!("jmp L; jmp A; jmp A; {} A: L: ret;", "nop;".repeat(120)));
+        assert_eq!(code.len(), 2 + 2 + 2 + 120 + 1);
+        assert_eq!(code[0], Op::jmp_8 as u8);
+        assert_eq!(code[1], 124);
+
+        // Jumps before alignment padding or raw data in the code keep their size
+        let code = code_bytes("jmp L; .align 8; L: ret;");
+        assert_eq!(code.len(), 8 + 1);
+        assert_eq!(code[0], Op::jmp as u8);
+        let code = code_bytes("jmp L; .u32 7; L: ret;");
+        assert_eq!(code.len(), 5 + 4 + 1);
+        assert_eq!(code[0], Op::jmp as u8);
+
+        // Jumps after them are still shrunk
+        let code = code_bytes("push_0; .align 4; L: jz L; jmp L; .data; .u8 1; .code; ret;");
+        assert_eq!(code, vec![Op::push_0 as u8, 0, 0, 0, Op::jz_8 as u8, (-2i8) as u8, Op::jmp_8 as u8, (-4i8) as u8, Op::ret as u8]);
+
+        // Explicit short jumps must be in range
+        parse_ok("L: jmp_8 L;");
+        parse_fails("jmp_8 L; .zero 200; L: ret;");
+    }
+
     #[test]
     fn test_strings()
     {
@@ -1355,6 +1817,50 @@ mod tests
         parse_ok(".data; STR_LABEL: .stringz \"hi!\"; .code; push_p32 STR_LABEL;");
     }
 
+    #[test]
+    fn test_rodata()
+    {
+        // Without read-only data, the data section is left as is
+        let prog = Assembler::new().parse_str(".data; .u64 1;").unwrap();
+        assert_eq!(prog.data.len(), 8);
+        assert!(prog.rodata.is_empty());
+
+        // Read-only data goes on its own pages after the data,
+        // and data labels keep their addresses
+        let src = "
+            .rodata; RO: .stringz \"hi!\"; .addr64 D;
+            .data; .u64 1; D: .addr64 RO;
+        ";
+        let prog = Assembler::new().parse_str(src).unwrap();
+        assert_eq!(prog.rodata, PAGE_SIZE..(2 * PAGE_SIZE));
+        assert_eq!(prog.data.len(), 2 * PAGE_SIZE);
+
+        let data = prog.data.as_slice();
+        let read_u64 = |addr: usize| u64::from_le_bytes(data[addr..(addr + 8)].try_into().unwrap());
+        assert_eq!(read_u64(8), PAGE_SIZE as u64);
+        assert_eq!(&data[PAGE_SIZE..(PAGE_SIZE + 4)], b"hi!\0");
+        assert_eq!(read_u64(PAGE_SIZE + 4), 8);
+    }
+
+    #[test]
+    fn test_null_guard()
+    {
+        // The data starts after the guard page, with either the directive or the option
+        let src = ".data; .u64 1; D: .u64 2; .code; push_p32 D;";
+        let with_directive = Assembler::new().parse_str(&format!(".null_guard; {}", src)).unwrap();
+        let with_option = Assembler::new().null_guard(true).parse_str(src).unwrap();
+
+        for prog in [with_directive, with_option] {
+            assert!(prog.null_guard);
+            assert_eq!(prog.data.len(), PAGE_SIZE + 16);
+            assert_eq!(prog.data.as_slice()[PAGE_SIZE], 1);
+            assert_eq!(&prog.code.as_slice()[1..5], &((PAGE_SIZE + 8) as u32).to_le_bytes());
+        }
+
+        let prog = Assembler::new().parse_str(".rodata; .u64 1; .null_guard;").unwrap();
+        assert_eq!(prog.rodata, PAGE_SIZE..(2 * PAGE_SIZE));
+    }
+
     #[test]
     fn test_invalid()
     {
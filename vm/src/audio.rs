@@ -1,6 +1,6 @@
 use sdl2::audio::{AudioCallback, AudioSpecDesired, AudioDevice};
 use std::sync::{Arc, Weak, Mutex};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::cell::RefCell;
 use crate::vm::{Value, VM, Thread};
 use crate::host::{get_sdl_context};
@@ -16,7 +16,8 @@ struct OutputCB
     buf_size: usize,
 
     // VM thread in which to execute the audio callback
-    thread: Thread,
+    // This is None if the device was opened to play voices only
+    thread: Option<Thread>,
 
     // Callback function pointer
     cb: u64,
@@ -38,10 +39,15 @@ impl AudioCallback for OutputCB
         out.fill(0);
 
         // Run the audio callback
-        let ptr = self.thread.call(self.cb, &[Value::from(self.num_channels), Value::from(samples_per_chan)]);
+        if let Some(thread) = self.thread.as_mut() {
+            let ptr = thread.call(self.cb, &[Value::from(self.num_channels), Value::from(samples_per_chan)]);
 
-        let mem_slice: &[i16] = self.thread.get_heap_slice_mut(ptr.as_usize(), output_len);
-        out.copy_from_slice(&mem_slice);
+            let mem_slice: &[i16] = thread.get_heap_slice_mut(ptr.as_usize(), output_len);
+            out.copy_from_slice(&mem_slice);
+        }
+
+        // Mix the voices on top of the callback output
+        MIXER.lock().unwrap().mix(out, self.num_channels);
     }
 }
 
@@ -110,45 +116,139 @@ thread_local! {
     static INPUT_STATE: RefCell<InputState> = RefCell::new(InputState::default());
 }
 
-pub fn audio_open_output(thread: &mut Thread, sample_rate: Value, num_channels: Value, format: Value, cb: Value) -> Value
+/// Maximum number of voices that can be created
+const MAX_VOICES: usize = 64;
+
+/// Voice whose samples are mixed into the audio output by the host
+struct Voice
 {
-    if thread.id != 0 {
-        panic!("audio functions should only be called from the main thread");
+    // Queued mono samples, normalized to the [-1, 1] range
+    samples: VecDeque<f32>,
+
+    // Volume multiplier
+    volume: f32,
+
+    // Stereo panning, from -1 (left) to 1 (right)
+    pan: f32,
+}
+
+impl Voice
+{
+    fn new() -> Self
+    {
+        Self {
+            samples: VecDeque::default(),
+            volume: 1.0,
+            pan: 0.0,
+        }
     }
 
-    AUDIO_STATE.with_borrow(|s| {
-        if s.output_dev.is_some() {
-            panic!("audio output device already open");
+    /// Compute the gains for the left and right channels
+    fn gains(&self) -> (f32, f32)
+    {
+        let left = (1.0 - self.pan).min(1.0);
+        let right = (1.0 + self.pan).min(1.0);
+        (self.volume * left, self.volume * right)
+    }
+}
+
+struct Mixer
+{
+    // Voices, indexed by voice id, None for destroyed voices
+    voices: Vec<Option<Voice>>,
+
+    // Buffer to accumulate samples into before clipping
+    accum: Vec<f32>,
+}
+
+impl Mixer
+{
+    const fn new() -> Self
+    {
+        Self {
+            voices: Vec::new(),
+            accum: Vec::new(),
         }
-    });
+    }
 
-    let sample_rate = sample_rate.as_u32();
-    let num_channels = num_channels.as_u16();
-    let format = format.as_u16();
-    let cb = cb.as_u64();
+    fn get_voice(&mut self, voice_id: u32) -> &mut Voice
+    {
+        match self.voices.get_mut(voice_id as usize) {
+            Some(Some(voice)) => voice,
+            _ => panic!("invalid audio voice id {}", voice_id)
+        }
+    }
 
-    if sample_rate != 44100 {
-        panic!("for now, only 44100Hz sample rate suppored");
+    /// Allocate a voice, reusing the id of a destroyed voice if possible
+    fn create_voice(&mut self) -> u32
+    {
+        if let Some(voice_id) = self.voices.iter().position(|v| v.is_none()) {
+            self.voices[voice_id] = Some(Voice::new());
+            return voice_id as u32;
+        }
+
+        if self.voices.len() >= MAX_VOICES {
+            panic!("cannot create more than {} audio voices", MAX_VOICES);
+        }
+
+        self.voices.push(Some(Voice::new()));
+        (self.voices.len() - 1) as u32
     }
 
-    //if num_channels > 2 {
-    if num_channels != 1 {
-        panic!("for now, only one output channel supported");
+    fn destroy_voice(&mut self, voice_id: u32)
+    {
+        self.get_voice(voice_id);
+        self.voices[voice_id as usize] = None;
+
+        // Trim destroyed voices at the end of the list
+        while let Some(None) = self.voices.last() {
+            self.voices.pop();
+        }
     }
 
-    if format != AUDIO_FORMAT_I16 {
-        panic!("for now, only i16, 16-bit signed audio format supported");
+    /// Mix the queued voice samples on top of an interleaved output buffer
+    fn mix(&mut self, out: &mut [i16], num_channels: usize)
+    {
+        let num_frames = out.len() / num_channels;
+
+        self.accum.clear();
+        self.accum.extend(out.iter().map(|s| *s as f32));
+
+        for voice in self.voices.iter_mut().flatten() {
+            let (gain_l, gain_r) = voice.gains();
+            let num_samples = std::cmp::min(num_frames, voice.samples.len());
+
+            for (idx, sample) in voice.samples.drain(..num_samples).enumerate() {
+                let sample = sample * i16::MAX as f32;
+
+                if num_channels == 1 {
+                    self.accum[idx] += sample * voice.volume;
+                } else {
+                    self.accum[num_channels * idx] += sample * gain_l;
+                    self.accum[num_channels * idx + 1] += sample * gain_r;
+                }
+            }
+        }
+
+        // Clip the mixed samples to the i16 range
+        for (dst, sample) in out.iter_mut().zip(&self.accum) {
+            *dst = sample.clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
     }
+}
+
+// The mixer is accessed both by VM threads and the audio output thread
+static MIXER: Mutex<Mixer> = Mutex::new(Mixer::new());
 
+/// Open the audio output device and start playback
+fn open_output_device(num_channels: u16, thread: Option<Thread>, cb: u64) -> AudioDevice<OutputCB>
+{
     let desired_spec = AudioSpecDesired {
-        freq: Some(sample_rate as i32),
+        freq: Some(44100),
         channels: Some(num_channels as u8),
         samples: Some(1024) // buffer size, 1024 samples
     };
 
-    // Create a new VM thread in which to run the audio callback
-    let audio_thread = VM::new_thread(&thread.vm);
-
     let sdl = get_sdl_context();
     let audio_subsystem = sdl.audio().unwrap();
 
@@ -156,17 +256,57 @@ pub fn audio_open_output(thread: &mut Thread, sample_rate: Value, num_channels:
         OutputCB {
             num_channels: num_channels.into(),
             buf_size: desired_spec.samples.unwrap() as usize,
-            thread: audio_thread,
-            cb: cb,
+            thread,
+            cb,
         }
     }).unwrap();
 
     // Start playback
     device.resume();
 
-    // Keep the audio device alive
+    device
+}
+
+pub fn audio_open_output(thread: &mut Thread, sample_rate: Value, num_channels: Value, format: Value, cb: Value) -> Value
+{
+    if thread.id != 0 {
+        panic!("audio functions should only be called from the main thread");
+    }
+
+    let sample_rate = sample_rate.as_u32();
+    let num_channels = num_channels.as_u16();
+    let format = format.as_u16();
+    let cb = cb.as_u64();
+
+    if sample_rate != 44100 {
+        panic!("for now, only 44100Hz sample rate suppored");
+    }
+
+    if num_channels != 1 && num_channels != 2 {
+        panic!("for now, only mono and stereo output supported");
+    }
+
+    if format != AUDIO_FORMAT_I16 {
+        panic!("for now, only i16, 16-bit signed audio format supported");
+    }
+
+    // Create a new VM thread in which to run the audio callback
+    let audio_thread = VM::new_thread(&thread.vm);
+
     AUDIO_STATE.with_borrow_mut(|s| {
-        s.output_dev = Some(device);
+        // If the device was opened to play voices, it is closed
+        // and reopened with the requested number of channels.
+        // Queued voice samples are kept in the mixer.
+        if let Some(device) = s.output_dev.as_mut() {
+            if device.lock().thread.is_some() {
+                panic!("audio output device already open");
+            }
+
+            s.output_dev = None;
+        }
+
+        // Keep the audio device alive
+        s.output_dev = Some(open_output_device(num_channels, Some(audio_thread), cb));
     });
 
     // FIXME: return the device_id (u32)
@@ -258,3 +398,157 @@ pub fn audio_read_samples(thread: &mut Thread, dst_ptr: Value, num_samples: Valu
         s.samples.clear();
     });
 }
+
+/// Create a new voice, opening the audio output device if needed
+pub fn audio_voice_create(thread: &mut Thread) -> Value
+{
+    if thread.id != 0 {
+        panic!("audio functions should only be called from the main thread");
+    }
+
+    AUDIO_STATE.with_borrow_mut(|s| {
+        if s.output_dev.is_none() {
+            s.output_dev = Some(open_output_device(2, None, 0));
+        }
+    });
+
+    let voice_id = MIXER.lock().unwrap().create_voice();
+    Value::from(voice_id)
+}
+
+/// Queue samples to be played on a voice
+pub fn audio_voice_submit(thread: &mut Thread, voice_id: Value, samples: Value, num_samples: Value, format: Value)
+{
+    let voice_id = voice_id.as_u32();
+    let samples = samples.as_usize();
+    let num_samples = num_samples.as_usize();
+    let format = format.as_u16();
+
+    // Copy and normalize the samples before taking the mixer lock
+    let samples: Vec<f32> = match format {
+        AUDIO_FORMAT_I16 => {
+            let src: &mut [i16] = thread.get_heap_slice_mut(samples, num_samples);
+            src.iter().map(|s| *s as f32 / i16::MAX as f32).collect()
+        }
+
+        AUDIO_FORMAT_F32 => {
+            let src: &mut [f32] = thread.get_heap_slice_mut(samples, num_samples);
+            src.to_vec()
+        }
+
+        _ => panic!("unknown audio sample format {}", format)
+    };
+
+    let mut mixer = MIXER.lock().unwrap();
+    mixer.get_voice(voice_id).samples.extend(samples);
+}
+
+/// Set the volume and stereo panning of a voice
+pub fn audio_voice_set_params(thread: &mut Thread, voice_id: Value, volume: Value, pan: Value)
+{
+    let mut mixer = MIXER.lock().unwrap();
+    let voice = mixer.get_voice(voice_id.as_u32());
+    voice.volume = volume.as_f32();
+    voice.pan = pan.as_f32().clamp(-1.0, 1.0);
+}
+
+/// Get the number of samples queued on a voice
+pub fn audio_voice_queued(thread: &mut Thread, voice_id: Value) -> Value
+{
+    let mut mixer = MIXER.lock().unwrap();
+    let num_samples = mixer.get_voice(voice_id.as_u32()).samples.len();
+    Value::from(num_samples)
+}
+
+/// Discard the samples queued on a voice
+pub fn audio_voice_stop(thread: &mut Thread, voice_id: Value)
+{
+    let mut mixer = MIXER.lock().unwrap();
+    mixer.get_voice(voice_id.as_u32()).samples.clear();
+}
+
+/// Destroy a voice so that its id can be reused
+pub fn audio_voice_destroy(thread: &mut Thread, voice_id: Value)
+{
+    MIXER.lock().unwrap().destroy_voice(voice_id.as_u32());
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn voice(samples: &[f32], volume: f32, pan: f32) -> Voice
+    {
+        Voice {
+            samples: samples.iter().copied().collect(),
+            volume,
+            pan,
+        }
+    }
+
+    #[test]
+    fn test_mix_mono()
+    {
+        let mut mixer = Mixer::new();
+        mixer.voices.push(Some(voice(&[0.5, 0.5, 0.5], 1.0, 0.0)));
+        mixer.voices.push(Some(voice(&[0.25], 2.0, -1.0)));
+
+        // Voices are mixed on top of the existing output
+        let mut out = [100, 0, 0, 0];
+        mixer.mix(&mut out, 1);
+        assert_eq!(out, [32767, 16383, 16383, 0]);
+
+        // Only the unplayed samples remain queued
+        assert_eq!(mixer.voices[0].as_ref().unwrap().samples.len(), 0);
+        assert_eq!(mixer.voices[1].as_ref().unwrap().samples.len(), 0);
+    }
+
+    #[test]
+    fn test_mix_stereo_pan()
+    {
+        let mut mixer = Mixer::new();
+        mixer.voices.push(Some(voice(&[0.5, 0.5], 1.0, -1.0)));
+        mixer.voices.push(Some(voice(&[-0.5, -0.5, -0.5], 0.5, 0.5)));
+
+        let mut out = [0; 4];
+        mixer.mix(&mut out, 2);
+        assert_eq!(out, [12287, -8191, 12287, -8191]);
+        assert_eq!(mixer.voices[1].as_ref().unwrap().samples.len(), 1);
+    }
+
+    #[test]
+    fn test_mix_clip()
+    {
+        let mut mixer = Mixer::new();
+        mixer.voices.push(Some(voice(&[-1.0, 1.0], 1.0, 0.0)));
+        mixer.voices.push(Some(voice(&[-1.0, 1.0], 1.0, 0.0)));
+
+        let mut out = [0; 2];
+        mixer.mix(&mut out, 1);
+        assert_eq!(out, [i16::MIN, i16::MAX]);
+    }
+
+    #[test]
+    fn test_voice_ids()
+    {
+        let mut mixer = Mixer::new();
+        assert_eq!(mixer.create_voice(), 0);
+        assert_eq!(mixer.create_voice(), 1);
+        assert_eq!(mixer.create_voice(), 2);
+
+        // Destroyed voice ids are reused
+        mixer.destroy_voice(1);
+        assert_eq!(mixer.create_voice(), 1);
+
+        // Voices at the end of the list are trimmed
+        mixer.destroy_voice(2);
+        mixer.destroy_voice(1);
+        assert_eq!(mixer.voices.len(), 1);
+
+        for _ in 1..MAX_VOICES {
+            mixer.create_voice();
+        }
+        assert_eq!(mixer.voices.len(), MAX_VOICES);
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::time::{Duration, Instant};
+use crate::vm::{Thread, Value};
+use crate::constants::*;
+
+struct ChanInner
+{
+    /// Messages waiting to be received
+    msgs: VecDeque<Vec<u8>>,
+
+    /// Set once the channel is closed
+    closed: bool,
+}
+
+/// Message queue shared between threads
+struct Channel
+{
+    /// Maximum number of queued messages, zero if unbounded
+    capacity: usize,
+
+    inner: Mutex<ChanInner>,
+
+    /// Signalled when a message is sent or the channel is closed
+    not_empty: Condvar,
+
+    /// Signalled when a message is received or the channel is closed
+    not_full: Condvar,
+}
+
+/// State for message-passing channels
+pub struct ChanState
+{
+    /// Next channel id to use
+    next_id: u64,
+
+    /// Map of channels
+    /// Channels are reference-counted so that threads
+    /// can block on them without holding the VM lock.
+    /// Closed channels are removed once they are drained.
+    channels: HashMap<u64, Arc<Channel>>,
+}
+
+impl Default for ChanState
+{
+    fn default() -> Self
+    {
+        Self {
+            // Start at 1 so that 0 can be used to mean no channel
+            next_id: 1,
+            channels: HashMap::default(),
+        }
+    }
+}
+
+/// Get a reference to a channel, or CHAN_ERR_CLOSED
+/// if the channel was closed and has been removed
+fn get_channel(thread: &Thread, chan_id: Value) -> Result<Arc<Channel>, i64>
+{
+    let chan_id = chan_id.as_u64();
+    let vm = thread.vm.lock().unwrap();
+    let chan_state = &vm.chan_state;
+
+    match chan_state.channels.get(&chan_id) {
+        Some(chan) => Ok(chan.clone()),
+        None if chan_id != 0 && chan_id < chan_state.next_id => Err(CHAN_ERR_CLOSED),
+        None => panic!("invalid channel id {}", chan_id)
+    }
+}
+
+/// Remove a channel that is closed and has no queued messages left
+fn remove_channel(thread: &Thread, chan_id: Value)
+{
+    let mut vm = thread.vm.lock().unwrap();
+    vm.chan_state.channels.remove(&chan_id.as_u64());
+}
+
+/// Wait on a condition variable while the condition holds,
+/// with an optional timeout. Returns false if the timeout expired.
+fn wait_while<'a, F>(
+    cond: &Condvar,
+    mut guard: MutexGuard<'a, ChanInner>,
+    deadline: Option<Instant>,
+    condition: F
+) -> (MutexGuard<'a, ChanInner>, bool)
+where F: Fn(&ChanInner) -> bool
+{
+    while condition(&guard) {
+        match deadline {
+            None => guard = cond.wait(guard).unwrap(),
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return (guard, false);
+                }
+                guard = cond.wait_timeout(guard, deadline - now).unwrap().0;
+            }
+        }
+    }
+
+    (guard, true)
+}
+
+// Syscall to create a channel
+// u64 chan_id = chan_create(u64 capacity)
+pub fn chan_create(thread: &mut Thread, capacity: Value) -> Value
+{
+    let capacity = capacity.as_usize();
+
+    let chan = Channel {
+        capacity,
+        inner: Mutex::new(ChanInner {
+            msgs: VecDeque::default(),
+            closed: false,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+    };
+
+    let mut vm = thread.vm.lock().unwrap();
+    let chan_state = &mut vm.chan_state;
+    let chan_id = chan_state.next_id;
+    chan_state.next_id += 1;
+    chan_state.channels.insert(chan_id, Arc::new(chan));
+
+    Value::from(chan_id)
+}
+
+// Syscall to send a message, blocks while the channel is full
+// i64 result = chan_send(u64 chan_id, const void* buf, u64 len)
+pub fn chan_send(thread: &mut Thread, chan_id: Value, buf_ptr: Value, len: Value) -> Value
+{
+    let chan = match get_channel(thread, chan_id) {
+        Ok(chan) => chan,
+        Err(code) => return Value::from(code),
+    };
+    let buf_ptr = buf_ptr.as_usize();
+    let len = len.as_usize();
+
+    // Copy the message before taking the channel lock
+    let msg = thread.get_heap_slice_mut::<u8>(buf_ptr, len).to_vec();
+
+    let inner = chan.inner.lock().unwrap();
+    let (mut inner, _) = wait_while(&chan.not_full, inner, None, |inner| {
+        !inner.closed && chan.capacity > 0 && inner.msgs.len() >= chan.capacity
+    });
+
+    if inner.closed {
+        return Value::from(CHAN_ERR_CLOSED);
+    }
+
+    inner.msgs.push_back(msg);
+    chan.not_empty.notify_one();
+
+    Value::from(0)
+}
+
+// Syscall to receive a message, blocks until one is available
+// i64 msg_len = chan_recv(u64 chan_id, void* buf, u64 buf_len, i64 timeout_ms)
+pub fn chan_recv(thread: &mut Thread, chan_id: Value, buf_ptr: Value, buf_len: Value, timeout_ms: Value) -> Value
+{
+    let chan = match get_channel(thread, chan_id) {
+        Ok(chan) => chan,
+        Err(code) => return Value::from(code),
+    };
+    let buf_ptr = buf_ptr.as_usize();
+    let buf_len = buf_len.as_usize();
+    let timeout_ms = timeout_ms.as_i64();
+
+    let deadline = if timeout_ms < 0 {
+        None
+    } else {
+        Some(Instant::now() + Duration::from_millis(timeout_ms as u64))
+    };
+
+    let inner = chan.inner.lock().unwrap();
+    let (mut inner, ready) = wait_while(&chan.not_empty, inner, deadline, |inner| {
+        !inner.closed && inner.msgs.is_empty()
+    });
+
+    // Remaining messages can still be received after the channel is closed
+    let msg_len = match inner.msgs.front() {
+        Some(msg) => msg.len(),
+        None if inner.closed => return Value::from(CHAN_ERR_CLOSED),
+        None => {
+            assert!(!ready);
+            return Value::from(CHAN_ERR_TIMED_OUT);
+        }
+    };
+
+    if msg_len > buf_len {
+        return Value::from(CHAN_ERR_TOO_LONG);
+    }
+
+    let msg = inner.msgs.pop_front().unwrap();
+    chan.not_full.notify_one();
+    let drained = inner.closed && inner.msgs.is_empty();
+    drop(inner);
+
+    if drained {
+        remove_channel(thread, chan_id);
+    }
+
+    let buf = thread.get_heap_slice_mut(buf_ptr, msg_len);
+    buf.copy_from_slice(&msg);
+
+    Value::from(msg_len)
+}
+
+// Syscall to close a channel
+// Blocked senders and receivers are woken up
+// void chan_close(u64 chan_id)
+pub fn chan_close(thread: &mut Thread, chan_id: Value)
+{
+    // Closing a channel that was already removed does nothing
+    let chan = match get_channel(thread, chan_id) {
+        Ok(chan) => chan,
+        Err(_) => return,
+    };
+
+    let mut inner = chan.inner.lock().unwrap();
+    inner.closed = true;
+    chan.not_empty.notify_all();
+    chan.not_full.notify_all();
+    let drained = inner.msgs.is_empty();
+    drop(inner);
+
+    // Blocked threads keep their own reference to the channel
+    if drained {
+        remove_channel(thread, chan_id);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::VM;
+    use crate::asm::Assembler;
+    use crate::vm::tests::eval_i64;
+
+    // Heap layout: message buffer at 0, receive buffer at 8
+    const DATA: &str = ".data; .u64 0x0102030405060708; .zero 8; .code;";
+
+    #[test]
+    fn test_send_recv()
+    {
+        eval_i64(&format!("{DATA}
+            push 0; syscall chan_create;
+            dup; push 0; push 8; syscall chan_send; pop;
+            push 8; push 8; push -1; syscall chan_recv; pop;
+            push 8; load_u64; ret;
+        "), 0x0102030405060708);
+    }
+
+    #[test]
+    fn test_recv_timeout()
+    {
+        eval_i64(&format!("{DATA}
+            push 0; syscall chan_create;
+            push 8; push 8; push 0; syscall chan_recv; ret;
+        "), -2);
+        eval_i64(&format!("{DATA}
+            push 0; syscall chan_create;
+            push 8; push 8; push 10; syscall chan_recv; ret;
+        "), -2);
+    }
+
+    #[test]
+    fn test_too_long()
+    {
+        // The message stays queued if the buffer is too small
+        eval_i64(&format!("{DATA}
+            push 0; syscall chan_create;
+            dup; push 0; push 8; syscall chan_send; pop;
+            dup; push 8; push 4; push 0; syscall chan_recv; pop;
+            push 8; push 8; push 0; syscall chan_recv; ret;
+        "), 8);
+    }
+
+    #[test]
+    fn test_close()
+    {
+        // Queued messages are received before the close notification
+        eval_i64(&format!("{DATA}
+            push 0; syscall chan_create;
+            dup; push 0; push 2; syscall chan_send; pop;
+            dup; syscall chan_close;
+            dup; push 0; push 2; syscall chan_send;
+            swap; dup; push 8; push 8; push -1; syscall chan_recv;
+            swap; push 8; push 8; push -1; syscall chan_recv;
+            add_u64; add_u64; ret;
+        "), -1 + 2 - 1);
+    }
+
+    #[test]
+    fn test_close_removes()
+    {
+        let prog = Assembler::new().parse_str(&format!("{DATA} push 0; ret;")).unwrap();
+        let vm = VM::new(prog);
+        let mut thread = VM::new_thread(&vm);
+
+        let chan_id = chan_create(&mut thread, Value::from(0));
+        chan_send(&mut thread, chan_id, Value::from(0), Value::from(8));
+        chan_close(&mut thread, chan_id);
+
+        // The channel is kept until its queued message is received
+        assert_eq!(vm.lock().unwrap().chan_state.channels.len(), 1);
+        let result = chan_recv(&mut thread, chan_id, Value::from(8), Value::from(8), Value::from(0));
+        assert_eq!(result.as_i64(), 8);
+        assert!(vm.lock().unwrap().chan_state.channels.is_empty());
+
+        // Removed channels still report that they are closed
+        let result = chan_recv(&mut thread, chan_id, Value::from(8), Value::from(8), Value::from(0));
+        assert_eq!(result.as_i64(), CHAN_ERR_CLOSED);
+        let result = chan_send(&mut thread, chan_id, Value::from(0), Value::from(8));
+        assert_eq!(result.as_i64(), CHAN_ERR_CLOSED);
+        chan_close(&mut thread, chan_id);
+
+        // Closing an empty channel removes it right away
+        let chan_id = chan_create(&mut thread, Value::from(0));
+        chan_close(&mut thread, chan_id);
+        assert!(vm.lock().unwrap().chan_state.channels.is_empty());
+
+        vm.lock().unwrap().vm = None;
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid channel id")]
+    fn test_invalid_id()
+    {
+        eval_i64("push 7; push 0; push 0; syscall chan_send; ret;", 0);
+    }
+
+    #[test]
+    fn test_threads()
+    {
+        // A worker thread sums the messages it receives until
+        // the channel is closed, while the main thread sends
+        // values 1..=100 through a bounded channel
+        eval_i64(&format!("{DATA}
+            push 0; push 0;
+            push 4; syscall chan_create; set_local 0;
+            push_p32 WORKER; get_local 0; syscall thread_spawn; set_local 1;
+            push 100;
+            SEND:
+            dup; push 0; swap; store_u64;
+            get_local 0; push 0; push 8; syscall chan_send; pop;
+            push 1; sub_u64; dup; jnz SEND;
+            pop;
+            get_local 0; syscall chan_close;
+            get_local 1; syscall thread_join;
+            ret;
+
+            WORKER:
+            push 0;
+            RECV:
+            get_arg 0; push 8; push 8; push -1; syscall chan_recv;
+            push 0; lt_i64; jnz DONE;
+            push 8; load_u64; add_u64;
+            jmp RECV;
+            DONE:
+            ret;
+        "), 5050);
+    }
+}
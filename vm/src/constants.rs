@@ -4,7 +4,7 @@
 
 #![allow(unused)]
 
-pub const SYSCALL_TBL_LEN: usize = 32;
+pub const SYSCALL_TBL_LEN: usize = 62;
 
 pub const TIME_CURRENT_MS: u16 = 0;
 pub const WINDOW_CREATE: u16 = 1;
@@ -21,9 +21,11 @@ pub const EXIT: u16 = 11;
 pub const AUDIO_OPEN_INPUT: u16 = 12;
 pub const AUDIO_READ_SAMPLES: u16 = 13;
 pub const VM_HEAP_SIZE: u16 = 14;
+pub const AUDIO_VOICE_CREATE: u16 = 15;
 pub const MEMSET32: u16 = 16;
 pub const VM_GROW_HEAP: u16 = 17;
 pub const AUDIO_OPEN_OUTPUT: u16 = 18;
+pub const AUDIO_VOICE_SUBMIT: u16 = 19;
 pub const PRINT_F32: u16 = 20;
 pub const NET_LISTEN: u16 = 21;
 pub const NET_ACCEPT: u16 = 22;
@@ -36,6 +38,36 @@ pub const THREAD_ID: u16 = 28;
 pub const THREAD_SPAWN: u16 = 29;
 pub const THREAD_SLEEP: u16 = 30;
 pub const THREAD_JOIN: u16 = 31;
+pub const AUDIO_VOICE_SET_PARAMS: u16 = 32;
+pub const AUDIO_VOICE_QUEUED: u16 = 33;
+pub const AUDIO_VOICE_STOP: u16 = 34;
+pub const NET_POLL: u16 = 35;
+pub const NET_CONNECT: u16 = 36;
+pub const NET_UDP_BIND: u16 = 37;
+pub const NET_UDP_SEND_TO: u16 = 38;
+pub const NET_UDP_RECV_FROM: u16 = 39;
+pub const FS_OPEN: u16 = 40;
+pub const FS_READ: u16 = 41;
+pub const FS_WRITE: u16 = 42;
+pub const FS_SEEK: u16 = 43;
+pub const FS_CLOSE: u16 = 44;
+pub const FS_STAT: u16 = 45;
+pub const FS_LIST_DIR: u16 = 46;
+pub const FUTEX_WAIT: u16 = 47;
+pub const FUTEX_WAKE: u16 = 48;
+pub const CHAN_CREATE: u16 = 49;
+pub const CHAN_SEND: u16 = 50;
+pub const CHAN_RECV: u16 = 51;
+pub const CHAN_CLOSE: u16 = 52;
+pub const TIMER_SET: u16 = 53;
+pub const TIMER_CANCEL: u16 = 54;
+pub const WINDOW_ON_EVENT: u16 = 55;
+pub const NET_ON_READY: u16 = 56;
+pub const VM_SNAPSHOT: u16 = 57;
+pub const TIME_MONOTONIC_NS: u16 = 58;
+pub const THREAD_SLEEP_US: u16 = 59;
+pub const TIME_LOCAL: u16 = 60;
+pub const AUDIO_VOICE_DESTROY: u16 = 61;
 
 pub struct SysCallDesc
 {
@@ -61,14 +93,14 @@ pub const SYSCALL_DESCS: [Option<SysCallDesc>; SYSCALL_TBL_LEN] = [
     Some(SysCallDesc { name: "audio_open_input", const_idx: 12, argc: 4, has_ret: true }),
     Some(SysCallDesc { name: "audio_read_samples", const_idx: 13, argc: 2, has_ret: false }),
     Some(SysCallDesc { name: "vm_heap_size", const_idx: 14, argc: 0, has_ret: true }),
-    None,
+    Some(SysCallDesc { name: "audio_voice_create", const_idx: 15, argc: 0, has_ret: true }),
     Some(SysCallDesc { name: "memset32", const_idx: 16, argc: 3, has_ret: false }),
     Some(SysCallDesc { name: "vm_grow_heap", const_idx: 17, argc: 1, has_ret: true }),
     Some(SysCallDesc { name: "audio_open_output", const_idx: 18, argc: 4, has_ret: true }),
-    None,
+    Some(SysCallDesc { name: "audio_voice_submit", const_idx: 19, argc: 4, has_ret: false }),
     Some(SysCallDesc { name: "print_f32", const_idx: 20, argc: 1, has_ret: false }),
-    Some(SysCallDesc { name: "net_listen", const_idx: 21, argc: 2, has_ret: true }),
-    Some(SysCallDesc { name: "net_accept", const_idx: 22, argc: 4, has_ret: true }),
+    Some(SysCallDesc { name: "net_listen", const_idx: 21, argc: 1, has_ret: true }),
+    Some(SysCallDesc { name: "net_accept", const_idx: 22, argc: 3, has_ret: true }),
     Some(SysCallDesc { name: "net_read", const_idx: 23, argc: 3, has_ret: true }),
     Some(SysCallDesc { name: "net_write", const_idx: 24, argc: 3, has_ret: true }),
     Some(SysCallDesc { name: "net_close", const_idx: 25, argc: 1, has_ret: false }),
@@ -78,8 +110,44 @@ pub const SYSCALL_DESCS: [Option<SysCallDesc>; SYSCALL_TBL_LEN] = [
     Some(SysCallDesc { name: "thread_spawn", const_idx: 29, argc: 2, has_ret: true }),
     Some(SysCallDesc { name: "thread_sleep", const_idx: 30, argc: 1, has_ret: false }),
     Some(SysCallDesc { name: "thread_join", const_idx: 31, argc: 1, has_ret: true }),
+    Some(SysCallDesc { name: "audio_voice_set_params", const_idx: 32, argc: 3, has_ret: false }),
+    Some(SysCallDesc { name: "audio_voice_queued", const_idx: 33, argc: 1, has_ret: true }),
+    Some(SysCallDesc { name: "audio_voice_stop", const_idx: 34, argc: 1, has_ret: false }),
+    Some(SysCallDesc { name: "net_poll", const_idx: 35, argc: 3, has_ret: true }),
+    Some(SysCallDesc { name: "net_connect", const_idx: 36, argc: 2, has_ret: true }),
+    Some(SysCallDesc { name: "net_udp_bind", const_idx: 37, argc: 1, has_ret: true }),
+    Some(SysCallDesc { name: "net_udp_send_to", const_idx: 38, argc: 4, has_ret: true }),
+    Some(SysCallDesc { name: "net_udp_recv_from", const_idx: 39, argc: 5, has_ret: true }),
+    Some(SysCallDesc { name: "fs_open", const_idx: 40, argc: 2, has_ret: true }),
+    Some(SysCallDesc { name: "fs_read", const_idx: 41, argc: 3, has_ret: true }),
+    Some(SysCallDesc { name: "fs_write", const_idx: 42, argc: 3, has_ret: true }),
+    Some(SysCallDesc { name: "fs_seek", const_idx: 43, argc: 3, has_ret: true }),
+    Some(SysCallDesc { name: "fs_close", const_idx: 44, argc: 1, has_ret: false }),
+    Some(SysCallDesc { name: "fs_stat", const_idx: 45, argc: 2, has_ret: true }),
+    Some(SysCallDesc { name: "fs_list_dir", const_idx: 46, argc: 3, has_ret: true }),
+    Some(SysCallDesc { name: "futex_wait", const_idx: 47, argc: 3, has_ret: true }),
+    Some(SysCallDesc { name: "futex_wake", const_idx: 48, argc: 2, has_ret: true }),
+    Some(SysCallDesc { name: "chan_create", const_idx: 49, argc: 1, has_ret: true }),
+    Some(SysCallDesc { name: "chan_send", const_idx: 50, argc: 3, has_ret: true }),
+    Some(SysCallDesc { name: "chan_recv", const_idx: 51, argc: 4, has_ret: true }),
+    Some(SysCallDesc { name: "chan_close", const_idx: 52, argc: 1, has_ret: false }),
+    Some(SysCallDesc { name: "timer_set", const_idx: 53, argc: 2, has_ret: true }),
+    Some(SysCallDesc { name: "timer_cancel", const_idx: 54, argc: 1, has_ret: false }),
+    Some(SysCallDesc { name: "window_on_event", const_idx: 55, argc: 3, has_ret: false }),
+    Some(SysCallDesc { name: "net_on_ready", const_idx: 56, argc: 3, has_ret: false }),
+    Some(SysCallDesc { name: "vm_snapshot", const_idx: 57, argc: 1, has_ret: true }),
+    Some(SysCallDesc { name: "time_monotonic_ns", const_idx: 58, argc: 0, has_ret: true }),
+    Some(SysCallDesc { name: "thread_sleep_us", const_idx: 59, argc: 1, has_ret: false }),
+    Some(SysCallDesc { name: "time_local", const_idx: 60, argc: 2, has_ret: true }),
+    Some(SysCallDesc { name: "audio_voice_destroy", const_idx: 61, argc: 1, has_ret: false }),
 ];
 
+pub const FUTEX_ERR_VALUE_CHANGED: i64 = -1;
+pub const FUTEX_ERR_TIMED_OUT: i64 = -2;
+pub const CHAN_ERR_CLOSED: i64 = -1;
+pub const CHAN_ERR_TIMED_OUT: i64 = -2;
+pub const CHAN_ERR_TOO_LONG: i64 = -3;
+pub const SNAPSHOT_ERR_UNSUPPORTED: i64 = -5;
 pub const EVENT_QUIT: u16 = 0;
 pub const EVENT_KEYDOWN: u16 = 1;
 pub const EVENT_KEYUP: u16 = 2;
@@ -141,3 +209,29 @@ pub const KEY_UP: u16 = 16003;
 pub const KEY_DOWN: u16 = 16004;
 pub const KEY_SHIFT: u16 = 16005;
 pub const AUDIO_FORMAT_I16: u16 = 0;
+pub const AUDIO_FORMAT_F32: u16 = 1;
+pub const NET_POLL_READ: u32 = 1;
+pub const NET_POLL_WRITE: u32 = 2;
+pub const NET_POLL_HUP: u32 = 4;
+pub const NET_ERR_WOULD_BLOCK: i64 = -1;
+pub const NET_ERR_DISCONNECTED: i64 = -2;
+pub const NET_ERR_INVALID_ADDR: i64 = -3;
+pub const NET_ERR_ADDR_IN_USE: i64 = -4;
+pub const NET_ERR_FAILED: i64 = -5;
+pub const NET_ERR_REFUSED: i64 = -6;
+pub const NET_ERR_TIMED_OUT: i64 = -7;
+pub const FS_OPEN_READ: u32 = 1;
+pub const FS_OPEN_WRITE: u32 = 2;
+pub const FS_OPEN_CREATE: u32 = 4;
+pub const FS_OPEN_TRUNCATE: u32 = 8;
+pub const FS_OPEN_APPEND: u32 = 16;
+pub const FS_SEEK_SET: u32 = 0;
+pub const FS_SEEK_CUR: u32 = 1;
+pub const FS_SEEK_END: u32 = 2;
+pub const FS_KIND_FILE: u32 = 1;
+pub const FS_KIND_DIR: u32 = 2;
+pub const FS_KIND_OTHER: u32 = 3;
+pub const FS_ERR_NOT_FOUND: i64 = -1;
+pub const FS_ERR_ACCESS_DENIED: i64 = -2;
+pub const FS_ERR_INVALID: i64 = -3;
+pub const FS_ERR_FAILED: i64 = -4;
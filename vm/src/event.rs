@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::vm::{Thread, Value};
+use crate::net::poll_sockets;
+use crate::window::dispatch_window_events;
+
+/// Longest time the event loop blocks before checking for new
+/// registrations, since other threads can set timers at any time
+const MAX_WAIT_MS: u64 = 100;
+
+/// Time to wait on sockets before pumping window
+/// events when both have callbacks registered
+const WINDOW_POLL_MS: u64 = 10;
+
+/// State of the host event loop
+pub struct EventState
+{
+    /// Next timer id to use
+    next_timer_id: u64,
+
+    /// Pending timers ordered by deadline and id,
+    /// mapped to the callback to call
+    timers: BTreeMap<(Instant, u64), u64>,
+
+    /// Event struct address and callback for window events
+    pub window_callback: Option<(usize, u64)>,
+}
+
+impl Default for EventState
+{
+    fn default() -> Self
+    {
+        Self {
+            // Start at 1 so that 0 can be used to mean no timer
+            next_timer_id: 1,
+            timers: BTreeMap::default(),
+            window_callback: None,
+        }
+    }
+}
+
+// Syscall to call a function once after a delay
+// The callback is called by the event loop with the timer id as argument
+// u64 timer_id = timer_set(u64 delay_ms, void* callback)
+pub fn timer_set(thread: &mut Thread, delay_ms: Value, callback: Value) -> Value
+{
+    let deadline = Instant::now() + Duration::from_millis(delay_ms.as_u64());
+    let callback = callback.as_u64();
+
+    let mut vm = thread.vm.lock().unwrap();
+    let event_state = &mut vm.event_state;
+    let timer_id = event_state.next_timer_id;
+    event_state.next_timer_id += 1;
+    event_state.timers.insert((deadline, timer_id), callback);
+
+    Value::from(timer_id)
+}
+
+// Syscall to cancel a pending timer
+// Cancelling a timer that has already fired does nothing
+// void timer_cancel(u64 timer_id)
+pub fn timer_cancel(thread: &mut Thread, timer_id: Value)
+{
+    let timer_id = timer_id.as_u64();
+    let mut vm = thread.vm.lock().unwrap();
+    vm.event_state.timers.retain(|(_, id), _| *id != timer_id);
+}
+
+/// Run the event loop on the main thread after main returns.
+/// Callbacks are dispatched until none are registered anymore.
+pub fn run_event_loop(thread: &mut Thread)
+{
+    loop
+    {
+        let now = Instant::now();
+        let mut vm = thread.vm.lock().unwrap();
+
+        // Fire the next expired timer, if any
+        if let Some(entry) = vm.event_state.timers.first_entry() {
+            if entry.key().0 <= now {
+                let ((_, timer_id), callback) = entry.remove_entry();
+                drop(vm);
+                thread.call(callback, &[Value::from(timer_id)]);
+                continue;
+            }
+        }
+
+        let next_deadline = vm.event_state.timers.keys().next().map(|(deadline, _)| *deadline);
+        let window_callback = vm.event_state.window_callback;
+        let net_sockets = vm.net_state.callback_sockets();
+        drop(vm);
+
+        // Nothing left that could call back into the program
+        if next_deadline.is_none() && window_callback.is_none() && net_sockets.is_empty() {
+            break;
+        }
+
+        let mut wait_ms = MAX_WAIT_MS;
+        if let Some(deadline) = next_deadline {
+            // Round up so we don't wake up just before the deadline
+            let wait_us = deadline.saturating_duration_since(now).as_micros() as u64;
+            wait_ms = wait_ms.min(wait_us.div_ceil(1000));
+        }
+
+        if !net_sockets.is_empty() {
+            let poll_ms = if window_callback.is_some() { wait_ms.min(WINDOW_POLL_MS) } else { wait_ms };
+            let sockets: Vec<_> = net_sockets.iter().map(|(_, socket, events)| (socket.clone(), *events)).collect();
+            let revents = poll_sockets(&sockets, poll_ms as i64);
+
+            for ((socket_id, _, _), revents) in net_sockets.iter().zip(revents) {
+                if revents == 0 {
+                    continue;
+                }
+
+                // A previous callback may have closed this socket
+                let callback = thread.vm.lock().unwrap().net_state.ready_callback(*socket_id);
+
+                if let Some(callback) = callback {
+                    thread.call(callback, &[Value::from(*socket_id), Value::from(revents)]);
+                }
+            }
+
+            // Window events are only polled after waiting on sockets
+            wait_ms = 0;
+        }
+
+        if let Some((p_event, callback)) = window_callback {
+            dispatch_window_events(thread, p_event, callback, wait_ms);
+        } else if net_sockets.is_empty() {
+            thread::sleep(Duration::from_millis(wait_ms));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::VM;
+    use crate::asm::Assembler;
+    use std::net::TcpStream;
+
+    /// Run main followed by the event loop, then read the u64 at address 0
+    fn run_events(src: &str) -> u64
+    {
+        let prog = Assembler::new().parse_str(src).unwrap();
+        let vm = VM::new(prog);
+        let mut thread = VM::new_thread(&vm);
+        thread.call(0, &[]);
+        run_event_loop(&mut thread);
+        thread.get_heap_slice_mut::<u64>(0, 1)[0]
+    }
+
+    // Callback appending its argument as a digit to the u64 at address 0
+    const APPEND: &str = "
+        APPEND:
+        push 0;
+        push 0; load_u64; push 10; mul_u64;
+        get_arg 0; add_u64;
+        store_u64;
+        push 0; ret;
+    ";
+
+    #[test]
+    fn test_no_callbacks()
+    {
+        assert_eq!(run_events(".data; .u64 7; .code; push 0; ret;"), 7);
+    }
+
+    #[test]
+    fn test_timers()
+    {
+        // Timers fire in deadline order, ids start at 1
+        let src = format!("
+            .data; .u64 0; .code;
+            push 30; push_p32 APPEND; syscall timer_set; pop;
+            push 10; push_p32 APPEND; syscall timer_set; pop;
+            push 20; push_p32 APPEND; syscall timer_set; pop;
+            push 0; ret;
+            {APPEND}
+        ");
+        assert_eq!(run_events(&src), 231);
+    }
+
+    #[test]
+    fn test_timer_cancel()
+    {
+        let src = format!("
+            .data; .u64 0; .code;
+            push 10; push_p32 APPEND; syscall timer_set; pop;
+            push 5; push_p32 APPEND; syscall timer_set;
+            syscall timer_cancel;
+            push 0; ret;
+            {APPEND}
+        ");
+        assert_eq!(run_events(&src), 1);
+    }
+
+    #[test]
+    fn test_timer_chain()
+    {
+        // A callback setting a new timer keeps the loop running
+        let src = format!("
+            .data; .u64 0; .code;
+            push 0; push_p32 CHAIN; syscall timer_set; pop;
+            push 0; ret;
+            CHAIN:
+            get_arg 0; call APPEND, 1; pop;
+            get_arg 0; push 3; lt_u64; jz DONE;
+            push 1; push_p32 CHAIN; syscall timer_set; pop;
+            DONE:
+            push 0; ret;
+            {APPEND}
+        ");
+        assert_eq!(run_events(&src), 123);
+    }
+
+    #[test]
+    fn test_net_ready()
+    {
+        // The callback accepts the pending connection, then
+        // closes both sockets, which unregisters the callback
+        let src = "
+            .data;
+            .u64 0;
+            ADDR: .stringz \"127.0.0.1:0\";
+            .code;
+            push_p32 ADDR; syscall net_listen;
+            dup; push 0; swap; store_u64;
+            push 1; push_p32 ON_READY; syscall net_on_ready;
+            push 0; ret;
+            ON_READY:
+            get_arg 0; push 0; push 0; syscall net_accept;
+            syscall net_close;
+            get_arg 0; syscall net_close;
+            push 0; push 77; store_u64;
+            push 0; ret;
+        ";
+
+        let prog = Assembler::new().parse_str(src).unwrap();
+        let vm = VM::new(prog);
+        let mut thread = VM::new_thread(&vm);
+        thread.call(0, &[]);
+
+        let listen_id = thread.get_heap_slice_mut::<u64>(0, 1)[0];
+        let sockets = vm.lock().unwrap().net_state.callback_sockets();
+        assert_eq!(sockets.len(), 1);
+        assert_eq!(sockets[0].0, listen_id);
+        let addr = match sockets[0].1.as_ref() {
+            crate::net::Socket::Listen(listener) => listener.local_addr().unwrap(),
+            _ => panic!()
+        };
+        drop(sockets);
+
+        let _stream = TcpStream::connect(addr).unwrap();
+        run_event_loop(&mut thread);
+        assert_eq!(thread.get_heap_slice_mut::<u64>(0, 1)[0], 77);
+    }
+}
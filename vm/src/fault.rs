@@ -0,0 +1,179 @@
+//! Reporting of faults caused by accesses to protected heap memory
+//!
+//! Writes to read-only data and accesses to the null guard page aren't checked
+//! by the interpreters or by compiled code, the host's memory protection traps
+//! them instead. When the fault lands in a VM heap, the fault handler prints an
+//! error with the heap address that was accessed, and exits with the same code
+//! as other execution errors. The guest pc isn't known in the handler, so no
+//! guest backtrace is printed, and output buffered by the process is lost.
+//! Other faults, such as native stack overflows, are passed on to the handler
+//! that was installed before.
+
+use std::ops::Range;
+use std::sync::{Once, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Address range of a heap mapping, or zero if the slot is free
+struct Region
+{
+    start: AtomicUsize,
+    end: AtomicUsize,
+
+    /// Size of the null guard at the start of the heap
+    guard_size: AtomicUsize,
+
+    /// Heap address range of the read-only data
+    rodata_start: AtomicUsize,
+    rodata_end: AtomicUsize,
+}
+
+/// Maximum number of heaps that faults are reported for
+const MAX_REGIONS: usize = 64;
+
+/// Heaps with protected pages. This is a fixed-size table of atomics
+/// because it is read from the signal handler, which can't take locks.
+static REGIONS: [Region; MAX_REGIONS] = [const {
+    Region {
+        start: AtomicUsize::new(0),
+        end: AtomicUsize::new(0),
+        guard_size: AtomicUsize::new(0),
+        rodata_start: AtomicUsize::new(0),
+        rodata_end: AtomicUsize::new(0),
+    }
+}; MAX_REGIONS];
+
+/// Exit code for faults in a VM heap, the same as for
+/// execution errors, which are reported with a panic
+const EXIT_FAULT: libc::c_int = 101;
+
+/// Handlers installed before ours, for SIGSEGV and SIGBUS
+static PREV_ACTIONS: OnceLock<[libc::sigaction; 2]> = OnceLock::new();
+
+const SIGNALS: [libc::c_int; 2] = [libc::SIGSEGV, libc::SIGBUS];
+
+/// Report faults in the given heap mapping, returning the slot
+/// to release, or None if too many heaps are registered
+pub fn register_heap(start: usize, size: usize, guard_size: usize, rodata: Range<usize>) -> Option<usize>
+{
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(install_handler);
+
+    for (slot, region) in REGIONS.iter().enumerate() {
+        if region.start.compare_exchange(0, start, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            region.guard_size.store(guard_size, Ordering::SeqCst);
+            region.rodata_start.store(rodata.start, Ordering::SeqCst);
+            region.rodata_end.store(rodata.end, Ordering::SeqCst);
+            region.end.store(start + size, Ordering::SeqCst);
+            return Some(slot);
+        }
+    }
+
+    None
+}
+
+/// Stop reporting faults for a heap that is being unmapped
+pub fn unregister_heap(slot: usize)
+{
+    REGIONS[slot].end.store(0, Ordering::SeqCst);
+    REGIONS[slot].start.store(0, Ordering::SeqCst);
+}
+
+fn install_handler()
+{
+    unsafe {
+        let mut prev_actions: [libc::sigaction; 2] = std::mem::zeroed();
+        for (i, &signal) in SIGNALS.iter().enumerate() {
+            libc::sigaction(signal, std::ptr::null(), &mut prev_actions[i]);
+        }
+        assert!(PREV_ACTIONS.set(prev_actions).is_ok());
+
+        // Run on the alternate signal stack like Rust's own handler,
+        // so that native stack overflows can still be reported
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = fault_handler as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        for signal in SIGNALS {
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+extern "C" fn fault_handler(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void)
+{
+    let fault_addr = unsafe { (*info).si_addr() } as usize;
+
+    for region in &REGIONS {
+        let start = region.start.load(Ordering::SeqCst);
+        let end = region.end.load(Ordering::SeqCst);
+        let guard_size = region.guard_size.load(Ordering::SeqCst);
+        let rodata_start = region.rodata_start.load(Ordering::SeqCst);
+        let rodata_end = region.rodata_end.load(Ordering::SeqCst);
+
+        if start != 0 && fault_addr >= start && fault_addr < end {
+            // Only async-signal-safe calls can be made here,
+            // so the message is formatted without allocating
+            let mut msg = [0u8; 96];
+            let mut len = 0;
+            let mut append = |bytes: &[u8]| {
+                msg[len..(len + bytes.len())].copy_from_slice(bytes);
+                len += bytes.len();
+            };
+
+            // The read-only data can be read, so faults there are writes
+            let mut addr = fault_addr - start;
+            if addr < guard_size {
+                append(b"Error: null pointer dereference at heap address ");
+            } else if addr >= rodata_start && addr < rodata_end {
+                append(b"Error: write to read-only memory at heap address ");
+            } else {
+                append(b"Error: invalid memory access at heap address ");
+            }
+
+            let mut digits = [0u8; 20];
+            let mut num_digits = 0;
+            loop {
+                digits[num_digits] = b'0' + (addr % 10) as u8;
+                num_digits += 1;
+                addr /= 10;
+                if addr == 0 {
+                    break;
+                }
+            }
+            digits[..num_digits].reverse();
+            append(&digits[..num_digits]);
+            append(b"\n");
+
+            unsafe {
+                libc::write(2, msg.as_ptr() as *const libc::c_void, len);
+                libc::_exit(EXIT_FAULT);
+            }
+        }
+    }
+
+    // Not a heap fault, forward it to the previous handler
+    let idx = SIGNALS.iter().position(|&s| s == signal).unwrap();
+    let prev = &PREV_ACTIONS.get().unwrap()[idx];
+
+    unsafe {
+        if prev.sa_sigaction == libc::SIG_DFL || prev.sa_sigaction == libc::SIG_IGN {
+            reset_default(signal);
+        }
+        else if prev.sa_flags & libc::SA_SIGINFO != 0 {
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) = std::mem::transmute(prev.sa_sigaction);
+            handler(signal, info, context);
+        }
+        else {
+            let handler: extern "C" fn(libc::c_int) = std::mem::transmute(prev.sa_sigaction);
+            handler(signal);
+        }
+    }
+}
+
+unsafe fn reset_default(signal: libc::c_int)
+{
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = libc::SIG_DFL;
+    libc::sigaction(signal, &action, std::ptr::null_mut());
+}
@@ -0,0 +1,512 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use std::mem::size_of;
+use std::os::unix::fs::OpenOptionsExt;
+use crate::vm::{Thread, Value};
+use crate::constants::*;
+
+/// State for the filesystem subsystem
+pub struct FsState
+{
+    /// Directories the program is allowed to access
+    roots: Vec<PathBuf>,
+
+    /// Next file id to use
+    next_id: u64,
+
+    /// Map of open files
+    /// Files are reference-counted so that I/O
+    /// can be performed without holding the VM lock
+    files: HashMap<u64, Arc<File>>,
+}
+
+impl Default for FsState
+{
+    fn default() -> Self
+    {
+        Self {
+            roots: Vec::default(),
+            // Start at 1 so that 0 can be used to mean no file
+            next_id: 1,
+            files: HashMap::default(),
+        }
+    }
+}
+
+impl FsState
+{
+    /// Grant the program access to a directory and everything under it
+    pub fn add_root(&mut self, path: &str) -> Result<(), String>
+    {
+        let root = match Path::new(path).canonicalize() {
+            Ok(root) => root,
+            Err(err) => return Err(format!("invalid fs root \"{}\": {}", path, err)),
+        };
+
+        if !root.is_dir() {
+            return Err(format!("fs root \"{}\" is not a directory", path));
+        }
+
+        self.roots.push(root);
+        Ok(())
+    }
+
+    /// Resolve a path given by the program into an absolute path,
+    /// making sure that it is inside one of the granted directories.
+    /// Relative paths are relative to the first granted directory.
+    pub fn resolve_path(&self, path: &str) -> Result<PathBuf, i64>
+    {
+        // Don't resolve relative paths against the host's current directory
+        let path = match self.roots.first() {
+            Some(root) => root.join(path),
+            None => return Err(FS_ERR_ACCESS_DENIED),
+        };
+
+        // Canonicalizing resolves symlinks and ".." components
+        let resolved = match path.canonicalize() {
+            Ok(resolved) => resolved,
+
+            // The file may not exist yet, resolve its parent directory instead
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                // A dangling symlink can't be canonicalized either, but
+                // creating the file would follow it outside of the roots
+                if path.symlink_metadata().is_ok() {
+                    return Err(FS_ERR_ACCESS_DENIED);
+                }
+
+                let file_name = match path.file_name() {
+                    Some(file_name) => file_name,
+                    None => return Err(FS_ERR_INVALID),
+                };
+
+                let parent = match path.parent() {
+                    Some(parent) if parent != Path::new("") => parent,
+                    _ => Path::new("."),
+                };
+
+                match parent.canonicalize() {
+                    Ok(parent) => parent.join(file_name),
+                    Err(err) => return Err(error_code(&err)),
+                }
+            }
+
+            Err(err) => return Err(error_code(&err)),
+        };
+
+        if self.roots.iter().any(|root| resolved.starts_with(root)) {
+            Ok(resolved)
+        } else {
+            Err(FS_ERR_ACCESS_DENIED)
+        }
+    }
+}
+
+/// Options to open a path returned by resolve_path. A symlink in the
+/// final component is refused, in case one was created after the check.
+pub fn open_options() -> OpenOptions
+{
+    let mut options = OpenOptions::new();
+    options.custom_flags(libc::O_NOFOLLOW);
+    options
+}
+
+/// Get a reference to an open file
+fn get_file(thread: &Thread, file_id: Value) -> Arc<File>
+{
+    let file_id = file_id.as_u64();
+    let vm = thread.vm.lock().unwrap();
+
+    match vm.fs_state.files.get(&file_id) {
+        Some(file) => file.clone(),
+        None => panic!("invalid file id {}", file_id)
+    }
+}
+
+/// Translate an I/O error into an error code for the guest program
+pub fn error_code(err: &io::Error) -> i64
+{
+    match err.kind() {
+        ErrorKind::NotFound => FS_ERR_NOT_FOUND,
+        ErrorKind::PermissionDenied => FS_ERR_ACCESS_DENIED,
+        ErrorKind::InvalidInput => FS_ERR_INVALID,
+        _ => FS_ERR_FAILED,
+    }
+}
+
+// Syscall to open a file
+// i64 file_id = fs_open(const char* path, u32 flags)
+pub fn fs_open(thread: &mut Thread, path: Value, flags: Value) -> Value
+{
+    let path = thread.get_heap_str(path.as_usize()).to_owned();
+    let flags = flags.as_u32();
+
+    if flags & !(FS_OPEN_READ | FS_OPEN_WRITE | FS_OPEN_CREATE | FS_OPEN_TRUNCATE | FS_OPEN_APPEND) != 0 {
+        panic!("invalid flags passed to fs_open");
+    }
+
+    if flags & (FS_OPEN_READ | FS_OPEN_WRITE) == 0 {
+        panic!("fs_open requires FS_OPEN_READ or FS_OPEN_WRITE");
+    }
+
+    let path = match thread.vm.lock().unwrap().fs_state.resolve_path(&path) {
+        Ok(path) => path,
+        Err(code) => return Value::from(code),
+    };
+
+    let result = open_options()
+        .read(flags & FS_OPEN_READ != 0)
+        .write(flags & FS_OPEN_WRITE != 0)
+        .create(flags & FS_OPEN_CREATE != 0)
+        .truncate(flags & FS_OPEN_TRUNCATE != 0)
+        .append(flags & FS_OPEN_APPEND != 0)
+        .open(path);
+
+    let file = match result {
+        Ok(file) => file,
+        Err(err) => return Value::from(error_code(&err)),
+    };
+
+    let mut vm = thread.vm.lock().unwrap();
+    let fs_state = &mut vm.fs_state;
+    let file_id = fs_state.next_id;
+    fs_state.next_id += 1;
+    fs_state.files.insert(file_id, Arc::new(file));
+    Value::from(file_id)
+}
+
+// Syscall to read data from a file
+// i64 num_bytes = fs_read(u64 file_id, u8* buf_ptr, u64 buf_len)
+pub fn fs_read(thread: &mut Thread, file_id: Value, buf_ptr: Value, buf_len: Value) -> Value
+{
+    let file = get_file(thread, file_id);
+    let buf: &mut [u8] = thread.get_heap_slice_mut(buf_ptr.as_usize(), buf_len.as_usize());
+
+    loop {
+        match file.as_ref().read(buf) {
+            Ok(num_bytes) => return Value::from(num_bytes),
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Value::from(error_code(&err)),
+        }
+    }
+}
+
+// Syscall to write data to a file
+// i64 num_bytes = fs_write(u64 file_id, const u8* buf_ptr, u64 buf_len)
+pub fn fs_write(thread: &mut Thread, file_id: Value, buf_ptr: Value, buf_len: Value) -> Value
+{
+    let file = get_file(thread, file_id);
+    let buf: &mut [u8] = thread.get_heap_slice_mut(buf_ptr.as_usize(), buf_len.as_usize());
+
+    match file.as_ref().write_all(buf) {
+        Ok(_) => Value::from(buf.len()),
+        Err(err) => Value::from(error_code(&err)),
+    }
+}
+
+// Syscall to move the read/write position of a file
+// i64 position = fs_seek(u64 file_id, i64 offset, u32 whence)
+pub fn fs_seek(thread: &mut Thread, file_id: Value, offset: Value, whence: Value) -> Value
+{
+    let file = get_file(thread, file_id);
+    let offset = offset.as_i64();
+
+    let pos = match whence.as_u32() {
+        FS_SEEK_SET => {
+            if offset < 0 {
+                return Value::from(FS_ERR_INVALID);
+            }
+            SeekFrom::Start(offset as u64)
+        }
+        FS_SEEK_CUR => SeekFrom::Current(offset),
+        FS_SEEK_END => SeekFrom::End(offset),
+        _ => panic!("invalid whence value passed to fs_seek")
+    };
+
+    match file.as_ref().seek(pos) {
+        Ok(pos) => Value::from(pos),
+        Err(err) => Value::from(error_code(&err)),
+    }
+}
+
+// Syscall to close a file
+// fs_close(u64 file_id)
+pub fn fs_close(thread: &mut Thread, file_id: Value)
+{
+    let file_id = file_id.as_u64();
+    let mut vm = thread.vm.lock().unwrap();
+
+    // The file is closed once no other thread is using it
+    if vm.fs_state.files.remove(&file_id).is_none() {
+        panic!("invalid file id {} in fs_close", file_id);
+    }
+}
+
+// C stat struct
+#[repr(C)]
+struct CStat
+{
+    size: u64,
+    mtime_ms: u64,
+    kind: u32,
+    pad: u32,
+}
+
+// Syscall to get information about a file or directory
+// i64 result = fs_stat(const char* path, void* stat_buf)
+pub fn fs_stat(thread: &mut Thread, path: Value, stat_buf: Value) -> Value
+{
+    let path = thread.get_heap_str(path.as_usize()).to_owned();
+
+    let path = match thread.vm.lock().unwrap().fs_state.resolve_path(&path) {
+        Ok(path) => path,
+        Err(code) => return Value::from(code),
+    };
+
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) => return Value::from(error_code(&err)),
+    };
+
+    let mtime_ms = match metadata.modified() {
+        Ok(time) => time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+        Err(_) => 0,
+    };
+
+    let kind = if metadata.is_file() {
+        FS_KIND_FILE
+    } else if metadata.is_dir() {
+        FS_KIND_DIR
+    } else {
+        FS_KIND_OTHER
+    };
+
+    let stat: &mut CStat = &mut thread.get_heap_slice_mut(stat_buf.as_usize(), 1)[0];
+    stat.size = metadata.len();
+    stat.mtime_ms = mtime_ms;
+    stat.kind = kind;
+    stat.pad = 0;
+
+    Value::from(0)
+}
+
+// Syscall to list the entries of a directory
+// i64 num_bytes = fs_list_dir(const char* path, char* buf_ptr, u64 buf_len)
+pub fn fs_list_dir(thread: &mut Thread, path: Value, buf_ptr: Value, buf_len: Value) -> Value
+{
+    let path = thread.get_heap_str(path.as_usize()).to_owned();
+    let buf_ptr = buf_ptr.as_usize();
+    let buf_len = buf_len.as_usize();
+
+    let path = match thread.vm.lock().unwrap().fs_state.resolve_path(&path) {
+        Ok(path) => path,
+        Err(code) => return Value::from(code),
+    };
+
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(err) => return Value::from(error_code(&err)),
+    };
+
+    let mut names = Vec::new();
+    for entry in entries {
+        match entry {
+            Ok(entry) => names.push(entry.file_name().to_string_lossy().into_owned()),
+            Err(err) => return Value::from(error_code(&err)),
+        }
+    }
+    names.sort();
+
+    // Write as many null-terminated names as fit in the buffer
+    let mut num_bytes = 0;
+    for name in names {
+        let name = name.as_bytes();
+
+        if num_bytes + name.len() < buf_len {
+            let buf: &mut [u8] = thread.get_heap_slice_mut(buf_ptr + num_bytes, name.len() + 1);
+            buf[..name.len()].copy_from_slice(name);
+            buf[name.len()] = 0;
+        }
+
+        num_bytes += name.len() + 1;
+    }
+
+    Value::from(num_bytes)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::VM;
+    use crate::asm::Assembler;
+
+    // Heap layout of the test program
+    const PATH: usize = 0;
+    const BUF: usize = 256;
+    const BUF_LEN: usize = 256;
+
+    /// Create an empty test directory and a thread with access to it
+    fn setup(name: &str) -> (Thread, PathBuf)
+    {
+        let dir = std::env::temp_dir().join(format!("uvm_fs_test_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let prog = Assembler::new().parse_str(".data; .zero 512; .code; push 0; ret;").unwrap();
+        let vm = VM::new(prog);
+        vm.lock().unwrap().fs_state.add_root(dir.to_str().unwrap()).unwrap();
+        let thread = VM::new_thread(&vm);
+
+        (thread, dir)
+    }
+
+    fn set_path(thread: &mut Thread, path: &Path)
+    {
+        let path = path.to_str().unwrap();
+        let buf: &mut [u8] = thread.get_heap_slice_mut(PATH, path.len() + 1);
+        buf[..path.len()].copy_from_slice(path.as_bytes());
+        buf[path.len()] = 0;
+    }
+
+    fn open(thread: &mut Thread, path: &Path, flags: u32) -> i64
+    {
+        set_path(thread, path);
+        fs_open(thread, Value::from(PATH), Value::from(flags)).as_i64()
+    }
+
+    #[test]
+    fn test_size_of_stat()
+    {
+        assert_eq!(size_of::<CStat>(), 24);
+    }
+
+    #[test]
+    fn test_write_read_seek()
+    {
+        let (mut thread, dir) = setup("rw");
+        let file_path = dir.join("foo.txt");
+
+        // The file doesn't exist yet
+        assert_eq!(open(&mut thread, &file_path, FS_OPEN_READ), FS_ERR_NOT_FOUND);
+
+        let file_id = open(&mut thread, &file_path, FS_OPEN_WRITE | FS_OPEN_CREATE);
+        assert!(file_id > 0);
+        let buf: &mut [u8] = thread.get_heap_slice_mut(BUF, 5);
+        buf.copy_from_slice(b"hello");
+        let result = fs_write(&mut thread, Value::from(file_id), Value::from(BUF), Value::from(5));
+        assert_eq!(result.as_i64(), 5);
+        fs_close(&mut thread, Value::from(file_id));
+        assert_eq!(fs::read(&file_path).unwrap(), b"hello");
+
+        let file_id = Value::from(open(&mut thread, &file_path, FS_OPEN_READ));
+        let result = fs_seek(&mut thread, file_id, Value::from(2), Value::from(FS_SEEK_SET));
+        assert_eq!(result.as_i64(), 2);
+        let result = fs_read(&mut thread, file_id, Value::from(BUF), Value::from(BUF_LEN));
+        assert_eq!(result.as_i64(), 3);
+        assert_eq!(thread.get_heap_slice_mut::<u8>(BUF, 3), b"llo");
+
+        // Reading at the end of the file produces zero bytes
+        let result = fs_read(&mut thread, file_id, Value::from(BUF), Value::from(BUF_LEN));
+        assert_eq!(result.as_i64(), 0);
+
+        let result = fs_seek(&mut thread, file_id, Value::from(-4), Value::from(FS_SEEK_END));
+        assert_eq!(result.as_i64(), 1);
+        fs_close(&mut thread, file_id);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stat_list_dir()
+    {
+        let (mut thread, dir) = setup("list");
+        fs::write(dir.join("b.txt"), b"abc").unwrap();
+        fs::write(dir.join("a.txt"), b"").unwrap();
+        fs::create_dir(dir.join("sub")).unwrap();
+
+        set_path(&mut thread, &dir.join("b.txt"));
+        let result = fs_stat(&mut thread, Value::from(PATH), Value::from(BUF));
+        assert_eq!(result.as_i64(), 0);
+        let stat: &CStat = &thread.get_heap_slice_mut(BUF, 1)[0];
+        assert_eq!(stat.size, 3);
+        assert_eq!(stat.kind, FS_KIND_FILE);
+        assert!(stat.mtime_ms > 0);
+
+        set_path(&mut thread, &dir.join("sub"));
+        fs_stat(&mut thread, Value::from(PATH), Value::from(BUF));
+        let stat: &CStat = &thread.get_heap_slice_mut(BUF, 1)[0];
+        assert_eq!(stat.kind, FS_KIND_DIR);
+
+        set_path(&mut thread, &dir);
+        let result = fs_list_dir(&mut thread, Value::from(PATH), Value::from(BUF), Value::from(BUF_LEN));
+        assert_eq!(result.as_i64(), 16);
+        assert_eq!(thread.get_heap_slice_mut::<u8>(BUF, 16), b"a.txt\0b.txt\0sub\0");
+
+        // Only the entries that fit are written
+        let buf: &mut [u8] = thread.get_heap_slice_mut(BUF, 16);
+        buf.fill(0xFF);
+        let result = fs_list_dir(&mut thread, Value::from(PATH), Value::from(BUF), Value::from(10));
+        assert_eq!(result.as_i64(), 16);
+        assert_eq!(thread.get_heap_slice_mut::<u8>(BUF, 7), b"a.txt\0\xFF");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_access_denied()
+    {
+        let (mut thread, dir) = setup("sandbox");
+        let outside = std::env::temp_dir().join(format!("uvm_fs_test_outside_{}", std::process::id()));
+        fs::write(&outside, b"secret").unwrap();
+
+        assert_eq!(open(&mut thread, &outside, FS_OPEN_READ), FS_ERR_ACCESS_DENIED);
+        assert_eq!(open(&mut thread, &dir.join("../foo"), FS_OPEN_WRITE | FS_OPEN_CREATE), FS_ERR_ACCESS_DENIED);
+        assert_eq!(open(&mut thread, &dir.join(".."), FS_OPEN_READ), FS_ERR_ACCESS_DENIED);
+
+        // Symlinks can't be used to escape the sandbox
+        std::os::unix::fs::symlink(&outside, dir.join("link")).unwrap();
+        assert_eq!(open(&mut thread, &dir.join("link"), FS_OPEN_READ), FS_ERR_ACCESS_DENIED);
+
+        // Including dangling symlinks, which would create their target
+        let target = std::env::temp_dir().join(format!("uvm_fs_test_dangling_{}", std::process::id()));
+        std::os::unix::fs::symlink(&target, dir.join("dangling")).unwrap();
+        assert_eq!(open(&mut thread, &dir.join("dangling"), FS_OPEN_WRITE | FS_OPEN_CREATE), FS_ERR_ACCESS_DENIED);
+        assert!(!target.exists());
+
+        set_path(&mut thread, &outside);
+        let result = fs_stat(&mut thread, Value::from(PATH), Value::from(BUF));
+        assert_eq!(result.as_i64(), FS_ERR_ACCESS_DENIED);
+
+        fs::remove_file(&outside).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_relative_path()
+    {
+        let (mut thread, dir) = setup("relative");
+        fs::create_dir(dir.join("sub")).unwrap();
+
+        // Relative paths are resolved against the root, not the current directory
+        let file_id = open(&mut thread, Path::new("sub/foo.txt"), FS_OPEN_WRITE | FS_OPEN_CREATE);
+        assert!(file_id > 0);
+        fs_close(&mut thread, Value::from(file_id));
+        assert!(dir.join("sub/foo.txt").exists());
+
+        assert_eq!(open(&mut thread, Path::new("../foo.txt"), FS_OPEN_WRITE | FS_OPEN_CREATE), FS_ERR_ACCESS_DENIED);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_close_invalid()
+    {
+        let (mut thread, _dir) = setup("close");
+        fs_close(&mut thread, Value::from(777));
+    }
+}
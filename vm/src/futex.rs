@@ -0,0 +1,172 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use crate::vm::{Thread, Value};
+use crate::constants::*;
+
+/// A thread blocked in futex_wait
+#[derive(Default)]
+struct Waiter
+{
+    /// Set by futex_wake while holding the queue lock
+    woken: Mutex<bool>,
+
+    cond: Condvar,
+}
+
+/// State for futex-style wait/wake synchronization
+#[derive(Default)]
+pub struct FutexState
+{
+    /// Wait queues keyed on heap addresses
+    /// Threads are woken in the order they started waiting
+    queues: Mutex<HashMap<usize, VecDeque<Arc<Waiter>>>>,
+}
+
+/// Get the futex state so that we can block without holding the VM lock
+fn get_futex_state(thread: &Thread) -> Arc<FutexState>
+{
+    thread.vm.lock().unwrap().futex_state.clone()
+}
+
+pub fn futex_wait(thread: &mut Thread, addr: Value, expected: Value, timeout_ms: Value) -> Value
+{
+    let addr = addr.as_usize();
+    let expected = expected.as_u32();
+    let timeout_ms = timeout_ms.as_i64();
+
+    let state = get_futex_state(thread);
+    let waiter = Arc::new(Waiter::default());
+
+    // Check the value and enqueue while holding the queue lock,
+    // so that a wake-up between the check and the wait can't be lost
+    {
+        let mut queues = state.queues.lock().unwrap();
+
+        let word_ptr = thread.get_heap_ptr_mut(addr, 1);
+        let word = unsafe { AtomicU32::from_ptr(word_ptr) };
+        if word.load(Ordering::Acquire) != expected {
+            return Value::from(FUTEX_ERR_VALUE_CHANGED);
+        }
+
+        queues.entry(addr).or_default().push_back(waiter.clone());
+    }
+
+    let woken = waiter.woken.lock().unwrap();
+
+    if timeout_ms < 0 {
+        let _woken = waiter.cond.wait_while(woken, |woken| !*woken).unwrap();
+        return Value::from(0);
+    }
+
+    let timeout = Duration::from_millis(timeout_ms as u64);
+    let (woken, _) = waiter.cond.wait_timeout_while(woken, timeout, |woken| !*woken).unwrap();
+    if *woken {
+        return Value::from(0);
+    }
+    drop(woken);
+
+    // The timeout expired, remove this thread from the wait queue.
+    // We may have been woken after the timeout but before taking the lock.
+    let mut queues = state.queues.lock().unwrap();
+    if let Some(queue) = queues.get_mut(&addr) {
+        queue.retain(|w| !Arc::ptr_eq(w, &waiter));
+        if queue.is_empty() {
+            queues.remove(&addr);
+        }
+    }
+
+    if *waiter.woken.lock().unwrap() {
+        return Value::from(0);
+    }
+
+    Value::from(FUTEX_ERR_TIMED_OUT)
+}
+
+pub fn futex_wake(thread: &mut Thread, addr: Value, count: Value) -> Value
+{
+    let addr = addr.as_usize();
+    let count = count.as_u64();
+
+    let state = get_futex_state(thread);
+    let mut queues = state.queues.lock().unwrap();
+    let mut num_woken: u64 = 0;
+
+    if let Some(queue) = queues.get_mut(&addr) {
+        while num_woken < count {
+            let Some(waiter) = queue.pop_front() else {
+                break;
+            };
+
+            *waiter.woken.lock().unwrap() = true;
+            waiter.cond.notify_one();
+            num_woken += 1;
+        }
+
+        if queue.is_empty() {
+            queues.remove(&addr);
+        }
+    }
+
+    Value::from(num_woken)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use crate::vm::tests::eval_i64;
+
+    #[test]
+    fn test_value_changed()
+    {
+        eval_i64(".data; .u32 5; .code; push 0; push 4; push -1; syscall futex_wait; ret;", -1);
+    }
+
+    #[test]
+    fn test_timeout()
+    {
+        eval_i64(".data; .u32 5; .code; push 0; push 5; push 10; syscall futex_wait; ret;", -2);
+    }
+
+    #[test]
+    fn test_wake_no_waiters()
+    {
+        eval_i64(".data; .u32 0; .code; push 0; push 1; syscall futex_wake; ret;", 0);
+    }
+
+    #[test]
+    fn test_wait_wake()
+    {
+        // Spawn three waiters, then keep waking until all three
+        // have been woken, and sum up their return values
+        eval_i64(concat!(
+            ".data; .u32 0; .code;",
+            "push_p32 WAITER; push 0; syscall thread_spawn;",
+            "push_p32 WAITER; push 0; syscall thread_spawn;",
+            "push_p32 WAITER; push 0; syscall thread_spawn;",
+            "push 0;",
+            "WAKE: push 0; push 3; syscall futex_wake; add_u64; dup; push 3; eq_u64; jz WAKE;",
+            "pop;",
+            "syscall thread_join; swap; syscall thread_join; add_u64;",
+            "swap; syscall thread_join; add_u64;",
+            "ret;",
+            "WAITER: push 0; push 0; push -1; syscall futex_wait; ret;",
+        ), 0);
+    }
+
+    #[test]
+    fn test_wake_count()
+    {
+        // Two waiters with a timeout, wake only one of them
+        eval_i64(concat!(
+            ".data; .u32 0; .code;",
+            "push_p32 WAITER; push 0; syscall thread_spawn;",
+            "push_p32 WAITER; push 0; syscall thread_spawn;",
+            "WAKE: push 0; push 1; syscall futex_wake; jz WAKE;",
+            "syscall thread_join; swap; syscall thread_join; add_u64;",
+            "ret;",
+            "WAITER: push 0; push 0; push 200; syscall futex_wait; ret;",
+        ), -2);
+    }
+}
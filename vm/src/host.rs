@@ -8,6 +8,11 @@ use crate::vm::{Value, VM, Thread};
 use crate::window::*;
 use crate::audio::*;
 use crate::net::*;
+use crate::fs::*;
+use crate::snapshot::*;
+use crate::futex::*;
+use crate::chan::*;
+use crate::event::*;
 use crate::time::*;
 use crate::constants::*;
 
@@ -31,6 +36,8 @@ pub enum HostFn
 
     Fn4_0(fn(&mut Thread, a0: Value, a1: Value, a2: Value, a3: Value)),
     Fn4_1(fn(&mut Thread, a0: Value, a1: Value, a2: Value, a3: Value) -> Value),
+
+    Fn5_1(fn(&mut Thread, a0: Value, a1: Value, a2: Value, a3: Value, a4: Value) -> Value),
 }
 
 impl HostFn
@@ -48,6 +55,7 @@ impl HostFn
             Self::Fn3_1(_) => 3,
             Self::Fn4_0(_) => 4,
             Self::Fn4_1(_) => 4,
+            Self::Fn5_1(_) => 5,
         }
     }
 
@@ -64,6 +72,7 @@ impl HostFn
             Self::Fn3_1(_) => true,
             Self::Fn4_0(_) => false,
             Self::Fn4_1(_) => true,
+            Self::Fn5_1(_) => true,
         }
     }
 }
@@ -93,6 +102,7 @@ pub fn get_syscall(const_idx: u16) -> HostFn
         // Core VM syscalls
         VM_HEAP_SIZE => HostFn::Fn0_1(vm_heap_size),
         VM_GROW_HEAP => HostFn::Fn1_1(vm_grow_heap),
+        VM_SNAPSHOT => HostFn::Fn1_1(vm_snapshot),
         MEMSET => HostFn::Fn3_0(memset),
         MEMSET32 => HostFn::Fn3_0(memset32),
         MEMCPY => HostFn::Fn3_0(memcpy),
@@ -103,6 +113,13 @@ pub fn get_syscall(const_idx: u16) -> HostFn
         THREAD_JOIN => HostFn::Fn1_1(thread_join),
         THREAD_ID => HostFn::Fn0_1(thread_id),
         THREAD_SLEEP => HostFn::Fn1_0(thread_sleep),
+        THREAD_SLEEP_US => HostFn::Fn1_0(thread_sleep_us),
+        FUTEX_WAIT => HostFn::Fn3_1(futex_wait),
+        FUTEX_WAKE => HostFn::Fn2_1(futex_wake),
+        CHAN_CREATE => HostFn::Fn1_1(chan_create),
+        CHAN_SEND => HostFn::Fn3_1(chan_send),
+        CHAN_RECV => HostFn::Fn4_1(chan_recv),
+        CHAN_CLOSE => HostFn::Fn1_0(chan_close),
 
         // Console I/O
         PRINT_I64 => HostFn::Fn1_0(print_i64),
@@ -113,15 +130,46 @@ pub fn get_syscall(const_idx: u16) -> HostFn
         GETCHAR => HostFn::Fn0_1(getchar),
 
         TIME_CURRENT_MS => HostFn::Fn0_1(time_current_ms),
+        TIME_MONOTONIC_NS => HostFn::Fn0_1(time_monotonic_ns),
+        TIME_LOCAL => HostFn::Fn2_1(time_local),
+        TIMER_SET => HostFn::Fn2_1(timer_set),
+        TIMER_CANCEL => HostFn::Fn1_0(timer_cancel),
 
         WINDOW_CREATE => HostFn::Fn4_1(window_create),
         WINDOW_DRAW_FRAME => HostFn::Fn2_0(window_draw_frame),
         WINDOW_POLL_EVENT => HostFn::Fn1_1(window_poll_event),
         WINDOW_WAIT_EVENT => HostFn::Fn1_0(window_wait_event),
+        WINDOW_ON_EVENT => HostFn::Fn3_0(window_on_event),
 
         AUDIO_OPEN_OUTPUT => HostFn::Fn4_1(audio_open_output),
         AUDIO_OPEN_INPUT => HostFn::Fn4_1(audio_open_input),
         AUDIO_READ_SAMPLES => HostFn::Fn2_0(audio_read_samples),
+        AUDIO_VOICE_CREATE => HostFn::Fn0_1(audio_voice_create),
+        AUDIO_VOICE_SUBMIT => HostFn::Fn4_0(audio_voice_submit),
+        AUDIO_VOICE_SET_PARAMS => HostFn::Fn3_0(audio_voice_set_params),
+        AUDIO_VOICE_QUEUED => HostFn::Fn1_1(audio_voice_queued),
+        AUDIO_VOICE_STOP => HostFn::Fn1_0(audio_voice_stop),
+        AUDIO_VOICE_DESTROY => HostFn::Fn1_0(audio_voice_destroy),
+
+        NET_LISTEN => HostFn::Fn1_1(net_listen),
+        NET_ACCEPT => HostFn::Fn3_1(net_accept),
+        NET_CONNECT => HostFn::Fn2_1(net_connect),
+        NET_READ => HostFn::Fn3_1(net_read),
+        NET_WRITE => HostFn::Fn3_1(net_write),
+        NET_CLOSE => HostFn::Fn1_0(net_close),
+        NET_UDP_BIND => HostFn::Fn1_1(net_udp_bind),
+        NET_UDP_SEND_TO => HostFn::Fn4_1(net_udp_send_to),
+        NET_UDP_RECV_FROM => HostFn::Fn5_1(net_udp_recv_from),
+        NET_POLL => HostFn::Fn3_1(net_poll),
+        NET_ON_READY => HostFn::Fn3_0(net_on_ready),
+
+        FS_OPEN => HostFn::Fn2_1(fs_open),
+        FS_READ => HostFn::Fn3_1(fs_read),
+        FS_WRITE => HostFn::Fn3_1(fs_write),
+        FS_SEEK => HostFn::Fn3_1(fs_seek),
+        FS_CLOSE => HostFn::Fn1_0(fs_close),
+        FS_STAT => HostFn::Fn2_1(fs_stat),
+        FS_LIST_DIR => HostFn::Fn3_1(fs_list_dir),
 
         _ => panic!("unknown syscall \"{}\"", const_idx),
     }
@@ -136,7 +184,9 @@ fn vm_grow_heap(thread: &mut Thread, num_bytes: Value) -> Value
 {
     let mut vm = thread.vm.lock().unwrap();
     let num_bytes = num_bytes.as_usize();
-    let new_size = vm.grow_heap(num_bytes);
+
+    // Zero signals that the heap couldn't be grown
+    let new_size = vm.grow_heap(num_bytes).unwrap_or(0);
     Value::from(new_size)
 }
 
@@ -154,6 +204,14 @@ fn thread_sleep(thread: &mut Thread, msecs: Value)
     thread::sleep(Duration::from_millis(msecs));
 }
 
+fn thread_sleep_us(thread: &mut Thread, usecs: Value)
+{
+    use std::thread;
+    use std::time::Duration;
+    let usecs = usecs.as_u64();
+    thread::sleep(Duration::from_micros(usecs));
+}
+
 // Spawn a new thread
 // Takes a function to call as argument
 // Returns a thread id
@@ -230,6 +288,11 @@ fn memcmp(thread: &mut Thread, ptr_a: Value, ptr_b: Value, num_bytes: Value) ->
 // End program execution
 fn exit(thread: &mut Thread, val: Value)
 {
+    let vm = thread.vm.lock().unwrap();
+    if vm.report_heap {
+        vm.print_heap_stats();
+    }
+
     unsafe { libc::exit(val.as_i32() & 0xFF) };
 }
 
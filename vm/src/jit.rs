@@ -0,0 +1,1790 @@
+//! Baseline JIT compiler for x86-64
+//!
+//! Functions are compiled to native code the Nth time they are called,
+//! one function at a time. Each value stack slot of the function gets a
+//! fixed slot in the native stack frame, so functions whose stack depth
+//! isn't the same along every path, or which use opcodes we don't support,
+//! are left to the interpreter. Calls and syscalls go through helper
+//! functions, which can fall back to the interpreter.
+//!
+//! Compiled code isn't counted by the count_insns feature. Fuel is
+//! taken for a whole basic block on entry, so when a block would use
+//! more than what's left, execution stops before the block starts.
+//! Compiled functions count towards the call depth limit, and their
+//! value slots towards the value stack limit, separately from the
+//! interpreter's value stack. They also stop with a stack overflow
+//! before running out of native stack. They aren't listed in guest
+//! backtraces.
+
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem::{offset_of, size_of, transmute};
+use std::panic::{self, AssertUnwindSafe};
+use crate::vm::{Op, ExtOp, Thread, Value};
+use crate::constants::{SYSCALL_DESCS, VM_SNAPSHOT};
+
+/// Value returned by compiled functions and helpers.
+/// This is returned in rax:rdx. When panicked is nonzero,
+/// the panic payload has been stored on the Jit.
+#[repr(C)]
+pub struct JitRet
+{
+    pub val: u64,
+    pub panicked: u64,
+}
+
+/// Compiled function, taking the thread, a pointer to the arguments and the argument count
+pub type JitFn = unsafe extern "C" fn(*mut Thread, *mut u64, u64) -> JitRet;
+
+/// Per-thread JIT state
+pub struct Jit
+{
+    /// Number of calls after which a function gets compiled
+    threshold: u64,
+
+    /// Call counts for functions not compiled yet
+    call_counts: HashMap<usize, u64>,
+
+    /// Compiled functions, None if a function can't be compiled
+    fns: HashMap<usize, Option<JitFn>>,
+
+    /// Executable memory holding the compiled functions
+    blocks: Vec<ExecBlock>,
+
+    /// Panic caught while running compiled code, to be resumed
+    /// once we're back in the interpreter
+    pub panic: Option<Box<dyn Any + Send>>,
+}
+
+impl Jit
+{
+    pub fn new(threshold: u64) -> Self
+    {
+        Self {
+            threshold,
+            call_counts: HashMap::default(),
+            fns: HashMap::default(),
+            blocks: Vec::default(),
+            panic: None,
+        }
+    }
+
+    /// Get the compiled code for a function, compiling it
+    /// if it has been called often enough
+    pub fn lookup(&mut self, callee_pc: usize, code: &[u8], heap_base: *mut u8, heap_size: *const usize) -> Option<JitFn>
+    {
+        if let Some(fun) = self.fns.get(&callee_pc) {
+            return *fun;
+        }
+
+        let count = self.call_counts.entry(callee_pc).or_insert(0);
+        *count += 1;
+        if *count < self.threshold {
+            return None;
+        }
+        self.call_counts.remove(&callee_pc);
+
+        let fun = compile(code, callee_pc, heap_base as u64, heap_size as u64, &self.fns).map(|bytes| {
+            let block = ExecBlock::new(&bytes);
+            let fun: JitFn = unsafe { transmute(block.ptr) };
+            self.blocks.push(block);
+            fun
+        });
+
+        self.fns.insert(callee_pc, fun);
+        fun
+    }
+}
+
+/// Block of executable memory
+struct ExecBlock
+{
+    ptr: *mut u8,
+    size: usize,
+}
+
+// Needed to move threads with compiled code
+unsafe impl Send for ExecBlock {}
+
+impl ExecBlock
+{
+    fn new(bytes: &[u8]) -> Self
+    {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let size = bytes.len().div_ceil(page_size) * page_size;
+
+        // Map the memory as writable to copy the code, then make it executable
+        let ptr = unsafe {libc::mmap(
+            std::ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0
+        )};
+
+        if ptr == libc::MAP_FAILED {
+            panic!("failed to allocate memory for JIT-compiled code");
+        }
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr as *mut u8, bytes.len());
+
+            if libc::mprotect(ptr, size, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                panic!("failed to make JIT-compiled code executable");
+            }
+        }
+
+        Self { ptr: ptr as *mut u8, size }
+    }
+}
+
+impl Drop for ExecBlock
+{
+    fn drop(&mut self)
+    {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.size) };
+    }
+}
+
+// Kinds of errors detected by compiled code
+const PANIC_OPCODE: u64 = 0;
+const PANIC_DIV_ZERO: u64 = 1;
+const PANIC_MOD_ZERO: u64 = 2;
+const PANIC_DIV_OVERFLOW: u64 = 3;
+const PANIC_MOD_OVERFLOW: u64 = 4;
+const PANIC_OUT_OF_BOUNDS: u64 = 5;
+const PANIC_UNALIGNED_U16: u64 = 6;
+const PANIC_UNALIGNED_U32: u64 = 7;
+const PANIC_UNALIGNED_U64: u64 = 8;
+const PANIC_GET_ARG: u64 = 9;
+const PANIC_SET_ARG: u64 = 10;
+const PANIC_GET_LOCAL: u64 = 11;
+const PANIC_SET_LOCAL: u64 = 12;
+const PANIC_STACK_OVERFLOW: u64 = 13;
+const PANIC_VALUE_STACK: u64 = 14;
+const PANIC_NATIVE_STACK: u64 = 15;
+
+/// Native stack space kept free below compiled code for the
+/// helpers, the interpreter and for reporting errors
+const NATIVE_STACK_RESERVE: u64 = 256 << 10;
+
+thread_local! {
+    /// Lowest native stack address that compiled code can use on this OS thread
+    static NATIVE_STACK_LIMIT: u64 = native_stack_limit();
+}
+
+#[cfg(target_os = "linux")]
+fn native_stack_limit() -> u64
+{
+    unsafe {
+        let mut attr: libc::pthread_attr_t = std::mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            panic!("could not get the native stack bounds");
+        }
+
+        let mut stack_addr: *mut libc::c_void = std::ptr::null_mut();
+        let mut stack_size: libc::size_t = 0;
+        libc::pthread_attr_getstack(&attr, &mut stack_addr, &mut stack_size);
+        libc::pthread_attr_destroy(&mut attr);
+
+        stack_addr as u64 + NATIVE_STACK_RESERVE
+    }
+}
+
+/// The JIT is only enabled on Linux
+#[cfg(not(target_os = "linux"))]
+fn native_stack_limit() -> u64
+{
+    0
+}
+
+/// Get the native stack limit for compiled code running on the current OS thread
+pub fn current_stack_limit() -> u64
+{
+    NATIVE_STACK_LIMIT.with(|limit| *limit)
+}
+
+/// Run a closure, catching any panic so that it
+/// doesn't unwind through compiled code
+fn catch_panic<F>(thread: *mut Thread, f: F) -> JitRet
+where F: FnOnce() -> Value
+{
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(val) => JitRet { val: val.as_u64(), panicked: 0 },
+        Err(payload) => {
+            let thread = unsafe { &mut *thread };
+            thread.jit.as_mut().unwrap().panic = Some(payload);
+            JitRet { val: 0, panicked: 1 }
+        }
+    }
+}
+
+/// Helper called by compiled code to call a function
+extern "C" fn jit_call(thread: *mut Thread, callee_pc: u64, args: *mut u64, argc: u64) -> JitRet
+{
+    let callee_pc = callee_pc as usize;
+
+    // Calls between compiled functions don't need to copy the arguments
+    if let Some(fun) = unsafe { &mut *thread }.jit_lookup(callee_pc) {
+        return unsafe { fun(thread, args, argc) };
+    }
+
+    catch_panic(thread, || {
+        let thread = unsafe { &mut *thread };
+        let args = unsafe { std::slice::from_raw_parts(args, argc as usize) };
+        for arg in args {
+            thread.push(*arg);
+        }
+        thread.run_fn(callee_pc, args.len())
+    })
+}
+
+/// Helper called by compiled code to make a syscall
+extern "C" fn jit_syscall(thread: *mut Thread, syscall_idx: u64, args: *const u64) -> JitRet
+{
+    catch_panic(thread, || {
+        let thread = unsafe { &mut *thread };
+        let desc = SYSCALL_DESCS[syscall_idx as usize].as_ref().unwrap();
+        let args = unsafe { std::slice::from_raw_parts(args, desc.argc) };
+        for arg in args {
+            thread.push(*arg);
+        }
+
+        thread.syscall(syscall_idx as u16);
+
+        if desc.has_ret {
+            thread.pop()
+        } else {
+            Value::from(0)
+        }
+    })
+}
+
+/// Helper called by compiled code when there isn't enough fuel for a block
+extern "C" fn jit_take_fuel(thread: *mut Thread, num_insns: u64) -> JitRet
+{
+    catch_panic(thread, || {
+        let thread = unsafe { &mut *thread };
+
+        // Give back the fuel taken before the check, then take it
+        // again once the thread has drawn enough from the budget
+        thread.fuel = thread.fuel.wrapping_add(num_insns);
+        thread.refill_fuel(num_insns);
+        thread.fuel -= num_insns;
+        Value::from(0)
+    })
+}
+
+/// Helper called by compiled code when an error is detected
+extern "C" fn jit_panic(thread: *mut Thread, kind: u64, a: u64, b: u64) -> JitRet
+{
+    catch_panic(thread, || {
+        let thread = unsafe { &mut *thread };
+        match kind {
+            PANIC_STACK_OVERFLOW => thread.call_depth_exceeded(),
+            PANIC_VALUE_STACK => thread.value_stack_exceeded(),
+            PANIC_NATIVE_STACK => thread.native_stack_exceeded(),
+            PANIC_OPCODE => panic!("execution error, encountered panic opcode"),
+            PANIC_DIV_ZERO => panic!("attempt to divide by zero"),
+            PANIC_MOD_ZERO => panic!("attempt to calculate the remainder with a divisor of zero"),
+            PANIC_DIV_OVERFLOW => panic!("attempt to divide with overflow"),
+            PANIC_MOD_OVERFLOW => panic!("attempt to calculate the remainder with overflow"),
+            PANIC_OUT_OF_BOUNDS => panic!("attempting to access memory slice past end of heap"),
+            PANIC_UNALIGNED_U16 => panic!("attempting to access data of type u16 at unaligned address {}", a),
+            PANIC_UNALIGNED_U32 => panic!("attempting to access data of type u32 at unaligned address {}", a),
+            PANIC_UNALIGNED_U64 => panic!("attempting to access data of type u64 at unaligned address {}", a),
+            PANIC_GET_ARG => panic!("invalid index in get_arg, idx={}, argc={}", a, b),
+            PANIC_SET_ARG => panic!("invalid index in set_arg, idx={}, argc={}", a, b),
+            PANIC_GET_LOCAL => panic!("invalid index {} in get_local", a),
+            PANIC_SET_LOCAL => panic!("invalid index in set_local"),
+            _ => unreachable!()
+        }
+    })
+}
+
+/// Helper for floating-point operations without a native instruction
+/// The operands and result are encoded as in Value
+extern "C" fn jit_float_op(op: u64, a: u64, b: u64) -> u64
+{
+    let op: Op = unsafe { transmute(op as u8) };
+    let a = Value::from(a);
+    let b = Value::from(b);
+
+    let val = match op {
+        Op::sin_f32 => Value::from(a.as_f32().sin()),
+        Op::cos_f32 => Value::from(a.as_f32().cos()),
+        Op::tan_f32 => Value::from(a.as_f32().tan()),
+        Op::asin_f32 => Value::from(a.as_f32().asin()),
+        Op::acos_f32 => Value::from(a.as_f32().acos()),
+        Op::atan_f32 => Value::from(a.as_f32().atan()),
+        Op::pow_f32 => Value::from(a.as_f32().powf(b.as_f32())),
+        Op::f32_to_i32 => Value::from(a.as_f32() as i32),
+        Op::floor_f32 => Value::from(a.as_f32().floor()),
+        Op::ceil_f32 => Value::from(a.as_f32().ceil()),
+        Op::round_f32 => Value::from(a.as_f32().round()),
+        Op::trunc_f32 => Value::from(a.as_f32().trunc()),
+        Op::abs_f32 => Value::from(a.as_f32().abs()),
+        Op::min_f32 => Value::from(a.as_f32().min(b.as_f32())),
+        Op::max_f32 => Value::from(a.as_f32().max(b.as_f32())),
+        Op::exp_f32 => Value::from(a.as_f32().exp()),
+        Op::log_f32 => Value::from(a.as_f32().ln()),
+        Op::atan2_f32 => Value::from(a.as_f32().atan2(b.as_f32())),
+        Op::mod_f32 => Value::from(a.as_f32() % b.as_f32()),
+        Op::f32_to_i64 => Value::from(a.as_f32() as i64),
+        Op::u64_to_f32 => Value::from(a.as_u64() as f32),
+        Op::f32_to_u32 => Value::from(a.as_f32() as u32),
+        Op::f32_to_u64 => Value::from(a.as_f32() as u64),
+        Op::f64_to_i32 => Value::from(a.as_f64() as i32),
+        Op::f64_to_i64 => Value::from(a.as_f64() as i64),
+        _ => unreachable!()
+    };
+
+    val.as_u64()
+}
+
+/// Helper for extended floating-point operations
+/// The operands and result are encoded as in Value
+extern "C" fn jit_float_ext_op(op: u64, a: u64, b: u64) -> u64
+{
+    let op: ExtOp = unsafe { transmute(op as u8) };
+    let a = Value::from(a);
+    let b = Value::from(b);
+
+    let val = match op {
+        ExtOp::sin_f64 => Value::from(a.as_f64().sin()),
+        ExtOp::cos_f64 => Value::from(a.as_f64().cos()),
+        ExtOp::tan_f64 => Value::from(a.as_f64().tan()),
+        ExtOp::asin_f64 => Value::from(a.as_f64().asin()),
+        ExtOp::acos_f64 => Value::from(a.as_f64().acos()),
+        ExtOp::atan_f64 => Value::from(a.as_f64().atan()),
+        ExtOp::pow_f64 => Value::from(a.as_f64().powf(b.as_f64())),
+        _ => unreachable!()
+    };
+
+    val.as_u64()
+}
+
+// x86-64 registers
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RSP: u8 = 4;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R12: u8 = 12;
+const R13: u8 = 13;
+const R14: u8 = 14;
+const R15: u8 = 15;
+
+// x86-64 condition codes
+const CC_B: u8 = 0x2;
+const CC_AE: u8 = 0x3;
+const CC_E: u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_BE: u8 = 0x6;
+const CC_A: u8 = 0x7;
+const CC_P: u8 = 0xA;
+const CC_NP: u8 = 0xB;
+const CC_L: u8 = 0xC;
+const CC_GE: u8 = 0xD;
+const CC_LE: u8 = 0xE;
+const CC_G: u8 = 0xF;
+
+// Opcodes of two-operand integer instructions, in the op r/m, reg form
+const ALU_ADD: u8 = 0x01;
+const ALU_OR: u8 = 0x09;
+const ALU_AND: u8 = 0x21;
+const ALU_SUB: u8 = 0x29;
+const ALU_XOR: u8 = 0x31;
+const ALU_CMP: u8 = 0x39;
+const ALU_TEST: u8 = 0x85;
+
+/// Buffer to encode x86-64 machine code into
+#[derive(Default)]
+struct CodeBuf
+{
+    bytes: Vec<u8>,
+
+    /// Positions of the labels once bound
+    labels: Vec<Option<usize>>,
+
+    /// Positions of 32-bit offsets to patch, and the label they refer to
+    fixups: Vec<(usize, usize)>,
+}
+
+impl CodeBuf
+{
+    fn emit(&mut self, bytes: &[u8])
+    {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn emit_u32(&mut self, val: u32)
+    {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn new_label(&mut self) -> usize
+    {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn bind(&mut self, label: usize)
+    {
+        assert!(self.labels[label].is_none());
+        self.labels[label] = Some(self.bytes.len());
+    }
+
+    fn rel32(&mut self, label: usize)
+    {
+        self.fixups.push((self.bytes.len(), label));
+        self.emit_u32(0);
+    }
+
+    /// REX prefix, omitted when not needed
+    fn rex(&mut self, w: bool, reg: u8, rm: u8)
+    {
+        let rex = 0x40 | ((w as u8) << 3) | (((reg >> 3) & 1) << 2) | ((rm >> 3) & 1);
+        if rex != 0x40 {
+            self.bytes.push(rex);
+        }
+    }
+
+    /// ModRM byte for a register operand
+    fn modrm_reg(&mut self, reg: u8, rm: u8)
+    {
+        self.bytes.push(0xC0 | ((reg & 7) << 3) | (rm & 7));
+    }
+
+    /// ModRM byte for a [base + disp32] memory operand
+    fn modrm_mem(&mut self, reg: u8, base: u8, disp: i32)
+    {
+        self.bytes.push(0x80 | ((reg & 7) << 3) | (base & 7));
+        if base & 7 == RSP {
+            self.bytes.push(0x24);
+        }
+        self.emit_u32(disp as u32);
+    }
+
+    /// mov dst, [base + disp]
+    fn load(&mut self, dst: u8, base: u8, disp: i32)
+    {
+        self.rex(true, dst, base);
+        self.bytes.push(0x8B);
+        self.modrm_mem(dst, base, disp);
+    }
+
+    /// mov [base + disp], src
+    fn store(&mut self, base: u8, disp: i32, src: u8)
+    {
+        self.rex(true, src, base);
+        self.bytes.push(0x89);
+        self.modrm_mem(src, base, disp);
+    }
+
+    /// lea dst, [base + disp]
+    fn lea(&mut self, dst: u8, base: u8, disp: i32)
+    {
+        self.rex(true, dst, base);
+        self.bytes.push(0x8D);
+        self.modrm_mem(dst, base, disp);
+    }
+
+    /// mov dst, imm
+    fn mov_imm(&mut self, dst: u8, imm: u64)
+    {
+        // Writing a 32-bit register zero-extends the value
+        if imm <= u32::MAX as u64 {
+            self.rex(false, 0, dst);
+            self.bytes.push(0xB8 + (dst & 7));
+            self.emit_u32(imm as u32);
+        } else {
+            self.rex(true, 0, dst);
+            self.bytes.push(0xB8 + (dst & 7));
+            self.emit(&imm.to_le_bytes());
+        }
+    }
+
+    /// mov dst, src
+    fn mov(&mut self, dst: u8, src: u8)
+    {
+        self.alu(0x89, true, dst, src);
+    }
+
+    /// Two-operand instruction on registers, e.g. add dst, src
+    fn alu(&mut self, opcode: u8, w: bool, dst: u8, src: u8)
+    {
+        self.rex(w, src, dst);
+        self.bytes.push(opcode);
+        self.modrm_reg(src, dst);
+    }
+
+    /// Single-operand instruction in the F7 or D3 group, e.g. not reg
+    /// sub qword [base + disp], imm32
+    fn sub_mem_imm(&mut self, base: u8, disp: i32, imm: u32)
+    {
+        self.rex(true, 0, base);
+        self.bytes.push(0x81);
+        self.modrm_mem(5, base, disp);
+        self.emit_u32(imm);
+    }
+
+    fn add_mem_imm(&mut self, base: u8, disp: i32, imm: u32)
+    {
+        self.rex(true, 0, base);
+        self.bytes.push(0x81);
+        self.modrm_mem(0, base, disp);
+        self.emit_u32(imm);
+    }
+
+    fn group(&mut self, opcode: u8, ext: u8, w: bool, reg: u8)
+    {
+        self.rex(w, 0, reg);
+        self.bytes.push(opcode);
+        self.modrm_reg(ext, reg);
+    }
+
+    /// setcc al followed by movzx eax, al
+    fn setcc(&mut self, cc: u8)
+    {
+        self.emit(&[0x0F, 0x90 + cc, 0xC0]);
+        self.emit(&[0x0F, 0xB6, 0xC0]);
+    }
+
+    fn jmp(&mut self, label: usize)
+    {
+        self.bytes.push(0xE9);
+        self.rel32(label);
+    }
+
+    fn call_label(&mut self, label: usize)
+    {
+        self.bytes.push(0xE8);
+        self.rel32(label);
+    }
+
+    fn jcc(&mut self, cc: u8, label: usize)
+    {
+        self.emit(&[0x0F, 0x80 + cc]);
+        self.rel32(label);
+    }
+
+    /// Call a function at an absolute address, clobbers rax
+    fn call(&mut self, fun: u64)
+    {
+        self.mov_imm(RAX, fun);
+        self.emit(&[0xFF, 0xD0]);
+    }
+
+    /// Patch jump offsets once all the labels are bound
+    fn link(&mut self)
+    {
+        for &(pos, label) in &self.fixups {
+            let target = self.labels[label].unwrap();
+            let offset = (target as i64 - (pos + 4) as i64) as i32;
+            self.bytes[pos..pos + 4].copy_from_slice(&offset.to_le_bytes());
+        }
+    }
+}
+
+/// Decoded instruction
+struct Insn
+{
+    op: Op,
+
+    /// Address of the next instruction
+    next_pc: usize,
+
+    /// Immediate operand, jump/call target or extended opcode
+    imm: u64,
+
+    /// Argument count for calls
+    argc: usize,
+
+    /// Value stack depth before the instruction, relative to the frame base
+    depth: usize,
+}
+
+/// Read a value from the code, None if it's past the end
+fn read<T: Copy>(code: &[u8], pc: &mut usize) -> Option<T>
+{
+    if *pc >= code.len() || code.len() - *pc < size_of::<T>() {
+        return None;
+    }
+
+    let val = unsafe { std::ptr::read_unaligned(code.as_ptr().add(*pc) as *const T) };
+    *pc += size_of::<T>();
+    Some(val)
+}
+
+fn decode(code: &[u8], pc: usize, depth: usize) -> Option<Insn>
+{
+    let mut next_pc = pc;
+
+    let op = read::<u8>(code, &mut next_pc)?;
+    if op > Op::LAST as u8 && op != Op::OP_EXT as u8 {
+        return None;
+    }
+    let op: Op = unsafe { transmute(op) };
+
+    let mut argc = 0;
+    let imm = match op {
+        Op::push_0n | Op::getn | Op::setn |
+        Op::get_arg | Op::set_arg | Op::get_local | Op::set_local => {
+            read::<u8>(code, &mut next_pc)? as u64
+        }
+
+        Op::push_i8 => read::<i8>(code, &mut next_pc)? as u64,
+
+        Op::OP_EXT => {
+            let ext_op = read::<u8>(code, &mut next_pc)?;
+            if ext_op > ExtOp::LAST as u8 {
+                return None;
+            }
+            ext_op as u64
+        }
+
+        Op::push_u64 => read::<u64>(code, &mut next_pc)?,
+        Op::syscall => read::<u16>(code, &mut next_pc)? as u64,
+
+        Op::push_u32 |
+        Op::load_u8_off | Op::load_u16_off | Op::load_u32_off | Op::load_u64_off |
+        Op::store_u8_off | Op::store_u16_off | Op::store_u32_off | Op::store_u64_off |
+        Op::load_global_u8 | Op::load_global_u16 | Op::load_global_u32 | Op::load_global_u64 |
+        Op::store_global_u8 | Op::store_global_u16 | Op::store_global_u32 | Op::store_global_u64 => {
+            read::<u32>(code, &mut next_pc)? as u64
+        }
+
+        Op::jmp | Op::jz | Op::jnz => {
+            let offset = read::<i32>(code, &mut next_pc)? as isize;
+            (next_pc as isize + offset) as u64
+        }
+
+        Op::jmp_8 | Op::jz_8 | Op::jnz_8 => {
+            let offset = read::<i8>(code, &mut next_pc)? as isize;
+            (next_pc as isize + offset) as u64
+        }
+
+        Op::call => {
+            let offset = read::<i32>(code, &mut next_pc)? as isize;
+            argc = read::<u8>(code, &mut next_pc)? as usize;
+            (next_pc as isize + offset) as u64
+        }
+
+        Op::call_fp => {
+            argc = read::<u8>(code, &mut next_pc)? as usize;
+            0
+        }
+
+        _ => 0
+    };
+
+    Some(Insn { op, next_pc, imm, argc, depth })
+}
+
+/// Number of values popped and pushed by an instruction,
+/// None if the instruction isn't supported
+fn stack_effect(insn: &Insn) -> Option<(usize, usize)>
+{
+    use Op::*;
+    let n = insn.imm as usize;
+
+    let effect = match insn.op {
+        nop | panic | jmp | jmp_8 => (0, 0),
+
+        push_0 | push_1 | push_2 | push_i8 | push_u32 | push_u64 |
+        get_arg | get_local |
+        load_global_u8 | load_global_u16 | load_global_u32 | load_global_u64 => (0, 1),
+
+        push_0n => (0, n),
+
+        pop | set_arg | set_local | jz | jnz | jz_8 | jnz_8 | ret |
+        store_global_u8 | store_global_u16 | store_global_u32 | store_global_u64 => (1, 0),
+
+        dup => (1, 2),
+        swap => (2, 2),
+
+        // These must not reach below the values they access
+        getn => (n + 1, n + 2),
+        setn => (n + 2, n + 1),
+
+        not_u32 | not_u64 |
+        sx_i8_i32 | sx_i8_i64 | sx_i16_i32 | sx_i16_i64 | sx_i32_i64 |
+        trunc_u8 | trunc_u16 | trunc_u32 |
+        sin_f32 | cos_f32 | tan_f32 | asin_f32 | acos_f32 | atan_f32 | sqrt_f32 |
+        i32_to_f32 | i64_to_f32 | f32_to_i32 |
+        floor_f32 | ceil_f32 | round_f32 | trunc_f32 | abs_f32 | exp_f32 | log_f32 |
+        f32_to_i64 | u32_to_f32 | u64_to_f32 | f32_to_u32 | f32_to_u64 |
+        get_var_arg | i32_to_f64 | i64_to_f64 | f64_to_i32 | f64_to_i64 | f32_to_f64 | f64_to_f32 |
+        load_u8 | load_u16 | load_u32 | load_u64 |
+        load_u8_off | load_u16_off | load_u32_off | load_u64_off => (1, 1),
+
+        and_u32 | or_u32 | xor_u32 | lshift_u32 | rshift_u32 | rshift_i32 |
+        add_u32 | sub_u32 | mul_u32 | div_u32 | mod_u32 | div_i32 | mod_i32 |
+        eq_u32 | ne_u32 | lt_u32 | le_u32 | gt_u32 | ge_u32 | lt_i32 | le_i32 | gt_i32 | ge_i32 |
+        and_u64 | or_u64 | xor_u64 | lshift_u64 | rshift_u64 | rshift_i64 |
+        add_u64 | sub_u64 | mul_u64 | div_u64 | mod_u64 | div_i64 | mod_i64 |
+        eq_u64 | ne_u64 | lt_u64 | le_u64 | gt_u64 | ge_u64 | lt_i64 | le_i64 | gt_i64 | ge_i64 |
+        add_f32 | sub_f32 | mul_f32 | div_f32 | pow_f32 |
+        eq_f32 | ne_f32 | lt_f32 | le_f32 | gt_f32 | ge_f32 |
+        min_f32 | max_f32 | atan2_f32 | mod_f32 |
+        add_f64 | sub_f64 | mul_f64 | div_f64 |
+        eq_f64 | ne_f64 | lt_f64 | le_f64 | gt_f64 | ge_f64 => (2, 1),
+
+        store_u8 | store_u16 | store_u32 | store_u64 |
+        store_u8_off | store_u16_off | store_u32_off | store_u64_off => (2, 0),
+
+        // Extended panics are left to the interpreter
+        OP_EXT => match unsafe { transmute::<u8, ExtOp>(n as u8) } {
+            ExtOp::panic => return None,
+            ExtOp::nop => (0, 0),
+            ExtOp::pow_f64 => (2, 1),
+            _ => (1, 1),
+        }
+
+        call => (insn.argc, 1),
+        call_fp => (insn.argc + 1, 1),
+
+        // Snapshots can only save the state of interpreted code
+        syscall if n == VM_SNAPSHOT as usize => return None,
+
+        syscall => {
+            let desc = SYSCALL_DESCS.get(n)?.as_ref()?;
+            (desc.argc, desc.has_ret as usize)
+        }
+
+        _ => return None
+    };
+
+    Some(effect)
+}
+
+/// Find the instructions reachable from the entry point and
+/// the stack depth before each of them. Returns None if the
+/// function can't be compiled.
+fn analyze(code: &[u8], entry_pc: usize) -> Option<(BTreeMap<usize, Insn>, usize)>
+{
+    let mut insns: BTreeMap<usize, Insn> = BTreeMap::new();
+    let mut max_depth = 0;
+    let mut worklist = vec![(entry_pc, 0)];
+
+    while let Some((pc, depth)) = worklist.pop() {
+        // Each stack slot must be at the same place along every path
+        if let Some(insn) = insns.get(&pc) {
+            if insn.depth != depth {
+                return None;
+            }
+            continue;
+        }
+
+        let insn = decode(code, pc, depth)?;
+        let (pops, pushes) = stack_effect(&insn)?;
+        if pops > depth {
+            return None;
+        }
+
+        let out_depth = depth - pops + pushes;
+        max_depth = max_depth.max(out_depth);
+
+        match insn.op {
+            Op::ret | Op::panic => {}
+            Op::jmp | Op::jmp_8 => worklist.push((insn.imm as usize, out_depth)),
+            Op::jz | Op::jnz | Op::jz_8 | Op::jnz_8 => {
+                worklist.push((insn.imm as usize, out_depth));
+                worklist.push((insn.next_pc, out_depth));
+            }
+            _ => worklist.push((insn.next_pc, out_depth)),
+        }
+
+        insns.insert(pc, insn);
+    }
+
+    Some((insns, max_depth))
+}
+
+/// Code generator for one function
+///
+/// Registers used by compiled code:
+/// - rbx: thread pointer
+/// - r12: pointer to the arguments
+/// - r13: argument count
+/// - r14: heap base address
+/// - r15: pointer to the current heap size
+/// - value stack slot i is at [rsp + 8 * i]
+struct CodeGen
+{
+    buf: CodeBuf,
+
+    /// Address of the function being compiled
+    entry_pc: usize,
+
+    /// Label at the start of the function, for recursive calls
+    entry: usize,
+
+    /// Label of the shared epilogue
+    epilogue: usize,
+
+    /// Functions already compiled, which can be called directly
+    compiled: HashMap<usize, JitFn>,
+
+    /// Label in the epilogue after the value slots are given back
+    epilogue_frames: usize,
+
+    /// Error exits to generate after the function body,
+    /// with the label, error kind, first argument
+    /// (rax holds the first argument when None)
+    /// and the label to exit through
+    panics: Vec<(usize, u64, Option<u64>, usize)>,
+
+    /// Calls to draw more fuel to generate after the function body,
+    /// with the label, number of instructions and label to resume at
+    refills: Vec<(usize, u64, usize)>,
+}
+
+impl CodeGen
+{
+    fn slot(idx: usize) -> i32
+    {
+        (idx * 8) as i32
+    }
+
+    fn ld(&mut self, reg: u8, idx: usize)
+    {
+        self.buf.load(reg, RSP, Self::slot(idx));
+    }
+
+    fn st(&mut self, idx: usize, reg: u8)
+    {
+        self.buf.store(RSP, Self::slot(idx), reg);
+    }
+
+    /// Create an error exit and get its label
+    fn panic_label(&mut self, kind: u64, arg: Option<u64>) -> usize
+    {
+        self.panic_label_to(kind, arg, self.epilogue)
+    }
+
+    /// Create an error exit which leaves through a given epilogue label
+    fn panic_label_to(&mut self, kind: u64, arg: Option<u64>, exit: usize) -> usize
+    {
+        let label = self.buf.new_label();
+        self.panics.push((label, kind, arg, exit));
+        label
+    }
+
+    /// Take fuel for the instructions of a basic block, calling
+    /// a helper to draw more or to stop if there isn't enough left
+    fn take_fuel(&mut self, num_insns: u64)
+    {
+        let refill = self.buf.new_label();
+        let resume = self.buf.new_label();
+        self.refills.push((refill, num_insns, resume));
+        self.buf.sub_mem_imm(RBX, offset_of!(Thread, fuel) as i32, num_insns as u32);
+        self.buf.jcc(CC_B, refill);
+        self.buf.bind(resume);
+    }
+
+    /// Return to the caller if a helper call panicked
+    fn check_panicked(&mut self)
+    {
+        self.buf.alu(ALU_TEST, true, RDX, RDX);
+        self.buf.jcc(CC_NE, self.epilogue);
+    }
+
+    /// Check that the heap address in rax can be accessed,
+    /// then turn it into a host pointer
+    fn heap_addr(&mut self, size: u64)
+    {
+        let out_of_bounds = self.panic_label(PANIC_OUT_OF_BOUNDS, Some(0));
+
+        // rdx = end address, checked against the current heap size
+        self.buf.mov(RDX, RAX);
+        self.buf.rex(true, 0, RDX);
+        self.buf.emit(&[0x83, 0xC2, size as u8]);
+        self.buf.jcc(CC_B, out_of_bounds);
+        self.buf.emit(&[0x49, 0x3B, 0x17]);
+        self.buf.jcc(CC_A, out_of_bounds);
+
+        if size > 1 {
+            let kind = match size {
+                2 => PANIC_UNALIGNED_U16,
+                4 => PANIC_UNALIGNED_U32,
+                _ => PANIC_UNALIGNED_U64,
+            };
+            let unaligned = self.panic_label(kind, None);
+
+            // test al, size - 1
+            self.buf.emit(&[0xA8, (size - 1) as u8]);
+            self.buf.jcc(CC_NE, unaligned);
+        }
+
+        self.buf.alu(ALU_ADD, true, RAX, R14);
+    }
+
+    /// Add a constant offset to the heap address in rax
+    fn add_offset(&mut self, offset: u64)
+    {
+        if offset != 0 {
+            let out_of_bounds = self.panic_label(PANIC_OUT_OF_BOUNDS, Some(0));
+            self.buf.mov_imm(RCX, offset);
+            self.buf.alu(ALU_ADD, true, RAX, RCX);
+            self.buf.jcc(CC_B, out_of_bounds);
+        }
+    }
+
+    /// Load a value of the given size from the host pointer in rax
+    fn load_mem(&mut self, size: u64)
+    {
+        match size {
+            1 => self.buf.emit(&[0x0F, 0xB6, 0x00]),
+            2 => self.buf.emit(&[0x0F, 0xB7, 0x00]),
+            4 => self.buf.emit(&[0x8B, 0x00]),
+            _ => self.buf.emit(&[0x48, 0x8B, 0x00]),
+        }
+    }
+
+    /// Store the value in rcx to the host pointer in rax
+    fn store_mem(&mut self, size: u64)
+    {
+        match size {
+            1 => self.buf.emit(&[0x88, 0x08]),
+            2 => self.buf.emit(&[0x66, 0x89, 0x08]),
+            4 => self.buf.emit(&[0x89, 0x08]),
+            _ => self.buf.emit(&[0x48, 0x89, 0x08]),
+        }
+    }
+
+    /// Integer division or remainder of rax by rcx
+    fn div(&mut self, w: bool, signed: bool, rem: bool)
+    {
+        let zero = self.panic_label(if rem { PANIC_MOD_ZERO } else { PANIC_DIV_ZERO }, Some(0));
+        self.buf.alu(ALU_TEST, w, RCX, RCX);
+        self.buf.jcc(CC_E, zero);
+
+        if signed {
+            // Dividing the minimum value by -1 overflows
+            let overflow = self.panic_label(if rem { PANIC_MOD_OVERFLOW } else { PANIC_DIV_OVERFLOW }, Some(0));
+            let no_overflow = self.buf.new_label();
+
+            self.buf.rex(w, 0, RCX);
+            self.buf.emit(&[0x83, 0xF9, 0xFF]);
+            self.buf.jcc(CC_NE, no_overflow);
+            self.buf.mov_imm(RDX, if w { i64::MIN as u64 } else { i32::MIN as u32 as u64 });
+            self.buf.alu(ALU_CMP, w, RAX, RDX);
+            self.buf.jcc(CC_E, overflow);
+            self.buf.bind(no_overflow);
+
+            // cdq/cqo, then idiv rcx
+            self.buf.rex(w, 0, 0);
+            self.buf.emit(&[0x99]);
+            self.buf.group(0xF7, 7, w, RCX);
+        } else {
+            self.buf.alu(ALU_XOR, false, RDX, RDX);
+            self.buf.group(0xF7, 6, w, RCX);
+        }
+
+        if rem {
+            self.buf.mov(RAX, RDX);
+        }
+
+        // Signed 32-bit results are sign-extended
+        if signed && !w {
+            self.buf.emit(&[0x48, 0x63, 0xC0]);
+        }
+    }
+
+    /// Move rax (and rcx) into xmm0 (and xmm1)
+    fn move_to_xmm(&mut self, f64: bool, binary: bool)
+    {
+        if f64 {
+            self.buf.emit(&[0x66, 0x48, 0x0F, 0x6E, 0xC0]);
+            if binary {
+                self.buf.emit(&[0x66, 0x48, 0x0F, 0x6E, 0xC9]);
+            }
+        } else {
+            self.buf.emit(&[0x66, 0x0F, 0x6E, 0xC0]);
+            if binary {
+                self.buf.emit(&[0x66, 0x0F, 0x6E, 0xC9]);
+            }
+        }
+    }
+
+    /// Move xmm0 into rax, f32 values are zero-extended
+    fn move_from_xmm(&mut self, f64: bool)
+    {
+        if f64 {
+            self.buf.emit(&[0x66, 0x48, 0x0F, 0x7E, 0xC0]);
+        } else {
+            self.buf.emit(&[0x66, 0x0F, 0x7E, 0xC0]);
+        }
+    }
+
+    /// Square root of the value at the stack top, with sqrtss/sqrtsd
+    fn sqrt(&mut self, f64: bool, d: usize)
+    {
+        self.ld(RAX, d - 1);
+        self.move_to_xmm(f64, false);
+        self.buf.emit(&[if f64 { 0xF2 } else { 0xF3 }, 0x0F, 0x51, 0xC0]);
+        self.move_from_xmm(f64);
+        self.st(d - 1, RAX);
+    }
+
+    /// Floating-point comparison of the values in rax and rcx
+    fn float_cmp(&mut self, f64: bool, op: Op)
+    {
+        use Op::*;
+        self.move_to_xmm(f64, true);
+
+        // ucomiss/ucomisd, the unordered case sets ZF, PF and CF
+        let ucomis = |buf: &mut CodeBuf, swapped: bool| {
+            if f64 {
+                buf.emit(&[0x66]);
+            }
+            buf.emit(&[0x0F, 0x2E, if swapped { 0xC8 } else { 0xC1 }]);
+        };
+
+        match op {
+            eq_f32 | eq_f64 => {
+                ucomis(&mut self.buf, false);
+                self.buf.emit(&[0x0F, 0x90 + CC_E, 0xC0]);
+                self.buf.emit(&[0x0F, 0x90 + CC_NP, 0xC1]);
+                self.buf.emit(&[0x20, 0xC8]);
+                self.buf.emit(&[0x0F, 0xB6, 0xC0]);
+            }
+            ne_f32 | ne_f64 => {
+                ucomis(&mut self.buf, false);
+                self.buf.emit(&[0x0F, 0x90 + CC_NE, 0xC0]);
+                self.buf.emit(&[0x0F, 0x90 + CC_P, 0xC1]);
+                self.buf.emit(&[0x08, 0xC8]);
+                self.buf.emit(&[0x0F, 0xB6, 0xC0]);
+            }
+            lt_f32 | lt_f64 => {
+                ucomis(&mut self.buf, true);
+                self.buf.setcc(CC_A);
+            }
+            le_f32 | le_f64 => {
+                ucomis(&mut self.buf, true);
+                self.buf.setcc(CC_AE);
+            }
+            gt_f32 | gt_f64 => {
+                ucomis(&mut self.buf, false);
+                self.buf.setcc(CC_A);
+            }
+            ge_f32 | ge_f64 => {
+                ucomis(&mut self.buf, false);
+                self.buf.setcc(CC_AE);
+            }
+            _ => unreachable!()
+        }
+    }
+
+    /// Generate code for one instruction
+    fn gen_insn(&mut self, insn: &Insn, pc_labels: &HashMap<usize, usize>)
+    {
+        use Op::*;
+        let d = insn.depth;
+        let imm = insn.imm;
+
+        match insn.op {
+            nop | pop => {}
+
+            panic => {
+                let label = self.panic_label(PANIC_OPCODE, Some(0));
+                self.buf.jmp(label);
+            }
+
+            push_0 | push_1 | push_2 | push_i8 | push_u32 | push_u64 => {
+                let val = match insn.op {
+                    push_0 => 0,
+                    push_1 => 1,
+                    push_2 => 2,
+                    _ => imm,
+                };
+                self.buf.mov_imm(RAX, val);
+                self.st(d, RAX);
+            }
+
+            push_0n => {
+                self.buf.alu(ALU_XOR, false, RAX, RAX);
+                for idx in d..d + imm as usize {
+                    self.st(idx, RAX);
+                }
+            }
+
+            dup => {
+                self.ld(RAX, d - 1);
+                self.st(d, RAX);
+            }
+
+            swap => {
+                self.ld(RAX, d - 1);
+                self.ld(RCX, d - 2);
+                self.st(d - 1, RCX);
+                self.st(d - 2, RAX);
+            }
+
+            getn => {
+                self.ld(RAX, d - 1 - imm as usize);
+                self.st(d, RAX);
+            }
+
+            setn => {
+                self.ld(RAX, d - 1);
+                self.st(d - 2 - imm as usize, RAX);
+            }
+
+            get_arg | set_arg => {
+                let kind = if insn.op == get_arg { PANIC_GET_ARG } else { PANIC_SET_ARG };
+                let invalid = self.panic_label(kind, Some(imm));
+
+                // cmp r13, idx
+                self.buf.emit(&[0x49, 0x81, 0xFD]);
+                self.buf.emit_u32(imm as u32);
+                self.buf.jcc(CC_BE, invalid);
+
+                if insn.op == get_arg {
+                    self.buf.load(RAX, R12, (imm * 8) as i32);
+                    self.st(d, RAX);
+                } else {
+                    self.ld(RAX, d - 1);
+                    self.buf.store(R12, (imm * 8) as i32, RAX);
+                }
+            }
+
+            get_var_arg => {
+                let invalid = self.panic_label(PANIC_GET_ARG, None);
+                self.ld(RAX, d - 1);
+                self.buf.alu(ALU_CMP, true, RAX, R13);
+                self.buf.jcc(CC_AE, invalid);
+
+                // mov rax, [r12 + rax * 8]
+                self.buf.emit(&[0x49, 0x8B, 0x04, 0xC4]);
+                self.st(d - 1, RAX);
+            }
+
+            get_local => {
+                if imm as usize >= d {
+                    let label = self.panic_label(PANIC_GET_LOCAL, Some(imm));
+                    self.buf.jmp(label);
+                } else {
+                    self.ld(RAX, imm as usize);
+                    self.st(d, RAX);
+                }
+            }
+
+            set_local => {
+                if imm as usize >= d - 1 {
+                    let label = self.panic_label(PANIC_SET_LOCAL, Some(imm));
+                    self.buf.jmp(label);
+                } else {
+                    self.ld(RAX, d - 1);
+                    self.st(imm as usize, RAX);
+                }
+            }
+
+            and_u32 | or_u32 | xor_u32 | add_u32 | sub_u32 |
+            and_u64 | or_u64 | xor_u64 | add_u64 | sub_u64 => {
+                let (opcode, w) = match insn.op {
+                    and_u32 => (ALU_AND, false),
+                    or_u32 => (ALU_OR, false),
+                    xor_u32 => (ALU_XOR, false),
+                    add_u32 => (ALU_ADD, false),
+                    sub_u32 => (ALU_SUB, false),
+                    and_u64 => (ALU_AND, true),
+                    or_u64 => (ALU_OR, true),
+                    xor_u64 => (ALU_XOR, true),
+                    add_u64 => (ALU_ADD, true),
+                    _ => (ALU_SUB, true),
+                };
+                self.ld(RAX, d - 2);
+                self.ld(RCX, d - 1);
+                self.buf.alu(opcode, w, RAX, RCX);
+                self.st(d - 2, RAX);
+            }
+
+            mul_u32 | mul_u64 => {
+                self.ld(RAX, d - 2);
+                self.ld(RCX, d - 1);
+                // imul rax, rcx
+                self.buf.rex(insn.op == mul_u64, RAX, RCX);
+                self.buf.emit(&[0x0F, 0xAF]);
+                self.buf.modrm_reg(RAX, RCX);
+                self.st(d - 2, RAX);
+            }
+
+            not_u32 | not_u64 => {
+                self.ld(RAX, d - 1);
+                self.buf.group(0xF7, 2, insn.op == not_u64, RAX);
+                self.st(d - 1, RAX);
+            }
+
+            // Shift counts are masked like wrapping_shl/wrapping_shr
+            lshift_u32 | rshift_u32 | rshift_i32 | lshift_u64 | rshift_u64 | rshift_i64 => {
+                let (ext, w) = match insn.op {
+                    lshift_u32 => (4, false),
+                    rshift_u32 => (5, false),
+                    rshift_i32 => (7, false),
+                    lshift_u64 => (4, true),
+                    rshift_u64 => (5, true),
+                    _ => (7, true),
+                };
+                self.ld(RAX, d - 2);
+                self.ld(RCX, d - 1);
+                self.buf.group(0xD3, ext, w, RAX);
+                if insn.op == rshift_i32 {
+                    self.buf.emit(&[0x48, 0x63, 0xC0]);
+                }
+                self.st(d - 2, RAX);
+            }
+
+            div_u32 | mod_u32 | div_i32 | mod_i32 | div_u64 | mod_u64 | div_i64 | mod_i64 => {
+                let w = matches!(insn.op, div_u64 | mod_u64 | div_i64 | mod_i64);
+                let signed = matches!(insn.op, div_i32 | mod_i32 | div_i64 | mod_i64);
+                let rem = matches!(insn.op, mod_u32 | mod_i32 | mod_u64 | mod_i64);
+                self.ld(RAX, d - 2);
+                self.ld(RCX, d - 1);
+                self.div(w, signed, rem);
+                self.st(d - 2, RAX);
+            }
+
+            eq_u32 | ne_u32 | lt_u32 | le_u32 | gt_u32 | ge_u32 | lt_i32 | le_i32 | gt_i32 | ge_i32 |
+            eq_u64 | ne_u64 | lt_u64 | le_u64 | gt_u64 | ge_u64 | lt_i64 | le_i64 | gt_i64 | ge_i64 => {
+                let (cc, w) = match insn.op {
+                    eq_u32 => (CC_E, false),
+                    ne_u32 => (CC_NE, false),
+                    lt_u32 => (CC_B, false),
+                    le_u32 => (CC_BE, false),
+                    gt_u32 => (CC_A, false),
+                    ge_u32 => (CC_AE, false),
+                    lt_i32 => (CC_L, false),
+                    le_i32 => (CC_LE, false),
+                    gt_i32 => (CC_G, false),
+                    ge_i32 => (CC_GE, false),
+                    eq_u64 => (CC_E, true),
+                    ne_u64 => (CC_NE, true),
+                    lt_u64 => (CC_B, true),
+                    le_u64 => (CC_BE, true),
+                    gt_u64 => (CC_A, true),
+                    ge_u64 => (CC_AE, true),
+                    lt_i64 => (CC_L, true),
+                    le_i64 => (CC_LE, true),
+                    gt_i64 => (CC_G, true),
+                    _ => (CC_GE, true),
+                };
+                self.ld(RAX, d - 2);
+                self.ld(RCX, d - 1);
+                self.buf.alu(ALU_CMP, w, RAX, RCX);
+                self.buf.setcc(cc);
+                self.st(d - 2, RAX);
+            }
+
+            sx_i8_i32 | sx_i8_i64 | sx_i16_i32 | sx_i16_i64 | sx_i32_i64 | trunc_u8 | trunc_u16 | trunc_u32 => {
+                self.ld(RAX, d - 1);
+                match insn.op {
+                    sx_i8_i32 | sx_i8_i64 => self.buf.emit(&[0x48, 0x0F, 0xBE, 0xC0]),
+                    sx_i16_i32 | sx_i16_i64 => self.buf.emit(&[0x48, 0x0F, 0xBF, 0xC0]),
+                    sx_i32_i64 => self.buf.emit(&[0x48, 0x63, 0xC0]),
+                    trunc_u8 => self.buf.emit(&[0x0F, 0xB6, 0xC0]),
+                    trunc_u16 => self.buf.emit(&[0x0F, 0xB7, 0xC0]),
+                    _ => self.buf.emit(&[0x89, 0xC0]),
+                }
+                self.st(d - 1, RAX);
+            }
+
+            add_f32 | sub_f32 | mul_f32 | div_f32 | add_f64 | sub_f64 | mul_f64 | div_f64 => {
+                let f64 = matches!(insn.op, add_f64 | sub_f64 | mul_f64 | div_f64);
+                let opcode = match insn.op {
+                    add_f32 | add_f64 => 0x58,
+                    sub_f32 | sub_f64 => 0x5C,
+                    mul_f32 | mul_f64 => 0x59,
+                    _ => 0x5E,
+                };
+                self.ld(RAX, d - 2);
+                self.ld(RCX, d - 1);
+                self.move_to_xmm(f64, true);
+                self.buf.emit(&[if f64 { 0xF2 } else { 0xF3 }, 0x0F, opcode, 0xC1]);
+                self.move_from_xmm(f64);
+                self.st(d - 2, RAX);
+            }
+
+            sqrt_f32 => self.sqrt(false, d),
+
+            eq_f32 | ne_f32 | lt_f32 | le_f32 | gt_f32 | ge_f32 |
+            eq_f64 | ne_f64 | lt_f64 | le_f64 | gt_f64 | ge_f64 => {
+                let f64 = matches!(insn.op, eq_f64 | ne_f64 | lt_f64 | le_f64 | gt_f64 | ge_f64);
+                self.ld(RAX, d - 2);
+                self.ld(RCX, d - 1);
+                self.float_cmp(f64, insn.op);
+                self.st(d - 2, RAX);
+            }
+
+            // Int to float conversions with cvtsi2ss/cvtsi2sd
+            i32_to_f32 | i64_to_f32 | u32_to_f32 | i32_to_f64 | i64_to_f64 => {
+                self.ld(RAX, d - 1);
+                if insn.op == u32_to_f32 {
+                    self.buf.emit(&[0x89, 0xC0]);
+                }
+                let prefix = if matches!(insn.op, i32_to_f64 | i64_to_f64) { 0xF2 } else { 0xF3 };
+                if matches!(insn.op, i32_to_f32 | i32_to_f64) {
+                    self.buf.emit(&[prefix, 0x0F, 0x2A, 0xC0]);
+                } else {
+                    self.buf.emit(&[prefix, 0x48, 0x0F, 0x2A, 0xC0]);
+                }
+                self.move_from_xmm(prefix == 0xF2);
+                self.st(d - 1, RAX);
+            }
+
+            f32_to_f64 => {
+                self.ld(RAX, d - 1);
+                self.move_to_xmm(false, false);
+                self.buf.emit(&[0xF3, 0x0F, 0x5A, 0xC0]);
+                self.move_from_xmm(true);
+                self.st(d - 1, RAX);
+            }
+
+            f64_to_f32 => {
+                self.ld(RAX, d - 1);
+                self.move_to_xmm(true, false);
+                self.buf.emit(&[0xF2, 0x0F, 0x5A, 0xC0]);
+                self.move_from_xmm(false);
+                self.st(d - 1, RAX);
+            }
+
+            // Remaining float operations go through a helper
+            sin_f32 | cos_f32 | tan_f32 | asin_f32 | acos_f32 | atan_f32 |
+            f32_to_i32 | floor_f32 | ceil_f32 | round_f32 | trunc_f32 | abs_f32 | exp_f32 | log_f32 |
+            f32_to_i64 | u64_to_f32 | f32_to_u32 | f32_to_u64 | f64_to_i32 | f64_to_i64 => {
+                self.ld(RSI, d - 1);
+                self.buf.mov_imm(RDI, insn.op as u64);
+                self.buf.call(jit_float_op as *const () as u64);
+                self.st(d - 1, RAX);
+            }
+
+            pow_f32 | min_f32 | max_f32 | atan2_f32 | mod_f32 => {
+                self.ld(RSI, d - 2);
+                self.ld(RDX, d - 1);
+                self.buf.mov_imm(RDI, insn.op as u64);
+                self.buf.call(jit_float_op as *const () as u64);
+                self.st(d - 2, RAX);
+            }
+
+            load_u8 | load_u16 | load_u32 | load_u64 |
+            load_u8_off | load_u16_off | load_u32_off | load_u64_off => {
+                let size = match insn.op {
+                    load_u8 | load_u8_off => 1,
+                    load_u16 | load_u16_off => 2,
+                    load_u32 | load_u32_off => 4,
+                    _ => 8,
+                };
+                self.ld(RAX, d - 1);
+                self.add_offset(imm);
+                self.heap_addr(size);
+                self.load_mem(size);
+                self.st(d - 1, RAX);
+            }
+
+            store_u8 | store_u16 | store_u32 | store_u64 |
+            store_u8_off | store_u16_off | store_u32_off | store_u64_off => {
+                let size = match insn.op {
+                    store_u8 | store_u8_off => 1,
+                    store_u16 | store_u16_off => 2,
+                    store_u32 | store_u32_off => 4,
+                    _ => 8,
+                };
+                self.ld(RAX, d - 2);
+                self.add_offset(imm);
+                self.heap_addr(size);
+                self.ld(RCX, d - 1);
+                self.store_mem(size);
+            }
+
+            load_global_u8 | load_global_u16 | load_global_u32 | load_global_u64 => {
+                let size = match insn.op {
+                    load_global_u8 => 1,
+                    load_global_u16 => 2,
+                    load_global_u32 => 4,
+                    _ => 8,
+                };
+                self.buf.mov_imm(RAX, imm);
+                self.heap_addr(size);
+                self.load_mem(size);
+                self.st(d, RAX);
+            }
+
+            store_global_u8 | store_global_u16 | store_global_u32 | store_global_u64 => {
+                let size = match insn.op {
+                    store_global_u8 => 1,
+                    store_global_u16 => 2,
+                    store_global_u32 => 4,
+                    _ => 8,
+                };
+                self.buf.mov_imm(RAX, imm);
+                self.heap_addr(size);
+                self.ld(RCX, d - 1);
+                self.store_mem(size);
+            }
+
+            jmp | jmp_8 => {
+                self.buf.jmp(pc_labels[&(imm as usize)]);
+            }
+
+            jz | jnz | jz_8 | jnz_8 => {
+                let cc = if matches!(insn.op, jz | jz_8) { CC_E } else { CC_NE };
+                self.ld(RAX, d - 1);
+                self.buf.alu(ALU_TEST, true, RAX, RAX);
+                self.buf.jcc(cc, pc_labels[&(imm as usize)]);
+            }
+
+            // Direct call to a compiled function
+            call if imm as usize == self.entry_pc || self.compiled.contains_key(&(imm as usize)) => {
+                let args_idx = d - insn.argc;
+                self.buf.mov(RDI, RBX);
+                self.buf.lea(RSI, RSP, Self::slot(args_idx));
+                self.buf.mov_imm(RDX, insn.argc as u64);
+                match self.compiled.get(&(imm as usize)) {
+                    Some(fun) => self.buf.call(*fun as *const () as u64),
+                    None => self.buf.call_label(self.entry),
+                }
+                self.check_panicked();
+                self.st(args_idx, RAX);
+            }
+
+            call | call_fp => {
+                let mut args_idx = d - insn.argc;
+                if insn.op == call {
+                    self.buf.mov_imm(RSI, imm);
+                } else {
+                    self.ld(RSI, d - 1);
+                    args_idx -= 1;
+                }
+                self.buf.mov(RDI, RBX);
+                self.buf.lea(RDX, RSP, Self::slot(args_idx));
+                self.buf.mov_imm(RCX, insn.argc as u64);
+                self.buf.call(jit_call as *const () as u64);
+                self.check_panicked();
+                self.st(args_idx, RAX);
+            }
+
+            syscall => {
+                let desc = SYSCALL_DESCS[imm as usize].as_ref().unwrap();
+                let args_idx = d - desc.argc;
+                self.buf.mov(RDI, RBX);
+                self.buf.mov_imm(RSI, imm);
+                self.buf.lea(RDX, RSP, Self::slot(args_idx));
+                self.buf.call(jit_syscall as *const () as u64);
+                self.check_panicked();
+                if desc.has_ret {
+                    self.st(args_idx, RAX);
+                }
+            }
+
+            ret => {
+                self.ld(RAX, d - 1);
+                self.buf.alu(ALU_XOR, false, RDX, RDX);
+                self.buf.jmp(self.epilogue);
+            }
+
+            OP_EXT => match unsafe { transmute::<u8, ExtOp>(imm as u8) } {
+                ExtOp::nop => {}
+
+                ExtOp::sqrt_f64 => self.sqrt(true, d),
+
+                ExtOp::pow_f64 => {
+                    self.ld(RSI, d - 2);
+                    self.ld(RDX, d - 1);
+                    self.buf.mov_imm(RDI, imm);
+                    self.buf.call(jit_float_ext_op as *const () as u64);
+                    self.st(d - 2, RAX);
+                }
+
+                ExtOp::sin_f64 | ExtOp::cos_f64 | ExtOp::tan_f64 |
+                ExtOp::asin_f64 | ExtOp::acos_f64 | ExtOp::atan_f64 => {
+                    self.ld(RSI, d - 1);
+                    self.buf.mov_imm(RDI, imm);
+                    self.buf.call(jit_float_ext_op as *const () as u64);
+                    self.st(d - 1, RAX);
+                }
+
+                ExtOp::panic => unreachable!(),
+            }
+
+            _ => unreachable!()
+        }
+    }
+}
+
+/// Count the instructions in the basic block starting at a given address
+fn block_len(insns: &BTreeMap<usize, Insn>, leaders: &HashSet<usize>, start_pc: usize) -> u64
+{
+    let mut num_insns = 0;
+    let mut pc = start_pc;
+
+    loop
+    {
+        let insn = &insns[&pc];
+        num_insns += 1;
+
+        if matches!(insn.op, Op::jmp | Op::jmp_8 | Op::jz | Op::jz_8 | Op::jnz | Op::jnz_8 | Op::ret | Op::panic) {
+            return num_insns;
+        }
+
+        pc = insn.next_pc;
+        if leaders.contains(&pc) {
+            return num_insns;
+        }
+    }
+}
+
+/// Compile the function at a given address to machine code
+/// Returns None if the function can't be compiled
+fn compile(
+    code: &[u8],
+    entry_pc: usize,
+    heap_base: u64,
+    heap_size: u64,
+    fns: &HashMap<usize, Option<JitFn>>
+) -> Option<Vec<u8>>
+{
+    let (insns, max_depth) = analyze(code, entry_pc)?;
+
+    let mut gen = CodeGen {
+        buf: CodeBuf::default(),
+        entry_pc,
+        entry: 0,
+        epilogue: 0,
+        epilogue_frames: 0,
+        compiled: fns.iter().filter_map(|(pc, fun)| Some((*pc, (*fun)?))).collect(),
+        panics: Vec::default(),
+        refills: Vec::default(),
+    };
+    gen.entry = gen.buf.new_label();
+    gen.epilogue = gen.buf.new_label();
+    gen.epilogue_frames = gen.buf.new_label();
+    gen.buf.bind(gen.entry);
+
+    let pc_labels: HashMap<usize, usize> = insns.keys().map(|pc| (*pc, gen.buf.new_label())).collect();
+
+    // After pushing 5 registers, rsp is 16-byte aligned, which must be
+    // preserved by the frame size so that we can call helpers
+    let frame_size = (max_depth * 8).next_multiple_of(16) as u32;
+
+    // Save the callee-saved registers we use
+    gen.buf.emit(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+
+    // sub rsp, frame_size
+    gen.buf.emit(&[0x48, 0x81, 0xEC]);
+    gen.buf.emit_u32(frame_size);
+
+    gen.buf.mov(RBX, RDI);
+    gen.buf.mov(R12, RSI);
+    gen.buf.mov(R13, RDX);
+    gen.buf.mov_imm(R14, heap_base);
+    gen.buf.mov_imm(R15, heap_size);
+
+    // Count this call towards the call depth limit, and its value slots
+    // towards the value stack limit. The epilogue gives them back, which
+    // also undoes the decrements when a limit is hit.
+    let call_depth_exceeded = gen.panic_label_to(PANIC_STACK_OVERFLOW, Some(0), gen.epilogue_frames);
+    gen.buf.sub_mem_imm(RBX, offset_of!(Thread, frames_left) as i32, 1);
+    gen.buf.jcc(CC_B, call_depth_exceeded);
+
+    let value_stack_exceeded = gen.panic_label(PANIC_VALUE_STACK, Some(0));
+    gen.buf.sub_mem_imm(RBX, offset_of!(Thread, jit_slots_left) as i32, max_depth as u32);
+    gen.buf.jcc(CC_B, value_stack_exceeded);
+
+    // The value slots are on the native stack, which can be smaller
+    // than the value stack limit, so it is checked as well
+    let native_stack_exceeded = gen.panic_label(PANIC_NATIVE_STACK, Some(0));
+    gen.buf.load(RAX, RBX, offset_of!(Thread, native_stack_limit) as i32);
+    gen.buf.alu(ALU_CMP, true, RSP, RAX);
+    gen.buf.jcc(CC_B, native_stack_exceeded);
+
+    // Instructions are laid out in address order
+    if insns.keys().next() != Some(&entry_pc) {
+        gen.buf.jmp(pc_labels[&entry_pc]);
+    }
+
+    // Basic blocks start at the entry, at jump targets and after conditional jumps
+    let mut leaders = HashSet::from([entry_pc]);
+    for insn in insns.values() {
+        if matches!(insn.op, Op::jmp | Op::jmp_8 | Op::jz | Op::jz_8 | Op::jnz | Op::jnz_8) {
+            leaders.insert(insn.imm as usize);
+            leaders.insert(insn.next_pc);
+        }
+    }
+
+    let mut iter = insns.iter().peekable();
+    while let Some((pc, insn)) = iter.next() {
+        gen.buf.bind(pc_labels[pc]);
+
+        if leaders.contains(pc) {
+            gen.take_fuel(block_len(&insns, &leaders, *pc));
+        }
+
+        gen.gen_insn(insn, &pc_labels);
+
+        // Jump to the next instruction if it isn't laid out right after
+        let falls_through = !matches!(insn.op, Op::ret | Op::panic | Op::jmp | Op::jmp_8);
+        if falls_through && iter.peek().map(|(pc, _)| **pc) != Some(insn.next_pc) {
+            gen.buf.jmp(pc_labels[&insn.next_pc]);
+        }
+    }
+
+    // Error exits, the helper's return value is passed on to the caller
+    for (label, num_insns, resume) in std::mem::take(&mut gen.refills) {
+        gen.buf.bind(label);
+        gen.buf.mov(RDI, RBX);
+        gen.buf.mov_imm(RSI, num_insns);
+        gen.buf.call(jit_take_fuel as *const () as u64);
+        gen.check_panicked();
+        gen.buf.jmp(resume);
+    }
+
+    for (label, kind, arg, exit) in std::mem::take(&mut gen.panics) {
+        gen.buf.bind(label);
+        match arg {
+            Some(arg) => gen.buf.mov_imm(RDX, arg),
+            None => gen.buf.mov(RDX, RAX),
+        }
+        gen.buf.mov(RCX, R13);
+        gen.buf.mov(RDI, RBX);
+        gen.buf.mov_imm(RSI, kind);
+        gen.buf.call(jit_panic as *const () as u64);
+        gen.buf.jmp(exit);
+    }
+
+    gen.buf.bind(gen.epilogue);
+    gen.buf.add_mem_imm(RBX, offset_of!(Thread, jit_slots_left) as i32, max_depth as u32);
+    gen.buf.bind(gen.epilogue_frames);
+    gen.buf.add_mem_imm(RBX, offset_of!(Thread, frames_left) as i32, 1);
+
+    // add rsp, frame_size
+    gen.buf.emit(&[0x48, 0x81, 0xC4]);
+    gen.buf.emit_u32(frame_size);
+
+    // Restore the callee-saved registers and return
+    gen.buf.emit(&[0x41, 0x5F, 0x41, 0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x5B, 0xC3]);
+
+    gen.buf.link();
+    Some(gen.buf.bytes)
+}
+
+#[cfg(all(test, target_arch = "x86_64", target_os = "linux"))]
+mod tests
+{
+    use super::*;
+    use crate::vm::VM;
+    use crate::asm::Assembler;
+
+    /// Run a program with every function compiled on its first call
+    fn eval_jit(src: &str) -> Value
+    {
+        let prog = Assembler::new().parse_str(src).unwrap();
+        let vm = VM::new(prog);
+        vm.lock().unwrap().jit_threshold = Some(1);
+        let mut thread = VM::new_thread(&vm);
+        thread.call(0, &[])
+    }
+
+    #[test]
+    fn test_analyze()
+    {
+        let prog = Assembler::new().parse_str("push 1; jz L; push 2; L: push 3; ret;").unwrap();
+        assert!(analyze(prog.code.as_slice(), 0).is_none());
+
+        let prog = Assembler::new().parse_str("push 1; jz L; push 2; ret; L: push 3; add_u64; ret;").unwrap();
+        assert!(analyze(prog.code.as_slice(), 0).is_none());
+
+        let prog = Assembler::new().parse_str("push 0; push 1; jz L; pop; push 2; L: ret;").unwrap();
+        let (insns, max_depth) = analyze(prog.code.as_slice(), 0).unwrap();
+        assert_eq!(insns.len(), 6);
+        assert_eq!(max_depth, 2);
+
+        // Unsupported opcode
+        let prog = Assembler::new().parse_str("push 1; push 2; add_u64_ovf; pop; ret;").unwrap();
+        assert!(analyze(prog.code.as_slice(), 0).is_none());
+
+        // Extended opcodes are supported, except for panic_ext
+        let prog = Assembler::new().parse_str("push_f64 2.0; push_f64 3.0; pow_f64; sqrt_f64; nop_ext; ret;").unwrap();
+        let (insns, max_depth) = analyze(prog.code.as_slice(), 0).unwrap();
+        assert_eq!(insns.len(), 6);
+        assert_eq!(max_depth, 2);
+        let prog = Assembler::new().parse_str("panic_ext; push 0; ret;").unwrap();
+        assert!(analyze(prog.code.as_slice(), 0).is_none());
+    }
+
+    #[test]
+    fn test_calls()
+    {
+        // Recursive calls between compiled functions
+        let fib = "
+            push 20; call FIB, 1; ret;
+            FIB:
+            get_arg 0; push 2; lt_i64; jz REC;
+            get_arg 0; ret;
+            REC:
+            get_arg 0; push 1; sub_u64; call FIB, 1;
+            get_arg 0; push 2; sub_u64; call FIB, 1;
+            add_u64; ret;
+        ";
+        assert_eq!(eval_jit(fib).as_i64(), 6765);
+
+        // The callee uses an unsupported opcode and stays interpreted,
+        // then calls back into a compiled function
+        let src = "
+            push 3; push 4; call F, 2; ret;
+            F: get_arg 0; get_arg 1; add_u64_ovf; pop; push_p32 G; call_fp 1; ret;
+            G: get_arg 0; push 10; mul_u64; ret;
+        ";
+        assert_eq!(eval_jit(src).as_i64(), 70);
+
+        // Variadic arguments
+        let src = "
+            push 5; push 6; push 7; call F, 3; ret;
+            F: push 2; get_var_arg; push 0; get_var_arg; sub_u64; ret;
+        ";
+        assert_eq!(eval_jit(src).as_i64(), 2);
+    }
+
+    #[test]
+    fn test_syscalls()
+    {
+        let src = "
+            .data; .zero 16; .code;
+            push 0; push 7; push 16; syscall memset;
+            push 8; load_u64; ret;
+        ";
+        assert_eq!(eval_jit(src).as_u64(), 0x0707070707070707);
+    }
+
+    #[test]
+    #[should_panic(expected = "attempting to access memory slice past end of heap")]
+    fn test_out_of_bounds()
+    {
+        eval_jit("push 0; call F, 0; ret; F: push -8; load_u64; ret;");
+    }
+
+    #[test]
+    #[should_panic(expected = "unaligned address 3")]
+    fn test_unaligned()
+    {
+        eval_jit(".data; .zero 16; .code; push 3; load_u32; ret;");
+    }
+
+    #[test]
+    #[should_panic(expected = "attempt to divide by zero")]
+    fn test_div_zero()
+    {
+        // The panic goes through an interpreted function
+        // and is caught by the compiled function calling it
+        eval_jit("
+            call F, 0; ret;
+            F: push 0; push 0; add_u64_ovf; pop; push_p32 G; call_fp 0; ret;
+            G: push 0; call H, 0; ret;
+            H: push 1; push 0; div_u32; ret;
+        ");
+    }
+}
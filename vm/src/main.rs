@@ -7,6 +7,14 @@
 mod window;
 mod audio;
 mod net;
+mod fs;
+mod futex;
+mod chan;
+mod event;
+mod jit;
+mod predecode;
+mod snapshot;
+mod fault;
 mod time;
 mod constants;
 mod host;
@@ -18,13 +26,23 @@ mod utils;
 extern crate sdl2;
 extern crate libc;
 use std::env;
-use std::thread::sleep;
+use std::thread::{self, sleep};
+use std::panic::{self, AssertUnwindSafe};
 use std::time::Duration;
 use std::process::exit;
 use std::sync::{Arc, Mutex};
-use crate::vm::{VM, Value};
+use crate::vm::{VM, Value, OutOfFuel};
 use crate::asm::{Assembler};
 use crate::utils::{thousands_sep};
+use crate::event::run_event_loop;
+use crate::snapshot::Snapshot;
+
+/// Exit code when the time limit set with --timeout is
+/// exceeded, the same as with the timeout command
+const EXIT_TIMEOUT: i32 = 124;
+
+/// Exit code when the instruction limit set with --max-insns is exceeded
+const EXIT_OUT_OF_FUEL: i32 = 125;
 
 /// Command-line options
 #[derive(Debug, Clone)]
@@ -33,6 +51,43 @@ struct Options
     // Only parse/validate the input, but don't run it
     parse_only: bool,
 
+    // Directories the program is allowed to access
+    fs_roots: Vec<String>,
+
+    // Environment variables passed to the program, as NAME=VALUE strings
+    env: Vec<String>,
+
+    // Number of calls after which functions get JIT-compiled
+    jit_threshold: Option<u64>,
+
+    // Run code with the pre-decoded interpreter
+    predecode: bool,
+
+    // Maximum number of instructions executed by the program, in total over
+    // all threads, main and all the callbacks made from the event loop
+    max_insns: Option<u64>,
+
+    // Wall-clock time limit for the whole program
+    timeout: Option<Duration>,
+
+    // Maximum heap size in bytes
+    max_heap: Option<usize>,
+
+    // Print the peak heap size on exit
+    heap_stats: bool,
+
+    // Maximum number of values on the value stack of each thread
+    max_stack: Option<usize>,
+
+    // Maximum call depth of each thread
+    max_frames: Option<usize>,
+
+    // Make the first heap page inaccessible to catch null pointer dereferences
+    null_guard: bool,
+
+    // Snapshot file to resume instead of running a program
+    resume: Option<String>,
+
     rest: Vec<String>,
 }
 
@@ -40,10 +95,35 @@ struct Options
 // --allow <permissions>
 // --deny <permissions>
 // --allow-all
+/// Parse a size in bytes with an optional K, M or G suffix
+fn parse_size(size: &str) -> Option<usize>
+{
+    let (digits, unit) = match size.chars().last()? {
+        'K' | 'k' => (&size[..size.len() - 1], 1 << 10),
+        'M' | 'm' => (&size[..size.len() - 1], 1 << 20),
+        'G' | 'g' => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
 fn parse_args(args: Vec<String>) -> Options
 {
     let mut opts = Options {
         parse_only: false,
+        fs_roots: Vec::default(),
+        env: Vec::default(),
+        jit_threshold: None,
+        predecode: false,
+        max_insns: None,
+        timeout: None,
+        max_heap: None,
+        heap_stats: false,
+        max_stack: None,
+        max_frames: None,
+        null_guard: false,
+        resume: None,
         rest: Vec::default(),
     };
 
@@ -69,6 +149,119 @@ fn parse_args(args: Vec<String>) -> Options
                 opts.parse_only = true;
             }
 
+            "--fs-root" => {
+                if idx >= args.len() {
+                    panic!("--fs-root requires a directory argument");
+                }
+
+                opts.fs_roots.push(args[idx].clone());
+                idx += 1;
+            }
+
+            // Pass an environment variable to the program, with the
+            // given value or with its value in the host environment
+            "--env" => {
+                let var = match args.get(idx) {
+                    Some(var) if !var.is_empty() && !var.starts_with('=') => var,
+                    _ => panic!("--env requires a variable name or NAME=VALUE"),
+                };
+
+                if var.contains('=') {
+                    opts.env.push(var.clone());
+                } else {
+                    match env::var(var) {
+                        Ok(value) => opts.env.push(format!("{}={}", var, value)),
+                        Err(env::VarError::NotPresent) => {}
+                        Err(env::VarError::NotUnicode(_)) => panic!("environment variable {} is not valid UTF-8", var),
+                    }
+                }
+                idx += 1;
+            }
+
+            // Compile functions on their first call
+            "--jit" => {
+                opts.jit_threshold = Some(opts.jit_threshold.unwrap_or(1));
+            }
+
+            // Compile functions after they've been called n times
+            "--jit-threshold" => {
+                let threshold = args.get(idx).and_then(|n| n.parse::<u64>().ok());
+                match threshold {
+                    Some(n) if n > 0 => opts.jit_threshold = Some(n),
+                    _ => panic!("--jit-threshold requires a positive call count"),
+                }
+                idx += 1;
+            }
+
+            // Decode instructions ahead of running them
+            "--predecode" => {
+                opts.predecode = true;
+            }
+
+            "--max-insns" => {
+                let max_insns = args.get(idx).and_then(|n| n.parse::<u64>().ok());
+                match max_insns {
+                    Some(n) => opts.max_insns = Some(n),
+                    _ => panic!("--max-insns requires an instruction count"),
+                }
+                idx += 1;
+            }
+
+            "--timeout" => {
+                let timeout = args.get(idx).and_then(|secs| secs.parse::<f64>().ok());
+                match timeout {
+                    Some(secs) if secs > 0.0 && secs.is_finite() => {
+                        opts.timeout = Some(Duration::from_secs_f64(secs))
+                    }
+                    _ => panic!("--timeout requires a positive number of seconds"),
+                }
+                idx += 1;
+            }
+
+            // Size in bytes, with an optional K, M or G suffix
+            "--max-heap" => {
+                match args.get(idx).and_then(|size| parse_size(size)) {
+                    Some(size) => opts.max_heap = Some(size),
+                    _ => panic!("--max-heap requires a size in bytes, such as 65536 or 64M"),
+                }
+                idx += 1;
+            }
+
+            "--heap-stats" => {
+                opts.heap_stats = true;
+            }
+
+            "--max-stack" => {
+                let max_stack = args.get(idx).and_then(|n| n.parse::<usize>().ok());
+                match max_stack {
+                    Some(n) if n > 0 => opts.max_stack = Some(n),
+                    _ => panic!("--max-stack requires a positive number of stack slots"),
+                }
+                idx += 1;
+            }
+
+            "--max-frames" => {
+                let max_frames = args.get(idx).and_then(|n| n.parse::<usize>().ok());
+                match max_frames {
+                    Some(n) if n > 0 => opts.max_frames = Some(n),
+                    _ => panic!("--max-frames requires a positive call depth"),
+                }
+                idx += 1;
+            }
+
+            "--null-guard" => {
+                opts.null_guard = true;
+            }
+
+            "--resume" => {
+                if idx >= args.len() {
+                    panic!("--resume requires a snapshot file argument");
+                }
+
+                opts.resume = Some(args[idx].clone());
+                idx += 1;
+            }
+
             _ => panic!("unknown option {}", arg)
         }
     }
@@ -81,15 +274,36 @@ fn main()
     let opts = parse_args(env::args().collect());
     //println!("{:?}", opts);
 
-    if opts.rest.len() != 1 {
-        panic!("must specify exactly one input file to run");
+    // A resumed snapshot contains the program, and
+    // the arguments it was originally started with
+    if opts.resume.is_some() && !opts.rest.is_empty() {
+        panic!("program arguments can't be passed when resuming a snapshot");
     }
 
-    let file_name = &opts.rest[0];
+    // Snapshots are only taken and resumed by the interpreters
+    if opts.resume.is_some() && opts.jit_threshold.is_some() {
+        panic!("snapshots can't be resumed with the JIT compiler");
+    }
+
+    if opts.resume.is_none() && opts.rest.is_empty() {
+        panic!("must specify an input file to run, or a snapshot with --resume");
+    }
 
-    // Parse/compile the program
-    let asm = Assembler::new();
-    let program = asm.parse_file(file_name);
+    // Load the snapshot or parse/compile the program
+    let mut snapshot = None;
+    let program = match &opts.resume {
+        Some(path) => Snapshot::load(path).map(|mut loaded| {
+            let program = loaded.take_program();
+            snapshot = Some(loaded);
+            program
+        }),
+        None => {
+            Assembler::new()
+                .null_guard(opts.null_guard)
+                .parse_file(&opts.rest[0])
+                .map_err(|error| error.to_string())
+        }
+    };
 
     if let Err(error) = program {
         println!("Error: {}", error);
@@ -101,9 +315,104 @@ fn main()
         exit(0);
     }
 
+    if opts.jit_threshold.is_some() && !cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+        println!("Error: the JIT compiler is only supported on x86-64 Linux");
+        exit(-1);
+    }
+
     let program = program.unwrap();
-    let mut vm = VM::new(program);
-    let ret_val = VM::call(&mut vm, 0, &[]);
+    let vm = VM::new(program);
+    vm.lock().unwrap().jit_threshold = opts.jit_threshold;
+    vm.lock().unwrap().predecode = opts.predecode;
+    vm.lock().unwrap().fuel = opts.max_insns;
+    vm.lock().unwrap().total_fuel = true;
+    vm.lock().unwrap().max_heap = opts.max_heap;
+    vm.lock().unwrap().report_heap = opts.heap_stats;
+
+    if let Some(max_stack) = opts.max_stack {
+        vm.lock().unwrap().max_stack = max_stack;
+    }
+
+    if let Some(max_frames) = opts.max_frames {
+        vm.lock().unwrap().max_frames = max_frames;
+    }
+
+    if let Some(max_heap) = opts.max_heap {
+        if vm.lock().unwrap().heap_size() > max_heap {
+            println!("Error: the program data doesn't fit in the maximum heap size");
+            exit(-1);
+        }
+    }
+
+    // The program file name is passed as argv[0], like in C. The host
+    // environment isn't passed on, envp only holds the --env variables.
+    let argc = opts.rest.len();
+    let (argv, envp) = if snapshot.is_none() {
+        let mut vm = vm.lock().unwrap();
+        match (vm.copy_args(&opts.rest), vm.copy_args(&opts.env)) {
+            (Some(argv), Some(envp)) => (argv, envp),
+            _ => {
+                println!("Error: the program arguments and environment don't fit in the maximum heap size");
+                exit(-1);
+            }
+        }
+    } else {
+        (0, 0)
+    };
+
+    // Exit from a separate thread so that this also
+    // works when the program is blocked in a syscall
+    if let Some(timeout) = opts.timeout {
+        thread::spawn(move || {
+            sleep(timeout);
+            println!("Error: time limit of {} seconds exceeded", timeout.as_secs_f64());
+            exit(EXIT_TIMEOUT);
+        });
+    }
+
+    for root in &opts.fs_roots {
+        if let Err(error) = vm.lock().unwrap().fs_state.add_root(root) {
+            println!("Error: {}", error);
+            exit(-1);
+        }
+    }
+
+    // Run main, or continue from where the snapshot was taken,
+    // then dispatch callbacks until none are registered
+    let mut thread = VM::new_thread(&vm);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let ret_val = match snapshot {
+            Some(snapshot) => {
+                vm.lock().unwrap().next_tid = snapshot.next_tid;
+
+                // vm_snapshot returns 1 in the resumed program
+                thread.resume(snapshot.thread, Value::from(1))
+            }
+            None => thread.call(0, &[Value::from(argc), Value::from(argv), Value::from(envp)]),
+        };
+
+        run_event_loop(&mut thread);
+        ret_val
+    }));
+
+    let ret_val = match result {
+        Ok(ret_val) => ret_val,
+        Err(payload) if payload.is::<OutOfFuel>() => {
+            println!("Error: instruction limit of {} exceeded", opts.max_insns.unwrap());
+            exit(EXIT_OUT_OF_FUEL);
+        }
+        Err(payload) => panic::resume_unwind(payload),
+    };
+
+    #[cfg(feature = "count_insns")]
+    {
+        let insn_count = crate::vm::INSN_COUNT.load(std::sync::atomic::Ordering::Relaxed);
+        println!("total instructions executed: {}", thousands_sep(insn_count));
+    }
+
+    if opts.heap_stats {
+        vm.lock().unwrap().print_heap_stats();
+    }
 
     exit(ret_val.as_i32());
 }
@@ -1,357 +1,671 @@
-use std::collections::{HashMap, VecDeque};
-use std::slice;
-use std::thread;
-use std::net::{TcpListener, TcpStream};
-use std::io::{self, Read, Write, Error};
-use std::sync::{Arc, Weak, Mutex};
-use crate::vm::{VM, Value};
-
-/*
-// State for the networking subsystem
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream, UdpSocket, SocketAddr};
+use std::io::{self, Read, Write, ErrorKind};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+use std::mem::size_of;
+use crate::vm::{Thread, Value};
+use crate::constants::*;
+
+/// Open socket
+pub enum Socket
+{
+    Listen(TcpListener),
+    Stream(TcpStream),
+    Udp(UdpSocket),
+}
+
+impl Socket
+{
+    fn as_raw_fd(&self) -> RawFd
+    {
+        match self {
+            Socket::Listen(listener) => listener.as_raw_fd(),
+            Socket::Stream(stream) => stream.as_raw_fd(),
+            Socket::Udp(socket) => socket.as_raw_fd(),
+        }
+    }
+}
+
+/// State for the networking subsystem
 pub struct NetState
 {
     /// Next socket id to use
     next_id: u64,
 
     /// Map of open sockets
-    sockets: HashMap<u64, Socket>,
+    /// Sockets are reference-counted so that blocking operations
+    /// can be performed without holding the VM lock
+    sockets: HashMap<u64, Arc<Socket>>,
+
+    /// Readiness callbacks registered with net_on_ready
+    /// Map of socket ids to poll events and callback
+    ready_callbacks: HashMap<u64, (u32, u64)>,
 }
-*/
 
-/*
 impl Default for NetState
 {
     fn default() -> Self
     {
         Self {
-            // Start at FFFF so we can reserve the low values for error codes
-            next_id: 0xFF_FF,
+            // Start at 1 so that 0 can be used to mean no socket
+            next_id: 1,
             sockets: HashMap::default(),
+            ready_callbacks: HashMap::default(),
         }
     }
 }
-*/
 
-/*
-/// TCP listening thread
-fn listen_thread(
-    vm_mutex: Weak<Mutex<VM>>,
-    listener: TcpListener,
-    socket_id: u64,
-    on_new_conn: u64
-)
+impl NetState
 {
-    // Block until a connection can be accepted
-    for result in listener.incoming() {
-        let stream = match result {
-            Ok(s) => s,
-
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                // Sleep for a bit, then try again
-                thread::sleep(std::time::Duration::from_millis(10));
-                continue;
-            }
+    /// Add a socket to the socket table and assign it an id
+    fn add_socket(&mut self, socket: Socket) -> u64
+    {
+        let socket_id = self.next_id;
+        self.next_id += 1;
+        self.sockets.insert(socket_id, Arc::new(socket));
+        socket_id
+    }
 
-            Err(e) => panic!("encountered IO error: {e}"),
-        };
+    /// Get the sockets with a readiness callback, along with their poll events
+    pub fn callback_sockets(&self) -> Vec<(u64, Arc<Socket>, u32)>
+    {
+        self.ready_callbacks.iter().map(|(socket_id, (events, _))| {
+            (*socket_id, self.sockets[socket_id].clone(), *events)
+        }).collect()
+    }
+
+    /// Get the readiness callback for a socket, if still registered
+    pub fn ready_callback(&self, socket_id: u64) -> Option<u64>
+    {
+        self.ready_callbacks.get(&socket_id).map(|(_, callback)| *callback)
+    }
+}
 
-        let arc = vm_mutex.upgrade().unwrap();
-        let mut vm = arc.lock().unwrap();
+/// Get a reference to an open socket
+fn get_socket(thread: &Thread, socket_id: Value) -> Arc<Socket>
+{
+    let socket_id = socket_id.as_u64();
+    let vm = thread.vm.lock().unwrap();
 
-        // Add the new connection to the queue
-        let mut net_state = &mut vm.sys_state.net_state;
-        match net_state.sockets.get_mut(&socket_id) {
-            Some(Socket::Listen{ incoming, .. }) => {
-                incoming.push_back(stream);
-            }
+    match vm.net_state.sockets.get(&socket_id) {
+        Some(socket) => socket.clone(),
+        None => panic!("invalid socket id {}", socket_id)
+    }
+}
 
-            // Socket has been closed, stop
-            _ => {
-                break;
-            }
-        }
+/// Translate an I/O error into an error code for the guest program
+fn error_code(err: &io::Error) -> i64
+{
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::Interrupted => NET_ERR_WOULD_BLOCK,
+        ErrorKind::ConnectionReset |
+        ErrorKind::ConnectionAborted |
+        ErrorKind::BrokenPipe |
+        ErrorKind::NotConnected => NET_ERR_DISCONNECTED,
+        ErrorKind::InvalidInput | ErrorKind::AddrNotAvailable => NET_ERR_INVALID_ADDR,
+        ErrorKind::AddrInUse => NET_ERR_ADDR_IN_USE,
+        ErrorKind::ConnectionRefused => NET_ERR_REFUSED,
+        ErrorKind::TimedOut => NET_ERR_TIMED_OUT,
+        _ => NET_ERR_FAILED,
+    }
+}
 
-        // Call on_new_conn to signal an incoming connection
-        match vm.call(on_new_conn, &[Value::from(socket_id)]) {
-            ExitReason::Return(val) => {}
-            _ => panic!()
-        }
+/// Write a socket address as a null-terminated string into a buffer,
+/// truncating it if necessary. The buffer pointer can be null.
+fn write_addr(thread: &mut Thread, addr: &SocketAddr, buf_ptr: usize, buf_len: usize)
+{
+    if buf_ptr == 0 || buf_len == 0 {
+        return;
     }
+
+    let addr_str = addr.to_string().into_bytes();
+    let num_bytes = std::cmp::min(addr_str.len(), buf_len - 1);
+    let addr_buf: &mut [u8] = thread.get_heap_slice_mut(buf_ptr, num_bytes + 1);
+    addr_buf[..num_bytes].copy_from_slice(&addr_str[..num_bytes]);
+    addr_buf[num_bytes] = 0;
+}
+
+/// Block until a file descriptor is ready for the given poll events
+fn wait_fd(fd: RawFd, events: libc::c_short)
+{
+    let mut pollfd = libc::pollfd { fd, events, revents: 0 };
+    unsafe { libc::poll(&mut pollfd, 1, -1) };
 }
-*/
 
-/*
 // Syscall to create a TCP listening socket to accept incoming connections
-// u64 socket_id = net_listen(
-//     const char* listen_addr,    // Network interface address to listen on, null for any address
-//     callback on_new_connection, // Called on new incoming connection
-// )
-pub fn net_listen(
-    vm: &mut VM,
-    listen_addr: Value,
-    on_new_conn: Value,
-) -> Value
+// i64 socket_id = net_listen(const char* listen_addr)
+pub fn net_listen(thread: &mut Thread, listen_addr: Value) -> Value
 {
     // Get the input address and port to listen on
-    let listen_addr = vm.get_heap_str(listen_addr.as_usize());
-
-    // TODO: return 0 on failure
-    let listener = TcpListener::bind(listen_addr).unwrap();
-
-    // Set the listener to non-blocking
-    // We do this because Rust offers us no way to close the TcpListener
-    // from another thread, and so the listening thread has to periodically
-    // check if it should exit.
-    listener.set_nonblocking(true).expect("Cannot set non-blocking");
-
-    // Assign a socket id to the socket
-    let mut net_state = &mut vm.sys_state.net_state;
-    let socket_id = net_state.next_id;
-    net_state.next_id += 1;
-    net_state.sockets.insert(
-        socket_id,
-        Socket::Listen {
-            listener: listener.try_clone().unwrap(),
-            incoming: VecDeque::default(),
-        }
-    );
-
-    // Create a listening thread to accept incoming connections
-    let vm_mutex = vm.sys_state.mutex.clone();
-    let on_new_conn = on_new_conn.as_u64();
-    thread::spawn(move || {
-        listen_thread(
-            vm_mutex,
-            listener,
-            socket_id,
-            on_new_conn,
-        )
-    });
-
-    // Return the socket id
+    let listen_addr = thread.get_heap_str(listen_addr.as_usize()).to_owned();
+
+    let listener = match TcpListener::bind(listen_addr) {
+        Ok(listener) => listener,
+        Err(err) => return Value::from(error_code(&err)),
+    };
+
+    // Accepting connections is done by polling the listening socket
+    listener.set_nonblocking(true).expect("could not set listener to non-blocking");
+
+    let mut vm = thread.vm.lock().unwrap();
+    let socket_id = vm.net_state.add_socket(Socket::Listen(listener));
     Value::from(socket_id)
 }
-*/
 
-/*
-/// TCP read thread
-fn read_thread(
-    vm_mutex: Weak<Mutex<VM>>,
-    mut stream: TcpStream,
-    socket_id: u64,
-    on_incoming_data: u64
-)
+// Syscall to accept a new connection
+// Writes the client address in the buffer you specify
+// i64 socket_id = net_accept(u64 socket_id, char* client_addr, u64 client_addr_len)
+pub fn net_accept(thread: &mut Thread, socket_id: Value, client_addr_buf: Value, addr_buf_len: Value) -> Value
 {
-    loop
-    {
-        let mut buf: [u8; 16384] = [0; 16384];
+    let socket = get_socket(thread, socket_id);
+    let listener = match socket.as_ref() {
+        Socket::Listen(listener) => listener,
+        _ => panic!("net_accept called on a socket that is not listening")
+    };
 
-        match stream.read(&mut buf) {
-            // End of file, connection closed, stop
-            Ok(0) => {
-                break;
-            }
+    let (stream, peer_addr) = match listener.accept() {
+        Ok(conn) => conn,
+        Err(err) => return Value::from(error_code(&err)),
+    };
 
-            Ok(num_bytes) => {
-                let arc = vm_mutex.upgrade().unwrap();
-                let mut vm = arc.lock().unwrap();
-
-                // Append to the read buffer
-                let mut net_state = &mut vm.sys_state.net_state;
-                match net_state.sockets.get_mut(&socket_id) {
-                    Some(Socket::Stream { read_buf, .. }) => {
-                        read_buf.extend_from_slice(&buf[0..num_bytes]);
-                    }
-
-                    Some(_) => panic!(),
-
-                    // net_close removes the socket
-                    // Stop the read thread
-                    None => break
-                }
-
-                // Call on_incoming_data to signal an incoming data
-                match vm.call(on_incoming_data, &[Value::from(socket_id), Value::from(num_bytes)]) {
-                    ExitReason::Return(val) => {}
-                    _ => panic!()
-                }
-            }
+    stream.set_nonblocking(true).expect("could not set stream to non-blocking");
 
-            // This error indicates blocking read stopped,
-            // but it can be retried
-            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
-                continue;
-            }
+    // Copy the null-terminated client address into the buffer
+    write_addr(thread, &peer_addr, client_addr_buf.as_usize(), addr_buf_len.as_usize());
 
-            // Most errors are the result of a lost connection
-            // Stop the read thread
-            Err(e) => {
-                println!("error in read thread: {e}");
-                break
-            }
+    let mut vm = thread.vm.lock().unwrap();
+    let socket_id = vm.net_state.add_socket(Socket::Stream(stream));
+    Value::from(socket_id)
+}
+
+// Syscall to open a TCP connection to a remote address
+// i64 socket_id = net_connect(const char* addr, i64 timeout_ms)
+pub fn net_connect(thread: &mut Thread, addr: Value, timeout_ms: Value) -> Value
+{
+    // Only numeric addresses are accepted, host names are not resolved
+    let addr: SocketAddr = match thread.get_heap_str(addr.as_usize()).parse() {
+        Ok(addr) => addr,
+        Err(_) => return Value::from(NET_ERR_INVALID_ADDR),
+    };
+
+    let timeout_ms = timeout_ms.as_i64();
+
+    let result = if timeout_ms > 0 {
+        TcpStream::connect_timeout(&addr, Duration::from_millis(timeout_ms as u64))
+    } else {
+        TcpStream::connect(addr)
+    };
+
+    let stream = match result {
+        Ok(stream) => stream,
+        Err(err) => return Value::from(error_code(&err)),
+    };
+
+    stream.set_nonblocking(true).expect("could not set stream to non-blocking");
+
+    let mut vm = thread.vm.lock().unwrap();
+    let socket_id = vm.net_state.add_socket(Socket::Stream(stream));
+    Value::from(socket_id)
+}
+
+// Syscall to read data from a given socket into a buffer you specify
+// i64 num_bytes_read = net_read(u64 socket_id, void* buf_ptr, u64 buf_len)
+pub fn net_read(thread: &mut Thread, socket_id: Value, buf_ptr: Value, buf_len: Value) -> Value
+{
+    let socket = get_socket(thread, socket_id);
+    let mut stream = match socket.as_ref() {
+        Socket::Stream(stream) => stream,
+        _ => panic!("net_read called on a socket that is not a stream")
+    };
+
+    let buf: &mut [u8] = thread.get_heap_slice_mut(buf_ptr.as_usize(), buf_len.as_usize());
+
+    match stream.read(buf) {
+        Ok(num_bytes) => Value::from(num_bytes),
+        Err(err) => Value::from(error_code(&err)),
+    }
+}
+
+// Syscall to write data on a given socket
+// i64 num_bytes = net_write(u64 socket_id, void* buf_ptr, u64 buf_len);
+pub fn net_write(thread: &mut Thread, socket_id: Value, buf_ptr: Value, buf_len: Value) -> Value
+{
+    let socket = get_socket(thread, socket_id);
+    let mut stream = match socket.as_ref() {
+        Socket::Stream(stream) => stream,
+        _ => panic!("net_write called on a socket that is not a stream")
+    };
+
+    let buf: &mut [u8] = thread.get_heap_slice_mut(buf_ptr.as_usize(), buf_len.as_usize());
+    let mut num_written = 0;
+
+    // Keep writing until the whole buffer is sent, waiting
+    // for the socket to be writable when its buffer is full
+    while num_written < buf.len() {
+        match stream.write(&buf[num_written..]) {
+            Ok(num_bytes) => num_written += num_bytes,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => wait_fd(stream.as_raw_fd(), libc::POLLOUT),
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Value::from(error_code(&err)),
         }
     }
+
+    Value::from(num_written)
 }
-*/
 
-/*
-// Syscall to accept a new connection
-// Writes the client address in the buffer you specify
-// u64 socket_id = net_accept(u64 socket_id, char* client_addr, u64 client_addr_len, callback on_incoming_data)
-pub fn net_accept(
-    vm: &mut VM,
-    socket_id: Value,
-    client_addr_buf: Value,
-    addr_buf_len: Value,
-    on_incoming_data: Value,
-) -> Value
+// Syscall to close a socket
+// net_close(u64 socket_id)
+pub fn net_close(thread: &mut Thread, socket_id: Value)
 {
     let socket_id = socket_id.as_u64();
-    let client_addr_buf = client_addr_buf.as_usize();
-    let addr_buf_len = addr_buf_len.as_usize();
-    let addr_buf_ptr: *mut u8 = vm.get_heap_ptr(client_addr_buf, addr_buf_len);
-    let on_incoming_data = on_incoming_data.as_u64();
-
-    let mut net_state = &mut vm.sys_state.net_state;
-
-    // If there is a connection waiting
-    match net_state.sockets.get_mut(&socket_id) {
-        Some(Socket::Listen { incoming, .. }) => {
-            if incoming.len() == 0 {
-                panic!();
-            }
+    let mut vm = thread.vm.lock().unwrap();
 
-            let stream = incoming.pop_front().unwrap();
-            stream.set_nonblocking(false).expect("could not set stream to blocking");
-
-            // TODO: handle the error case here
-            // The connection could have dropped
-            // Copy the client address into the buffer
-            let peer_addr = stream.peer_addr().unwrap();
-            let mut addr_str = peer_addr.to_string().into_bytes();
-            addr_str.push(0);
-            let num_bytes = std::cmp::min(addr_str.len(), addr_buf_len);
-            unsafe {
-                std::ptr::copy_nonoverlapping(addr_str.as_ptr(), addr_buf_ptr, num_bytes);
-            }
+    vm.net_state.ready_callbacks.remove(&socket_id);
 
-            // Assign a socket id to the socket
-            let socket_id = net_state.next_id;
-            net_state.next_id += 1;
-            net_state.sockets.insert(
-                socket_id,
-                Socket::Stream {
-                    stream: stream.try_clone().unwrap(),
-                    read_buf: Vec::default(),
-                }
-            );
-
-            // Create a listening thread to accept incoming connections
-            let vm_mutex = vm.sys_state.mutex.clone();
-            thread::spawn(move || {
-                read_thread(
-                    vm_mutex,
-                    stream,
-                    socket_id,
-                    on_incoming_data,
-                )
-            });
-
-            // Return the socket id
-            Value::from(socket_id)
+    // This drops the socket once no other thread is using it
+    match vm.net_state.sockets.remove(&socket_id) {
+        Some(socket) => {
+            // Shut down the connection so that threads blocked
+            // on this socket are woken up
+            if let Socket::Stream(stream) = socket.as_ref() {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            }
         }
-        _ => panic!()
+
+        None => panic!("invalid socket id {} in net_close", socket_id)
     }
 }
-*/
 
-/*
-// Syscall to read data from a given socket into a buffer you specify
-// u64 num_bytes_read = net_read(u64 socket_id, void* buf_ptr, u64 buf_len)
-pub fn net_read(
-    vm: &mut VM,
-    socket_id: Value,
-    buf_ptr: Value,
-    buf_len: Value,
-) -> Value
+// Syscall to open a UDP socket bound to a local address
+// i64 socket_id = net_udp_bind(const char* bind_addr)
+pub fn net_udp_bind(thread: &mut Thread, bind_addr: Value) -> Value
 {
-    let socket_id = socket_id.as_u64();
-    let buf_len = buf_len.as_usize();
-    let buf_ptr = buf_ptr.as_usize();
-    let buf_ptr: *mut u8 = vm.get_heap_ptr(buf_ptr, buf_len);
+    let bind_addr = thread.get_heap_str(bind_addr.as_usize()).to_owned();
 
-    let mut net_state = &mut vm.sys_state.net_state;
-    match net_state.sockets.get_mut(&socket_id) {
-        Some(Socket::Stream { read_buf, .. }) => {
-            let num_bytes = std::cmp::min(buf_len, read_buf.len());
+    let socket = match UdpSocket::bind(bind_addr) {
+        Ok(socket) => socket,
+        Err(err) => return Value::from(error_code(&err)),
+    };
 
-            unsafe {
-                std::ptr::copy_nonoverlapping(read_buf.as_ptr(), buf_ptr, num_bytes);
-            }
+    socket.set_nonblocking(true).expect("could not set UDP socket to non-blocking");
 
-            read_buf.rotate_left(num_bytes);
-            read_buf.truncate(read_buf.len() - num_bytes);
+    let mut vm = thread.vm.lock().unwrap();
+    let socket_id = vm.net_state.add_socket(Socket::Udp(socket));
+    Value::from(socket_id)
+}
 
-            Value::from(num_bytes)
+// Syscall to send a datagram to a given address
+// i64 num_bytes = net_udp_send_to(u64 socket_id, const char* dst_addr, const u8* buf_ptr, u64 buf_len)
+pub fn net_udp_send_to(thread: &mut Thread, socket_id: Value, dst_addr: Value, buf_ptr: Value, buf_len: Value) -> Value
+{
+    let socket = get_socket(thread, socket_id);
+    let socket = match socket.as_ref() {
+        Socket::Udp(socket) => socket,
+        _ => panic!("net_udp_send_to called on a socket that is not a UDP socket")
+    };
+
+    // Only numeric addresses are accepted, host names are not resolved
+    let dst_addr: SocketAddr = match thread.get_heap_str(dst_addr.as_usize()).parse() {
+        Ok(addr) => addr,
+        Err(_) => return Value::from(NET_ERR_INVALID_ADDR),
+    };
+
+    let buf: &mut [u8] = thread.get_heap_slice_mut(buf_ptr.as_usize(), buf_len.as_usize());
+
+    match socket.send_to(buf, dst_addr) {
+        Ok(num_bytes) => Value::from(num_bytes),
+        Err(err) => Value::from(error_code(&err)),
+    }
+}
+
+// Syscall to receive a datagram and the address of its sender
+// i64 num_bytes = net_udp_recv_from(u64 socket_id, u8* buf_ptr, u64 buf_len, char* src_addr_buf, u64 addr_buf_len)
+pub fn net_udp_recv_from(thread: &mut Thread, socket_id: Value, buf_ptr: Value, buf_len: Value, src_addr_buf: Value, addr_buf_len: Value) -> Value
+{
+    let socket = get_socket(thread, socket_id);
+    let socket = match socket.as_ref() {
+        Socket::Udp(socket) => socket,
+        _ => panic!("net_udp_recv_from called on a socket that is not a UDP socket")
+    };
+
+    let buf: &mut [u8] = thread.get_heap_slice_mut(buf_ptr.as_usize(), buf_len.as_usize());
+
+    let (num_bytes, src_addr) = match socket.recv_from(buf) {
+        Ok(result) => result,
+        Err(err) => return Value::from(error_code(&err)),
+    };
+
+    write_addr(thread, &src_addr, src_addr_buf.as_usize(), addr_buf_len.as_usize());
+
+    Value::from(num_bytes)
+}
+
+/// Wait until sockets are ready for the given NET_POLL_* events
+/// Returns the NET_POLL_* flags for the events that occurred on each socket
+pub fn poll_sockets(sockets: &[(Arc<Socket>, u32)], timeout_ms: i64) -> Vec<u32>
+{
+    let mut pollfds: Vec<libc::pollfd> = sockets.iter().map(|(socket, events)| {
+        let mut poll_events = 0;
+        if events & NET_POLL_READ != 0 { poll_events |= libc::POLLIN; }
+        if events & NET_POLL_WRITE != 0 { poll_events |= libc::POLLOUT; }
+        libc::pollfd { fd: socket.as_raw_fd(), events: poll_events, revents: 0 }
+    }).collect();
+
+    // Negative timeouts mean waiting indefinitely
+    let timeout_ms = timeout_ms.clamp(-1, i32::MAX as i64) as libc::c_int;
+
+    let result = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout_ms) };
+
+    if result < 0 {
+        // The wait was interrupted by a signal, report that nothing is ready
+        if io::Error::last_os_error().kind() == ErrorKind::Interrupted {
+            return vec![0; sockets.len()];
         }
-        _ => panic!("invalid socket id {} in net_read", socket_id)
+
+        panic!("poll failed: {}", io::Error::last_os_error());
     }
+
+    pollfds.iter().map(|pollfd| {
+        let mut revents = 0;
+        if pollfd.revents & libc::POLLIN != 0 { revents |= NET_POLL_READ; }
+        if pollfd.revents & libc::POLLOUT != 0 { revents |= NET_POLL_WRITE; }
+        if pollfd.revents & (libc::POLLHUP | libc::POLLERR | libc::POLLNVAL) != 0 { revents |= NET_POLL_HUP; }
+        revents
+    }).collect()
 }
-*/
 
-/*
-// Syscall to write data on a given socket
-// u64 num_bytes = net_write(u64 socket_id, void* buf_ptr, u64 buf_len);
-pub fn net_write(
-    vm: &mut VM,
-    socket_id: Value,
-    buf_ptr: Value,
-    buf_len: Value,
-) -> Value
+// C poll entry struct
+#[repr(C)]
+struct CPollEntry
 {
-    let socket_id = socket_id.as_u64();
-    let buf_len = buf_len.as_usize();
-    let buf_ptr = buf_ptr.as_usize();
-    let buf_ptr: *mut u8 = vm.get_heap_ptr(buf_ptr, buf_len);
-
-    let mut net_state = &mut vm.sys_state.net_state;
-    match net_state.sockets.get_mut(&socket_id) {
-        Some(Socket::Stream { stream, .. }) => {
-            let mem_slice = unsafe { slice::from_raw_parts(buf_ptr, buf_len) };
-            stream.write_all(&mem_slice).unwrap();
-            Value::from(buf_len)
+    socket_id: u64,
+    events: u32,
+    revents: u32,
+}
+
+// Syscall to wait until sockets are ready for reading or writing
+// u64 num_ready = net_poll(void* entries, u64 num_entries, i64 timeout_ms)
+pub fn net_poll(thread: &mut Thread, entries: Value, num_entries: Value, timeout_ms: Value) -> Value
+{
+    let entries = entries.as_usize();
+    let num_entries = num_entries.as_usize();
+
+    let socket_ids: Vec<(Value, u32)> = {
+        let entries: &mut [CPollEntry] = thread.get_heap_slice_mut(entries, num_entries);
+        entries.iter().map(|e| (Value::from(e.socket_id), e.events)).collect()
+    };
+
+    // Hold references to the sockets so they stay open while we wait
+    let sockets: Vec<(Arc<Socket>, u32)> = socket_ids.iter().map(|(id, events)| {
+        (get_socket(thread, *id), *events)
+    }).collect();
+
+    let revents = poll_sockets(&sockets, timeout_ms.as_i64());
+
+    // Write the events that occurred back into the entries
+    let entries: &mut [CPollEntry] = thread.get_heap_slice_mut(entries, num_entries);
+    let mut num_ready: u64 = 0;
+
+    for (entry, revents) in entries.iter_mut().zip(revents) {
+        entry.revents = revents;
+
+        if revents != 0 {
+            num_ready += 1;
         }
-        _ => panic!()
     }
+
+    Value::from(num_ready)
 }
-*/
 
-/*
-// Syscall to close a socket
-// net_close(u64 socket_id)
-pub fn net_close(
-    vm: &mut VM,
-    socket_id: Value,
-)
+// Syscall to register a callback called by the event loop when a socket is ready
+// The callback is called with the socket id and the NET_POLL_* flags that occurred
+// void net_on_ready(u64 socket_id, u32 events, void* callback)
+pub fn net_on_ready(thread: &mut Thread, socket_id: Value, events: Value, callback: Value)
 {
     let socket_id = socket_id.as_u64();
+    let events = events.as_u32();
+    let callback = callback.as_u64();
+    let mut vm = thread.vm.lock().unwrap();
+
+    if !vm.net_state.sockets.contains_key(&socket_id) {
+        panic!("invalid socket id {} in net_on_ready", socket_id);
+    }
 
-    let mut net_state = &mut vm.sys_state.net_state;
+    // Zero events or a null callback unregisters the callback
+    if events == 0 || callback == 0 {
+        vm.net_state.ready_callbacks.remove(&socket_id);
+    } else {
+        vm.net_state.ready_callbacks.insert(socket_id, (events, callback));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::VM;
+    use crate::asm::Assembler;
+    use std::net::SocketAddr;
+
+    // Heap layout of the test program
+    const ADDR: u64 = 0;
+    const BUF: u64 = 64;
+    const ENTRIES: u64 = 128;
+    const ADDR1: u64 = 192;
+    const SRC_ADDR: u64 = 256;
+
+    fn new_thread() -> Thread
+    {
+        let src = concat!(
+            ".data;",
+            ".stringz \"127.0.0.1:0\";",
+            ".align 64; .zero 64;",
+            ".zero 64;",
+            ".zero 64;",
+            ".zero 128;",
+            ".code; push 0; ret;"
+        );
+
+        let prog = Assembler::new().parse_str(src).unwrap();
+        let vm = VM::new(prog);
+        VM::new_thread(&vm)
+    }
 
-    match net_state.sockets.get_mut(&socket_id) {
-        Some(Socket::Stream { stream, .. }) => {
-            stream.shutdown(std::net::Shutdown::Both).unwrap();
+    fn local_addr(thread: &Thread, socket_id: Value) -> SocketAddr
+    {
+        match get_socket(thread, socket_id).as_ref() {
+            Socket::Listen(listener) => listener.local_addr().unwrap(),
+            Socket::Stream(stream) => stream.local_addr().unwrap(),
+            Socket::Udp(socket) => socket.local_addr().unwrap(),
         }
+    }
+
+    fn poll_one(thread: &mut Thread, socket_id: Value, events: u32, timeout_ms: i64) -> u32
+    {
+        let entries: &mut [CPollEntry] = thread.get_heap_slice_mut(ENTRIES as usize, 1);
+        entries[0] = CPollEntry { socket_id: socket_id.as_u64(), events, revents: 0 };
+        net_poll(thread, Value::from(ENTRIES), Value::from(1), Value::from(timeout_ms));
+        let entries: &mut [CPollEntry] = thread.get_heap_slice_mut(ENTRIES as usize, 1);
+        entries[0].revents
+    }
+
+    #[test]
+    fn test_size_of_poll_entry()
+    {
+        assert_eq!(size_of::<CPollEntry>(), 16);
+    }
+
+    #[test]
+    fn test_listen_accept()
+    {
+        let mut thread = new_thread();
+        let listen_sock = net_listen(&mut thread, Value::from(ADDR));
+        assert!(listen_sock.as_i64() > 0);
+
+        // No connection is pending yet
+        assert_eq!(poll_one(&mut thread, listen_sock, NET_POLL_READ, 0), 0);
+        let result = net_accept(&mut thread, listen_sock, Value::from(BUF), Value::from(64));
+        assert_eq!(result.as_i64(), NET_ERR_WOULD_BLOCK);
+
+        let mut client = TcpStream::connect(local_addr(&thread, listen_sock)).unwrap();
+        assert_eq!(poll_one(&mut thread, listen_sock, NET_POLL_READ, 5000), NET_POLL_READ);
+
+        let conn_sock = net_accept(&mut thread, listen_sock, Value::from(BUF), Value::from(64));
+        assert!(conn_sock.as_i64() > 0);
+        assert_eq!(thread.get_heap_str(BUF as usize), client.local_addr().unwrap().to_string());
+
+        // Nothing to read yet
+        let result = net_read(&mut thread, conn_sock, Value::from(BUF), Value::from(64));
+        assert_eq!(result.as_i64(), NET_ERR_WOULD_BLOCK);
+
+        client.write_all(b"ping").unwrap();
+        assert_eq!(poll_one(&mut thread, conn_sock, NET_POLL_READ, 5000), NET_POLL_READ);
+        let result = net_read(&mut thread, conn_sock, Value::from(BUF), Value::from(64));
+        assert_eq!(result.as_i64(), 4);
+        assert_eq!(thread.get_heap_slice_mut::<u8>(BUF as usize, 4), b"ping");
+
+        // Write a response back to the client
+        let result = net_write(&mut thread, conn_sock, Value::from(BUF), Value::from(4));
+        assert_eq!(result.as_i64(), 4);
+        let mut response = [0; 4];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"ping");
+
+        // Reading after the client disconnects produces zero bytes
+        drop(client);
+        assert_ne!(poll_one(&mut thread, conn_sock, NET_POLL_READ, 5000), 0);
+        let result = net_read(&mut thread, conn_sock, Value::from(BUF), Value::from(64));
+        assert_eq!(result.as_i64(), 0);
+
+        net_close(&mut thread, conn_sock);
+        net_close(&mut thread, listen_sock);
+    }
+
+    #[test]
+    fn test_listen_addr_in_use()
+    {
+        let mut thread = new_thread();
+        let sock0 = net_listen(&mut thread, Value::from(ADDR));
+        let addr = local_addr(&thread, sock0).to_string();
+        write_str(&mut thread, BUF, &addr);
 
-        Some(Socket::Listen { listener, .. }) => {
-            // The listen thread will detect that the socket state
-            // has been removed and exit
+        let sock1 = net_listen(&mut thread, Value::from(BUF));
+        assert_eq!(sock1.as_i64(), NET_ERR_ADDR_IN_USE);
+    }
+
+    // Write a null-terminated string into a heap buffer
+    fn write_str(thread: &mut Thread, ptr: u64, s: &str)
+    {
+        let buf: &mut [u8] = thread.get_heap_slice_mut(ptr as usize, s.len() + 1);
+        buf[..s.len()].copy_from_slice(s.as_bytes());
+        buf[s.len()] = 0;
+    }
+
+    #[test]
+    fn test_connect()
+    {
+        let mut thread = new_thread();
+        let listen_sock = net_listen(&mut thread, Value::from(ADDR));
+        let addr = local_addr(&thread, listen_sock).to_string();
+
+        write_str(&mut thread, BUF, &addr);
+        let client_sock = net_connect(&mut thread, Value::from(BUF), Value::from(5000));
+        assert!(client_sock.as_i64() > 0);
+
+        assert_eq!(poll_one(&mut thread, listen_sock, NET_POLL_READ, 5000), NET_POLL_READ);
+        let server_sock = net_accept(&mut thread, listen_sock, Value::from(0), Value::from(0));
+        assert!(server_sock.as_i64() > 0);
+
+        // Send data from the client socket to the server socket
+        write_str(&mut thread, BUF, "pong");
+        let result = net_write(&mut thread, client_sock, Value::from(BUF), Value::from(4));
+        assert_eq!(result.as_i64(), 4);
+
+        assert_eq!(poll_one(&mut thread, server_sock, NET_POLL_READ, 5000), NET_POLL_READ);
+        let result = net_read(&mut thread, server_sock, Value::from(BUF), Value::from(64));
+        assert_eq!(result.as_i64(), 4);
+        assert_eq!(thread.get_heap_slice_mut::<u8>(BUF as usize, 4), b"pong");
+
+        net_close(&mut thread, client_sock);
+        net_close(&mut thread, server_sock);
+        net_close(&mut thread, listen_sock);
+    }
+
+    #[test]
+    fn test_connect_refused()
+    {
+        let mut thread = new_thread();
+
+        // Find a port that nothing is listening on
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+
+        write_str(&mut thread, BUF, &addr);
+        let result = net_connect(&mut thread, Value::from(BUF), Value::from(5000));
+        assert_eq!(result.as_i64(), NET_ERR_REFUSED);
+    }
+
+    #[test]
+    fn test_connect_invalid_addr()
+    {
+        let mut thread = new_thread();
+
+        for addr in ["localhost:80", "127.0.0.1", "foo", "127.0.0.1:99999"] {
+            write_str(&mut thread, BUF, addr);
+            let result = net_connect(&mut thread, Value::from(BUF), Value::from(0));
+            assert_eq!(result.as_i64(), NET_ERR_INVALID_ADDR);
         }
+    }
 
-        _ => panic!()
+    #[test]
+    fn test_udp()
+    {
+        let mut thread = new_thread();
+        let sock0 = net_udp_bind(&mut thread, Value::from(ADDR));
+        let sock1 = net_udp_bind(&mut thread, Value::from(ADDR));
+        assert!(sock0.as_i64() > 0 && sock1.as_i64() > 0);
+        let addr0 = local_addr(&thread, sock0).to_string();
+        let addr1 = local_addr(&thread, sock1).to_string();
+
+        // Nothing received yet
+        let result = net_udp_recv_from(&mut thread, sock1, Value::from(BUF), Value::from(64), Value::from(0), Value::from(0));
+        assert_eq!(result.as_i64(), NET_ERR_WOULD_BLOCK);
+        assert_eq!(poll_one(&mut thread, sock1, NET_POLL_READ, 0), 0);
+
+        write_str(&mut thread, ADDR1, &addr1);
+        write_str(&mut thread, BUF, "hello");
+        let result = net_udp_send_to(&mut thread, sock0, Value::from(ADDR1), Value::from(BUF), Value::from(5));
+        assert_eq!(result.as_i64(), 5);
+
+        assert_eq!(poll_one(&mut thread, sock1, NET_POLL_READ, 5000), NET_POLL_READ);
+        let result = net_udp_recv_from(&mut thread, sock1, Value::from(BUF), Value::from(64), Value::from(SRC_ADDR), Value::from(64));
+        assert_eq!(result.as_i64(), 5);
+        assert_eq!(thread.get_heap_slice_mut::<u8>(BUF as usize, 5), b"hello");
+        assert_eq!(thread.get_heap_str(SRC_ADDR as usize), addr0);
+
+        // The sender address is truncated to fit the buffer
+        write_str(&mut thread, BUF, "hello");
+        net_udp_send_to(&mut thread, sock0, Value::from(ADDR1), Value::from(BUF), Value::from(5));
+        assert_eq!(poll_one(&mut thread, sock1, NET_POLL_READ, 5000), NET_POLL_READ);
+        let result = net_udp_recv_from(&mut thread, sock1, Value::from(BUF), Value::from(64), Value::from(SRC_ADDR), Value::from(4));
+        assert_eq!(result.as_i64(), 5);
+        assert_eq!(thread.get_heap_str(SRC_ADDR as usize), &addr0[..3]);
+
+        // Host names are not resolved
+        write_str(&mut thread, BUF, "localhost:9000");
+        let result = net_udp_send_to(&mut thread, sock0, Value::from(BUF), Value::from(BUF), Value::from(1));
+        assert_eq!(result.as_i64(), NET_ERR_INVALID_ADDR);
+
+        net_close(&mut thread, sock0);
+        net_close(&mut thread, sock1);
     }
 
-    // This drops the socket
-    net_state.sockets.remove(&socket_id);
+    #[test]
+    #[should_panic]
+    fn test_close_invalid()
+    {
+        let mut thread = new_thread();
+        net_close(&mut thread, Value::from(777));
+    }
 }
-*/
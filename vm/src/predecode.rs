@@ -0,0 +1,368 @@
+//! Pre-decoded instructions for the threaded interpreter
+//!
+//! Code is decoded lazily, one region at a time. When execution first
+//! reaches an address, everything reachable from it through fallthrough
+//! and jumps (but not calls) is decoded into a compact array of
+//! instructions, with their operands read and jump targets resolved to
+//! indices in that array. Each decoded instruction keeps its original
+//! address, so errors and function pointers still refer to the code space.
+//!
+//! Decoding errors such as unknown opcodes are only raised if the
+//! instruction actually executes, like in the bytecode interpreter.
+
+use std::mem::{size_of, transmute};
+use crate::vm::{Op, ExtOp};
+
+/// Marks addresses that haven't been decoded yet
+const NOT_DECODED: u32 = u32::MAX;
+
+/// Error kinds for the panic instruction, stored in its small operand
+const ERR_PANIC: u8 = 0;
+const ERR_EXT_PANIC: u8 = 1;
+const ERR_UNKNOWN_OP: u8 = 2;
+const ERR_UNKNOWN_EXT_OP: u8 = 3;
+const ERR_OUT_OF_BOUNDS: u8 = 4;
+
+#[derive(Copy, Clone, Debug)]
+pub struct DecodedInsn
+{
+    pub op: Op,
+
+    /// Small operand: u8 immediate, argument count, extended opcode or error kind
+    pub n: u8,
+
+    /// Address of the instruction in the code space
+    pub pc: u32,
+
+    /// Wide operand: immediate value or jump target index. For calls, the
+    /// low 32 bits are the callee address and the high 32 bits are the
+    /// callee index plus one, or zero if it hasn't been looked up yet.
+    pub imm: u64,
+}
+
+impl DecodedInsn
+{
+    fn new(op: Op, pc: usize) -> Self
+    {
+        Self { op, n: 0, pc: pc as u32, imm: 0 }
+    }
+
+    fn error(kind: u8, pc: usize, imm: u64) -> Self
+    {
+        Self { op: Op::panic, n: kind, pc: pc as u32, imm }
+    }
+
+    /// Raise the error for a panic instruction
+    pub fn raise_error(&self) -> !
+    {
+        match self.n {
+            ERR_PANIC => panic!("execution error, encountered panic opcode"),
+            ERR_EXT_PANIC => panic!("execution error, encountered extended panic opcode"),
+            ERR_UNKNOWN_OP => panic!("unknown opcode {}", self.imm),
+            ERR_UNKNOWN_EXT_OP => panic!("unknown extended opcode {}", self.imm),
+            ERR_OUT_OF_BOUNDS => panic!("pc outside of bounds of code space"),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Per-thread pre-decoded code
+pub struct Predecoded
+{
+    /// Decoded instructions. Instructions that fall through to an
+    /// address decoded earlier are followed by a jmp to it.
+    pub insns: Vec<DecodedInsn>,
+
+    /// Index of the decoded instruction at each address
+    pc_map: Vec<u32>,
+}
+
+impl Predecoded
+{
+    pub fn new() -> Self
+    {
+        Self {
+            insns: Vec::default(),
+            pc_map: Vec::default(),
+        }
+    }
+
+    /// Get the index of the instruction at a given address,
+    /// decoding the code reachable from it if needed
+    pub fn index_of(&mut self, code: &[u8], pc: usize) -> usize
+    {
+        if pc >= code.len() {
+            return self.push(DecodedInsn::error(ERR_OUT_OF_BOUNDS, pc, 0));
+        }
+
+        if self.pc_map.len() < code.len() {
+            assert!(code.len() < NOT_DECODED as usize);
+            self.pc_map.resize(code.len(), NOT_DECODED);
+        }
+
+        if self.pc_map[pc] == NOT_DECODED {
+            self.decode_region(code, pc);
+        }
+
+        self.pc_map[pc] as usize
+    }
+
+    fn push(&mut self, insn: DecodedInsn) -> usize
+    {
+        self.insns.push(insn);
+        self.insns.len() - 1
+    }
+
+    /// Decode the code reachable from an address without going through calls
+    fn decode_region(&mut self, code: &[u8], start_pc: usize)
+    {
+        let mut worklist = vec![start_pc];
+
+        // Jumps whose target still needs to be resolved to an index
+        let mut jumps = Vec::new();
+
+        while let Some(mut pc) = worklist.pop() {
+            if self.pc_map[pc] != NOT_DECODED {
+                continue;
+            }
+
+            loop
+            {
+                if pc >= code.len() {
+                    self.push(DecodedInsn::error(ERR_OUT_OF_BOUNDS, pc, 0));
+                    break;
+                }
+
+                // Join up with code that was already decoded
+                let idx = self.pc_map[pc];
+                if idx != NOT_DECODED {
+                    let mut jmp = DecodedInsn::new(Op::jmp, pc);
+                    jmp.imm = idx as u64;
+                    self.push(jmp);
+                    break;
+                }
+
+                let (insn, next_pc) = decode(code, pc);
+                self.pc_map[pc] = self.push(insn) as u32;
+
+                match insn.op {
+                    Op::jmp | Op::jz | Op::jnz => {
+                        jumps.push(self.insns.len() - 1);
+                        if (insn.imm as usize) < code.len() {
+                            worklist.push(insn.imm as usize);
+                        }
+                    }
+                    _ => {}
+                }
+
+                if let Op::jmp | Op::ret | Op::panic = insn.op {
+                    break;
+                }
+
+                pc = next_pc;
+            }
+        }
+
+        for jump_idx in jumps {
+            let target = self.insns[jump_idx].imm as usize;
+
+            let target_idx = if target < code.len() {
+                self.pc_map[target] as usize
+            } else {
+                self.push(DecodedInsn::error(ERR_OUT_OF_BOUNDS, target, 0))
+            };
+
+            self.insns[jump_idx].imm = target_idx as u64;
+        }
+    }
+}
+
+fn read<T: Copy>(code: &[u8], pc: &mut usize) -> Option<T>
+{
+    if *pc >= code.len() || code.len() - *pc < size_of::<T>() {
+        return None;
+    }
+
+    let val = unsafe { std::ptr::read_unaligned(code.as_ptr().add(*pc) as *const T) };
+    *pc += size_of::<T>();
+    Some(val)
+}
+
+/// Decode the instruction at a given address, returning it and the address of the next one.
+/// Jump targets are returned as addresses.
+fn decode(code: &[u8], pc: usize) -> (DecodedInsn, usize)
+{
+    let mut next_pc = pc;
+    match decode_operands(code, &mut next_pc) {
+        Some(insn) => (insn, next_pc),
+        None => (DecodedInsn::error(ERR_OUT_OF_BOUNDS, pc, 0), next_pc),
+    }
+}
+
+fn decode_operands(code: &[u8], pc: &mut usize) -> Option<DecodedInsn>
+{
+    let insn_pc = *pc;
+    let op = read::<u8>(code, pc)?;
+
+    if op == Op::OP_EXT as u8 {
+        let ext_op = read::<u8>(code, pc)?;
+
+        if ext_op > ExtOp::LAST as u8 {
+            return Some(DecodedInsn::error(ERR_UNKNOWN_EXT_OP, insn_pc, ext_op as u64));
+        }
+
+        return Some(match unsafe { transmute::<u8, ExtOp>(ext_op) } {
+            ExtOp::panic => DecodedInsn::error(ERR_EXT_PANIC, insn_pc, 0),
+            ExtOp::nop => DecodedInsn::new(Op::nop, insn_pc),
+
+            // Other extended instructions keep the extended opcode in n
+            _ => {
+                let mut insn = DecodedInsn::new(Op::OP_EXT, insn_pc);
+                insn.n = ext_op;
+                insn
+            }
+        });
+    }
+
+    if op > Op::LAST as u8 {
+        return Some(DecodedInsn::error(ERR_UNKNOWN_OP, insn_pc, op as u64));
+    }
+
+    let op: Op = unsafe { transmute(op) };
+    let mut insn = DecodedInsn::new(op, insn_pc);
+
+    match op {
+        Op::panic => insn = DecodedInsn::error(ERR_PANIC, insn_pc, 0),
+
+        Op::push_0n | Op::getn | Op::setn |
+        Op::get_arg | Op::set_arg | Op::get_local | Op::set_local |
+        Op::thread_set | Op::thread_get => {
+            insn.n = read::<u8>(code, pc)?;
+        }
+
+        // All pushes of immediates become push_u64
+        Op::push_i8 => {
+            insn.op = Op::push_u64;
+            insn.imm = read::<i8>(code, pc)? as i64 as u64;
+        }
+        Op::push_u32 => {
+            insn.op = Op::push_u64;
+            insn.imm = read::<u32>(code, pc)? as u64;
+        }
+        Op::push_u64 => insn.imm = read::<u64>(code, pc)?,
+
+        Op::load_u8_off | Op::load_u16_off | Op::load_u32_off | Op::load_u64_off |
+        Op::store_u8_off | Op::store_u16_off | Op::store_u32_off | Op::store_u64_off |
+        Op::load_global_u8 | Op::load_global_u16 | Op::load_global_u32 | Op::load_global_u64 |
+        Op::store_global_u8 | Op::store_global_u16 | Op::store_global_u32 | Op::store_global_u64 => {
+            insn.imm = read::<u32>(code, pc)? as u64;
+        }
+
+        Op::jmp | Op::jz | Op::jnz => {
+            let offset = read::<i32>(code, pc)? as isize;
+            insn.imm = (*pc as isize + offset) as u64;
+        }
+
+        // Short jumps become regular jumps
+        Op::jmp_8 | Op::jz_8 | Op::jnz_8 => {
+            let offset = read::<i8>(code, pc)? as isize;
+            insn.op = match op {
+                Op::jmp_8 => Op::jmp,
+                Op::jz_8 => Op::jz,
+                _ => Op::jnz,
+            };
+            insn.imm = (*pc as isize + offset) as u64;
+        }
+
+        Op::call => {
+            let offset = read::<i32>(code, pc)? as isize;
+            insn.n = read::<u8>(code, pc)?;
+
+            // The callee address must fit in 32 bits, see DecodedInsn
+            let callee_pc = (*pc as isize + offset) as usize;
+            insn.imm = callee_pc.min(u32::MAX as usize) as u64;
+        }
+
+        Op::call_fp => insn.n = read::<u8>(code, pc)?,
+
+        Op::syscall => insn.imm = read::<u16>(code, pc)? as u64,
+
+        _ => {}
+    }
+
+    Some(insn)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::asm::Assembler;
+
+    fn decode_src(src: &str) -> Predecoded
+    {
+        let prog = Assembler::new().parse_str(src).unwrap();
+        let mut decoded = Predecoded::new();
+        assert_eq!(decoded.index_of(prog.code.as_slice(), 0), 0);
+        decoded
+    }
+
+    #[test]
+    fn test_decode()
+    {
+        let decoded = decode_src("push_i8 -3; push_u32 7; add_u64; ret;");
+        assert_eq!(decoded.insns.len(), 4);
+        assert_eq!(decoded.insns[0].op, Op::push_u64);
+        assert_eq!(decoded.insns[0].imm, -3i64 as u64);
+        assert_eq!(decoded.insns[1].imm, 7);
+        assert_eq!(decoded.insns[1].pc, 2);
+        assert_eq!(decoded.insns[2].op, Op::add_u64);
+    }
+
+    #[test]
+    fn test_jumps()
+    {
+        // Loop with a backward jump and a forward exit
+        let decoded = decode_src("
+            push_0;
+            LOOP:
+            dup;
+            push_i8 10;
+            eq_u64;
+            jnz DONE;
+            push_1;
+            add_u64;
+            jmp LOOP;
+            DONE:
+            ret;
+        ");
+
+        let jnz = decoded.insns.iter().find(|insn| insn.op == Op::jnz).unwrap();
+        assert_eq!(decoded.insns[jnz.imm as usize].op, Op::ret);
+
+        let jmp = decoded.insns.iter().find(|insn| insn.op == Op::jmp).unwrap();
+        assert_eq!(decoded.insns[jmp.imm as usize].op, Op::dup);
+        assert_eq!(decoded.insns[jmp.imm as usize].pc, 1);
+    }
+
+    #[test]
+    fn test_errors()
+    {
+        // Jump before the start of the code space
+        let mut decoded = Predecoded::new();
+        decoded.index_of(&[Op::jmp_8 as u8, -10i8 as u8], 0);
+        let target = decoded.insns[0].imm as usize;
+        assert_eq!(decoded.insns[target].op, Op::panic);
+        assert_eq!(decoded.insns[target].n, ERR_OUT_OF_BOUNDS);
+
+        // Truncated operand
+        let mut decoded = Predecoded::new();
+        decoded.index_of(&[Op::push_u32 as u8, 0, 0], 0);
+        assert_eq!(decoded.insns[0].n, ERR_OUT_OF_BOUNDS);
+
+        // Unknown extended opcode
+        let mut decoded = Predecoded::new();
+        decoded.index_of(&[Op::OP_EXT as u8, 200], 0);
+        assert_eq!(decoded.insns[0].n, ERR_UNKNOWN_EXT_OP);
+    }
+}
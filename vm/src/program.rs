@@ -1,6 +1,7 @@
 use std::collections::HashSet;
 use std::mem::transmute;
-use crate::vm::Op;
+use std::ops::Range;
+use crate::vm::{Op, ExtOp};
 
 pub struct ByteArray
 {
@@ -16,6 +17,13 @@ impl ByteArray
         }
     }
 
+    pub fn from_vec(data: Vec<u8>) -> Self
+    {
+        Self {
+            data
+        }
+    }
+
     pub fn as_slice(&self) -> &[u8]
     {
         &self.data[..]
@@ -32,6 +40,12 @@ impl ByteArray
         self.data.push(op as u8);
     }
 
+    pub fn push_ext_op(&mut self, op: ExtOp)
+    {
+        self.data.push(Op::OP_EXT as u8);
+        self.data.push(op as u8);
+    }
+
     pub fn push_u8(&mut self, val: u8)
     {
         self.data.push(val);
@@ -70,6 +84,11 @@ impl ByteArray
         }
     }
 
+    pub fn push_bytes(&mut self, bytes: &[u8])
+    {
+        self.data.extend_from_slice(bytes);
+    }
+
     /// Write a value at the given address
     pub fn write<T>(&mut self, pos: usize, val: T) where T: Copy
     {
@@ -81,6 +100,10 @@ impl ByteArray
     }
 }
 
+/// Page size used to lay out the data segment, so that protected
+/// parts of the heap don't share pages with writable data
+pub const PAGE_SIZE: usize = 4096;
+
 pub struct Program
 {
     // Executable code
@@ -89,6 +112,13 @@ pub struct Program
     // Data segment
     pub data: ByteArray,
 
+    // Part of the data segment that is read-only
+    pub rodata: Range<usize>,
+
+    // The first page of the heap is left unused and made
+    // inaccessible, so that null pointer dereferences trap
+    pub null_guard: bool,
+
     // Set of syscalls referenced by this program
     pub syscalls: HashSet<u16>,
 }
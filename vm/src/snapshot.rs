@@ -0,0 +1,393 @@
+//! Snapshots of a running program, which can be resumed with `uvm --resume`
+//!
+//! A snapshot is taken with the vm_snapshot syscall. It holds the code, the
+//! heap and the state of the calling thread, so that execution can continue
+//! right after the syscall. Return addresses are saved as code addresses, so
+//! a snapshot can be resumed with a different engine than the one that saved
+//! it. Host resources such as windows, files and sockets aren't saved.
+
+use std::fs;
+use std::io::Write;
+use std::collections::HashSet;
+use std::ops::Range;
+use crate::vm::{Thread, Value, StackFrame};
+use crate::program::{Program, ByteArray, PAGE_SIZE};
+use crate::fs::{error_code, open_options};
+use crate::constants::*;
+
+/// Identifies snapshot files and the version of their format
+const MAGIC: &[u8; 8] = b"UVMSNAP1";
+
+/// State of a thread stopped in a syscall
+pub struct ThreadState
+{
+    /// Thread id
+    pub id: u64,
+
+    /// Value stack
+    pub stack: Vec<Value>,
+
+    /// Stack frames, with return addresses in the code space
+    pub frames: Vec<StackFrame>,
+
+    /// Thread-local variables
+    pub locals: Vec<Value>,
+
+    /// Address to continue at and base pointer there
+    pub pc: usize,
+    pub bp: usize,
+}
+
+pub struct Snapshot
+{
+    /// Executable code
+    pub code: Vec<u8>,
+
+    /// Heap contents, for the whole heap size
+    pub heap: Vec<u8>,
+
+    /// Read-only part of the heap
+    pub rodata: Range<usize>,
+
+    /// The first heap page is a null guard
+    pub null_guard: bool,
+
+    /// Next thread id to assign
+    pub next_tid: u64,
+
+    /// State of the thread that took the snapshot
+    pub thread: ThreadState,
+}
+
+impl Snapshot
+{
+    /// Serialize the snapshot. All values are stored as little-endian u64s,
+    /// and byte arrays and lists are preceded by their length.
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        let mut out = MAGIC.to_vec();
+        let mut put = |val: u64| out.extend_from_slice(&val.to_le_bytes());
+
+        put(self.next_tid);
+
+        let thread = &self.thread;
+        put(thread.id);
+        put(thread.pc as u64);
+        put(thread.bp as u64);
+
+        put(thread.stack.len() as u64);
+        for val in &thread.stack {
+            put(val.as_u64());
+        }
+
+        put(thread.locals.len() as u64);
+        for val in &thread.locals {
+            put(val.as_u64());
+        }
+
+        put(thread.frames.len() as u64);
+        for frame in &thread.frames {
+            put(frame.prev_bp as u64);
+            put(frame.ret_addr as u64);
+            put(frame.argc as u64);
+            put(frame.callee_pc as u64);
+        }
+
+        for bytes in [&self.code, &self.heap] {
+            out.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+            out.extend_from_slice(bytes);
+        }
+
+        out.extend_from_slice(&(self.rodata.start as u64).to_le_bytes());
+        out.extend_from_slice(&(self.rodata.end as u64).to_le_bytes());
+        out.extend_from_slice(&(self.null_guard as u64).to_le_bytes());
+
+        out
+    }
+
+    /// Parse a serialized snapshot, see to_bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String>
+    {
+        if !bytes.starts_with(MAGIC) {
+            return Err("not a snapshot file, or from an incompatible version".to_string());
+        }
+
+        let mut reader = Reader { bytes, pos: MAGIC.len() };
+        let next_tid = reader.u64()?;
+        let id = reader.u64()?;
+        let pc = reader.u64()? as usize;
+        let bp = reader.u64()? as usize;
+
+        let stack_len = reader.len(8)?;
+        let stack = (0..stack_len).map(|_| reader.u64().map(Value::from)).collect::<Result<Vec<_>, _>>()?;
+
+        let num_locals = reader.len(8)?;
+        let locals = (0..num_locals).map(|_| reader.u64().map(Value::from)).collect::<Result<Vec<_>, _>>()?;
+
+        let num_frames = reader.len(32)?;
+        let mut frames = Vec::with_capacity(num_frames);
+        for _ in 0..num_frames {
+            frames.push(StackFrame {
+                prev_bp: reader.u64()? as usize,
+                ret_addr: reader.u64()? as usize,
+                argc: reader.u64()? as usize,
+                callee_pc: reader.u64()? as usize,
+            });
+        }
+
+        let code_len = reader.len(1)?;
+        let code = reader.bytes(code_len)?.to_vec();
+        let heap_len = reader.len(1)?;
+        let heap = reader.bytes(heap_len)?.to_vec();
+        let rodata = (reader.u64()? as usize)..(reader.u64()? as usize);
+        let null_guard = reader.u64()? != 0;
+
+        if reader.pos != bytes.len() {
+            return Err("unexpected data at the end of the snapshot".to_string());
+        }
+
+        // The interpreter checks stack accesses, but it
+        // expects the frames to be consistent with each other
+        let ret_addr_valid = |addr: usize| addr == usize::MAX || addr < code.len();
+        let frames_valid = !frames.is_empty() && frames.iter().all(|frame| {
+            ret_addr_valid(frame.ret_addr) && frame.callee_pc < code.len() &&
+            (frame.prev_bp == usize::MAX || frame.prev_bp <= stack.len())
+        });
+
+        if !frames_valid || pc >= code.len() || bp > stack.len() {
+            return Err("invalid thread state in snapshot".to_string());
+        }
+
+        if rodata.start > rodata.end || rodata.end > heap.len() {
+            return Err("invalid read-only data range in snapshot".to_string());
+        }
+
+        if null_guard && heap.len() < PAGE_SIZE {
+            return Err("heap too small for a null guard in snapshot".to_string());
+        }
+
+        Ok(Self {
+            code,
+            heap,
+            rodata,
+            null_guard,
+            next_tid,
+            thread: ThreadState { id, stack, frames, locals, pc, bp },
+        })
+    }
+
+    /// Read a snapshot file
+    pub fn load(path: &str) -> Result<Self, String>
+    {
+        match fs::read(path) {
+            Ok(bytes) => Self::from_bytes(&bytes).map_err(|err| format!("invalid snapshot \"{}\": {}", path, err)),
+            Err(err) => Err(format!("could not read snapshot \"{}\": {}", path, err)),
+        }
+    }
+
+    /// Take the code and heap out of the snapshot as a program to create a VM with
+    pub fn take_program(&mut self) -> Program
+    {
+        Program {
+            code: ByteArray::from_vec(std::mem::take(&mut self.code)),
+            data: ByteArray::from_vec(std::mem::take(&mut self.heap)),
+            rodata: self.rodata.clone(),
+            null_guard: self.null_guard,
+            syscalls: HashSet::default(),
+        }
+    }
+}
+
+/// Reads values from a serialized snapshot
+struct Reader<'a>
+{
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_>
+{
+    fn bytes(&mut self, len: usize) -> Result<&[u8], String>
+    {
+        if self.bytes.len() - self.pos < len {
+            return Err("snapshot is truncated".to_string());
+        }
+
+        let bytes = &self.bytes[self.pos..(self.pos + len)];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, String>
+    {
+        let bytes = self.bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Read the length of a list whose elements take elem_size bytes,
+    /// checking that the list fits in what's left of the snapshot
+    fn len(&mut self, elem_size: usize) -> Result<usize, String>
+    {
+        let len = self.u64()? as usize;
+        match len.checked_mul(elem_size) {
+            Some(size) if size <= self.bytes.len() - self.pos => Ok(len),
+            _ => Err("snapshot is truncated".to_string()),
+        }
+    }
+}
+
+// Syscall to save a snapshot of the program
+// i64 result = vm_snapshot(const char* path)
+pub fn vm_snapshot(thread: &mut Thread, path: Value) -> Value
+{
+    let path = thread.get_heap_str(path.as_usize()).to_owned();
+
+    let vm = thread.vm.lock().unwrap();
+    let path = match vm.fs_state.resolve_path(&path) {
+        Ok(path) => path,
+        Err(code) => return Value::from(code),
+    };
+
+    // Other threads would keep running while the snapshot is taken,
+    // and the state of compiled code isn't known to the interpreter
+    if vm.num_threads() != 0 || thread.jit.is_some() {
+        return Value::from(SNAPSHOT_ERR_UNSUPPORTED);
+    }
+
+    let next_tid = vm.next_tid;
+    let rodata = vm.rodata.clone();
+    let null_guard = vm.null_guard;
+    drop(vm);
+
+    let state = match thread.save_state() {
+        Some(state) => state,
+        None => return Value::from(SNAPSHOT_ERR_UNSUPPORTED),
+    };
+
+    // The null guard page can't be read, and it is always zero
+    let guard_size = if null_guard { PAGE_SIZE } else { 0 };
+    let mut heap = vec![0; guard_size];
+    let heap_size = thread.heap_size();
+    heap.extend_from_slice(thread.get_heap_slice_mut::<u8>(guard_size, heap_size - guard_size));
+
+    let snapshot = Snapshot {
+        code: thread.code_bytes().to_vec(),
+        heap,
+        rodata,
+        null_guard,
+        next_tid,
+        thread: state,
+    };
+
+    let result = open_options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(&snapshot.to_bytes()));
+
+    match result {
+        Ok(()) => Value::from(0),
+        Err(err) => Value::from(error_code(&err)),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::path::Path;
+    use crate::vm::VM;
+    use crate::asm::Assembler;
+
+    // Takes a snapshot with a call and a local variable live,
+    // and returns 82 after saving it or 83 once resumed. The path
+    // of the snapshot file is written at address 0.
+    const SRC: &str = "
+        .data;
+        .zero 256;
+        .code;
+        push 5;
+        call F, 1;
+        ret;
+        F:
+        push_0n 1;
+        push 77;
+        set_local 0;
+        get_arg 0;
+        push 0;
+        syscall vm_snapshot;
+        add_u64;
+        get_local 0;
+        add_u64;
+        ret;
+    ";
+
+    /// Run main, or resume a snapshot if one is given
+    fn run(prog: Program, dir: &Path, jit_threshold: Option<u64>, predecode: bool, state: Option<ThreadState>) -> i64
+    {
+        let vm = VM::new(prog);
+        vm.lock().unwrap().jit_threshold = jit_threshold;
+        vm.lock().unwrap().predecode = predecode;
+        vm.lock().unwrap().fs_state.add_root(dir.to_str().unwrap()).unwrap();
+
+        let mut thread = VM::new_thread(&vm);
+        let ret_val = match state {
+            Some(state) => thread.resume(state, Value::from(1)),
+            None => {
+                let path = dir.join("test.snap");
+                let path = path.to_str().unwrap();
+                let buf: &mut [u8] = thread.get_heap_slice_mut(0, path.len() + 1);
+                buf[..path.len()].copy_from_slice(path.as_bytes());
+                buf[path.len()] = 0;
+                thread.call(0, &[])
+            }
+        };
+
+        ret_val.as_i64()
+    }
+
+    #[test]
+    fn test_resume()
+    {
+        let dir = std::env::temp_dir().join(format!("uvm_snapshot_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let snapshot_path = dir.join("test.snap");
+
+        // Resume with each interpreter, including the one that didn't take the snapshot
+        for (save_predecoded, resume_predecoded) in [(false, false), (false, true), (true, false), (true, true)] {
+            let prog = Assembler::new().parse_str(SRC).unwrap();
+            assert_eq!(run(prog, &dir, None, save_predecoded, None), 82);
+
+            let mut snapshot = Snapshot::load(snapshot_path.to_str().unwrap()).unwrap();
+            let prog = snapshot.take_program();
+            assert_eq!(run(prog, &dir, None, resume_predecoded, Some(snapshot.thread)), 83);
+            fs::remove_file(&snapshot_path).unwrap();
+        }
+
+        // Snapshots aren't supported with the JIT enabled, even
+        // before anything is compiled
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        for jit_threshold in [1, 100] {
+            let prog = Assembler::new().parse_str(SRC).unwrap();
+            assert_eq!(run(prog, &dir, Some(jit_threshold), false, None), 82 + SNAPSHOT_ERR_UNSUPPORTED);
+            assert!(!snapshot_path.exists());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid()
+    {
+        assert!(Snapshot::from_bytes(b"UVMSNAP0").is_err());
+        assert!(Snapshot::from_bytes(b"UVMSNAP1").is_err());
+
+        let mut bytes = MAGIC.to_vec();
+        for val in [0, 0, 0, 0, u64::MAX] {
+            bytes.extend_from_slice(&val.to_le_bytes());
+        }
+        assert_eq!(Snapshot::from_bytes(&bytes).err().unwrap(), "snapshot is truncated");
+    }
+}
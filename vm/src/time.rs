@@ -1,4 +1,5 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{SystemTime, Instant, UNIX_EPOCH};
+use std::sync::OnceLock;
 use crate::vm::{Thread, Value};
 
 /// Get the current time stamp in milliseconds
@@ -12,3 +13,52 @@ pub fn time_current_ms(thread: &mut Thread) -> Value
 {
     Value::from(get_time_ms())
 }
+
+/// Point in time the monotonic clock counts from
+static CLOCK_START: OnceLock<Instant> = OnceLock::new();
+
+/// Get the time in nanoseconds from a monotonic clock
+pub fn time_monotonic_ns(thread: &mut Thread) -> Value
+{
+    let start = CLOCK_START.get_or_init(Instant::now);
+    Value::from(start.elapsed().as_nanos() as u64)
+}
+
+// C struct filled in by time_local
+#[repr(C)]
+struct CLocalTime
+{
+    year: i32,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+    utc_offset: i32,
+}
+
+// Syscall to break down a time stamp into the local date and time
+// bool ok = time_local(u64 timestamp_ms, void* p_time)
+pub fn time_local(thread: &mut Thread, timestamp_ms: Value, p_time: Value) -> Value
+{
+    let secs = match libc::time_t::try_from(timestamp_ms.as_u64() / 1000) {
+        Ok(secs) => secs,
+        Err(_) => return Value::from(false),
+    };
+
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return Value::from(false);
+    }
+
+    let local_time: &mut CLocalTime = &mut thread.get_heap_slice_mut(p_time.as_usize(), 1)[0];
+    local_time.year = tm.tm_year + 1900;
+    local_time.month = (tm.tm_mon + 1) as u32;
+    local_time.day = tm.tm_mday as u32;
+    local_time.hour = tm.tm_hour as u32;
+    local_time.minute = tm.tm_min as u32;
+    local_time.second = tm.tm_sec as u32;
+    local_time.utc_offset = tm.tm_gmtoff as i32;
+
+    Value::from(true)
+}
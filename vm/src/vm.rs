@@ -1,11 +1,23 @@
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{Ordering, AtomicU64};
+use std::sync::atomic::{fence, Ordering, AtomicU32, AtomicU64};
 use std::mem::{transmute, size_of, align_of};
 use std::collections::{HashSet, HashMap};
+use std::ops::Range;
 use std::thread;
 use std::ffi::CStr;
+use std::panic;
 use crate::host::*;
-use crate::program::Program;
+use crate::program::{Program, PAGE_SIZE};
+use crate::net::NetState;
+use crate::fs::FsState;
+use crate::futex::FutexState;
+use crate::chan::ChanState;
+use crate::event::EventState;
+use crate::jit::{Jit, JitFn};
+use crate::predecode::{Predecoded, DecodedInsn};
+use crate::snapshot::ThreadState;
+use crate::utils::thousands_sep;
+use crate::fault;
 
 /// Instruction opcodes
 /// Note: commonly used upcodes should be in the [0, 127] range (one byte)
@@ -132,15 +144,6 @@ pub enum Op
     div_i64,
     mod_i64,
 
-    // TODO: arithmetic with overflow
-    // These instructions probably shouldn't jump directly,
-    // as this would add more branch instructions to the
-    // instruction set.
-    // We don't need to worry about compactness.
-    // add_u64_ovf,
-    // sub_u64_ovf,
-    // mul_i64_ovf, // produces two 64-bit words of output
-
     // 64-bit integer comparisons
     eq_u64,
     ne_u64,
@@ -171,16 +174,6 @@ pub enum Op
     mul_f32,
     div_f32,
 
-    // Floating-point math functions
-    sin_f32,
-    cos_f32,
-    tan_f32,
-    asin_f32,
-    acos_f32,
-    atan_f32,
-    pow_f32,
-    sqrt_f32,
-
     // 32-bit floating-point comparison instructions
     eq_f32,
     ne_f32,
@@ -208,30 +201,36 @@ pub enum Op
     store_u32,
     store_u64,
 
-    /*
-    // TODO:
-    // Load from heap at fixed address
+    // Load a value at an address plus a constant offset
+    // This is used for reading struct fields
+    // load_u32_off <offset:u32> (addr)
+    load_u8_off,
+    load_u16_off,
+    load_u32_off,
+    load_u64_off,
+
+    // Store a value at an address plus a constant offset
+    // store_u32_off <offset:u32> (addr) (value)
+    store_u8_off,
+    store_u16_off,
+    store_u32_off,
+    store_u64_off,
+
+    // Load from the heap at a fixed address
     // This is used for reading global variables
-    // The address is multiplied by the data size (x 4 or x8)
-    // If we save 24 bits for the offset, then that gives us quite a lot
-    load_global_u64 <addr:u24>
-    */
-
-    // Atomic load with acquire semantics
-    // atomic_load (addr)
-    atomic_load_u64,
-
-    // Atomic store with release semantics
-    // atomic_store (addr) (value)
-    atomic_store_u64,
-
-    // Compare-and-swap
-    // Uses acquire semantics on success, relaxed on failure.
-    // Store has relaxed semantics.
-    // This instruction can be used to implement spin locks.
-    // atomic_cas (addr) (cmp-val) (store-val)
-    // Pushes the value found at the memory address
-    atomic_cas_u64,
+    // load_global_u32 <addr:u32>
+    load_global_u8,
+    load_global_u16,
+    load_global_u32,
+    load_global_u64,
+
+    // Store to the heap at a fixed address
+    // This is used for writing global variables
+    // store_global_u32 <addr:u32> (value)
+    store_global_u8,
+    store_global_u16,
+    store_global_u32,
+    store_global_u64,
 
     // Set thread-local variable
     // thread_set <idx:u8> (val)
@@ -251,10 +250,6 @@ pub enum Op
     // test_bit_z <bit_idx:u8>
     // test_bit_nz <bit_idx:u8>
 
-    // TODO: we should probably have 8-bit offset versions of jump insns
-    // However, this can wait. Premature optimization.
-    // jmp_8, jz_8, jnz_8
-
     // Jump to pc offset
     // jmp <offset:i32>
     jmp,
@@ -267,6 +262,17 @@ pub enum Op
     // jnz <offset:i32>
     jnz,
 
+    // Short-offset versions of the jump instructions
+    // The assembler selects these automatically when the target is in range
+    // jmp_8 <offset:i8>
+    jmp_8,
+
+    // jz_8 <offset:i8>
+    jz_8,
+
+    // jnz_8 <offset:i8>
+    jnz_8,
+
     // Call a function using the call stack
     // call <offset:i32> <num_args:u8> (arg0, arg1, ..., argN)
     call,
@@ -284,13 +290,185 @@ pub enum Op
     // ret (value)
     ret,
 
+    // Less frequently used opcodes go after ret, so that
+    // the commonly used ones above stay in the [0, 127] range
+
+    // Floating-point math functions
+    sin_f32,
+    cos_f32,
+    tan_f32,
+    asin_f32,
+    acos_f32,
+    atan_f32,
+    pow_f32,
+    sqrt_f32,
+
+    // Extended 32-bit floating-point math functions
+    // NaN inputs produce NaN outputs, except for min/max
+    // floor/ceil/round/trunc return infinities and zeros unchanged
+    floor_f32,
+    ceil_f32,
+    // Rounds half-way cases away from zero, like C's roundf
+    round_f32,
+    trunc_f32,
+    abs_f32,
+    // If exactly one input is NaN, the other input is returned, like C's fminf
+    min_f32,
+    max_f32,
+    // exp of -inf is 0, exp overflows to +inf
+    exp_f32,
+    // Natural logarithm, log of 0 is -inf, log of a negative number is NaN
+    log_f32,
+    // atan2_f32 (y) (x)
+    atan2_f32,
+    // Remainder with the sign of the dividend, like C's fmodf
+    // Division by zero or an infinite dividend produces NaN
+    mod_f32,
+
+    // Additional int/float conversions
+    // Float to int conversions round towards zero, saturate
+    // to the min/max int values, and convert NaN to zero
+    f32_to_i64,
+    u32_to_f32,
+    u64_to_f32,
+    f32_to_u32,
+    f32_to_u64,
+
+    // Integer arithmetic with overflow detection
+    // These push the wrapped result, followed by an overflow flag
+    // which is 1 if the result doesn't fit in the output type
+    // Signed 32-bit results are sign-extended
+    // add_u64_ovf (a) (b) => (result) (overflow)
+    add_u32_ovf,
+    sub_u32_ovf,
+    mul_u32_ovf,
+    add_i32_ovf,
+    sub_i32_ovf,
+    mul_i32_ovf,
+    add_u64_ovf,
+    sub_u64_ovf,
+    mul_u64_ovf,
+    add_i64_ovf,
+    sub_i64_ovf,
+    mul_i64_ovf,
+
+    // Widening 64x64 -> 128-bit multiplication
+    // These push the low 64 bits of the product, followed by the high 64 bits
+    // mul_u64_wide (a) (b) => (lo) (hi)
+    mul_u64_wide,
+    mul_i64_wide,
+
+    // 64-bit floating-point arithmetic
+    add_f64,
+    sub_f64,
+    mul_f64,
+    div_f64,
+
+    // 64-bit floating-point comparison instructions
+    eq_f64,
+    ne_f64,
+    lt_f64,
+    le_f64,
+    gt_f64,
+    ge_f64,
+
+    // 64-bit int/float and float/float conversion
+    i32_to_f64,
+    i64_to_f64,
+    f64_to_i32,
+    f64_to_i64,
+    f32_to_f64,
+    f64_to_f32,
+
+    // Atomic load with acquire semantics
+    // atomic_load (addr)
+    atomic_load_u32,
+    atomic_load_u64,
+
+    // Atomic store with release semantics
+    // atomic_store (addr) (value)
+    atomic_store_u32,
+    atomic_store_u64,
+
+    // Compare-and-swap
+    // Uses acquire-release semantics on success, acquire on failure.
+    // This instruction can be used to implement spin locks.
+    // atomic_cas (addr) (cmp-val) (store-val)
+    // Pushes the value found at the memory address
+    atomic_cas_u32,
+    atomic_cas_u64,
+
+    // Atomic read-modify-write with acquire-release semantics
+    // atomic_fetch_add (addr) (value)
+    // Pushes the value found at the memory address before the update
+    atomic_fetch_add_u32,
+    atomic_fetch_sub_u32,
+    atomic_fetch_and_u32,
+    atomic_fetch_or_u32,
+    atomic_fetch_xor_u32,
+    atomic_xchg_u32,
+    atomic_fetch_add_u64,
+    atomic_fetch_sub_u64,
+    atomic_fetch_and_u64,
+    atomic_fetch_or_u64,
+    atomic_fetch_xor_u64,
+    atomic_xchg_u64,
+
+    // Sequentially consistent memory fence
+    fence,
+
     // NOTE: last opcode must have value < 255
-    // Currently, every opcode is just one byte long,
-    // and we hope to keep it that way, but the value
-    // 255 is reserved for future 16-bit opcode extensions.
+    // Frequently used opcodes are just one byte long.
+    // The value 255 is a prefix for two-byte extended
+    // opcodes, where the second byte is an ExtOp.
+    // OP_EXT <ext_op:u8>
     OP_EXT = 255,
 }
 
+impl Op
+{
+    /// Last valid one-byte opcode, used to validate decoding
+    /// This must be updated when adding new opcodes
+    pub const LAST: Op = Op::fence;
+}
+
+/// Extended opcodes, encoded as OP_EXT followed by one byte
+/// These are for less frequently used instructions, so that
+/// they don't use up the one-byte opcode space.
+#[allow(non_camel_case_types)]
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum ExtOp
+{
+    // Halt execution and produce an error
+    panic = 0,
+
+    // Two-byte no-op
+    nop,
+
+    // 64-bit floating-point math functions
+    sin_f64,
+    cos_f64,
+    tan_f64,
+    asin_f64,
+    acos_f64,
+    atan_f64,
+    pow_f64,
+    sqrt_f64,
+}
+
+impl ExtOp
+{
+    /// Last valid extended opcode, used to validate decoding
+    /// This must be updated when adding new extended opcodes
+    pub const LAST: ExtOp = ExtOp::sqrt_f64;
+}
+
+/// Total number of instructions executed by all threads
+/// Only counted when the count_insns feature is enabled
+#[cfg(feature = "count_insns")]
+pub static INSN_COUNT: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Value(u64);
 
@@ -351,6 +529,11 @@ impl Value
         let val = val as i32;
         unsafe { transmute(val) }
     }
+
+    pub fn as_f64(&self) -> f64 {
+        let Value(val) = *self;
+        f64::from_bits(val)
+    }
 }
 
 impl From<bool> for Value {
@@ -414,16 +597,45 @@ impl From<f32> for Value {
     }
 }
 
-struct StackFrame
+impl From<f64> for Value {
+    fn from(val: f64) -> Self {
+        Value(val.to_bits())
+    }
+}
+
+/// Error raised when a call runs out of its instruction budget.
+/// Execution unwinds with this as the panic payload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutOfFuel;
+
+/// Number of instructions a thread takes at a time from
+/// the total budget shared by all threads of the VM
+const FUEL_CHUNK: u64 = 1 << 16;
+
+/// Default maximum number of values on the value stack of a thread
+pub const DEFAULT_MAX_STACK: usize = 1 << 20;
+
+/// Native stack size of spawned threads
+const THREAD_STACK_SIZE: usize = 8 << 20;
+
+/// Default maximum call depth of a thread. Compiled code and host calls use the
+/// native stack, so this is kept low enough for calls that nest through them.
+pub const DEFAULT_MAX_FRAMES: usize = 16_384;
+
+#[derive(Debug, Clone, Copy)]
+pub struct StackFrame
 {
     // Previous base pointer at the time of call
-    prev_bp: usize,
+    pub prev_bp: usize,
 
     // Return address
-    ret_addr: usize,
+    pub ret_addr: usize,
 
     // Argument count
-    argc: usize,
+    pub argc: usize,
+
+    // Address of the function this frame belongs to
+    pub callee_pc: usize,
 }
 
 
@@ -446,6 +658,10 @@ struct MemBlock
     // This is a box because we need a pointer
     // To access this value from threads using MemView
     cur_size: Box<usize>,
+
+    // Slot under which faults in this block are reported,
+    // once some of its pages have been protected
+    fault_slot: Option<usize>,
 }
 
 impl MemBlock
@@ -493,15 +709,48 @@ impl MemBlock
             mapping_size: alloc_size,
             page_size,
             cur_size: Box::new(0),
+            fault_slot: None,
+        }
+    }
+
+    /// Set the access protection (libc::PROT_*) of the pages within an
+    /// address range. On hosts with pages larger than the range's alignment,
+    /// only the pages entirely contained within the range are protected.
+    pub fn protect(&mut self, start: usize, end: usize, prot: libc::c_int)
+    {
+        assert!(end <= *self.cur_size);
+        let start = start.next_multiple_of(self.page_size);
+        let end = end - end % self.page_size;
+
+        if start >= end {
+            return;
+        }
+
+        let ret = unsafe {libc::mprotect(
+            self.mem_block.add(start) as *mut libc::c_void,
+            end - start,
+            prot
+        )};
+        assert!(ret == 0, "mprotect failed");
+    }
+
+    /// Report faults caused by accesses to protected pages of this block
+    /// as heap access errors, with accesses below guard_size reported
+    /// as null pointer dereferences and accesses to rodata as writes
+    pub fn report_faults(&mut self, guard_size: usize, rodata: Range<usize>)
+    {
+        if self.fault_slot.is_none() {
+            self.fault_slot = fault::register_heap(self.mem_block as usize, self.mapping_size, guard_size, rodata);
         }
     }
 
-    /// Grow to a new size in bytes
+    /// Grow to a new size in bytes, without exceeding max_size
     /// This operation is a no-op if the existing size
     /// is greater or equal to the requested size
+    /// Returns None if the memory block can't be grown
     ///
     /// Note: this operation must be guarded by the VM
-    pub fn grow(&mut self, mut new_size: usize) -> usize
+    pub fn grow(&mut self, mut new_size: usize, max_size: usize) -> Option<usize>
     {
         // Round up to a page size multiple
         let rem = new_size % self.page_size;
@@ -514,7 +763,11 @@ impl MemBlock
 
         // Growing the memory block, need to map as read | write
         if new_size <= cur_size {
-            return cur_size;
+            return Some(cur_size);
+        }
+
+        if new_size > max_size || new_size > self.mapping_size {
+            return None;
         }
 
         // Compute the address from which to mmap
@@ -533,13 +786,19 @@ impl MemBlock
         )};
 
         if mem_block == libc::MAP_FAILED {
-            panic!();
+            return None;
         }
 
         // Update the currently accessible size
         *self.cur_size = new_size;
 
-        new_size
+        Some(new_size)
+    }
+
+    /// Get the currently accessible size in bytes
+    pub fn size_bytes(&self) -> usize
+    {
+        *self.cur_size
     }
 
     // Create a new thread-local view on this memory block
@@ -552,6 +811,18 @@ impl MemBlock
     }
 }
 
+impl Drop for MemBlock
+{
+    fn drop(&mut self)
+    {
+        if let Some(slot) = self.fault_slot {
+            fault::unregister_heap(slot);
+        }
+
+        unsafe { libc::munmap(self.mem_block as *mut libc::c_void, self.mapping_size) };
+    }
+}
+
 struct MemView
 {
     // Underlying memory block
@@ -626,6 +897,12 @@ impl MemView
         }
     }
 
+    /// Get a slice over the whole accessible memory
+    pub fn as_slice(&self) -> &[u8]
+    {
+        unsafe { std::slice::from_raw_parts(self.mem_block, self.size_bytes()) }
+    }
+
     /// Read a value at the current PC and then increment the PC
     pub fn read_pc<T>(&self, pc: &mut usize) -> T where T: Copy
     {
@@ -666,11 +943,46 @@ pub struct Thread
 
     // Thread-local variables
     locals: Vec<Value>,
+
+    // JIT compiler state, None if the JIT is disabled
+    pub jit: Option<Jit>,
+
+    // Pre-decoded instructions, None if using the bytecode interpreter
+    predecoded: Option<Predecoded>,
+
+    // Number of instructions left before running out of fuel,
+    // only checked if limit_fuel is set. Compiled code updates this directly.
+    pub fuel: u64,
+
+    // Whether the current call has an instruction budget
+    limit_fuel: bool,
+
+    // Maximum number of values on the value stack
+    pub max_stack: usize,
+
+    // Maximum number of stack frames
+    pub max_frames: usize,
+
+    // Number of frames that can still be pushed before reaching max_frames.
+    // This also counts compiled functions, which update it directly.
+    pub frames_left: u64,
+
+    // Number of value slots that compiled functions can still take on
+    // the native stack before reaching max_stack. Updated by compiled code.
+    pub jit_slots_left: u64,
+
+    // Lowest native stack address that compiled code can use
+    pub native_stack_limit: u64,
+
+    // Where execution continues after the syscall being run, as an address
+    // or an instruction index in pre-decoded code, and the base pointer there
+    syscall_ret: usize,
+    syscall_bp: usize,
 }
 
 impl Thread
 {
-    fn new(tid: u64, vm: Arc<Mutex<VM>>, code: MemView, heap: MemView) -> Self
+    fn new(tid: u64, vm: Arc<Mutex<VM>>, code: MemView, heap: MemView, jit: Option<Jit>, predecoded: Option<Predecoded>) -> Self
     {
         Self {
             id: tid,
@@ -680,14 +992,134 @@ impl Thread
             stack: Vec::default(),
             frames: Vec::default(),
             locals: Vec::default(),
+            jit,
+            predecoded,
+            fuel: u64::MAX,
+            limit_fuel: false,
+            max_stack: DEFAULT_MAX_STACK,
+            max_frames: DEFAULT_MAX_FRAMES,
+            frames_left: DEFAULT_MAX_FRAMES as u64,
+            jit_slots_left: DEFAULT_MAX_STACK as u64,
+            native_stack_limit: 0,
+            syscall_ret: usize::MAX,
+            syscall_bp: usize::MAX,
         }
     }
 
+    #[inline(always)]
     pub fn push<T>(&mut self, val: T) where Value: From<T>
     {
+        // The stack capacity never goes past the limit,
+        // so the limit only needs checking when it's full
+        if self.stack.len() == self.stack.capacity() {
+            self.reserve_stack(1);
+        }
+
         self.stack.push(Value::from(val));
     }
 
+    /// Push a number of zero values on the stack
+    fn push_zeros(&mut self, n: usize)
+    {
+        if self.stack.capacity() - self.stack.len() < n {
+            self.reserve_stack(n);
+        }
+
+        self.stack.resize(self.stack.len() + n, Value::from(0));
+    }
+
+    /// Make room for n more values on the stack,
+    /// raising a stack overflow past the limit
+    #[cold]
+    fn reserve_stack(&mut self, n: usize)
+    {
+        let len = self.stack.len();
+        if len + n > self.max_stack {
+            self.value_stack_exceeded();
+        }
+
+        // Grow geometrically, but not past the limit
+        let new_cap = (len * 2).max(len + n).max(64).min(self.max_stack);
+        self.stack.reserve_exact(new_cap - len);
+    }
+
+    #[inline(always)]
+    fn push_frame(&mut self, frame: StackFrame)
+    {
+        // The frame is pushed first so that it shows up in the backtrace
+        self.frames.push(frame);
+
+        if self.frames_left == 0 {
+            self.call_depth_exceeded();
+        }
+        self.frames_left -= 1;
+    }
+
+    /// Raise a stack overflow error for going past the call depth limit
+    #[cold]
+    pub fn call_depth_exceeded(&self) -> !
+    {
+        self.stack_overflow(format!("call depth limit of {} frames exceeded", self.max_frames));
+    }
+
+    /// Raise a stack overflow error for going past the value stack limit
+    #[cold]
+    pub fn value_stack_exceeded(&self) -> !
+    {
+        self.stack_overflow(format!("value stack limit of {} slots exceeded", self.max_stack));
+    }
+
+    /// Raise a stack overflow error for compiled code running out of native stack
+    #[cold]
+    pub fn native_stack_exceeded(&self) -> !
+    {
+        self.stack_overflow("native stack exhausted by compiled code".to_string());
+    }
+
+    /// Raise a stack overflow error with the guest backtrace
+    #[cold]
+    fn stack_overflow(&self, reason: String) -> !
+    {
+        panic!("stack overflow, {}\n{}", reason, self.backtrace());
+    }
+
+    /// Describe the frames on the call stack, innermost first.
+    /// Long backtraces are shortened by leaving out middle frames.
+    pub fn backtrace(&self) -> String
+    {
+        const MAX_SHOWN: usize = 20;
+
+        let mut out = "guest backtrace, innermost call first:".to_string();
+        let num_frames = self.frames.len();
+
+        for (idx, frame) in self.frames.iter().rev().enumerate() {
+            if num_frames > MAX_SHOWN && idx >= MAX_SHOWN / 2 && idx < num_frames - MAX_SHOWN / 2 {
+                if idx == MAX_SHOWN / 2 {
+                    out += &format!("\n  ... {} more frames", num_frames - MAX_SHOWN);
+                }
+                continue;
+            }
+
+            out += &format!("\n  #{} function at pc {}", idx, frame.callee_pc);
+
+            // Frames entered from the host or from compiled code have no return address.
+            // In pre-decoded code, return addresses are instruction indices.
+            if frame.ret_addr != usize::MAX {
+                let ret_pc = match &self.predecoded {
+                    Some(predecoded) => predecoded.insns[frame.ret_addr].pc as usize,
+                    None => frame.ret_addr,
+                };
+                out += &format!(", returning to pc {}", ret_pc);
+            }
+        }
+
+        if self.jit.is_some() {
+            out += "\n  (calls made from compiled code aren't listed)";
+        }
+
+        out
+    }
+
     pub fn pop(&mut self) -> Value
     {
         match self.stack.pop() {
@@ -742,103 +1174,396 @@ impl Thread
         rust_str
     }
 
-    /// Call a function at a given address
-    pub fn call(&mut self, callee_pc: u64, args: &[Value]) -> Value
+    /// Get the compiled code for a function if the JIT is enabled,
+    /// compiling the function if it has been called often enough
+    pub fn jit_lookup(&mut self, callee_pc: usize) -> Option<JitFn>
     {
-        assert!(self.stack.len() == 0);
-        assert!(self.frames.len() == 0);
-
-        // Push a new stack frame
-        self.frames.push(StackFrame {
-            prev_bp: usize::MAX,
-            ret_addr: usize::MAX,
-            argc: args.len(),
-        });
+        let jit = self.jit.as_mut()?;
+        jit.lookup(callee_pc, self.code.as_slice(), self.heap.mem_block, self.heap.cur_size)
+    }
 
-        // Push the arguments on the stack
-        for arg in args {
-            self.stack.push(*arg);
+    /// Call a compiled function with arguments taken off the value stack
+    fn call_jit(&mut self, fun: JitFn, num_args: usize) -> Value
+    {
+        let arg_idx = self.stack.len() - num_args;
+        let mut args: Vec<u64> = self.stack.drain(arg_idx..).map(|arg| arg.as_u64()).collect();
+        let ret = unsafe { fun(self, args.as_mut_ptr(), num_args as u64) };
+
+        // Panics can't unwind through compiled code, so
+        // they get resumed once we're back in Rust code
+        if ret.panicked != 0 {
+            let payload = self.jit.as_mut().unwrap().panic.take().unwrap();
+            panic::resume_unwind(payload);
         }
 
-        // The base pointer will point at the first local
-        let mut bp = self.stack.len();
-        let mut pc = callee_pc as usize;
+        Value::from(ret.val)
+    }
 
-        // For each instruction to execute
-        loop
-        {
-            let op = self.code.read_pc::<Op>(&mut pc);
-            //dbg!(op);
+    /// Call a host function, taking its arguments off the
+    /// stack and pushing its return value if it has one
+    pub fn syscall(&mut self, syscall_idx: u16)
+    {
+        let syscall_fn = get_syscall(syscall_idx);
 
-            match op
-            {
-                Op::panic => panic!("execution error, encountered panic opcode"),
+        match syscall_fn
+        {
+            HostFn::Fn0_0(fun) => {
+                fun(self)
+            }
 
-                Op::nop => continue,
+            HostFn::Fn0_1(fun) => {
+                let v = fun(self);
+                self.push(v);
+            }
 
-                Op::pop => {
-                    self.pop();
-                }
+            HostFn::Fn1_0(fun) => {
+                let a0 = self.pop();
+                fun(self, a0)
+            }
 
-                Op::getn => {
-                    let n = self.code.read_pc::<u8>(&mut pc) as usize;
-                    let val = self.stack[self.stack.len() - (1 + n)];
-                    self.push(val);
-                }
+            HostFn::Fn1_1(fun) => {
+                let a0 = self.pop();
+                let v = fun(self, a0);
+                self.push(v);
+            }
 
-                Op::setn => {
-                    let n = self.code.read_pc::<u8>(&mut pc) as usize;
-                    let val = self.pop();
-                    let len = self.stack.len();
-                    self.stack[len - (1 + n)] = val;
-                }
+            HostFn::Fn2_0(fun) => {
+                let a1 = self.pop();
+                let a0 = self.pop();
+                fun(self, a0, a1)
+            }
 
-                Op::dup => {
-                    let val = self.pop();
-                    self.push(val);
-                    self.push(val);
-                }
+            HostFn::Fn2_1(fun) => {
+                let a1 = self.pop();
+                let a0 = self.pop();
+                let v = fun(self, a0, a1);
+                self.push(v);
+            }
 
-                Op::swap => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(a);
-                    self.push(b);
-                }
+            HostFn::Fn3_0(fun) => {
+                let a2 = self.pop();
+                let a1 = self.pop();
+                let a0 = self.pop();
+                fun(self, a0, a1, a2)
+            }
 
-                Op::get_arg => {
-                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
+            HostFn::Fn3_1(fun) => {
+                let a2 = self.pop();
+                let a1 = self.pop();
+                let a0 = self.pop();
+                let v = fun(self, a0, a1, a2);
+                self.push(v);
+            }
 
-                    let argc = self.frames[self.frames.len() - 1].argc;
-                    if idx >= argc {
-                        panic!("invalid index in get_arg, idx={}, argc={}", idx, argc);
-                    }
+            HostFn::Fn4_0(fun) => {
+                let a3 = self.pop();
+                let a2 = self.pop();
+                let a1 = self.pop();
+                let a0 = self.pop();
+                fun(self, a0, a1, a2, a3)
+            }
 
-                    // Last argument is at bp - 1 (if there are arguments)
-                    let stack_idx = (bp - argc) + idx;
-                    self.push(self.stack[stack_idx]);
-                }
+            HostFn::Fn4_1(fun) => {
+                let a3 = self.pop();
+                let a2 = self.pop();
+                let a1 = self.pop();
+                let a0 = self.pop();
+                let v = fun(self, a0, a1, a2, a3);
+                self.push(v);
+            }
 
-                Op::get_var_arg => {
-                    let idx = self.pop().as_usize();
+            HostFn::Fn5_1(fun) => {
+                let a4 = self.pop();
+                let a3 = self.pop();
+                let a2 = self.pop();
+                let a1 = self.pop();
+                let a0 = self.pop();
+                let v = fun(self, a0, a1, a2, a3, a4);
+                self.push(v);
+            }
+        }
+    }
 
-                    let argc = self.frames[self.frames.len() - 1].argc;
-                    if idx >= argc {
-                        panic!("invalid index in get_arg, idx={}, argc={}", idx, argc);
-                    }
+    /// Stop execution because the instruction budget has been used up
+    #[cold]
+    pub fn out_of_fuel(&self) -> !
+    {
+        // This doesn't go through the panic hook, so no message is printed
+        panic::resume_unwind(Box::new(OutOfFuel))
+    }
 
-                    // Last argument is at bp - 1 (if there are arguments)
-                    let stack_idx = (bp - argc) + idx;
-                    self.push(self.stack[stack_idx]);
-                }
+    /// Make sure the thread has at least the given amount of fuel, drawing
+    /// from the total budget of the VM, or stop if there isn't enough left
+    pub fn refill_fuel(&mut self, needed: u64)
+    {
+        if self.fuel >= needed {
+            return;
+        }
 
-                Op::set_arg => {
-                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
+        let mut vm = self.vm.lock().unwrap();
+        if let (true, Some(budget)) = (vm.total_fuel, vm.fuel.as_mut()) {
+            let amount = (needed - self.fuel).max(FUEL_CHUNK).min(*budget);
+            *budget -= amount;
+            self.fuel += amount;
+        }
+        drop(vm);
 
-                    let argc = self.frames[self.frames.len() - 1].argc;
-                    if idx >= argc {
-                        panic!("invalid index in set_arg, idx={}, argc={}", idx, argc);
-                    }
+        if self.fuel < needed {
+            self.out_of_fuel();
+        }
+    }
+
+    /// Return the fuel held by the thread to the total budget of the VM
+    pub fn give_back_fuel(&mut self)
+    {
+        let mut vm = self.vm.lock().unwrap();
+        if let (true, Some(budget)) = (vm.total_fuel, vm.fuel.as_mut()) {
+            *budget += self.fuel;
+            self.fuel = 0;
+        }
+    }
+
+    /// Call a function, returning an error if it runs out of fuel.
+    /// The thread can still be used after that.
+    pub fn try_call(&mut self, callee_pc: u64, args: &[Value]) -> Result<Value, OutOfFuel>
+    {
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| self.call(callee_pc, args)));
+
+        match result {
+            Ok(val) => Ok(val),
+            Err(payload) => match payload.downcast::<OutOfFuel>() {
+                Ok(_) => {
+                    self.stack.clear();
+                    self.frames.clear();
+                    Err(OutOfFuel)
+                }
+                Err(payload) => panic::resume_unwind(payload),
+            }
+        }
+    }
+
+    /// Reset the limits of the thread before running code from the host
+    fn begin_call(&mut self)
+    {
+        assert!(self.stack.len() == 0);
+        assert!(self.frames.len() == 0);
+
+        let (fuel, total_fuel) = {
+            let vm = self.vm.lock().unwrap();
+            (vm.fuel, vm.total_fuel)
+        };
+
+        // With a total budget, the fuel is drawn from the VM as the
+        // thread runs, and what the thread holds carries over between calls
+        match fuel {
+            Some(_) if total_fuel => {
+                if !self.limit_fuel {
+                    self.fuel = 0;
+                }
+            }
+            _ => self.fuel = fuel.unwrap_or(u64::MAX),
+        }
+        self.limit_fuel = fuel.is_some();
+
+        self.frames_left = self.max_frames as u64;
+        self.jit_slots_left = self.max_stack as u64;
+
+        // The thread may be called from a different OS thread each time
+        if self.jit.is_some() {
+            self.native_stack_limit = crate::jit::current_stack_limit();
+        }
+    }
+
+    /// Call a function at a given address. Each call gets the
+    /// instruction budget set on the VM, if any, unless the budget
+    /// is a total for the thread, in which case what's left carries over.
+    pub fn call(&mut self, callee_pc: u64, args: &[Value]) -> Value
+    {
+        self.begin_call();
+
+        // Push the arguments on the stack
+        for arg in args {
+            self.push(*arg);
+        }
+
+        if let Some(fun) = self.jit_lookup(callee_pc as usize) {
+            return self.call_jit(fun, args.len());
+        }
+
+        self.run_fn(callee_pc as usize, args.len())
+    }
+
+    /// Get the code of the program
+    pub fn code_bytes(&self) -> &[u8]
+    {
+        self.code.as_slice()
+    }
+
+    /// Save the state of the thread while it's running a syscall, so that it can be
+    /// resumed after the syscall. Return addresses are saved as code addresses.
+    /// Returns None if compiled code or the host is on the call stack, since their
+    /// state isn't known to the interpreter.
+    pub fn save_state(&self) -> Option<ThreadState>
+    {
+        // Calls from the host or from compiled code into the interpreter
+        // push a frame without a return address, and compiled functions
+        // only count towards the call depth
+        let depth = self.max_frames as u64 - self.frames_left;
+        let nested = self.frames.iter().skip(1).any(|frame| frame.ret_addr == usize::MAX);
+        if self.frames.is_empty() || nested || depth != self.frames.len() as u64 {
+            return None;
+        }
+
+        let to_pc = |ret_addr: usize| match &self.predecoded {
+            Some(predecoded) if ret_addr != usize::MAX => predecoded.insns[ret_addr].pc as usize,
+            _ => ret_addr,
+        };
+
+        Some(ThreadState {
+            id: self.id,
+            stack: self.stack.clone(),
+            frames: self.frames.iter().map(|frame| StackFrame { ret_addr: to_pc(frame.ret_addr), ..*frame }).collect(),
+            locals: self.locals.clone(),
+            pc: to_pc(self.syscall_ret),
+            bp: self.syscall_bp,
+        })
+    }
+
+    /// Continue running a thread from a saved state, with a value pushed
+    /// as the return value of the syscall during which it was saved
+    pub fn resume(&mut self, state: ThreadState, ret_val: Value) -> Value
+    {
+        self.begin_call();
+
+        self.id = state.id;
+        self.stack = state.stack;
+        self.locals = state.locals;
+
+        for frame in state.frames {
+            let ret_addr = match self.predecoded {
+                Some(_) if frame.ret_addr != usize::MAX => self.predecoded_index(frame.ret_addr),
+                _ => frame.ret_addr,
+            };
+            self.push_frame(StackFrame { ret_addr, ..frame });
+        }
+
+        self.push(ret_val);
+        self.run_from(state.pc, state.bp, 0)
+    }
+
+    /// Run a function in the interpreter until it returns.
+    /// The arguments must already be on the stack. This can be
+    /// called while other functions are running on this thread.
+    pub fn run_fn(&mut self, callee_pc: usize, argc: usize) -> Value
+    {
+        // Number of frames below the function we're running
+        let base_frames = self.frames.len();
+
+        // Push a new stack frame
+        self.push_frame(StackFrame {
+            prev_bp: usize::MAX,
+            ret_addr: usize::MAX,
+            argc,
+            callee_pc,
+        });
+
+        // The base pointer will point at the first local
+        let bp = self.stack.len();
+
+        self.run_from(callee_pc, bp, base_frames)
+    }
+
+    /// Run the interpreter from a given address, with the frames for the
+    /// code being run already pushed, until returning to base_frames frames
+    fn run_from(&mut self, pc: usize, bp: usize, base_frames: usize) -> Value
+    {
+        // The interpreter loops are instantiated separately for when there is
+        // an instruction budget, so that there's no cost when there isn't one
+        match (self.predecoded.is_some(), self.limit_fuel) {
+            (false, false) => self.run_bytecode::<false>(pc, bp, base_frames),
+            (false, true) => self.run_bytecode::<true>(pc, bp, base_frames),
+            (true, false) => self.run_predecoded::<false>(pc, bp, base_frames),
+            (true, true) => self.run_predecoded::<true>(pc, bp, base_frames),
+        }
+    }
+
+    /// Run code by decoding each instruction as it executes, see run_from
+    fn run_bytecode<const LIMIT_FUEL: bool>(&mut self, mut pc: usize, mut bp: usize, base_frames: usize) -> Value
+    {
+        // Fuel is kept in a local while running, and written
+        // back to the thread before compiled code runs
+        let mut fuel = self.fuel;
+
+        // For each instruction to execute
+        loop
+        {
+            #[cfg(feature = "count_insns")]
+            INSN_COUNT.fetch_add(1, Ordering::Relaxed);
+
+            if LIMIT_FUEL {
+                if fuel == 0 {
+                    self.fuel = 0;
+                    self.refill_fuel(1);
+                    fuel = self.fuel;
+                }
+                fuel -= 1;
+            }
+
+            let op = self.code.read_pc::<Op>(&mut pc);
+            //dbg!(op);
+
+            match op
+            {
+                Op::panic => panic!("execution error, encountered panic opcode"),
+
+                Op::nop => continue,
+
+                Op::getn => {
+                    let n = self.code.read_pc::<u8>(&mut pc) as usize;
+                    let val = self.stack[self.stack.len() - (1 + n)];
+                    self.push(val);
+                }
+
+                Op::setn => {
+                    let n = self.code.read_pc::<u8>(&mut pc) as usize;
+                    let val = self.pop();
+                    let len = self.stack.len();
+                    self.stack[len - (1 + n)] = val;
+                }
+
+                Op::get_arg => {
+                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
+
+                    let argc = self.frames[self.frames.len() - 1].argc;
+                    if idx >= argc {
+                        panic!("invalid index in get_arg, idx={}, argc={}", idx, argc);
+                    }
+
+                    // Last argument is at bp - 1 (if there are arguments)
+                    let stack_idx = (bp - argc) + idx;
+                    self.push(self.stack[stack_idx]);
+                }
+
+                Op::get_var_arg => {
+                    let idx = self.pop().as_usize();
+
+                    let argc = self.frames[self.frames.len() - 1].argc;
+                    if idx >= argc {
+                        panic!("invalid index in get_arg, idx={}, argc={}", idx, argc);
+                    }
+
+                    // Last argument is at bp - 1 (if there are arguments)
+                    let stack_idx = (bp - argc) + idx;
+                    self.push(self.stack[stack_idx]);
+                }
+
+                Op::set_arg => {
+                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
+
+                    let argc = self.frames[self.frames.len() - 1].argc;
+                    if idx >= argc {
+                        panic!("invalid index in set_arg, idx={}, argc={}", idx, argc);
+                    }
 
                     // Last argument is at bp - 1 (if there are arguments)
                     let stack_idx = (bp - argc) + idx;
@@ -867,19 +1592,9 @@ impl Thread
                     self.stack[bp + idx] = val;
                 }
 
-                Op::push_0 => {
-                    self.push(0);
-                }
-                Op::push_1 => {
-                    self.push(1);
-                }
-                Op::push_2 => {
-                    self.push(2);
-                }
-
                 Op::push_0n => {
                     let n = self.code.read_pc::<u8>(&mut pc);
-                    self.stack.resize(self.stack.len() + n as usize, Value::from(0));
+                    self.push_zeros(n as usize);
                 }
 
                 Op::push_i8 => {
@@ -897,614 +1612,526 @@ impl Thread
                     self.push(val);
                 }
 
-                Op::and_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() & v1.as_u32());
+                Op::load_u8_off => {
+                    let offset = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let addr = self.pop().as_usize() + offset;
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    let val: u8 = unsafe { *heap_ptr };
+                    self.push(val);
                 }
 
-                Op::or_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() | v1.as_u32());
+                Op::load_u16_off => {
+                    let offset = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let addr = self.pop().as_usize() + offset;
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    let val: u16 = unsafe { *heap_ptr };
+                    self.push(val);
                 }
 
-                Op::xor_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() ^ v1.as_u32());
+                Op::load_u32_off => {
+                    let offset = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let addr = self.pop().as_usize() + offset;
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    let val: u32 = unsafe { *heap_ptr };
+                    self.push(val);
                 }
 
-                Op::not_u32 => {
-                    let v0 = self.pop();
-                    self.push(!v0.as_u32());
+                Op::load_u64_off => {
+                    let offset = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let addr = self.pop().as_usize() + offset;
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    let val: u64 = unsafe { *heap_ptr };
+                    self.push(val);
                 }
 
-                Op::lshift_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u32().wrapping_shl(v1.as_u32())
-                    );
+                Op::store_u8_off => {
+                    let offset = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let val = self.pop().as_u8();
+                    let addr = self.pop().as_usize() + offset;
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    unsafe { *heap_ptr = val; }
                 }
 
-                Op::rshift_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u32().wrapping_shr(v1.as_u32())
-                    );
+                Op::store_u16_off => {
+                    let offset = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let val = self.pop().as_u16();
+                    let addr = self.pop().as_usize() + offset;
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    unsafe { *heap_ptr = val; }
                 }
 
-                Op::rshift_i32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_i32().wrapping_shr(v1.as_u32())
-                    );
+                Op::store_u32_off => {
+                    let offset = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let val = self.pop().as_u32();
+                    let addr = self.pop().as_usize() + offset;
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    unsafe { *heap_ptr = val; }
                 }
 
-                Op::add_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u32().wrapping_add(v1.as_u32())
-                    );
+                Op::store_u64_off => {
+                    let offset = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let val = self.pop().as_u64();
+                    let addr = self.pop().as_usize() + offset;
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    unsafe { *heap_ptr = val; }
                 }
 
-                Op::sub_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u32().wrapping_sub(v1.as_u32())
-                    );
+                Op::load_global_u8 => {
+                    let addr = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    let val: u8 = unsafe { *heap_ptr };
+                    self.push(val);
                 }
 
-                Op::mul_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u32().wrapping_mul(v1.as_u32())
-                    );
+                Op::load_global_u16 => {
+                    let addr = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    let val: u16 = unsafe { *heap_ptr };
+                    self.push(val);
                 }
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::div_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u32() / v1.as_u32()
-                    );
+                Op::load_global_u32 => {
+                    let addr = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    let val: u32 = unsafe { *heap_ptr };
+                    self.push(val);
                 }
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::mod_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u32() % v1.as_u32()
-                    );
+                Op::load_global_u64 => {
+                    let addr = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    let val: u64 = unsafe { *heap_ptr };
+                    self.push(val);
                 }
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::div_i32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_i32() / v1.as_i32()
-                    );
+                Op::store_global_u8 => {
+                    let addr = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let val = self.pop().as_u8();
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    unsafe { *heap_ptr = val; }
                 }
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::mod_i32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_i32() % v1.as_i32()
-                    );
+                Op::store_global_u16 => {
+                    let addr = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let val = self.pop().as_u16();
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    unsafe { *heap_ptr = val; }
                 }
 
-                Op::eq_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() == v1.as_u32());
+                Op::store_global_u32 => {
+                    let addr = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let val = self.pop().as_u32();
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    unsafe { *heap_ptr = val; }
                 }
 
-                Op::ne_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() != v1.as_u32());
+                Op::store_global_u64 => {
+                    let addr = self.code.read_pc::<u32>(&mut pc) as usize;
+                    let val = self.pop().as_u64();
+                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                    unsafe { *heap_ptr = val; }
                 }
 
-                Op::lt_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() < v1.as_u32());
-                }
+                Op::thread_set => {
+                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
+                    let val = self.pop();
 
-                Op::le_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() <= v1.as_u32());
-                }
+                    if idx >= self.locals.len() {
+                        self.locals.resize(idx + 1, Value::from(0));
+                    }
 
-                Op::gt_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() > v1.as_u32());
+                    self.locals[idx] = val;
                 }
 
-                Op::ge_u32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u32() >= v1.as_u32());
-                }
+                Op::thread_get => {
+                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
 
-                Op::lt_i32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i32() < v1.as_i32());
+                    if idx >= self.locals.len() {
+                        self.push(Value::from(0));
+                    } else {
+                        self.push(self.locals[idx])
+                    }
                 }
 
-                Op::le_i32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i32() <= v1.as_i32());
+                Op::jmp => {
+                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
+                    pc = ((pc as isize) + offset) as usize;
                 }
 
-                Op::gt_i32 => {
-                    let v1 = self.pop();
+                Op::jz => {
+                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
                     let v0 = self.pop();
-                    self.push(v0.as_i32() > v1.as_i32());
-                }
 
-                Op::ge_i32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i32() >= v1.as_i32());
+                    if v0.as_i64() == 0 {
+                        pc = ((pc as isize) + offset) as usize;
+                    }
                 }
 
-                Op::and_u64 => {
-                    let v1 = self.pop();
+                Op::jnz => {
+                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
                     let v0 = self.pop();
-                    self.push(v0.as_u64() & v1.as_u64());
-                }
 
-                Op::or_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() | v1.as_u64());
+                    if v0.as_i64() != 0 {
+                        pc = ((pc as isize) + offset) as usize;
+                    }
                 }
 
-                Op::xor_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() ^ v1.as_u64());
+                Op::jmp_8 => {
+                    let offset = self.code.read_pc::<i8>(&mut pc) as isize;
+                    pc = ((pc as isize) + offset) as usize;
                 }
 
-                Op::not_u64 => {
+                Op::jz_8 => {
+                    let offset = self.code.read_pc::<i8>(&mut pc) as isize;
                     let v0 = self.pop();
-                    self.push(!v0.as_u64());
-                }
 
-                Op::lshift_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u64().wrapping_shl(v1.as_u32())
-                    );
+                    if v0.as_i64() == 0 {
+                        pc = ((pc as isize) + offset) as usize;
+                    }
                 }
 
-                Op::rshift_u64 => {
-                    let v1 = self.pop();
+                Op::jnz_8 => {
+                    let offset = self.code.read_pc::<i8>(&mut pc) as isize;
                     let v0 = self.pop();
-                    self.push(
-                        v0.as_u64().wrapping_shr(v1.as_u32())
-                    );
-                }
 
-                Op::rshift_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_i64().wrapping_shr(v1.as_u32())
-                    );
+                    if v0.as_i64() != 0 {
+                        pc = ((pc as isize) + offset) as usize;
+                    }
                 }
 
-                Op::add_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u64().wrapping_add(v1.as_u64())
-                    );
-                }
+                // call <num_args:u8> <offset:i32> (arg0, arg1, ..., argN)
+                Op::call => {
+                    // Offset of the function to call
+                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
 
-                Op::sub_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u64().wrapping_sub(v1.as_u64())
-                    );
-                }
+                    // Argument count
+                    let num_args = self.code.read_pc::<u8>(&mut pc) as usize;
+                    assert!(num_args <= self.stack.len() - bp);
 
-                Op::mul_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u64().wrapping_mul(v1.as_u64())
-                    );
-                }
+                    let callee_pc = ((pc as isize) + offset) as usize;
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::div_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u64() / v1.as_u64()
-                    );
-                }
+                    if let Some(fun) = self.jit_lookup(callee_pc) {
+                        self.fuel = fuel;
+                        let ret_val = self.call_jit(fun, num_args);
+                        fuel = self.fuel;
+                        self.push(ret_val);
+                        continue;
+                    }
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::mod_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_u64() % v1.as_u64()
-                    );
-                }
+                    self.push_frame(StackFrame {
+                        prev_bp: bp,
+                        ret_addr: pc,
+                        argc: num_args,
+                        callee_pc,
+                    });
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::div_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_i64() / v1.as_i64()
-                    );
+                    // The base pointer will point at the first local
+                    bp = self.stack.len();
+                    pc = callee_pc;
                 }
 
-                // Division by zero will cause a panic (this is intentional)
-                Op::mod_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(
-                        v0.as_i64() % v1.as_i64()
-                    );
-                }
+                // call <num_args:u8> (arg0, arg1, ..., argN, f_ptr)
+                Op::call_fp => {
+                    // Absolute address of the function to call
+                    let fp = self.pop();
 
-                Op::eq_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() == v1.as_u64());
-                }
+                    // Argument count
+                    let num_args = self.code.read_pc::<u8>(&mut pc) as usize;
+                    assert!(num_args <= self.stack.len() - bp);
 
-                Op::ne_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() != v1.as_u64());
-                }
+                    if let Some(fun) = self.jit_lookup(fp.as_usize()) {
+                        self.fuel = fuel;
+                        let ret_val = self.call_jit(fun, num_args);
+                        fuel = self.fuel;
+                        self.push(ret_val);
+                        continue;
+                    }
 
-                Op::lt_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() < v1.as_u64());
-                }
+                    self.push_frame(StackFrame {
+                        prev_bp: bp,
+                        ret_addr: pc,
+                        argc: num_args,
+                        callee_pc: fp.as_usize(),
+                    });
 
-                Op::le_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() <= v1.as_u64());
+                    // The base pointer will point at the first local
+                    bp = self.stack.len();
+                    pc = fp.as_usize();
                 }
 
-                Op::gt_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() > v1.as_u64());
+                Op::syscall => {
+                    let syscall_idx = self.code.read_pc::<u16>(&mut pc);
+                    self.syscall_ret = pc;
+                    self.syscall_bp = bp;
+                    self.syscall(syscall_idx);
                 }
 
-                Op::ge_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_u64() >= v1.as_u64());
-                }
+                Op::OP_EXT => {
+                    let ext_op = self.code.read_pc::<u8>(&mut pc);
 
-                Op::lt_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i64() < v1.as_i64());
-                }
+                    if ext_op > ExtOp::LAST as u8 {
+                        panic!("unknown extended opcode {}", ext_op);
+                    }
 
-                Op::le_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i64() <= v1.as_i64());
+                    let ext_op: ExtOp = unsafe { transmute(ext_op) };
+                    self.exec_ext_op(ext_op);
                 }
 
-                Op::gt_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i64() > v1.as_i64());
-                }
+                Op::ret => {
+                    if self.stack.len() <= bp {
+                        panic!("ret with no return value on stack");
+                    }
 
-                Op::ge_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_i64() >= v1.as_i64());
-                }
+                    let ret_val = self.pop();
 
-                Op::sx_i8_i32 => {
-                    let v = self.pop();
-                    self.push(v.as_i8() as i32);
-                }
+                    assert!(self.frames.len() > base_frames);
+                    let top_frame = self.frames.pop().unwrap();
+                    self.frames_left += 1;
 
-                Op::sx_i8_i64 => {
-                    let v = self.pop();
-                    self.push(v.as_i8() as i64);
-                }
+                    // Pop all local variables and arguments
+                    // We pop arguments in the callee so we can support tail calls
+                    assert!(self.stack.len() >= bp - top_frame.argc);
+                    self.stack.truncate(bp - top_frame.argc);
 
-                Op::sx_i16_i32 => {
-                    let v = self.pop();
-                    self.push(v.as_i16() as i32);
-                }
+                    // If we're returning from the function we were running
+                    if self.frames.len() == base_frames {
+                        self.fuel = fuel;
+                        return ret_val;
+                    }
 
-                Op::sx_i16_i64 => {
-                    let v = self.pop();
-                    self.push(v.as_i16() as i64);
-                }
+                    pc = top_frame.ret_addr;
+                    bp = top_frame.prev_bp;
 
-                Op::sx_i32_i64 => {
-                    let v = self.pop();
-                    self.push(v.as_i32() as i64);
+                    self.push(ret_val);
                 }
 
-                Op::trunc_u8 => {
-                    let v = self.pop();
-                    self.push(v.as_u8());
-                }
+                _ => self.exec_op(op),
+            }
+        }
+    }
 
-                Op::trunc_u16 => {
-                    let v = self.pop();
-                    self.push(v.as_u16());
-                }
+    /// Get the index of the pre-decoded instruction at a given address
+    fn predecoded_index(&mut self, pc: usize) -> usize
+    {
+        let code = self.code.as_slice();
+        self.predecoded.as_mut().unwrap().index_of(code, pc)
+    }
 
-                Op::trunc_u32 => {
-                    let v = self.pop();
-                    self.push(v.as_u32());
-                }
+    /// Get a pointer to the pre-decoded instructions. Decoding more
+    /// code can move the instructions, which invalidates this pointer.
+    fn predecoded_insns(&self) -> *const DecodedInsn
+    {
+        self.predecoded.as_ref().unwrap().insns.as_ptr()
+    }
 
-                Op::add_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() + v1.as_f32());
-                }
+    /// Run code using pre-decoded instructions, see run_from.
+    /// The return address in stack frames is an instruction index.
+    fn run_predecoded<const LIMIT_FUEL: bool>(&mut self, pc: usize, mut bp: usize, base_frames: usize) -> Value
+    {
+        // Fuel is kept in a local while running, and written
+        // back to the thread before compiled code runs
+        let mut fuel = self.fuel;
+        let mut idx = self.predecoded_index(pc);
+        let mut insns = self.predecoded_insns();
 
-                Op::sub_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() - v1.as_f32());
+        // For each instruction to execute
+        loop
+        {
+            #[cfg(feature = "count_insns")]
+            INSN_COUNT.fetch_add(1, Ordering::Relaxed);
+
+            if LIMIT_FUEL {
+                if fuel == 0 {
+                    self.fuel = 0;
+                    self.refill_fuel(1);
+                    fuel = self.fuel;
                 }
+                fuel -= 1;
+            }
 
-                Op::mul_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() * v1.as_f32());
-                }
+            // Instruction indices all come from the decoder
+            let insn = unsafe { &*insns.add(idx) };
+            idx += 1;
 
-                // Should return NaN for invalid inputs
-                Op::div_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() / v1.as_f32());
-                }
+            match insn.op
+            {
+                Op::panic => insn.raise_error(),
+
+                Op::nop => continue,
 
-                Op::sin_f32 => {
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.sin());
+                Op::getn => {
+                    let n = insn.n as usize;
+                    let val = self.stack[self.stack.len() - (1 + n)];
+                    self.push(val);
                 }
 
-                Op::cos_f32 => {
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.cos());
+                Op::setn => {
+                    let n = insn.n as usize;
+                    let val = self.pop();
+                    let len = self.stack.len();
+                    self.stack[len - (1 + n)] = val;
                 }
 
-                // Should return NaN for invalid inputs
-                Op::tan_f32 => {
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.tan());
+                Op::get_arg => {
+                    let idx = insn.n as usize;
+
+                    let argc = self.frames[self.frames.len() - 1].argc;
+                    if idx >= argc {
+                        panic!("invalid index in get_arg, idx={}, argc={}", idx, argc);
+                    }
+
+                    // Last argument is at bp - 1 (if there are arguments)
+                    let stack_idx = (bp - argc) + idx;
+                    self.push(self.stack[stack_idx]);
                 }
 
-                // Should return NaN for invalid inputs
-                Op::asin_f32 => {
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.asin());
+                Op::get_var_arg => {
+                    let idx = self.pop().as_usize();
+
+                    let argc = self.frames[self.frames.len() - 1].argc;
+                    if idx >= argc {
+                        panic!("invalid index in get_arg, idx={}, argc={}", idx, argc);
+                    }
+
+                    // Last argument is at bp - 1 (if there are arguments)
+                    let stack_idx = (bp - argc) + idx;
+                    self.push(self.stack[stack_idx]);
                 }
 
-                // Should return NaN for invalid inputs
-                Op::acos_f32 => {
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.acos());
+                Op::set_arg => {
+                    let idx = insn.n as usize;
+
+                    let argc = self.frames[self.frames.len() - 1].argc;
+                    if idx >= argc {
+                        panic!("invalid index in set_arg, idx={}, argc={}", idx, argc);
+                    }
+
+                    // Last argument is at bp - 1 (if there are arguments)
+                    let stack_idx = (bp - argc) + idx;
+                    let val = self.pop();
+                    self.stack[stack_idx] = val;
                 }
 
-                Op::atan_f32 => {
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.atan());
+                Op::get_local => {
+                    let idx = insn.n as usize;
+
+                    if bp + idx >= self.stack.len() {
+                        panic!("invalid index {} in get_local", idx);
+                    }
+
+                    self.push(self.stack[bp + idx]);
                 }
 
-                // Should return NaN for invalid inputs
-                Op::pow_f32 => {
-                    let v1 = self.pop().as_f32();
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.powf(v1));
+                Op::set_local => {
+                    let idx = insn.n as usize;
+                    let val = self.pop();
+
+                    if bp + idx >= self.stack.len() {
+                        panic!("invalid index in set_local");
+                    }
+
+                    self.stack[bp + idx] = val;
                 }
 
-                // Should return NaN for invalid inputs
-                Op::sqrt_f32 => {
-                    let v0 = self.pop().as_f32();
-                    self.push(v0.sqrt());
+                Op::push_0n => {
+                    self.push_zeros(insn.n as usize);
                 }
 
-                Op::eq_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() == v1.as_f32());
+                // push_i8 and push_u32 are decoded as push_u64
+                Op::push_u64 => {
+                    self.push(insn.imm);
                 }
 
-                Op::ne_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() != v1.as_f32());
+                Op::load_u8_off => {
+                    let addr = self.pop().as_usize() + insn.imm as usize;
+                    let val: u8 = unsafe { *self.get_heap_ptr_mut(addr, 1) };
+                    self.push(val);
                 }
 
-                Op::lt_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() < v1.as_f32());
+                Op::load_u16_off => {
+                    let addr = self.pop().as_usize() + insn.imm as usize;
+                    let val: u16 = unsafe { *self.get_heap_ptr_mut(addr, 1) };
+                    self.push(val);
                 }
 
-                Op::le_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() <= v1.as_f32());
+                Op::load_u32_off => {
+                    let addr = self.pop().as_usize() + insn.imm as usize;
+                    let val: u32 = unsafe { *self.get_heap_ptr_mut(addr, 1) };
+                    self.push(val);
                 }
 
-                Op::gt_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() > v1.as_f32());
+                Op::load_u64_off => {
+                    let addr = self.pop().as_usize() + insn.imm as usize;
+                    let val: u64 = unsafe { *self.get_heap_ptr_mut(addr, 1) };
+                    self.push(val);
                 }
 
-                Op::ge_f32 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push(v0.as_f32() >= v1.as_f32());
+                Op::store_u8_off => {
+                    let val = self.pop().as_u8();
+                    let addr = self.pop().as_usize() + insn.imm as usize;
+                    unsafe { *self.get_heap_ptr_mut(addr, 1) = val; }
                 }
 
-                // Follows Rust semantics:
-                // - Round ties to even
-                // - Never panics
-                Op::i32_to_f32 => {
-                    let v = self.pop();
-                    self.push(v.as_i32() as f32);
+                Op::store_u16_off => {
+                    let val = self.pop().as_u16();
+                    let addr = self.pop().as_usize() + insn.imm as usize;
+                    unsafe { *self.get_heap_ptr_mut(addr, 1) = val; }
                 }
 
-                // Follows Rust semantics:
-                // - Round ties to even
-                // - Never panics
-                Op::i64_to_f32 => {
-                    let v = self.pop();
-                    self.push(v.as_i64() as f32);
+                Op::store_u32_off => {
+                    let val = self.pop().as_u32();
+                    let addr = self.pop().as_usize() + insn.imm as usize;
+                    unsafe { *self.get_heap_ptr_mut(addr, 1) = val; }
                 }
 
-                // Follows Rust semantics:
-                // - Rounds towards zero (truncates)
-                // - Saturates to min/max int values
-                // - NaN converts to zero
-                // - Never panics
-                Op::f32_to_i32 => {
-                    let v = self.pop();
-                    self.push(v.as_f32() as i32);
+                Op::store_u64_off => {
+                    let val = self.pop().as_u64();
+                    let addr = self.pop().as_usize() + insn.imm as usize;
+                    unsafe { *self.get_heap_ptr_mut(addr, 1) = val; }
                 }
 
-                Op::load_u8 => {
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
-                    let val: u8 = unsafe { *heap_ptr };
+                Op::load_global_u8 => {
+                    let val: u8 = unsafe { *self.get_heap_ptr_mut(insn.imm as usize, 1) };
                     self.push(val);
                 }
 
-                Op::load_u16 => {
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
-                    let val: u16 = unsafe { *heap_ptr };
+                Op::load_global_u16 => {
+                    let val: u16 = unsafe { *self.get_heap_ptr_mut(insn.imm as usize, 1) };
                     self.push(val);
                 }
 
-                Op::load_u32 => {
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
-                    let val: u32 = unsafe { *heap_ptr };
+                Op::load_global_u32 => {
+                    let val: u32 = unsafe { *self.get_heap_ptr_mut(insn.imm as usize, 1) };
                     self.push(val);
                 }
 
-                Op::load_u64 => {
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
-                    let val: u64 = unsafe { *heap_ptr };
+                Op::load_global_u64 => {
+                    let val: u64 = unsafe { *self.get_heap_ptr_mut(insn.imm as usize, 1) };
                     self.push(val);
                 }
 
-                Op::store_u8 => {
+                Op::store_global_u8 => {
                     let val = self.pop().as_u8();
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
-                    unsafe { *heap_ptr = val; }
+                    unsafe { *self.get_heap_ptr_mut(insn.imm as usize, 1) = val; }
                 }
 
-                Op::store_u16 => {
+                Op::store_global_u16 => {
                     let val = self.pop().as_u16();
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
-                    unsafe { *heap_ptr = val; }
+                    unsafe { *self.get_heap_ptr_mut(insn.imm as usize, 1) = val; }
                 }
 
-                Op::store_u32 => {
+                Op::store_global_u32 => {
                     let val = self.pop().as_u32();
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
-                    unsafe { *heap_ptr = val; }
-                }
-
-                Op::store_u64 => {
-                    let val = self.pop().as_u64();
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
-                    unsafe { *heap_ptr = val; }
+                    unsafe { *self.get_heap_ptr_mut(insn.imm as usize, 1) = val; }
                 }
 
-                Op::atomic_load_u64 => {
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
-                    let atomic = unsafe {AtomicU64::from_ptr(heap_ptr) };
-                    let val = atomic.load(Ordering::Acquire);
-                    self.push(Value::from(val));
-                }
-
-                Op::atomic_store_u64 => {
+                Op::store_global_u64 => {
                     let val = self.pop().as_u64();
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
-                    let atomic = unsafe {AtomicU64::from_ptr(heap_ptr) };
-                    atomic.store(val, Ordering::Release);
-                }
-
-                // Compare-and-swap
-                // Uses acquire semantics on success, relaxed on failure.
-                // Store has relaxed semantics.
-                // This instruction can be used to implement spin locks.
-                // atomic_cas (addr) (cmp-val) (store-val)
-                // Pushes the value found at the memory address
-                Op::atomic_cas_u64 => {
-                    let store_val = self.pop().as_u64();
-                    let cmp_val = self.pop().as_u64();
-                    let addr = self.pop().as_usize();
-
-                    let heap_ptr = self.get_heap_ptr_mut(addr, 1);
-                    let atomic = unsafe {AtomicU64::from_ptr(heap_ptr) };
-
-                    let result = atomic.compare_exchange(
-                        cmp_val,
-                        store_val,
-                        Ordering::Acquire,
-                        Ordering::Relaxed,
-                    );
-
-                    match result {
-                        Ok(val) => self.push(Value::from(val)),
-                        Err(actual_val) => self.push(Value::from(actual_val)),
-                    }
+                    unsafe { *self.get_heap_ptr_mut(insn.imm as usize, 1) = val; }
                 }
 
                 Op::thread_set => {
-                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
+                    let idx = insn.n as usize;
                     let val = self.pop();
 
                     if idx >= self.locals.len() {
@@ -1515,7 +2142,7 @@ impl Thread
                 }
 
                 Op::thread_get => {
-                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
+                    let idx = insn.n as usize;
 
                     if idx >= self.locals.len() {
                         self.push(Value::from(0));
@@ -1524,171 +2151,1241 @@ impl Thread
                     }
                 }
 
+                // Short jumps are decoded as regular jumps,
+                // and jump targets are instruction indices
                 Op::jmp => {
-                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
-                    pc = ((pc as isize) + offset) as usize;
+                    idx = insn.imm as usize;
+                }
+
+                Op::jz => {
+                    let v0 = self.pop();
+
+                    if v0.as_i64() == 0 {
+                        idx = insn.imm as usize;
+                    }
+                }
+
+                Op::jnz => {
+                    let v0 = self.pop();
+
+                    if v0.as_i64() != 0 {
+                        idx = insn.imm as usize;
+                    }
+                }
+
+                Op::call => {
+                    // The callee address is in the low 32 bits of the immediate
+                    let callee_pc = insn.imm as u32 as usize;
+                    let num_args = insn.n as usize;
+                    assert!(num_args <= self.stack.len() - bp);
+
+                    if let Some(fun) = self.jit_lookup(callee_pc) {
+                        self.fuel = fuel;
+                        let ret_val = self.call_jit(fun, num_args);
+                        fuel = self.fuel;
+                        self.push(ret_val);
+
+                        // Compiled code may call back into this engine
+                        insns = self.predecoded_insns();
+                        continue;
+                    }
+
+                    self.push_frame(StackFrame {
+                        prev_bp: bp,
+                        ret_addr: idx,
+                        argc: num_args,
+                        callee_pc,
+                    });
+
+                    // The base pointer will point at the first local
+                    bp = self.stack.len();
+
+                    // The callee index is cached in the high 32 bits once known
+                    if insn.imm >> 32 != 0 {
+                        idx = (insn.imm >> 32) as usize - 1;
+                    } else {
+                        let call_idx = idx - 1;
+                        idx = self.predecoded_index(callee_pc);
+                        let predecoded = self.predecoded.as_mut().unwrap();
+                        predecoded.insns[call_idx].imm |= ((idx + 1) as u64) << 32;
+                        insns = self.predecoded_insns();
+                    }
+                }
+
+                Op::call_fp => {
+                    let callee_pc = self.pop().as_usize();
+                    let num_args = insn.n as usize;
+                    assert!(num_args <= self.stack.len() - bp);
+
+                    if let Some(fun) = self.jit_lookup(callee_pc) {
+                        self.fuel = fuel;
+                        let ret_val = self.call_jit(fun, num_args);
+                        fuel = self.fuel;
+                        self.push(ret_val);
+
+                        // Compiled code may call back into this engine
+                        insns = self.predecoded_insns();
+                        continue;
+                    }
+
+                    self.push_frame(StackFrame {
+                        prev_bp: bp,
+                        ret_addr: idx,
+                        argc: num_args,
+                        callee_pc,
+                    });
+
+                    // The base pointer will point at the first local
+                    bp = self.stack.len();
+                    idx = self.predecoded_index(callee_pc);
+                    insns = self.predecoded_insns();
+                }
+
+                Op::syscall => {
+                    self.syscall_ret = idx;
+                    self.syscall_bp = bp;
+                    self.syscall(insn.imm as u16);
                 }
 
-                Op::jz => {
-                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
-                    let v0 = self.pop();
+                Op::ret => {
+                    if self.stack.len() <= bp {
+                        panic!("ret with no return value on stack");
+                    }
+
+                    let ret_val = self.pop();
+
+                    assert!(self.frames.len() > base_frames);
+                    let top_frame = self.frames.pop().unwrap();
+                    self.frames_left += 1;
+
+                    // Pop all local variables and arguments
+                    // We pop arguments in the callee so we can support tail calls
+                    assert!(self.stack.len() >= bp - top_frame.argc);
+                    self.stack.truncate(bp - top_frame.argc);
+
+                    // If we're returning from the function we were running
+                    if self.frames.len() == base_frames {
+                        self.fuel = fuel;
+                        return ret_val;
+                    }
+
+                    idx = top_frame.ret_addr;
+                    bp = top_frame.prev_bp;
+
+                    self.push(ret_val);
+                }
+
+                // The extended opcode was validated by the decoder
+                Op::OP_EXT => self.exec_ext_op(unsafe { transmute::<u8, ExtOp>(insn.n) }),
+
+                _ => self.exec_op(insn.op),
+            }
+        }
+    }
+
+    /// Execute an instruction that has no operands and does not
+    /// affect control flow. This is shared by the execution engines.
+    #[inline(always)]
+    fn exec_op(&mut self, op: Op)
+    {
+        match op
+        {
+            Op::pop => {
+                self.pop();
+            }
+
+            Op::dup => {
+                let val = self.pop();
+                self.push(val);
+                self.push(val);
+            }
+
+            Op::swap => {
+                let a = self.pop();
+                let b = self.pop();
+                self.push(a);
+                self.push(b);
+            }
+
+            Op::push_0 => {
+                self.push(0);
+            }
+
+            Op::push_1 => {
+                self.push(1);
+            }
+
+            Op::push_2 => {
+                self.push(2);
+            }
+
+            Op::and_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u32() & v1.as_u32());
+            }
+
+            Op::or_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u32() | v1.as_u32());
+            }
+
+            Op::xor_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u32() ^ v1.as_u32());
+            }
+
+            Op::not_u32 => {
+                let v0 = self.pop();
+                self.push(!v0.as_u32());
+            }
+
+            Op::lshift_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_u32().wrapping_shl(v1.as_u32())
+                );
+            }
+
+            Op::rshift_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_u32().wrapping_shr(v1.as_u32())
+                );
+            }
+
+            Op::rshift_i32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_i32().wrapping_shr(v1.as_u32())
+                );
+            }
+
+            Op::add_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_u32().wrapping_add(v1.as_u32())
+                );
+            }
+
+            Op::sub_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_u32().wrapping_sub(v1.as_u32())
+                );
+            }
+
+            Op::mul_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_u32().wrapping_mul(v1.as_u32())
+                );
+            }
+
+            // Division by zero will cause a panic (this is intentional)
+            Op::div_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_u32() / v1.as_u32()
+                );
+            }
+
+            // Division by zero will cause a panic (this is intentional)
+            Op::mod_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_u32() % v1.as_u32()
+                );
+            }
+
+            // Division by zero will cause a panic (this is intentional)
+            Op::div_i32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_i32() / v1.as_i32()
+                );
+            }
+
+            // Division by zero will cause a panic (this is intentional)
+            Op::mod_i32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_i32() % v1.as_i32()
+                );
+            }
+
+            Op::eq_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u32() == v1.as_u32());
+            }
+
+            Op::ne_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u32() != v1.as_u32());
+            }
+
+            Op::lt_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u32() < v1.as_u32());
+            }
+
+            Op::le_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u32() <= v1.as_u32());
+            }
+
+            Op::gt_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u32() > v1.as_u32());
+            }
+
+            Op::ge_u32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u32() >= v1.as_u32());
+            }
+
+            Op::lt_i32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_i32() < v1.as_i32());
+            }
+
+            Op::le_i32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_i32() <= v1.as_i32());
+            }
+
+            Op::gt_i32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_i32() > v1.as_i32());
+            }
+
+            Op::ge_i32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_i32() >= v1.as_i32());
+            }
+
+            Op::and_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u64() & v1.as_u64());
+            }
+
+            Op::or_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u64() | v1.as_u64());
+            }
+
+            Op::xor_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u64() ^ v1.as_u64());
+            }
+
+            Op::not_u64 => {
+                let v0 = self.pop();
+                self.push(!v0.as_u64());
+            }
+
+            Op::lshift_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_u64().wrapping_shl(v1.as_u32())
+                );
+            }
+
+            Op::rshift_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_u64().wrapping_shr(v1.as_u32())
+                );
+            }
+
+            Op::rshift_i64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_i64().wrapping_shr(v1.as_u32())
+                );
+            }
+
+            Op::add_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_u64().wrapping_add(v1.as_u64())
+                );
+            }
+
+            Op::sub_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_u64().wrapping_sub(v1.as_u64())
+                );
+            }
+
+            Op::mul_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_u64().wrapping_mul(v1.as_u64())
+                );
+            }
+
+            // Division by zero will cause a panic (this is intentional)
+            Op::div_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_u64() / v1.as_u64()
+                );
+            }
+
+            // Division by zero will cause a panic (this is intentional)
+            Op::mod_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_u64() % v1.as_u64()
+                );
+            }
+
+            // Division by zero will cause a panic (this is intentional)
+            Op::div_i64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_i64() / v1.as_i64()
+                );
+            }
+
+            // Division by zero will cause a panic (this is intentional)
+            Op::mod_i64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(
+                    v0.as_i64() % v1.as_i64()
+                );
+            }
+
+            Op::add_u32_ovf => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                let (result, overflow) = v0.as_u32().overflowing_add(v1.as_u32());
+                self.push(result);
+                self.push(overflow);
+            }
+
+            Op::sub_u32_ovf => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                let (result, overflow) = v0.as_u32().overflowing_sub(v1.as_u32());
+                self.push(result);
+                self.push(overflow);
+            }
+
+            Op::mul_u32_ovf => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                let (result, overflow) = v0.as_u32().overflowing_mul(v1.as_u32());
+                self.push(result);
+                self.push(overflow);
+            }
+
+            Op::add_i32_ovf => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                let (result, overflow) = v0.as_i32().overflowing_add(v1.as_i32());
+                self.push(result);
+                self.push(overflow);
+            }
+
+            Op::sub_i32_ovf => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                let (result, overflow) = v0.as_i32().overflowing_sub(v1.as_i32());
+                self.push(result);
+                self.push(overflow);
+            }
+
+            Op::mul_i32_ovf => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                let (result, overflow) = v0.as_i32().overflowing_mul(v1.as_i32());
+                self.push(result);
+                self.push(overflow);
+            }
+
+            Op::add_u64_ovf => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                let (result, overflow) = v0.as_u64().overflowing_add(v1.as_u64());
+                self.push(result);
+                self.push(overflow);
+            }
+
+            Op::sub_u64_ovf => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                let (result, overflow) = v0.as_u64().overflowing_sub(v1.as_u64());
+                self.push(result);
+                self.push(overflow);
+            }
+
+            Op::mul_u64_ovf => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                let (result, overflow) = v0.as_u64().overflowing_mul(v1.as_u64());
+                self.push(result);
+                self.push(overflow);
+            }
+
+            Op::add_i64_ovf => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                let (result, overflow) = v0.as_i64().overflowing_add(v1.as_i64());
+                self.push(result);
+                self.push(overflow);
+            }
+
+            Op::sub_i64_ovf => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                let (result, overflow) = v0.as_i64().overflowing_sub(v1.as_i64());
+                self.push(result);
+                self.push(overflow);
+            }
+
+            Op::mul_i64_ovf => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                let (result, overflow) = v0.as_i64().overflowing_mul(v1.as_i64());
+                self.push(result);
+                self.push(overflow);
+            }
+
+            Op::mul_u64_wide => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                let result = (v0.as_u64() as u128) * (v1.as_u64() as u128);
+                self.push(result as u64);
+                self.push((result >> 64) as u64);
+            }
+
+            Op::mul_i64_wide => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                let result = (v0.as_i64() as i128) * (v1.as_i64() as i128);
+                self.push(result as u64);
+                self.push((result >> 64) as u64);
+            }
+
+            Op::eq_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u64() == v1.as_u64());
+            }
+
+            Op::ne_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u64() != v1.as_u64());
+            }
+
+            Op::lt_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u64() < v1.as_u64());
+            }
+
+            Op::le_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u64() <= v1.as_u64());
+            }
+
+            Op::gt_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u64() > v1.as_u64());
+            }
+
+            Op::ge_u64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_u64() >= v1.as_u64());
+            }
+
+            Op::lt_i64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_i64() < v1.as_i64());
+            }
+
+            Op::le_i64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_i64() <= v1.as_i64());
+            }
+
+            Op::gt_i64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_i64() > v1.as_i64());
+            }
+
+            Op::ge_i64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_i64() >= v1.as_i64());
+            }
+
+            Op::sx_i8_i32 => {
+                let v = self.pop();
+                self.push(v.as_i8() as i32);
+            }
+
+            Op::sx_i8_i64 => {
+                let v = self.pop();
+                self.push(v.as_i8() as i64);
+            }
+
+            Op::sx_i16_i32 => {
+                let v = self.pop();
+                self.push(v.as_i16() as i32);
+            }
+
+            Op::sx_i16_i64 => {
+                let v = self.pop();
+                self.push(v.as_i16() as i64);
+            }
+
+            Op::sx_i32_i64 => {
+                let v = self.pop();
+                self.push(v.as_i32() as i64);
+            }
+
+            Op::trunc_u8 => {
+                let v = self.pop();
+                self.push(v.as_u8());
+            }
+
+            Op::trunc_u16 => {
+                let v = self.pop();
+                self.push(v.as_u16());
+            }
+
+            Op::trunc_u32 => {
+                let v = self.pop();
+                self.push(v.as_u32());
+            }
+
+            Op::add_f32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f32() + v1.as_f32());
+            }
+
+            Op::sub_f32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f32() - v1.as_f32());
+            }
+
+            Op::mul_f32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f32() * v1.as_f32());
+            }
+
+            // Should return NaN for invalid inputs
+            Op::div_f32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f32() / v1.as_f32());
+            }
+
+            Op::sin_f32 => {
+                let v0 = self.pop().as_f32();
+                self.push(v0.sin());
+            }
+
+            Op::cos_f32 => {
+                let v0 = self.pop().as_f32();
+                self.push(v0.cos());
+            }
+
+            // Should return NaN for invalid inputs
+            Op::tan_f32 => {
+                let v0 = self.pop().as_f32();
+                self.push(v0.tan());
+            }
+
+            // Should return NaN for invalid inputs
+            Op::asin_f32 => {
+                let v0 = self.pop().as_f32();
+                self.push(v0.asin());
+            }
+
+            // Should return NaN for invalid inputs
+            Op::acos_f32 => {
+                let v0 = self.pop().as_f32();
+                self.push(v0.acos());
+            }
+
+            Op::atan_f32 => {
+                let v0 = self.pop().as_f32();
+                self.push(v0.atan());
+            }
+
+            // Should return NaN for invalid inputs
+            Op::pow_f32 => {
+                let v1 = self.pop().as_f32();
+                let v0 = self.pop().as_f32();
+                self.push(v0.powf(v1));
+            }
+
+            // Should return NaN for invalid inputs
+            Op::sqrt_f32 => {
+                let v0 = self.pop().as_f32();
+                self.push(v0.sqrt());
+            }
+
+            Op::eq_f32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f32() == v1.as_f32());
+            }
+
+            Op::ne_f32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f32() != v1.as_f32());
+            }
+
+            Op::lt_f32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f32() < v1.as_f32());
+            }
+
+            Op::le_f32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f32() <= v1.as_f32());
+            }
+
+            Op::gt_f32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f32() > v1.as_f32());
+            }
+
+            Op::ge_f32 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f32() >= v1.as_f32());
+            }
+
+            // Follows Rust semantics:
+            // - Round ties to even
+            // - Never panics
+            Op::i32_to_f32 => {
+                let v = self.pop();
+                self.push(v.as_i32() as f32);
+            }
+
+            // Follows Rust semantics:
+            // - Round ties to even
+            // - Never panics
+            Op::i64_to_f32 => {
+                let v = self.pop();
+                self.push(v.as_i64() as f32);
+            }
+
+            // Follows Rust semantics:
+            // - Rounds towards zero (truncates)
+            // - Saturates to min/max int values
+            // - NaN converts to zero
+            // - Never panics
+            Op::f32_to_i32 => {
+                let v = self.pop();
+                self.push(v.as_f32() as i32);
+            }
+
+            Op::floor_f32 => {
+                let v0 = self.pop().as_f32();
+                self.push(v0.floor());
+            }
+
+            Op::ceil_f32 => {
+                let v0 = self.pop().as_f32();
+                self.push(v0.ceil());
+            }
+
+            Op::round_f32 => {
+                let v0 = self.pop().as_f32();
+                self.push(v0.round());
+            }
+
+            Op::trunc_f32 => {
+                let v0 = self.pop().as_f32();
+                self.push(v0.trunc());
+            }
+
+            Op::abs_f32 => {
+                let v0 = self.pop().as_f32();
+                self.push(v0.abs());
+            }
+
+            Op::min_f32 => {
+                let v1 = self.pop().as_f32();
+                let v0 = self.pop().as_f32();
+                self.push(v0.min(v1));
+            }
+
+            Op::max_f32 => {
+                let v1 = self.pop().as_f32();
+                let v0 = self.pop().as_f32();
+                self.push(v0.max(v1));
+            }
+
+            Op::exp_f32 => {
+                let v0 = self.pop().as_f32();
+                self.push(v0.exp());
+            }
+
+            // Should return NaN for invalid inputs
+            Op::log_f32 => {
+                let v0 = self.pop().as_f32();
+                self.push(v0.ln());
+            }
+
+            Op::atan2_f32 => {
+                let x = self.pop().as_f32();
+                let y = self.pop().as_f32();
+                self.push(y.atan2(x));
+            }
+
+            // Should return NaN for invalid inputs
+            Op::mod_f32 => {
+                let v1 = self.pop().as_f32();
+                let v0 = self.pop().as_f32();
+                self.push(v0 % v1);
+            }
+
+            Op::f32_to_i64 => {
+                let v = self.pop();
+                self.push(v.as_f32() as i64);
+            }
+
+            // Round ties to even
+            Op::u32_to_f32 => {
+                let v = self.pop();
+                self.push(v.as_u32() as f32);
+            }
+
+            // Round ties to even
+            Op::u64_to_f32 => {
+                let v = self.pop();
+                self.push(v.as_u64() as f32);
+            }
+
+            // Negative values saturate to zero
+            Op::f32_to_u32 => {
+                let v = self.pop();
+                self.push(v.as_f32() as u32);
+            }
+
+            // Negative values saturate to zero
+            Op::f32_to_u64 => {
+                let v = self.pop();
+                self.push(v.as_f32() as u64);
+            }
+
+            Op::add_f64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f64() + v1.as_f64());
+            }
+
+            Op::sub_f64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f64() - v1.as_f64());
+            }
+
+            Op::mul_f64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f64() * v1.as_f64());
+            }
+
+            // Should return NaN for invalid inputs
+            Op::div_f64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f64() / v1.as_f64());
+            }
+
+            Op::eq_f64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f64() == v1.as_f64());
+            }
+
+            Op::ne_f64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f64() != v1.as_f64());
+            }
+
+            Op::lt_f64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f64() < v1.as_f64());
+            }
+
+            Op::le_f64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f64() <= v1.as_f64());
+            }
+
+            Op::gt_f64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f64() > v1.as_f64());
+            }
+
+            Op::ge_f64 => {
+                let v1 = self.pop();
+                let v0 = self.pop();
+                self.push(v0.as_f64() >= v1.as_f64());
+            }
+
+            // Exact, every i32 value is representable as an f64
+            Op::i32_to_f64 => {
+                let v = self.pop();
+                self.push(v.as_i32() as f64);
+            }
+
+            // Follows Rust semantics:
+            // - Round ties to even
+            // - Never panics
+            Op::i64_to_f64 => {
+                let v = self.pop();
+                self.push(v.as_i64() as f64);
+            }
+
+            // Follows Rust semantics:
+            // - Rounds towards zero (truncates)
+            // - Saturates to min/max int values
+            // - NaN converts to zero
+            // - Never panics
+            Op::f64_to_i32 => {
+                let v = self.pop();
+                self.push(v.as_f64() as i32);
+            }
+
+            // Follows Rust semantics:
+            // - Rounds towards zero (truncates)
+            // - Saturates to min/max int values
+            // - NaN converts to zero
+            // - Never panics
+            Op::f64_to_i64 => {
+                let v = self.pop();
+                self.push(v.as_f64() as i64);
+            }
+
+            // Exact, every f32 value is representable as an f64
+            Op::f32_to_f64 => {
+                let v = self.pop();
+                self.push(v.as_f32() as f64);
+            }
+
+            // Follows Rust semantics:
+            // - Round ties to even
+            // - Out of range values become infinities
+            // - Never panics
+            Op::f64_to_f32 => {
+                let v = self.pop();
+                self.push(v.as_f64() as f32);
+            }
+
+            Op::load_u8 => {
+                let addr = self.pop().as_usize();
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                let val: u8 = unsafe { *heap_ptr };
+                self.push(val);
+            }
+
+            Op::load_u16 => {
+                let addr = self.pop().as_usize();
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                let val: u16 = unsafe { *heap_ptr };
+                self.push(val);
+            }
+
+            Op::load_u32 => {
+                let addr = self.pop().as_usize();
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                let val: u32 = unsafe { *heap_ptr };
+                self.push(val);
+            }
+
+            Op::load_u64 => {
+                let addr = self.pop().as_usize();
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                let val: u64 = unsafe { *heap_ptr };
+                self.push(val);
+            }
+
+            Op::store_u8 => {
+                let val = self.pop().as_u8();
+                let addr = self.pop().as_usize();
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                unsafe { *heap_ptr = val; }
+            }
+
+            Op::store_u16 => {
+                let val = self.pop().as_u16();
+                let addr = self.pop().as_usize();
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                unsafe { *heap_ptr = val; }
+            }
+
+            Op::store_u32 => {
+                let val = self.pop().as_u32();
+                let addr = self.pop().as_usize();
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                unsafe { *heap_ptr = val; }
+            }
+
+            Op::store_u64 => {
+                let val = self.pop().as_u64();
+                let addr = self.pop().as_usize();
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                unsafe { *heap_ptr = val; }
+            }
 
-                    if v0.as_i64() == 0 {
-                        pc = ((pc as isize) + offset) as usize;
-                    }
-                }
+            Op::atomic_load_u32 => {
+                let addr = self.pop().as_usize();
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                let atomic = unsafe { AtomicU32::from_ptr(heap_ptr) };
+                let val = atomic.load(Ordering::Acquire);
+                self.push(Value::from(val));
+            }
 
-                Op::jnz => {
-                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
-                    let v0 = self.pop();
+            Op::atomic_load_u64 => {
+                let addr = self.pop().as_usize();
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                let atomic = unsafe {AtomicU64::from_ptr(heap_ptr) };
+                let val = atomic.load(Ordering::Acquire);
+                self.push(Value::from(val));
+            }
 
-                    if v0.as_i64() != 0 {
-                        pc = ((pc as isize) + offset) as usize;
-                    }
+            Op::atomic_store_u32 => {
+                let val = self.pop().as_u32();
+                let addr = self.pop().as_usize();
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                let atomic = unsafe { AtomicU32::from_ptr(heap_ptr) };
+                atomic.store(val, Ordering::Release);
+            }
+
+            Op::atomic_store_u64 => {
+                let val = self.pop().as_u64();
+                let addr = self.pop().as_usize();
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                let atomic = unsafe {AtomicU64::from_ptr(heap_ptr) };
+                atomic.store(val, Ordering::Release);
+            }
+
+            // Compare-and-swap
+            // Uses acquire-release semantics on success, acquire on failure.
+            // This instruction can be used to implement spin locks.
+            // atomic_cas (addr) (cmp-val) (store-val)
+            // Pushes the value found at the memory address
+            Op::atomic_cas_u32 => {
+                let store_val = self.pop().as_u32();
+                let cmp_val = self.pop().as_u32();
+                let addr = self.pop().as_usize();
+
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                let atomic = unsafe { AtomicU32::from_ptr(heap_ptr) };
+
+                let result = atomic.compare_exchange(
+                    cmp_val,
+                    store_val,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                );
+
+                match result {
+                    Ok(val) => self.push(Value::from(val)),
+                    Err(actual_val) => self.push(Value::from(actual_val)),
                 }
+            }
 
-                // call <num_args:u8> <offset:i32> (arg0, arg1, ..., argN)
-                Op::call => {
-                    // Offset of the function to call
-                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
+            Op::atomic_cas_u64 => {
+                let store_val = self.pop().as_u64();
+                let cmp_val = self.pop().as_u64();
+                let addr = self.pop().as_usize();
 
-                    // Argument count
-                    let num_args = self.code.read_pc::<u8>(&mut pc) as usize;
-                    assert!(num_args <= self.stack.len() - bp);
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                let atomic = unsafe {AtomicU64::from_ptr(heap_ptr) };
 
-                    self.frames.push(StackFrame {
-                        prev_bp: bp,
-                        ret_addr: pc,
-                        argc: num_args,
-                    });
+                let result = atomic.compare_exchange(
+                    cmp_val,
+                    store_val,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                );
 
-                    // The base pointer will point at the first local
-                    bp = self.stack.len();
-                    pc = ((pc as isize) + offset) as usize;
+                match result {
+                    Ok(val) => self.push(Value::from(val)),
+                    Err(actual_val) => self.push(Value::from(actual_val)),
                 }
+            }
 
-                // call <num_args:u8> (arg0, arg1, ..., argN, f_ptr)
-                Op::call_fp => {
-                    // Absolute address of the function to call
-                    let fp = self.pop();
+            // atomic_fetch_add (addr) (value)
+            // Pushes the value found at the memory address before the update
+            Op::atomic_fetch_add_u32 |
+            Op::atomic_fetch_sub_u32 |
+            Op::atomic_fetch_and_u32 |
+            Op::atomic_fetch_or_u32 |
+            Op::atomic_fetch_xor_u32 |
+            Op::atomic_xchg_u32 => {
+                let val = self.pop().as_u32();
+                let addr = self.pop().as_usize();
+
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                let atomic = unsafe { AtomicU32::from_ptr(heap_ptr) };
+
+                let old_val = match op {
+                    Op::atomic_fetch_add_u32 => atomic.fetch_add(val, Ordering::AcqRel),
+                    Op::atomic_fetch_sub_u32 => atomic.fetch_sub(val, Ordering::AcqRel),
+                    Op::atomic_fetch_and_u32 => atomic.fetch_and(val, Ordering::AcqRel),
+                    Op::atomic_fetch_or_u32 => atomic.fetch_or(val, Ordering::AcqRel),
+                    Op::atomic_fetch_xor_u32 => atomic.fetch_xor(val, Ordering::AcqRel),
+                    Op::atomic_xchg_u32 => atomic.swap(val, Ordering::AcqRel),
+                    _ => unreachable!()
+                };
+
+                self.push(Value::from(old_val));
+            }
 
-                    // Argument count
-                    let num_args = self.code.read_pc::<u8>(&mut pc) as usize;
-                    assert!(num_args <= self.stack.len() - bp);
+            Op::atomic_fetch_add_u64 |
+            Op::atomic_fetch_sub_u64 |
+            Op::atomic_fetch_and_u64 |
+            Op::atomic_fetch_or_u64 |
+            Op::atomic_fetch_xor_u64 |
+            Op::atomic_xchg_u64 => {
+                let val = self.pop().as_u64();
+                let addr = self.pop().as_usize();
+
+                let heap_ptr = self.get_heap_ptr_mut(addr, 1);
+                let atomic = unsafe { AtomicU64::from_ptr(heap_ptr) };
+
+                let old_val = match op {
+                    Op::atomic_fetch_add_u64 => atomic.fetch_add(val, Ordering::AcqRel),
+                    Op::atomic_fetch_sub_u64 => atomic.fetch_sub(val, Ordering::AcqRel),
+                    Op::atomic_fetch_and_u64 => atomic.fetch_and(val, Ordering::AcqRel),
+                    Op::atomic_fetch_or_u64 => atomic.fetch_or(val, Ordering::AcqRel),
+                    Op::atomic_fetch_xor_u64 => atomic.fetch_xor(val, Ordering::AcqRel),
+                    Op::atomic_xchg_u64 => atomic.swap(val, Ordering::AcqRel),
+                    _ => unreachable!()
+                };
+
+                self.push(Value::from(old_val));
+            }
 
-                    self.frames.push(StackFrame {
-                        prev_bp: bp,
-                        ret_addr: pc,
-                        argc: num_args,
-                    });
+            Op::fence => {
+                fence(Ordering::SeqCst);
+            }
 
-                    // The base pointer will point at the first local
-                    bp = self.stack.len();
-                    pc = fp.as_usize();
-                }
+            _ => panic!("unknown opcode {:?}", op),
+        }
+    }
 
-                Op::syscall => {
-                    let syscall_idx = self.code.read_pc::<u16>(&mut pc);
-                    let syscall_fn = get_syscall(syscall_idx);
-
-                    match syscall_fn
-                    {
-                        HostFn::Fn0_0(fun) => {
-                            fun(self)
-                        }
-
-                        HostFn::Fn0_1(fun) => {
-                            let v = fun(self);
-                            self.push(v);
-                        }
-
-                        HostFn::Fn1_0(fun) => {
-                            let a0 = self.pop();
-                            fun(self, a0)
-                        }
-
-                        HostFn::Fn1_1(fun) => {
-                            let a0 = self.pop();
-                            let v = fun(self, a0);
-                            self.push(v);
-                        }
-
-                        HostFn::Fn2_0(fun) => {
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            fun(self, a0, a1)
-                        }
-
-                        HostFn::Fn2_1(fun) => {
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            let v = fun(self, a0, a1);
-                            self.push(v);
-                        }
-
-                        HostFn::Fn3_0(fun) => {
-                            let a2 = self.pop();
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            fun(self, a0, a1, a2)
-                        }
-
-                        HostFn::Fn3_1(fun) => {
-                            let a2 = self.pop();
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            let v = fun(self, a0, a1, a2);
-                            self.push(v);
-                        }
-
-                        HostFn::Fn4_0(fun) => {
-                            let a3 = self.pop();
-                            let a2 = self.pop();
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            fun(self, a0, a1, a2, a3)
-                        }
-
-                        HostFn::Fn4_1(fun) => {
-                            let a3 = self.pop();
-                            let a2 = self.pop();
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            let v = fun(self, a0, a1, a2, a3);
-                            self.push(v);
-                        }
-                    }
-                }
+    /// Execute an extended instruction. This is shared by the execution engines.
+    fn exec_ext_op(&mut self, op: ExtOp)
+    {
+        match op
+        {
+            ExtOp::panic => panic!("execution error, encountered extended panic opcode"),
 
-                Op::ret => {
-                    if self.stack.len() <= bp {
-                        panic!("ret with no return value on stack");
-                    }
+            ExtOp::nop => {}
 
-                    let ret_val = self.pop();
+            ExtOp::sin_f64 => {
+                let v0 = self.pop().as_f64();
+                self.push(v0.sin());
+            }
 
-                    // If this is a top-level return
-                    if self.frames.len() == 1 {
-                        self.stack.clear();
-                        self.frames.clear();
-                        return ret_val;
-                    }
+            ExtOp::cos_f64 => {
+                let v0 = self.pop().as_f64();
+                self.push(v0.cos());
+            }
 
-                    assert!(self.frames.len() > 0);
-                    let top_frame = self.frames.pop().unwrap();
+            // Should return NaN for invalid inputs
+            ExtOp::tan_f64 => {
+                let v0 = self.pop().as_f64();
+                self.push(v0.tan());
+            }
 
-                    // Pop all local variables and arguments
-                    // We pop arguments in the callee so we can support tail calls
-                    assert!(self.stack.len() >= bp - top_frame.argc);
-                    self.stack.truncate(bp - top_frame.argc);
+            // Should return NaN for invalid inputs
+            ExtOp::asin_f64 => {
+                let v0 = self.pop().as_f64();
+                self.push(v0.asin());
+            }
 
-                    pc = top_frame.ret_addr;
-                    bp = top_frame.prev_bp;
+            // Should return NaN for invalid inputs
+            ExtOp::acos_f64 => {
+                let v0 = self.pop().as_f64();
+                self.push(v0.acos());
+            }
 
-                    self.push(ret_val);
-                }
+            ExtOp::atan_f64 => {
+                let v0 = self.pop().as_f64();
+                self.push(v0.atan());
+            }
 
-                _ => panic!("unknown opcode {:?}", op),
+            // Should return NaN for invalid inputs
+            ExtOp::pow_f64 => {
+                let v1 = self.pop().as_f64();
+                let v0 = self.pop().as_f64();
+                self.push(v0.powf(v1));
+            }
+
+            // Should return NaN for invalid inputs
+            ExtOp::sqrt_f64 => {
+                let v0 = self.pop().as_f64();
+                self.push(v0.sqrt());
             }
         }
     }
@@ -1703,14 +3400,62 @@ pub struct VM
     code: MemBlock,
 
     // Next thread id to assign
-    next_tid: u64,
+    pub next_tid: u64,
 
     // Map from actor ids to thread join handles
     threads: HashMap<u64, thread::JoinHandle<Value>>,
 
+    // State of the networking subsystem
+    pub net_state: NetState,
+
+    // State of the filesystem subsystem
+    pub fs_state: FsState,
+
+    // Futex wait queues, shared so that threads
+    // can block without holding the VM lock
+    pub futex_state: Arc<FutexState>,
+
+    // Message-passing channels between threads
+    pub chan_state: ChanState,
+
+    // Callbacks dispatched by the event loop
+    pub event_state: EventState,
+
+    // Number of calls after which functions get JIT-compiled,
+    // None if the JIT is disabled
+    pub jit_threshold: Option<u64>,
+
+    // Run code with the pre-decoded interpreter
+    pub predecode: bool,
+
+    // Maximum number of instructions executed by each
+    // call into the program, None if there is no limit
+    pub fuel: Option<u64>,
+
+    // Make the fuel a total budget shared by all threads and by all
+    // calls into the program, such as event loop callbacks. Threads
+    // take it in chunks, and what isn't taken yet is kept in fuel.
+    pub total_fuel: bool,
+
+    // Maximum heap size in bytes, None if there is no limit
+    pub max_heap: Option<usize>,
+
+    // Print the peak heap size when the program exits
+    pub report_heap: bool,
+
+    // Value stack and call depth limits for new threads
+    pub max_stack: usize,
+    pub max_frames: usize,
+
+    // Read-only part of the heap
+    pub rodata: Range<usize>,
+
+    // The first heap page is inaccessible
+    pub null_guard: bool,
+
     // Reference to self
     // Needed to instantiate actors
-    vm: Option<Arc<Mutex<VM>>>,
+    pub(crate) vm: Option<Arc<Mutex<VM>>>,
 }
 
 // Needed to send Arc<Mutex<VM>> to thread
@@ -1724,8 +3469,8 @@ impl VM
         let mut heap = MemBlock::new();
 
         // Resize the code and memory blocks to accomodate the program
-        code.grow(prog.code.len());
-        heap.grow(prog.data.len());
+        code.grow(prog.code.len(), usize::MAX).unwrap();
+        heap.grow(prog.data.len(), usize::MAX).unwrap();
 
         // Copy the program code
         let mut code_view = code.new_view();
@@ -1737,11 +3482,40 @@ impl VM
         let mut heap_slice: &mut [u8] = heap_view.get_slice_mut(0, prog.data.len());
         heap_slice.clone_from_slice(prog.data.as_slice());
 
+        // Writes to the code or to read-only data now fault
+        code.protect(0, code.size_bytes(), libc::PROT_READ);
+        if !prog.rodata.is_empty() {
+            heap.protect(prog.rodata.start, prog.rodata.end, libc::PROT_READ);
+        }
+
+        // Any access to the null guard page faults
+        let guard_size = if prog.null_guard { PAGE_SIZE } else { 0 };
+        heap.protect(0, guard_size, libc::PROT_NONE);
+
+        if !prog.rodata.is_empty() || prog.null_guard {
+            heap.report_faults(guard_size, prog.rodata.clone());
+        }
+
         let vm = Self {
             code,
             heap,
             next_tid: 0,
             threads: HashMap::default(),
+            net_state: NetState::default(),
+            fs_state: FsState::default(),
+            futex_state: Arc::default(),
+            chan_state: ChanState::default(),
+            event_state: EventState::default(),
+            jit_threshold: None,
+            predecode: false,
+            fuel: None,
+            total_fuel: false,
+            max_heap: None,
+            report_heap: false,
+            max_stack: DEFAULT_MAX_STACK,
+            max_frames: DEFAULT_MAX_FRAMES,
+            rodata: prog.rodata,
+            null_guard: prog.null_guard,
             vm: None,
         };
 
@@ -1755,9 +3529,49 @@ impl VM
     }
 
     /// Grow the heap to a new size in bytes
-    pub fn grow_heap(&mut self, num_bytes: usize) -> usize
+    /// Returns None if this would exceed the heap size limit
+    pub fn grow_heap(&mut self, num_bytes: usize) -> Option<usize>
+    {
+        self.heap.grow(num_bytes, self.max_heap.unwrap_or(usize::MAX))
+    }
+
+    /// Get the current heap size in bytes
+    pub fn heap_size(&self) -> usize
+    {
+        self.heap.size_bytes()
+    }
+
+    /// Copy program arguments or environment variables to the end of the heap, as a
+    /// null-terminated array of pointers to C strings followed by the strings themselves.
+    /// Returns the address of the array, or None if the heap can't be grown.
+    pub fn copy_args(&mut self, args: &[String]) -> Option<usize>
+    {
+        let argv = self.heap_size();
+        let strs_start = argv + (args.len() + 1) * size_of::<u64>();
+        let strs_size: usize = args.iter().map(|arg| arg.len() + 1).sum();
+        self.grow_heap(strs_start + strs_size)?;
+
+        let mut heap = self.heap.new_view();
+        let mut str_addr = strs_start;
+        for (idx, arg) in args.iter().enumerate() {
+            heap.get_slice_mut::<u64>(argv, args.len() + 1)[idx] = str_addr as u64;
+
+            let str_bytes: &mut [u8] = heap.get_slice_mut(str_addr, arg.len() + 1);
+            str_bytes[..arg.len()].copy_from_slice(arg.as_bytes());
+            str_bytes[arg.len()] = 0;
+            str_addr += arg.len() + 1;
+        }
+
+        // The array ends with a null pointer, which is
+        // already there since the heap was just grown
+        Some(argv)
+    }
+
+    /// Print the peak heap size, which is the current
+    /// size since the heap never shrinks
+    pub fn print_heap_stats(&self)
     {
-        self.heap.grow(num_bytes)
+        println!("peak heap size: {} bytes", thousands_sep(self.heap_size() as u64));
     }
 
     // Create a new thread object without beginning execution
@@ -1771,12 +3585,25 @@ impl VM
         // Create thread-local code and heap memory block views
         let code = vm_ref.code.new_view();
         let heap = vm_ref.heap.new_view();
+        let jit = vm_ref.jit_threshold.map(Jit::new);
+        let predecoded = vm_ref.predecode.then(Predecoded::new);
+        let max_stack = vm_ref.max_stack;
+        let max_frames = vm_ref.max_frames;
 
         drop(vm_ref);
 
         let vm_mutex = vm.clone();
 
-        Thread::new(tid, vm_mutex, code, heap)
+        let mut thread = Thread::new(tid, vm_mutex, code, heap, jit, predecoded);
+        thread.max_stack = max_stack;
+        thread.max_frames = max_frames;
+        thread
+    }
+
+    /// Get the number of threads that haven't been joined yet
+    pub fn num_threads(&self) -> usize
+    {
+        self.threads.len()
     }
 
     // Spawn a new thread and begin executing the specified function
@@ -1785,10 +3612,13 @@ impl VM
         let mut thread = VM::new_thread(vm);
         let tid = thread.id;
 
-        // Spawn a new thread
-        let handle = thread::spawn(move || {
-            thread.call(callee_pc, args.as_slice())
-        });
+        // Spawn a new thread, with as much native stack
+        // as the main thread usually gets for nested calls
+        let handle = thread::Builder::new().stack_size(THREAD_STACK_SIZE).spawn(move || {
+            let ret_val = thread.call(callee_pc, args.as_slice());
+            thread.give_back_fuel();
+            ret_val
+        }).unwrap();
 
         // Store the join handle on the VM
         let mut vm_ref = vm.lock().unwrap();
@@ -1806,48 +3636,76 @@ impl VM
         let mut handle = vm.threads.remove(&tid).unwrap();
         drop(vm);
 
-        handle.join().expect(&format!("could join thread with id {}", tid))
+        match handle.join() {
+            Ok(val) => val,
+
+            // The joining thread stops too if the thread ran out of fuel
+            Err(payload) if payload.is::<OutOfFuel>() => panic::resume_unwind(payload),
+
+            Err(_) => panic!("could join thread with id {}", tid),
+        }
     }
 
     // Call a function in the main actor
     pub fn call(vm: &mut Arc<Mutex<VM>>, callee_pc: u64, args: &[Value]) -> Value
     {
-        // Assign a thread id
-        let mut vm_ref = vm.lock().unwrap();
-        let tid = vm_ref.next_tid;
-        assert!(tid == 0);
-        vm_ref.next_tid += 1;
-
-        // Create thread-local code and heap memory block views
-        let code = vm_ref.code.new_view();
-        let heap = vm_ref.heap.new_view();
-
-        drop(vm_ref);
-
-        let vm_mutex = vm.clone();
-        let mut thread = Thread::new(tid, vm_mutex, code, heap);
+        let mut thread = VM::new_thread(vm);
+        assert!(thread.id == 0);
         thread.call(callee_pc, args)
     }
 }
 
 #[cfg(test)]
-mod tests
+pub(crate) mod tests
 {
     use super::*;
     use crate::asm::*;
 
-    fn eval_src(src: &str) -> Value
+    fn run_src(src: &str, jit_threshold: Option<u64>, predecode: bool) -> Value
     {
-        dbg!(src);
         let asm = Assembler::new();
         let prog = asm.parse_str(src).unwrap();
         let mut vm = VM::new(prog);
+        vm.lock().unwrap().jit_threshold = jit_threshold;
+        vm.lock().unwrap().predecode = predecode;
         let result = VM::call(&mut vm, 0, &[]);
 
+        // Break the reference cycle so the VM's memory gets unmapped
+        vm.lock().unwrap().vm = None;
+
+        result
+    }
+
+    /// Execution engines to test, as (jit_threshold, predecode) pairs
+    /// The JIT compiler only supports x86-64 Linux
+    const ENGINES: &[(Option<u64>, bool)] = if cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+        &[(None, false), (None, true), (Some(1), false)]
+    } else {
+        &[(None, false), (None, true)]
+    };
+
+    fn eval_src(src: &str) -> Value
+    {
+        dbg!(src);
+        let result = run_src(src, None, false);
+
+        // Run again with the pre-decoded interpreter
+        let predecoded_result = run_src(src, None, true);
+        assert_eq!(result, predecoded_result);
+
+        // Run again with every function JIT-compiled on its first call
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        {
+            let jit_result = run_src(src, Some(1), false);
+            assert_eq!(result, jit_result);
+        }
+
         result
     }
 
-    fn eval_i64(src: &str, expected: i64)
+    /// Run a program with each execution engine and check its result.
+    /// This is shared with the tests of other modules.
+    pub(crate) fn eval_i64(src: &str, expected: i64)
     {
         let result = eval_src(src);
         assert_eq!(result, expected.into());
@@ -1857,11 +3715,288 @@ mod tests
     fn test_opcodes()
     {
         // We can have at most 254 short single-byte opcodes
-        assert!(Op::ret as usize <= 254);
+        assert!(Op::LAST as usize <= 254);
 
-        // Keep track of how many short opcodes we have so far
+        // Commonly used opcodes such as loads, stores, jumps and calls
+        // come before ret, and must stay in the [0, 127] range
         dbg!(Op::ret as usize);
-        assert!(Op::ret as usize <= 117);
+        assert!(Op::ret as usize <= 127);
+
+        // The decoders transmute any byte up to LAST into an opcode,
+        // so LAST must be the last variant, with no gaps before it
+        fn variants(enum_name: &str) -> Vec<String> {
+            let src = include_str!("vm.rs");
+            let start = src.find(&format!("pub enum {}\n{{\n", enum_name)).unwrap();
+            let body = &src[start..];
+            let body = &body[body.find('{').unwrap() + 1..body.find("\n}").unwrap()];
+
+            body.lines()
+                .map(|line| line.split("//").next().unwrap().trim())
+                .filter(|line| !line.is_empty())
+                .map(|line| line.split([',', '=']).next().unwrap().trim().to_string())
+                .collect()
+        }
+
+        // OP_EXT is fixed at 255, after the one-byte opcodes
+        let mut ops = variants("Op");
+        assert_eq!(ops.pop().unwrap(), "OP_EXT");
+        assert_eq!(ops.len(), Op::LAST as usize + 1);
+        assert_eq!(ops.last().unwrap(), &format!("{:?}", Op::LAST));
+
+        let ext_ops = variants("ExtOp");
+        assert_eq!(ext_ops.len(), ExtOp::LAST as usize + 1);
+        assert_eq!(ext_ops.last().unwrap(), &format!("{:?}", ExtOp::LAST));
+    }
+
+    #[test]
+    fn test_ext_ops()
+    {
+        eval_i64("push 5; nop_ext; nop_ext; ret;", 5);
+
+        // The extended opcode is encoded after the prefix
+        let prog = Assembler::new().parse_str("nop_ext; panic_ext;").unwrap();
+        assert_eq!(prog.code.as_slice(), &[Op::OP_EXT as u8, ExtOp::nop as u8, Op::OP_EXT as u8, ExtOp::panic as u8]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_ext_panic()
+    {
+        eval_src("panic_ext; push 0; ret;");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_ext_invalid()
+    {
+        eval_src(".code; .u8 255; .u8 255; push 0; ret;");
+    }
+
+    #[test]
+    fn test_fuel()
+    {
+        for &(jit_threshold, predecode) in ENGINES {
+            let call_with_fuel = |src: &str, fuel: u64| {
+                let prog = Assembler::new().parse_str(src).unwrap();
+                let vm = VM::new(prog);
+                vm.lock().unwrap().jit_threshold = jit_threshold;
+                vm.lock().unwrap().predecode = predecode;
+                vm.lock().unwrap().fuel = Some(fuel);
+
+                // Each call gets the whole budget, even after running out
+                let mut thread = VM::new_thread(&vm);
+                let result = thread.try_call(0, &[]);
+                assert_eq!(thread.try_call(0, &[]), result);
+
+                vm.lock().unwrap().vm = None;
+                result
+            };
+
+            assert_eq!(call_with_fuel("push 1; push 2; add_u64; ret;", 4), Ok(Value::from(3)));
+            assert_eq!(call_with_fuel("push 1; push 2; add_u64; ret;", 3), Err(OutOfFuel));
+            assert_eq!(call_with_fuel("L: jmp L;", 1000), Err(OutOfFuel));
+            assert_eq!(call_with_fuel("L: push 0; jz L; push 1; ret;", 1000), Err(OutOfFuel));
+        }
+    }
+
+    #[test]
+    fn test_total_fuel()
+    {
+        for &(jit_threshold, predecode) in ENGINES {
+            let prog = Assembler::new().parse_str("push 1; push 2; add_u64; ret;").unwrap();
+            let vm = VM::new(prog);
+            vm.lock().unwrap().jit_threshold = jit_threshold;
+            vm.lock().unwrap().predecode = predecode;
+            vm.lock().unwrap().fuel = Some(10);
+            vm.lock().unwrap().total_fuel = true;
+
+            // The budget left over from each call carries over to the next
+            let mut thread = VM::new_thread(&vm);
+            assert_eq!(thread.try_call(0, &[]), Ok(Value::from(3)));
+            assert_eq!(thread.try_call(0, &[]), Ok(Value::from(3)));
+            assert_eq!(thread.try_call(0, &[]), Err(OutOfFuel));
+            assert_eq!(thread.try_call(0, &[]), Err(OutOfFuel));
+
+            // Without a budget, nothing is taken from the VM
+            vm.lock().unwrap().fuel = None;
+            let mut thread = VM::new_thread(&vm);
+            assert_eq!(thread.try_call(0, &[]), Ok(Value::from(3)));
+
+            vm.lock().unwrap().vm = None;
+        }
+    }
+
+    #[test]
+    fn test_total_fuel_threads()
+    {
+        // Four threads running about 400k instructions each
+        let src = concat!(
+            "push_p32 WORKER; push 0; syscall thread_spawn;",
+            "push_p32 WORKER; push 0; syscall thread_spawn;",
+            "push_p32 WORKER; push 0; syscall thread_spawn;",
+            "push_p32 WORKER; push 0; syscall thread_spawn;",
+            "syscall thread_join; pop; syscall thread_join; pop;",
+            "syscall thread_join; pop; syscall thread_join; pop;",
+            "push 1; ret;",
+            "WORKER: push 100000; LOOP: push 1; sub_u64; dup; jnz LOOP; ret;",
+        );
+
+        for &(jit_threshold, predecode) in ENGINES {
+            let run = |fuel: u64| {
+                let prog = Assembler::new().parse_str(src).unwrap();
+                let vm = VM::new(prog);
+                vm.lock().unwrap().jit_threshold = jit_threshold;
+                vm.lock().unwrap().predecode = predecode;
+                vm.lock().unwrap().fuel = Some(fuel);
+                vm.lock().unwrap().total_fuel = true;
+
+                let mut thread = VM::new_thread(&vm);
+                let result = thread.try_call(0, &[]);
+                vm.lock().unwrap().vm = None;
+                result
+            };
+
+            // The budget is shared, so spawning threads doesn't add to it
+            assert_eq!(run(2_000_000), Ok(Value::from(1)));
+            assert_eq!(run(1_000_000), Err(OutOfFuel));
+        }
+    }
+
+    #[test]
+    fn test_call_limits()
+    {
+        // VM::call uses the limits configured on the VM
+        let prog = Assembler::new().parse_str("push 10; call F, 1; ret; F: get_arg 0; jz DONE; get_arg 0; push 1; sub_u64; call F, 1; ret; DONE: push 0; ret;").unwrap();
+        let mut vm = VM::new(prog);
+        vm.lock().unwrap().max_frames = 5;
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| VM::call(&mut vm, 0, &[])));
+        vm.lock().unwrap().vm = None;
+        assert!(result.unwrap_err().downcast_ref::<String>().unwrap().starts_with("stack overflow"));
+    }
+
+    #[test]
+    fn test_stack_overflow()
+    {
+        for &(jit_threshold, predecode) in ENGINES {
+            let overflows = |src: &str, max_stack: usize, max_frames: usize| {
+                let prog = Assembler::new().parse_str(src).unwrap();
+                let vm = VM::new(prog);
+                vm.lock().unwrap().jit_threshold = jit_threshold;
+                vm.lock().unwrap().predecode = predecode;
+                vm.lock().unwrap().max_stack = max_stack;
+                vm.lock().unwrap().max_frames = max_frames;
+
+                let mut thread = VM::new_thread(&vm);
+                let result = panic::catch_unwind(panic::AssertUnwindSafe(|| thread.call(0, &[])));
+
+                vm.lock().unwrap().vm = None;
+                match result {
+                    Ok(_) => false,
+                    Err(payload) => payload.downcast_ref::<String>().unwrap().starts_with("stack overflow"),
+                }
+            };
+
+            // Recursion to a depth of 12 frames, including the main function
+            let recurse = "
+                push 10;
+                call F, 1;
+                ret;
+                F:
+                get_arg 0;
+                jz DONE;
+                get_arg 0;
+                push 1;
+                sub_u64;
+                call F, 1;
+                ret;
+                DONE:
+                push 0;
+                ret;
+            ";
+            assert!(!overflows(recurse, 100, 12));
+            assert!(overflows(recurse, 100, 11));
+
+            assert!(overflows("L: push 0; jmp L;", 100, 100));
+
+            // Deep recursion with large frames, within the default frame limit
+            let big_frames = "
+                push 16000;
+                call F, 1;
+                ret;
+                F:
+                push_0n 250;
+                get_arg 0;
+                jz DONE;
+                get_arg 0;
+                push 1;
+                sub_u64;
+                call F, 1;
+                ret;
+                DONE:
+                push 0;
+                ret;
+            ";
+            assert!(overflows(big_frames, DEFAULT_MAX_STACK, DEFAULT_MAX_FRAMES));
+        }
+    }
+
+    #[test]
+    fn test_max_heap()
+    {
+        let prog = Assembler::new().parse_str("push 0; ret;").unwrap();
+        let vm = VM::new(prog);
+        let mut vm = vm.lock().unwrap();
+        vm.max_heap = Some(1 << 16);
+
+        // Growing past the limit fails and leaves the heap unchanged
+        let heap_size = vm.heap_size();
+        assert_eq!(vm.grow_heap(1 << 17), None);
+        assert_eq!(vm.heap_size(), heap_size);
+        assert_eq!(vm.grow_heap(1 << 16), Some(1 << 16));
+        assert_eq!(vm.heap_size(), 1 << 16);
+
+        vm.vm = None;
+    }
+
+    #[test]
+    fn test_copy_args()
+    {
+        let prog = Assembler::new().parse_str(".data; .u64 7; .code; push 0; ret;").unwrap();
+        let vm = VM::new(prog);
+        let mut vm = vm.lock().unwrap();
+        let heap_size = vm.heap_size();
+
+        let args = ["prog.asm".to_string(), "".to_string(), "foo bar".to_string()];
+        let argv = vm.copy_args(&args).unwrap();
+        assert_eq!(argv, heap_size);
+
+        // The program data is left untouched
+        let mut heap = vm.heap.new_view();
+        assert_eq!(heap.get_slice_mut::<u64>(0, 1)[0], 7);
+
+        let ptrs = heap.get_slice_mut::<u64>(argv, args.len() + 1).to_vec();
+        assert_eq!(ptrs[args.len()], 0);
+        for (ptr, arg) in ptrs.iter().zip(&args) {
+            let str_bytes: &mut [u8] = heap.get_slice_mut(*ptr as usize, arg.len() + 1);
+            assert_eq!(&str_bytes[..arg.len()], arg.as_bytes());
+            assert_eq!(str_bytes[arg.len()], 0);
+        }
+
+        // Arguments that don't fit in the heap limit
+        vm.max_heap = Some(vm.heap_size());
+        assert_eq!(vm.copy_args(&["x".repeat(1 << 20)]), None);
+
+        vm.vm = None;
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown extended opcode")]
+    fn test_predecode_ext_invalid()
+    {
+        // Errors are raised when the instruction executes, not when it's decoded
+        run_src("push 0; jz L; .code; .u8 255; .u8 255; L: push 0; ret;", None, true);
+        run_src(".code; .u8 255; .u8 255; push 0; ret;", None, true);
     }
 
     #[test]
@@ -1903,17 +4038,154 @@ mod tests
         eval_i64(".code; push 0; push 77; set_local 0; get_local 0; ret;", 77);
     }
 
+    #[test]
+    fn test_overflow()
+    {
+        // Results are pushed before the overflow flag
+        eval_i64("push 3; push 4; add_u32_ovf; pop; ret;", 7);
+        eval_i64("push 3; push 4; add_u32_ovf; ret;", 0);
+        eval_i64("push 0xFFFFFFFF; push 1; add_u32_ovf; ret;", 1);
+        eval_i64("push 0xFFFFFFFF; push 1; add_u32_ovf; pop; ret;", 0);
+        eval_i64("push 0; push 1; sub_u32_ovf; ret;", 1);
+        eval_i64("push 0x10000; push 0x10000; mul_u32_ovf; ret;", 1);
+        eval_i64("push 0xFFFF; push 0x10000; mul_u32_ovf; ret;", 0);
+
+        eval_i64("push 0x7FFFFFFF; push 1; add_i32_ovf; ret;", 1);
+        eval_i64("push 0x7FFFFFFF; push 1; add_i32_ovf; pop; ret;", i32::MIN as i64);
+        eval_i64("push 0; push 1; sub_i32_ovf; ret;", 0);
+        eval_i64("push 0; push 1; sub_i32_ovf; pop; ret;", -1);
+        eval_i64("push 0x80000000; push -1; mul_i32_ovf; ret;", 1);
+
+        eval_i64("push -1; push 1; add_u64_ovf; ret;", 1);
+        eval_i64("push -1; push 1; add_u64_ovf; pop; ret;", 0);
+        eval_i64("push 5; push 7; sub_u64_ovf; ret;", 1);
+        eval_i64("push 0x100000000; push 0x100000000; mul_u64_ovf; ret;", 1);
+        eval_i64("push 0x7FFFFFFFFFFFFFFF; push 1; add_i64_ovf; ret;", 1);
+        eval_i64("push 5; push 7; sub_i64_ovf; ret;", 0);
+        eval_i64("push 5; push 7; sub_i64_ovf; pop; ret;", -2);
+        eval_i64("push -3; push 0x4000000000000000; mul_i64_ovf; ret;", 1);
+        eval_i64("push -3; push 7; mul_i64_ovf; pop; ret;", -21);
+    }
+
+    #[test]
+    fn test_mul_wide()
+    {
+        // Low word is pushed first, then the high word
+        eval_i64("push -1; push -1; mul_u64_wide; ret;", -2);
+        eval_i64("push -1; push -1; mul_u64_wide; pop; ret;", 1);
+        eval_i64("push 0x100000000; push 0x100000000; mul_u64_wide; ret;", 1);
+        eval_i64("push 0x100000000; push 0x100000000; mul_u64_wide; pop; ret;", 0);
+        eval_i64("push -1; push -1; mul_i64_wide; ret;", 0);
+        eval_i64("push -1; push -1; mul_i64_wide; pop; ret;", 1);
+        eval_i64("push -2; push 3; mul_i64_wide; ret;", -1);
+        eval_i64("push -2; push 3; mul_i64_wide; pop; ret;", -6);
+    }
+
     #[test]
     fn test_floats()
     {
         eval_i64("push_f32 1.5; push_f32 2.5; add_f32; push_f32 4.0; eq_u64; ret;", 1);
     }
 
+    #[test]
+    fn test_f32_math()
+    {
+        eval_i64("push_f32 -2.5; floor_f32; push_f32 -3.0; eq_f32; ret;", 1);
+        eval_i64("push_f32 -2.5; ceil_f32; push_f32 -2.0; eq_f32; ret;", 1);
+        eval_i64("push_f32 -2.5; round_f32; push_f32 -3.0; eq_f32; ret;", 1);
+        eval_i64("push_f32 2.5; round_f32; push_f32 3.0; eq_f32; ret;", 1);
+        eval_i64("push_f32 -2.5; trunc_f32; push_f32 -2.0; eq_f32; ret;", 1);
+        eval_i64("push_f32 -2.5; abs_f32; push_f32 2.5; eq_f32; ret;", 1);
+        eval_i64("push_f32 1.0; push_f32 2.0; min_f32; push_f32 1.0; eq_f32; ret;", 1);
+        eval_i64("push_f32 1.0; push_f32 2.0; max_f32; push_f32 2.0; eq_f32; ret;", 1);
+        eval_i64("push_f32 0.0; exp_f32; push_f32 1.0; eq_f32; ret;", 1);
+        eval_i64("push_f32 1.0; log_f32; push_f32 0.0; eq_f32; ret;", 1);
+        eval_i64("push_f32 1.0; push_f32 -1.0; atan2_f32; push_f32 2.3561945; eq_f32; ret;", 1);
+        eval_i64("push_f32 -7.5; push_f32 2.0; mod_f32; push_f32 -1.5; eq_f32; ret;", 1);
+
+        // NaN and infinity semantics
+        eval_i64("push_f32 -1.0; sqrt_f32; push_f32 2.0; min_f32; push_f32 2.0; eq_f32; ret;", 1);
+        eval_i64("push_f32 -1.0; sqrt_f32; floor_f32; dup; eq_f32; ret;", 0);
+        eval_i64("push_f32 0.0; log_f32; push_f32 -1e38; lt_f32; ret;", 1);
+        eval_i64("push_f32 -1.0; log_f32; dup; eq_f32; ret;", 0);
+        eval_i64("push_f32 1.0; push_f32 0.0; mod_f32; dup; eq_f32; ret;", 0);
+        eval_i64("push_f32 1e30; exp_f32; push_f32 1e38; gt_f32; ret;", 1);
+
+        // Conversions
+        eval_i64("push_f32 -3e10; f32_to_i64; ret;", -30000001024);
+        eval_i64("push 0xFFFFFFFF; u32_to_f32; push_f32 4294967296.0; eq_f32; ret;", 1);
+        eval_i64("push -1; u64_to_f32; push_f32 18446744073709551616.0; eq_f32; ret;", 1);
+        eval_i64("push_f32 3e9; f32_to_u32; ret;", 3000000000);
+        eval_i64("push_f32 -5.0; f32_to_u32; ret;", 0);
+        eval_i64("push_f32 1e20; f32_to_u64; ret;", -1);
+        eval_i64("push_f32 -1.0; sqrt_f32; f32_to_u64; ret;", 0);
+    }
+
+    #[test]
+    fn test_f64()
+    {
+        eval_i64("push_f64 1.5; push_f64 2.5; add_f64; push_f64 4.0; eq_u64; ret;", 1);
+        eval_i64("push_f64 1.5; push_f64 2.5; sub_f64; push_f64 -1.0; eq_f64; ret;", 1);
+        eval_i64("push_f64 0.1; push_f64 0.2; add_f64; push_f64 0.3; sub_f64; push_f64 1e-15; lt_f64; ret;", 1);
+        eval_i64("push_f64 9.0; sqrt_f64; f64_to_i64; ret;", 3);
+        eval_i64("push_f64 2.0; push_f64 10.0; pow_f64; f64_to_i32; ret;", 1024);
+        eval_i64("push_f64 -7.9; f64_to_i64; ret;", -7);
+        eval_i64("push_i8 -5; i64_to_f64; push_f64 -5.0; eq_f64; ret;", 1);
+        eval_i64("push_i8 3; i32_to_f64; push_f64 2.0; div_f64; push_f64 1.5; eq_f64; ret;", 1);
+        eval_i64("push_f32 1.5; f32_to_f64; push_f64 1.5; eq_f64; ret;", 1);
+        eval_i64("push_f64 0.25; f64_to_f32; push_f32 0.25; eq_f32; ret;", 1);
+
+        // NaN compares unequal to itself
+        eval_i64("push_f64 -1.0; sqrt_f64; dup; eq_f64; ret;", 0);
+        eval_i64("push_f64 -1.0; sqrt_f64; dup; ne_f64; ret;", 1);
+        eval_i64("push_f64 0.0; push_f64 0.0; div_f64; f64_to_i64; ret;", 0);
+
+        // Out of range conversions saturate
+        eval_i64("push_f64 1e300; f64_to_i32; sx_i32_i64; ret;", i32::MAX as i64);
+        eval_i64("push_f64 1e300; f64_to_f32; push_f64 1.0; f64_to_f32; div_f32; push_f32 1e38; gt_f32; ret;", 1);
+    }
+
+    #[test]
+    fn test_value_f64()
+    {
+        assert_eq!(Value::from(3.25f64).as_f64(), 3.25);
+        assert_eq!(Value::from(-0.0f64).as_u64(), 1 << 63);
+        assert!(Value::from(f64::NAN).as_f64().is_nan());
+    }
+
     #[test]
     fn test_loop()
     {
         // Simple loop
         eval_i64("push_i8 0; LOOP: push_i8 1; add_u64; dup; push_i8 10; eq_u64; jz LOOP; ret;", 10);
+
+        // Explicit short jumps
+        eval_i64("push_i8 0; LOOP: push_i8 1; add_u64; dup; push_i8 10; eq_u64; jz_8 LOOP; ret;", 10);
+        eval_i64("push_i8 1; jnz_8 L; push_i8 7; ret; L: push_i8 5; jmp_8 M; N: ret; M: jmp_8 N;", 5);
+
+        // Relaxed jumps and calls around a long jump
+        eval_i64("push_i8 0; call F, 1; ret; F: get_arg 0; jz L; .zero 300; L: push_i8 3; jmp M; M: ret;", 3);
+    }
+
+    #[test]
+    fn test_atomics()
+    {
+        let data = ".data; .zero 16; .code;";
+        eval_i64(&format!("{data} push 8; push 77; atomic_store_u64; push 8; atomic_load_u64; ret;"), 77);
+        eval_i64(&format!("{data} push 4; push -1; atomic_store_u32; push 4; atomic_load_u32; ret;"), 0xFFFF_FFFF);
+
+        // Compare-and-swap pushes the value found in memory
+        eval_i64(&format!("{data} push 4; push 0; push 5; atomic_cas_u32; push 4; atomic_load_u32; add_u64; ret;"), 5);
+        eval_i64(&format!("{data} push 4; push 1; push 5; atomic_cas_u32; push 4; atomic_load_u32; add_u64; ret;"), 0);
+
+        // Read-modify-write operations push the previous value
+        eval_i64(&format!("{data} push 0; push 3; atomic_fetch_add_u32; push 0; push 2; atomic_fetch_add_u32; add_u64; ret;"), 3);
+        eval_i64(&format!("{data} push 0; push 1; atomic_fetch_sub_u32; pop; push 0; atomic_load_u32; ret;"), 0xFFFF_FFFF);
+        eval_i64(&format!("{data} push 8; push 0b1100; atomic_fetch_or_u64; pop; push 8; push 0b1010; atomic_fetch_and_u64; ret;"), 0b1100);
+        eval_i64(&format!("{data} push 8; push 0b0110; atomic_fetch_xor_u64; pop; push 8; push 0b0101; atomic_fetch_xor_u64; ret;"), 0b0110);
+        eval_i64(&format!("{data} push 8; push 9; atomic_xchg_u64; pop; push 8; push 4; atomic_xchg_u64; ret;"), 9);
+        eval_i64(&format!("{data} push 0; push 7; atomic_xchg_u32; push 0; push 1; atomic_fetch_sub_u64; add_u64; ret;"), 7);
+        eval_i64(&format!("{data} fence; push 1; ret;"), 1);
     }
 
     #[test]
@@ -1923,6 +4195,30 @@ mod tests
         eval_i64(".data; .zero 255; .code; push_i8 0; push_i8 77; store_u8; push_i8 11; ret;", 11);
     }
 
+    #[test]
+    fn test_load_store_off()
+    {
+        eval_i64(".data; .zero 16; .code; push 4; push 77; store_u32_off 8; push 12; load_u32; ret;", 77);
+        eval_i64(".data; .u16 0; .u16 0; .u16 555; .code; push 0; load_u16_off 4; ret;", 555);
+        eval_i64(".data; .zero 16; .code; push 8; push -1; store_u8_off 1; push 0; load_u64_off 8; ret;", 0xFF00);
+        eval_i64(".data; .zero 8; FOO: .u64 0; .code; push FOO; push 3; store_u64_off 0; load_global_u64 FOO; ret;", 3);
+    }
+
+    #[test]
+    fn test_global_load_store()
+    {
+        eval_i64(".data; .zero 3; FOO: .u8 9; .code; load_global_u8 FOO; ret;", 9);
+        eval_i64(".data; .zero 8; FOO: .u64 0; .code; push 1234; store_global_u64 FOO; push FOO; load_u64; ret;", 1234);
+        eval_i64(".data; .zero 8; .code; push 0x12345678; store_global_u32 4; load_global_u16 6; ret;", 0x1234);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_global_load_oob()
+    {
+        eval_src(".data; .zero 8; .code; load_global_u64 4; ret;");
+    }
+
     #[test]
     fn test_setn()
     {
@@ -224,6 +224,55 @@ pub fn window_wait_event(thread: &mut Thread, p_event: Value)
     }
 }
 
+/// Register a callback called by the event loop for each window event
+/// The event is written into the given event struct before each call
+pub fn window_on_event(thread: &mut Thread, window_id: Value, p_event: Value, callback: Value)
+{
+    if thread.id != 0 {
+        panic!("window functions should only be called from the main thread");
+    }
+
+    get_window(window_id.as_u32());
+
+    let p_event = p_event.as_usize();
+    let callback = callback.as_u64();
+
+    // A null callback unregisters the callback
+    let mut vm = thread.vm.lock().unwrap();
+    vm.event_state.window_callback = if callback == 0 {
+        None
+    } else {
+        assert!(p_event != 0);
+        Some((p_event, callback))
+    };
+}
+
+/// Wait up to a timeout for window events and
+/// pass them to the callback registered with window_on_event
+pub fn dispatch_window_events(thread: &mut Thread, p_event: usize, callback: u64, timeout_ms: u64)
+{
+    // Collect the events first, since the callback may create its own event pump
+    let events: Vec<Event> = {
+        let mut event_pump = get_sdl_context().event_pump().unwrap();
+        let mut events = Vec::new();
+
+        if let Some(event) = event_pump.wait_event_timeout(timeout_ms as u32) {
+            events.push(event);
+        }
+
+        events.extend(event_pump.poll_iter());
+        events
+    };
+
+    for event in events {
+        let c_event: &mut CEvent = &mut thread.get_heap_slice_mut(p_event, 1)[0];
+
+        if translate_event(event, c_event) {
+            thread.call(callback, &[Value::from(p_event)]);
+        }
+    }
+}
+
 /// Translate an SDL event and write the result to an event struct in memory
 fn translate_event(sdl_event: Event, c_event: &mut CEvent) -> bool
 {